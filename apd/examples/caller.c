@@ -0,0 +1,32 @@
+/*
+ * Sample caller of the apatch_core C API
+ *
+ *   cargo ndk -t arm64-v8a build --release
+ *   $CC -Iinclude examples/caller.c \
+ *       target/aarch64-linux-android/release/libapatch_core.a -o caller
+ */
+
+#include <stdio.h>
+
+#include "apatch_core.h"
+
+static void print_and_free(const char *name, char *json)
+{
+    if (json == NULL) {
+        fprintf(stderr, "%s failed\n", name);
+        return;
+    }
+    printf("%s: %s\n", name, json);
+    free_json_string(json);
+}
+
+int main(void)
+{
+    print_and_free("status", get_status_json());
+    print_and_free("modules", list_modules_json());
+    print_and_free("mount report", get_mount_report_json());
+    print_and_free("mount_mode", get_config_json("mount_mode"));
+    if (set_config("log_level", "debug") != 0)
+        fprintf(stderr, "set_config failed\n");
+    return 0;
+}
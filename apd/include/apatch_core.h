@@ -0,0 +1,64 @@
+/*
+ * apatch_core - queries of the APatch daemon state for the manager
+ *
+ * Functions are only ever added, existing signatures never change.
+ *
+ * Functions returning a string return a NUL terminated JSON string owned by
+ * the caller, release it with free_json_string(). NULL is returned on error.
+ */
+
+#ifndef APATCH_CORE_H
+#define APATCH_CORE_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Release a string returned by one of the `_json` functions
+ *
+ * # Safety
+ * `json` must be NULL or a string returned by one of the `_json` functions,
+ * and must not be used afterwards.
+ */
+void free_json_string(char *json);
+
+/**
+ * Value of config `key` as a JSON string, JSON null if it is not set
+ *
+ * # Safety
+ * `key` must be a NUL terminated string.
+ */
+char *get_config_json(const char *key);
+
+/**
+ * Per module plan of the next mount, without touching anything
+ */
+char *get_mount_report_json(void);
+
+/**
+ * Version, mount mode, module count and the last boot health record
+ */
+char *get_status_json(void);
+
+/**
+ * Array of installed modules, module.prop keys plus state flags
+ */
+char *list_modules_json(void);
+
+/**
+ * Set config `key` to `value`, a NULL `value` removes the key. Returns 0 on
+ * success, -1 on error.
+ *
+ * # Safety
+ * `key` must be a NUL terminated string, `value` NULL or one.
+ */
+int set_config(const char *key, const char *value);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* APATCH_CORE_H */
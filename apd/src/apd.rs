@@ -11,6 +11,7 @@ use rustix::thread::{Gid, Uid, set_thread_res_gid, set_thread_res_uid};
 use crate::pty::prepare_pty;
 use crate::{
     defs,
+    namespace::{self, NamespaceMode},
     utils::{self, umask},
 };
 
@@ -186,6 +187,16 @@ pub fn root_shell() -> Result<()> {
     if PathBuf::from(defs::AP_RC_PATH).exists() && env::var("ENV").is_err() {
         command = command.env("ENV", defs::AP_RC_PATH);
     }
+    // joining a namespace needs a single threaded process, so before the pty
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mode = if mount_master {
+            NamespaceMode::Global
+        } else {
+            namespace::caller_uid().map_or_else(namespace::default_mode, namespace::mode_for_uid)
+        };
+        namespace::enter(mode);
+    }
     #[cfg(target_os = "android")]
     if !matches.opt_present("no-pty") {
         if let Err(e) = prepare_pty() {
@@ -199,15 +210,6 @@ pub fn root_shell() -> Result<()> {
         command.pre_exec(move || {
             umask(0o22);
             utils::switch_cgroups();
-
-            // switch to global mount namespace
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            let global_namespace_enable =
-                std::fs::read_to_string(defs::GLOBAL_NAMESPACE_FILE).unwrap_or("0".to_string());
-            if global_namespace_enable.trim() == "1" || mount_master {
-                let _ = utils::switch_mnt_ns(1);
-            }
-
             set_identity(uid, gid);
 
             Result::Ok(())
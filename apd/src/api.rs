@@ -0,0 +1,180 @@
+//! Library API for the manager
+//!
+//! Everything here only reads the state below `/data/adb`, apart from
+//! [`set_config`] writing apd.toml, and keeps no process-global state, so it can
+//! be called from any thread of the manager. Privileged mutations (install,
+//! enable, mount, ...) stay in the daemon and are deliberately not exported.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Result;
+use serde::Serialize;
+
+pub use crate::status::Health;
+use crate::{config, critical, defs, magic_mount, messages::Message, module, utils};
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub version_name: String,
+    pub version_code: String,
+    pub mount_mode: String,
+    pub modules: usize,
+    /// record of the last boot, `None` before the first boot with apd
+    pub health: Option<Health>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ModuleMountPlan {
+    pub id: String,
+    pub mounted: bool,
+    /// why the module is not mounted
+    pub reason: Option<String>,
+    /// code of `reason` in the message catalog
+    pub reason_code: Option<&'static str>,
+    pub partitions: Vec<String>,
+    pub kind: module::ModuleKind,
+    /// critical paths which will be skipped as the module was not acknowledged
+    pub blocked_critical: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PartitionPlan {
+    pub target: String,
+    /// modules providing files below `target`, the first one providing a file wins
+    pub modules: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MountReport {
+    pub mode: String,
+    pub modules: Vec<ModuleMountPlan>,
+    /// magic mount targets, empty in other modes as the metamodule decides
+    pub partitions: Vec<PartitionPlan>,
+}
+
+/// The value of config `key` as `apd config get` prints it, `None` if not set
+pub fn get_config(key: &str) -> Result<Option<String>> {
+    config::value(key)
+}
+
+/// Set config `key` as `apd config set` does, `None` removes it
+pub fn set_config(key: &str, value: Option<&str>) -> Result<()> {
+    config::set_key(key, value)
+}
+
+/// Installed modules with their module.prop and state flags
+pub fn list_modules() -> Vec<HashMap<String, String>> {
+    module::_list_modules(defs::MODULE_DIR)
+}
+
+pub fn get_status() -> Status {
+    Status {
+        version_name: defs::VERSION_NAME.trim().to_string(),
+        version_code: defs::VERSION_CODE.trim().to_string(),
+        mount_mode: utils::get_mount_mode(),
+        modules: std::fs::read_dir(defs::MODULE_DIR).map_or(0, |dir| {
+            dir.flatten()
+                .filter(|e| e.path().join("module.prop").exists())
+                .count()
+        }),
+        health: Health::load(),
+    }
+}
+
+/// What the next mount would do for every module, without touching anything
+pub fn get_mount_report() -> Result<MountReport> {
+    let mode = utils::get_mount_mode();
+    let patterns = critical::Patterns::load();
+    let blocked = module::blocked_modules();
+    let mut modules = Vec::new();
+    let mut partitions: Vec<PartitionPlan> = Vec::new();
+    if mode == defs::MOUNT_MODE_MAGIC
+        && let Some(local) = magic_mount::local_overlay()
+    {
+        let targets = magic_mount::mount_targets(&local);
+        partitions.extend(targets.iter().map(|target| PartitionPlan {
+            target: target.clone(),
+            modules: vec![magic_mount::LOCAL_OVERLAY_NAME.to_string()],
+        }));
+        modules.push(ModuleMountPlan {
+            id: magic_mount::LOCAL_OVERLAY_NAME.to_string(),
+            mounted: true,
+            reason: None,
+            reason_code: None,
+            partitions: targets
+                .iter()
+                .map(|target| target.trim_start_matches('/').to_string())
+                .collect(),
+            kind: module::ModuleKind::Payload,
+            blocked_critical: Vec::new(),
+        });
+    }
+    for entry in std::fs::read_dir(defs::MODULE_DIR)?.flatten() {
+        let path = entry.path();
+        if !path.join("module.prop").exists() {
+            continue;
+        }
+        let module_partitions: Vec<String> = std::iter::once("system")
+            .chain(critical::PARTITIONS)
+            .filter(|partition| path.join(partition).is_dir())
+            .map(str::to_string)
+            .collect();
+        let kind = module::classify(&path);
+        let id = entry.file_name().to_string_lossy().into_owned();
+        let reason = mount_skip_reason(&path, &mode, kind).or_else(|| blocked.get(&id).cloned());
+        let blocked_critical = if reason.is_none() && !critical::is_acknowledged(&path) {
+            patterns.scan(&path)
+        } else {
+            Vec::new()
+        };
+        if reason.is_none() && mode == defs::MOUNT_MODE_MAGIC {
+            for target in magic_mount::mount_targets(&path) {
+                match partitions.iter_mut().find(|p| p.target == target) {
+                    Some(plan) => plan.modules.push(id.clone()),
+                    None => partitions.push(PartitionPlan {
+                        target,
+                        modules: vec![id.clone()],
+                    }),
+                }
+            }
+        }
+        modules.push(ModuleMountPlan {
+            id,
+            mounted: reason.is_none(),
+            reason_code: reason.as_ref().map(Message::code),
+            reason: reason.as_ref().map(Message::to_string),
+            partitions: module_partitions,
+            kind,
+            blocked_critical,
+        });
+    }
+    Ok(MountReport {
+        mode,
+        modules,
+        partitions,
+    })
+}
+
+fn mount_skip_reason(path: &Path, mode: &str, kind: module::ModuleKind) -> Option<Message> {
+    if mode == defs::MOUNT_MODE_DISABLED {
+        Some(Message::MountSkipMountDisabled {})
+    } else if crate::flags::is_set(path.join(defs::DISABLE_FILE_NAME)) {
+        Some(Message::MountSkipDisabled {})
+    } else if crate::flags::is_set(path.join(defs::REMOVE_FILE_NAME)) {
+        Some(Message::MountSkipRemoved {})
+    } else if crate::flags::is_set(path.join(defs::SKIP_MOUNT_FILE_NAME)) {
+        Some(Message::MountSkipSkipMount {})
+    } else if kind == module::ModuleKind::ScriptOnly {
+        Some(Message::MountSkipScriptOnly {})
+    } else if kind == module::ModuleKind::Empty {
+        Some(Message::MountSkipEmpty {})
+    } else if mode == defs::MOUNT_MODE_MAGIC && crate::targeted::is_targeted(path) {
+        Some(Message::MountSkipTargeted {})
+    } else if mode == defs::MOUNT_MODE_MAGIC
+        && crate::flags::is_set(path.join(defs::DEFER_MOUNT_FILE_NAME))
+    {
+        Some(Message::MountSkipDeferred {})
+    } else {
+        None
+    }
+}
@@ -1,22 +1,183 @@
+//! Tools in `/data/adb/ap/bin`
+//!
+//! busybox is shipped by the manager, resetprop and magiskpolicy are links to
+//! apd itself. Each is checked for existing, being executable and being built
+//! for the ABI of the device. Broken links are recreated, a broken busybox
+//! cannot be, the steps needing it are skipped then instead of failing the
+//! whole post-fs-data stage.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::Command,
+};
+
 use anyhow::Result;
 use const_format::concatcp;
+use log::warn;
+use serde::Serialize;
 
-use crate::{defs::BINARY_DIR, utils};
+use crate::{
+    defs::{BINARY_DIR, DAEMON_PATH},
+    utils,
+};
 
 pub const RESETPROP_PATH: &str = concatcp!(BINARY_DIR, "resetprop");
 pub const BUSYBOX_PATH: &str = concatcp!(BINARY_DIR, "busybox");
 pub const MAGISKPOLICY_PATH: &str = concatcp!(BINARY_DIR, "magiskpolicy");
 
-pub fn ensure_binaries() -> Result<()> {
+struct Tool {
+    name: &'static str,
+    path: &'static str,
+    /// links to apd are recreated, other tools can only be chmod'ed
+    link_to_apd: bool,
+    /// what is lost while the tool is broken
+    features: &'static [&'static str],
+}
+
+const TOOLS: &[Tool] = &[
+    Tool {
+        name: "busybox",
+        path: BUSYBOX_PATH,
+        link_to_apd: false,
+        features: &["common scripts", "module scripts", "webui exec"],
+    },
+    Tool {
+        name: "resetprop",
+        path: RESETPROP_PATH,
+        link_to_apd: true,
+        features: &["resetprop in scripts"],
+    },
+    Tool {
+        name: "magiskpolicy",
+        path: MAGISKPOLICY_PATH,
+        link_to_apd: true,
+        features: &["magiskpolicy in scripts"],
+    },
+];
+
+#[derive(Serialize, Debug)]
+pub struct BinaryStatus {
+    pub name: &'static str,
+    pub path: &'static str,
+    /// first line of its version output, `None` if it did not run
+    pub version: Option<String>,
+    /// why the tool is unusable, `None` if it is healthy
+    pub problem: Option<String>,
+    pub features: &'static [&'static str],
+}
+
+/// ELF `e_machine` of the ABI apd was built for
+fn native_machine() -> Option<u16> {
+    match std::env::consts::ARCH {
+        "aarch64" => Some(183),
+        "arm" => Some(40),
+        "x86_64" => Some(62),
+        "x86" => Some(3),
+        "riscv64" => Some(243),
+        _ => None,
+    }
+}
 
-    utils::ensure_binary(BUSYBOX_PATH)?;
-    let resetprop_link = RESETPROP_PATH;
-    let _ = std::fs::remove_file(resetprop_link);
-    std::os::unix::fs::symlink("/data/adb/apd", resetprop_link)?;
+fn elf_machine(path: &Path) -> io::Result<u16> {
+    let mut header = [0u8; 20];
+    File::open(path)?.read_exact(&mut header)?;
+    if header[..4] != *b"\x7fELF" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not an ELF file"));
+    }
+    let machine = [header[18], header[19]];
+    // EI_DATA, 1 is little endian
+    Ok(if header[5] == 1 {
+        u16::from_le_bytes(machine)
+    } else {
+        u16::from_be_bytes(machine)
+    })
+}
 
-    let magiskpolicy_link = MAGISKPOLICY_PATH;
-    let _ = std::fs::remove_file(magiskpolicy_link);
-    std::os::unix::fs::symlink("/data/adb/apd", magiskpolicy_link)?;
+fn problem_of(path: &Path) -> Option<String> {
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => return Some(format!("missing: {e}")),
+    };
+    if metadata.permissions().mode() & 0o111 == 0 {
+        return Some("not executable".to_string());
+    }
+    match (elf_machine(path), native_machine()) {
+        (Err(e), _) => Some(format!("unreadable: {e}")),
+        (Ok(machine), Some(native)) if machine != native => Some(format!(
+            "built for ELF machine {machine}, the device needs {native}"
+        )),
+        _ => None,
+    }
+}
 
+fn repair(tool: &Tool) -> Result<()> {
+    if tool.link_to_apd {
+        let _ = std::fs::remove_file(tool.path);
+        std::os::unix::fs::symlink(DAEMON_PATH, tool.path)?;
+    } else {
+        utils::ensure_binary(tool.path)?;
+    }
     Ok(())
 }
+
+fn version_of(tool: &Tool) -> Option<String> {
+    if tool.link_to_apd {
+        return Some(format!("apd {}", crate::defs::VERSION_NAME.trim()));
+    }
+    let output = Command::new(tool.path).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .next()
+        .map(|line| line.trim().to_string())
+}
+
+/// Check every tool, repairing what can be repaired, returns the broken ones
+pub fn ensure_binaries() -> Vec<BinaryStatus> {
+    let mut broken = Vec::new();
+    for tool in TOOLS {
+        // links are always recreated, apd may have moved since they were made
+        if tool.link_to_apd || problem_of(Path::new(tool.path)).is_some() {
+            if let Err(e) = repair(tool) {
+                warn!("Failed to repair {}: {}", tool.path, e);
+            }
+        }
+        if let Some(problem) = problem_of(Path::new(tool.path)) {
+            warn!(
+                "{} is unusable ({problem}), disabled: {}",
+                tool.path,
+                tool.features.join(", ")
+            );
+            broken.push(BinaryStatus {
+                name: tool.name,
+                path: tool.path,
+                version: None,
+                problem: Some(problem),
+                features: tool.features,
+            });
+        }
+    }
+    broken
+}
+
+/// State of every tool, without repairing anything
+pub fn binaries() -> Vec<BinaryStatus> {
+    TOOLS
+        .iter()
+        .map(|tool| {
+            let problem = problem_of(Path::new(tool.path));
+            BinaryStatus {
+                name: tool.name,
+                path: tool.path,
+                version: problem.is_none().then(|| version_of(tool)).flatten(),
+                problem,
+                features: tool.features,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,283 @@
+//! `apd backup` and `apd restore`: settings and modules in one zstd compressed tar
+//!
+//! The archive starts with `manifest.json`, followed by the settings of
+//! `/data/adb/ap` below `config/` and the module dirs below `modules/<id>/`.
+//! Module zips are not kept after install, so modules are backed up as their
+//! installed tree, `module.img` included. Logs and the records of a boot are
+//! left out, as are modules flagged for removal.
+//!
+//! Restore stages every module in `/data/adb/modules_update` like an update,
+//! it is moved into place on the next boot. The installer does not run again,
+//! the tree already is its result. Settings are written right away.
+
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{defs, flags, module, restorecon};
+
+/// Version of the archive layout, raised on incompatible changes
+const SCHEMA: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const ZSTD_LEVEL: i32 = 9;
+
+/// Settings of `/data/adb/ap` which are backed up, the only names restored
+const CONFIG_FILES: &[&str] = &[
+    "apd.toml",
+    "package_config",
+    "manager_pkg",
+    "umount_packages",
+    "critical_paths",
+    "hosts",
+    "hosts_enable",
+    "prop_overrides",
+    "shell_su_enable",
+    "ignore_magisk",
+    "revoke_on_reinstall_enable",
+    "compat_shims_enable",
+    "file_contexts_enable",
+    "factory_props_enable",
+    "script_timeout",
+    "mount_mode",
+    "bootloop_threshold",
+    "data_remount_action",
+];
+/// Flags of a module which belong to the device it was on
+const SKIPPED_FLAGS: &[&str] = &[defs::UPDATE_FILE_NAME, defs::REMOVE_FILE_NAME];
+
+#[derive(Serialize, Deserialize)]
+struct ModuleEntry {
+    id: String,
+    enabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    schema: u32,
+    created: u64,
+    apd_version: String,
+    config: Vec<String>,
+    modules: Vec<ModuleEntry>,
+}
+
+fn append_file(
+    builder: &mut tar::Builder<impl std::io::Write>,
+    name: &str,
+    data: &[u8],
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    );
+    header.set_cksum();
+    builder
+        .append_data(&mut header, name, data)
+        .with_context(|| format!("Failed to add {name}"))
+}
+
+/// Add the module dir at `module_path` below `modules/<id>/`
+fn append_module(
+    builder: &mut tar::Builder<impl std::io::Write>,
+    module_path: &Path,
+    id: &str,
+) -> Result<()> {
+    for entry in walkdir::WalkDir::new(module_path).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(module_path)?;
+        if entry.depth() == 1 && SKIPPED_FLAGS.iter().any(|flag| relative == Path::new(flag)) {
+            continue;
+        }
+        let name = Path::new("modules").join(id).join(relative);
+        builder
+            .append_path_with_name(entry.path(), &name)
+            .with_context(|| format!("Failed to add {}", entry.path().display()))?;
+    }
+    Ok(())
+}
+
+/// `apd backup <output>`
+pub fn backup(output: &Path) -> Result<()> {
+    let mut modules = Vec::new();
+    module::foreach_module(module::ModuleType::All, |module_path| {
+        if flags::is_set(module_path.join(defs::REMOVE_FILE_NAME)) {
+            return Ok(());
+        }
+        let id = module_path.file_name().unwrap_or_default().to_string_lossy();
+        modules.push(ModuleEntry {
+            id: id.into_owned(),
+            enabled: !flags::is_set(module_path.join(defs::DISABLE_FILE_NAME)),
+        });
+        Ok(())
+    })?;
+    modules.sort_by(|a, b| a.id.cmp(&b.id));
+    let config: Vec<String> = CONFIG_FILES
+        .iter()
+        .filter(|name| flags::is_set(Path::new(defs::WORKING_DIR).join(name)))
+        .map(|name| name.to_string())
+        .collect();
+    let manifest = Manifest {
+        schema: SCHEMA,
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        apd_version: defs::VERSION_CODE.trim().to_string(),
+        config,
+        modules,
+    };
+
+    let tmp = PathBuf::from(format!("{}.tmp", output.display()));
+    let write = || -> Result<()> {
+        let file =
+            File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+        let encoder = zstd::Encoder::new(file, ZSTD_LEVEL).context("Failed to start zstd")?;
+        let mut builder = tar::Builder::new(encoder);
+        // links inside a module must stay links
+        builder.follow_symlinks(false);
+        append_file(&mut builder, MANIFEST, &serde_json::to_vec_pretty(&manifest)?)?;
+        for name in &manifest.config {
+            let data = fs::read(Path::new(defs::WORKING_DIR).join(name))?;
+            append_file(&mut builder, &format!("config/{name}"), &data)?;
+        }
+        for module in &manifest.modules {
+            let module_path = Path::new(defs::MODULE_DIR).join(&module.id);
+            append_module(&mut builder, &module_path, &module.id)?;
+        }
+        let encoder = builder.into_inner().context("Failed to finish tar")?;
+        encoder.finish().context("Failed to finish zstd")?.sync_all()?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    fs::rename(&tmp, output)
+        .with_context(|| format!("Failed to rename {} to {}", tmp.display(), output.display()))?;
+    println!(
+        "{}: {} setting(s), {} module(s)",
+        output.display(),
+        manifest.config.len(),
+        manifest.modules.len()
+    );
+    Ok(())
+}
+
+/// Stage the restored module at `staged`, checked before, to be moved into
+/// place on the next boot
+fn stage_module(staged: &Path, entry: &ModuleEntry) -> Result<()> {
+    let update_dir = Path::new(defs::MODULE_UPDATE_DIR).join(&entry.id);
+    if update_dir.exists() {
+        warn!("replacing the pending update of {}", entry.id);
+        fs::remove_dir_all(&update_dir)
+            .with_context(|| format!("Failed to remove {}", update_dir.display()))?;
+    }
+    let disable = staged.join(defs::DISABLE_FILE_NAME);
+    if entry.enabled {
+        if disable.exists() {
+            fs::remove_file(&disable)?;
+        }
+    } else {
+        flags::set(&disable)?;
+    }
+    fs::rename(staged, &update_dir).with_context(|| {
+        format!("Failed to move {} to {}", staged.display(), update_dir.display())
+    })?;
+    // like on install, the module shows up as installed until the reboot
+    let module_dir = Path::new(defs::MODULE_DIR).join(&entry.id);
+    if !module_dir.exists() {
+        fs::create_dir(&module_dir)
+            .with_context(|| format!("Failed to create {}", module_dir.display()))?;
+    }
+    Ok(())
+}
+
+fn restore_config(staging: &Path, name: &str) -> Result<()> {
+    ensure!(CONFIG_FILES.contains(&name), "unknown setting {name}");
+    let target = Path::new(defs::WORKING_DIR).join(name);
+    let tmp = Path::new(defs::WORKING_DIR).join(format!(".{name}.restore"));
+    fs::copy(staging.join("config").join(name), &tmp)
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    restorecon::lsetfilecon(&tmp, restorecon::ADB_CON)?;
+    fs::rename(&tmp, &target)
+        .with_context(|| format!("Failed to rename {} to {}", tmp.display(), target.display()))
+}
+
+/// `apd restore <input>`
+pub fn restore(input: &Path) -> Result<()> {
+    let file = File::open(input).with_context(|| format!("Failed to open {}", input.display()))?;
+    let decoder = zstd::Decoder::new(file).context("Failed to start zstd")?;
+    let mut archive = tar::Archive::new(decoder);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    let mut entries = archive.entries().context("Failed to read the backup")?;
+
+    let mut first = entries.next().context("the backup is empty")??;
+    ensure!(
+        first.path()?.as_ref() == Path::new(MANIFEST),
+        "{} is not an apd backup",
+        input.display()
+    );
+    let mut content = String::new();
+    first.read_to_string(&mut content)?;
+    let manifest: Manifest =
+        serde_json::from_str(&content).context("Failed to parse the backup manifest")?;
+    if manifest.schema > SCHEMA {
+        bail!(
+            "the backup has format {}, this apd reads up to {}, update APatch first",
+            manifest.schema,
+            SCHEMA
+        );
+    }
+    // ids name dirs, one like `../x` would leave the staging dir
+    if let Some(module) = manifest.modules.iter().find(|m| !module::valid_module_id(&m.id)) {
+        bail!("invalid module id in the backup: {}", module.id);
+    }
+
+    let _guard = module::lock_modules()?;
+    let staging = Path::new(defs::WORKING_DIR).join(".restore");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    let result = (|| -> Result<()> {
+        for entry in entries {
+            let mut entry = entry?;
+            // refuses paths leaving the staging dir
+            entry.unpack_in(&staging)?;
+        }
+        // nothing is staged unless every module passes
+        for module in &manifest.modules {
+            module::ModuleInfo::parse(&staging.join("modules").join(&module.id))
+                .with_context(|| format!("module {} of the backup", module.id))?;
+        }
+        fs::create_dir_all(defs::MODULE_UPDATE_DIR)?;
+        for module in &manifest.modules {
+            stage_module(&staging.join("modules").join(&module.id), module)?;
+        }
+        for name in &manifest.config {
+            restore_config(&staging, name)?;
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_dir_all(&staging);
+    result?;
+    if !manifest.modules.is_empty() {
+        module::mark_update()?;
+    }
+    println!(
+        "restored {} setting(s), {} module(s) are applied on the next boot",
+        manifest.config.len(),
+        manifest.modules.len()
+    );
+    Ok(())
+}
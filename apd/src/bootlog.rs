@@ -0,0 +1,201 @@
+//! Boot log capture
+//!
+//! post-fs-data starts `apd boot-log`, which streams `logcat` and `dmesg -w`
+//! into `log/logcat.log` and `log/dmesg.log` until boot-completed. A file
+//! reaching `boot_log_max_kb` is rotated, the previous segments are kept
+//! gzipped as `<file>.1.gz` (newest) and `<file>.2.gz`. `log/boot_log.json`
+//! holds the boot id, the capture window and the files written, so the manager
+//! can bundle the logs of exactly one boot.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use flate2::{Compression, write::GzEncoder};
+use log::{info, warn};
+use serde::Serialize;
+use signal_hook::consts::signal::{SIGINT, SIGTERM};
+
+use crate::{config, defs, script_history, shutdown, utils};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Gzipped segments kept besides the file being written
+const KEPT_SEGMENTS: usize = 2;
+
+const SOURCES: [(&str, &[&str]); 2] = [
+    (
+        "logcat.log",
+        &[
+            "logcat",
+            "-b",
+            "main,system,crash",
+            "DrmLibFs:S",
+            "logcatcher-bootlog:S",
+        ],
+    ),
+    ("dmesg.log", &["dmesg", "-w"]),
+];
+
+#[derive(Serialize)]
+struct Index {
+    boot_id: String,
+    started: u64,
+    /// `None` while the capture is running
+    stopped: Option<u64>,
+    files: Vec<String>,
+}
+
+impl Index {
+    fn store(&self) -> Result<()> {
+        fs::write(defs::BOOT_LOG_INDEX_FILE, serde_json::to_string(self)? + "\n")
+            .with_context(|| format!("Failed to write {}", defs::BOOT_LOG_INDEX_FILE))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Start `apd boot-log` in its own process group, tracked for `apd shutdown`
+pub fn spawn() -> Result<()> {
+    let child = unsafe {
+        Command::new(defs::DAEMON_PATH)
+            .arg("boot-log")
+            .process_group(0)
+            .pre_exec(|| {
+                utils::switch_cgroups();
+                Ok(())
+            })
+            .spawn()
+    }
+    .context("Failed to start boot log capture")?;
+    shutdown::track(child.id());
+    Ok(())
+}
+
+fn segment(path: &Path, n: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{n}.gz", path.display()))
+}
+
+/// Compress `path` into `<path>.1.gz`, shifting the older segments up
+fn rotate(path: &Path) -> io::Result<()> {
+    for n in (1..KEPT_SEGMENTS).rev() {
+        let from = segment(path, n);
+        if from.exists() {
+            fs::rename(&from, segment(path, n + 1))?;
+        }
+    }
+    let mut encoder = GzEncoder::new(File::create(segment(path, 1))?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Copy the lines of `source` to `path` until it ends, rotating at `max_size`
+fn capture(source: impl Read, path: &Path, max_size: u64) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let mut written = 0;
+    for line in BufReader::new(source).split(b'\n') {
+        let mut line = line?;
+        line.push(b'\n');
+        if written > 0 && written + line.len() as u64 > max_size {
+            rotate(path)?;
+            file = File::create(path)?;
+            written = 0;
+        }
+        file.write_all(&line)?;
+        written += line.len() as u64;
+    }
+    Ok(())
+}
+
+fn start(file: &'static str, args: &[&str], max_size: u64) -> Result<(Child, JoinHandle<()>)> {
+    let mut child = unsafe {
+        Command::new(args[0])
+            .args(&args[1..])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .pre_exec(|| {
+                utils::switch_cgroups();
+                Ok(())
+            })
+            .spawn()
+    }
+    .with_context(|| format!("Failed to start {}", args[0]))?;
+    let stdout = child.stdout.take().context("no stdout")?;
+    let path = Path::new(defs::APATCH_LOG_FOLDER).join(file);
+    let capture = thread::spawn(move || {
+        if let Err(e) = capture(stdout, &path, max_size) {
+            warn!("Failed to capture {}: {}", path.display(), e);
+        }
+    });
+    Ok((child, capture))
+}
+
+/// `apd boot-log`: capture until boot-completed, a SIGTERM or, if configured,
+/// `boot_log_duration` seconds passed
+pub fn run() -> Result<()> {
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, stop.clone())?;
+    }
+
+    let mut index = Index {
+        boot_id: script_history::boot_id(),
+        started: now(),
+        stopped: None,
+        files: SOURCES.iter().map(|(file, _)| file.to_string()).collect(),
+    };
+    index.store()?;
+
+    let max_size = config::boot_log_max_kb() * 1024;
+    let mut captures = Vec::new();
+    for (file, args) in SOURCES {
+        match start(file, args, max_size) {
+            Ok(capture) => captures.push(capture),
+            Err(e) => warn!("{e:#}"),
+        }
+    }
+
+    let deadline = config::boot_log_duration().map(|secs| Instant::now() + Duration::from_secs(secs));
+    while !stop.load(Ordering::Relaxed)
+        && utils::getprop("sys.boot_completed").as_deref() != Some("1")
+        && deadline.is_none_or(|deadline| Instant::now() < deadline)
+    {
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    for (mut child, capture) in captures {
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = capture.join();
+    }
+
+    index.stopped = Some(now());
+    index.files = SOURCES
+        .iter()
+        .flat_map(|(file, _)| {
+            let path = Path::new(defs::APATCH_LOG_FOLDER).join(file);
+            std::iter::once(path.clone())
+                .chain((1..=KEPT_SEGMENTS).map(move |n| segment(&path, n)))
+        })
+        .filter(|path| path.exists())
+        .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .collect();
+    index.store()?;
+    info!("boot log capture stopped");
+    Ok(())
+}
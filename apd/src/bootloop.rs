@@ -0,0 +1,212 @@
+//! Bootloop protection
+//!
+//! Every post-fs-data counts one more boot in `incomplete_boots`, boot-completed
+//! clears it again. Safe mode detected by accident only skips modules for that
+//! boot. Modules are disabled once [`DEFAULT_THRESHOLD`] boots in a row never
+//! completed, or when safe mode was requested from the system. The modules
+//! disabled that way are listed in `modules_disabled_by_safemode`, so
+//! `apd module re-enable-safemode` can restore exactly that set.
+//!
+//! `apd safemode enter` forces safe mode on every boot until `apd safemode
+//! exit`. What triggered safe mode last is kept in `safemode_reason`.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use crate::{
+    defs,
+    messages::Message,
+    module,
+    utils::{self, SafeModeReason},
+};
+
+/// Incomplete boots in a row after which all modules are disabled
+const DEFAULT_THRESHOLD: u32 = 3;
+
+/// Configured in `bootloop_threshold`, `0` turns the counter off
+fn threshold() -> u32 {
+    fs::read_to_string(defs::BOOTLOOP_THRESHOLD_FILE)
+        .ok()
+        .and_then(|content| content.trim().parse().ok())
+        .unwrap_or(DEFAULT_THRESHOLD)
+}
+
+fn incomplete_boots() -> u32 {
+    fs::read_to_string(defs::INCOMPLETE_BOOTS_FILE)
+        .ok()
+        .and_then(|content| content.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Count this boot, returns whether it is one incomplete boot too many
+pub fn begin_boot() -> bool {
+    let boots = incomplete_boots() + 1;
+    if let Err(e) = fs::write(defs::INCOMPLETE_BOOTS_FILE, boots.to_string()) {
+        warn!("Failed to write {}: {}", defs::INCOMPLETE_BOOTS_FILE, e);
+    }
+    let threshold = threshold();
+    info!("incomplete boots: {boots}/{threshold}");
+    threshold > 0 && boots >= threshold
+}
+
+pub fn finish_boot() {
+    if let Err(e) = fs::remove_file(defs::INCOMPLETE_BOOTS_FILE)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        warn!("Failed to remove {}: {}", defs::INCOMPLETE_BOOTS_FILE, e);
+    }
+}
+
+/// Safe mode chosen on purpose, e.g. from the power menu, as opposed to a
+/// detection which may be spurious
+pub fn safe_mode_requested() -> bool {
+    utils::getprop("persist.sys.safemode").as_deref() == Some("1")
+}
+
+/// Remember what triggered safe mode, for `apd safemode status`
+pub fn record_trigger(reason: SafeModeReason) {
+    let record = format!("{reason} {}\n", crate::script_history::boot_id());
+    if let Err(e) = fs::write(defs::SAFEMODE_REASON_FILE, record) {
+        warn!("Failed to write {}: {}", defs::SAFEMODE_REASON_FILE, e);
+    }
+    crate::events::emit(
+        crate::events::Code::SafeModeEntered,
+        &Message::SafeModeEntered {
+            trigger: reason.to_string(),
+        },
+    );
+}
+
+/// The last trigger and whether it fired in this boot
+fn last_trigger() -> Option<(SafeModeReason, bool)> {
+    let record = fs::read_to_string(defs::SAFEMODE_REASON_FILE).ok()?;
+    let mut words = record.split_whitespace();
+    let reason = words.next()?.parse().ok()?;
+    let this_boot = words.next() == Some(crate::script_history::boot_id().as_str());
+    Some((reason, this_boot))
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SafeModeStatus {
+    active: bool,
+    reason: Option<SafeModeReason>,
+    forced: bool,
+    last_trigger: Option<SafeModeReason>,
+    disabled_modules: Vec<String>,
+}
+
+pub fn status(superkey: Option<String>) -> SafeModeStatus {
+    let reason = utils::safe_mode_reason(superkey);
+    SafeModeStatus {
+        active: reason.is_some(),
+        reason,
+        forced: Path::new(defs::SAFEMODE_FORCE_FILE).exists(),
+        last_trigger: last_trigger().map(|(reason, _)| reason),
+        disabled_modules: read_disabled(),
+    }
+}
+
+pub fn safemode_status(superkey: Option<String>) -> Result<()> {
+    let status = match crate::daemon::query(&crate::daemon::Request::SafeMode)? {
+        Some(status) => status,
+        None => status(superkey),
+    };
+    if crate::output::json() {
+        return crate::output::print("safemode status", status);
+    }
+    match status.reason {
+        Some(reason) => println!("safe mode: active ({reason})"),
+        None => println!("safe mode: inactive"),
+    }
+    if status.forced {
+        println!("forced: yes, until apd safemode exit");
+    }
+    match last_trigger() {
+        Some((reason, true)) => println!("last trigger: {reason} (this boot)"),
+        Some((reason, false)) => println!("last trigger: {reason} (an earlier boot)"),
+        None => println!("last trigger: none"),
+    }
+    if status.disabled_modules.is_empty() {
+        println!("disabled by safe mode: none");
+    } else {
+        println!("disabled by safe mode: {}", status.disabled_modules.join(", "));
+    }
+    Ok(())
+}
+
+/// Force safe mode from the next boot on
+pub fn enter() -> Result<()> {
+    utils::ensure_file_exists(defs::SAFEMODE_FORCE_FILE)?;
+    println!("Safe mode forced, modules are skipped from the next boot on");
+    Ok(())
+}
+
+/// Stop forcing safe mode and enable the modules safe mode disabled
+pub fn exit() -> Result<()> {
+    if Path::new(defs::SAFEMODE_FORCE_FILE).exists() {
+        fs::remove_file(defs::SAFEMODE_FORCE_FILE)
+            .with_context(|| format!("Failed to remove {}", defs::SAFEMODE_FORCE_FILE))?;
+    }
+    reenable_modules()?;
+    println!("Safe mode cleared, reboot to load modules again");
+    Ok(())
+}
+
+fn read_disabled() -> Vec<String> {
+    fs::read_to_string(defs::MODULES_DISABLED_BY_SAFEMODE_FILE)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Disable all modules for `reason`, a message code, remembering which ones
+/// were enabled
+pub fn disable_modules(reason: &str) -> Result<()> {
+    let mut disabled = read_disabled();
+    // the modules disable_all_modules is about to disable
+    module::foreach_module(module::ModuleType::All, |module| {
+        if module::disabled_by(module).is_some() {
+            return Ok(());
+        }
+        if let Some(id) = module.file_name().map(|n| n.to_string_lossy().into_owned())
+            && !disabled.contains(&id)
+        {
+            disabled.push(id);
+        }
+        Ok(())
+    })?;
+    fs::write(
+        defs::MODULES_DISABLED_BY_SAFEMODE_FILE,
+        disabled.join("\n") + "\n",
+    )
+    .with_context(|| format!("Failed to write {}", defs::MODULES_DISABLED_BY_SAFEMODE_FILE))?;
+    module::disable_all_modules(reason)?;
+    finish_boot();
+    Ok(())
+}
+
+/// Enable the modules disabled by safe mode or bootloop protection again
+pub fn reenable_modules() -> Result<()> {
+    let disabled = read_disabled();
+    if disabled.is_empty() {
+        println!("No modules were disabled by safe mode");
+        return Ok(());
+    }
+    for id in &disabled {
+        if !Path::new(defs::MODULE_DIR).join(id).exists() {
+            warn!("module {id} is gone, skip");
+            continue;
+        }
+        match module::enable_module(id) {
+            Ok(()) => println!("Enabled {id}"),
+            Err(e) => warn!("Failed to enable {id}: {e}"),
+        }
+    }
+    fs::remove_file(defs::MODULES_DISABLED_BY_SAFEMODE_FILE)
+        .with_context(|| format!("Failed to remove {}", defs::MODULES_DISABLED_BY_SAFEMODE_FILE))
+}
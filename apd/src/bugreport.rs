@@ -0,0 +1,435 @@
+//! `apd bugreport`: what a bug report needs, in one zstd compressed tar
+//!
+//! The archive holds the logs of `/data/adb/ap/log`, the mount records, the
+//! mount tables, the stage timing and boot profile, module.prop and the flags
+//! of every module, the props and the versions of the kernel, KernelPatch and
+//! apd. Module files are left out, except the script history of the modules
+//! which warning or error events of the current boot name.
+//!
+//! Props whose key names a serial, an account or an address are dropped, their
+//! values and e-mail addresses are replaced wherever they appear in text and
+//! in paths. Whatever could not be collected is listed in `manifest.json`
+//! inside the archive instead of failing the report.
+
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{
+    defs,
+    events::{self, Code, Entry, Severity},
+    flags, script_history,
+    supercall::Handshake,
+    timing,
+};
+
+/// Prop keys containing any of these are dropped from the report
+const REDACTED_KEYS: &[&str] = &[
+    "serial", "imei", "meid", "imsi", "iccid", "msisdn", "account", "email", "phone",
+    "macaddr", "mac_address", "wifimac", "bt_addr", "bluetooth.address", "gsm.sim",
+];
+const REDACTED: &str = "[redacted]";
+const ZSTD_LEVEL: i32 = 9;
+
+#[derive(Serialize)]
+struct Gap {
+    item: String,
+    reason: String,
+}
+
+#[derive(Serialize, Default)]
+struct Manifest {
+    created: u64,
+    apd_version: String,
+    boot_id: String,
+    files: Vec<String>,
+    missing: Vec<Gap>,
+}
+
+/// Modules which warning or error events of boot `boot_id` are about
+fn degraded_modules(entries: &[Entry], boot_id: &str) -> BTreeSet<String> {
+    entries
+        .iter()
+        .filter(|entry| entry.boot_id == boot_id && entry.severity != Severity::Info)
+        // the subject of stage events is the stage
+        .filter(|entry| !matches!(entry.code, Code::StageDegraded | Code::StageFailed))
+        .filter_map(|entry| entry.subject.clone())
+        .collect()
+}
+
+fn redacted_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    REDACTED_KEYS.iter().any(|word| key.contains(word))
+}
+
+/// Replaces what identifies the device or its user in text
+struct Scrubber {
+    values: Vec<String>,
+    email: Regex,
+}
+
+impl Scrubber {
+    fn new(props: &[(String, String)]) -> Self {
+        let mut values: Vec<String> = props
+            .iter()
+            .filter(|(key, _)| redacted_key(key))
+            .map(|(_, value)| value.clone())
+            // short values such as `0` or `true` would garble everything
+            .filter(|value| value.len() >= 6)
+            .collect();
+        values.sort();
+        values.dedup();
+        // longest first, a value may contain another
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        Scrubber {
+            values,
+            email: Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap(),
+        }
+    }
+
+    fn scrub(&self, text: &str) -> String {
+        let mut text = self.email.replace_all(text, REDACTED).into_owned();
+        for value in &self.values {
+            text = text.replace(value.as_str(), REDACTED);
+        }
+        text
+    }
+}
+
+/// `[key]: [value]` lines of `getprop`
+fn read_props() -> Result<Vec<(String, String)>> {
+    let output = Command::new("getprop").output().context("Failed to run getprop")?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once("]: [")?;
+            Some((
+                key.trim_start_matches('[').to_string(),
+                value.trim_end_matches(']').to_string(),
+            ))
+        })
+        .collect())
+}
+
+struct Report {
+    builder: tar::Builder<zstd::Encoder<'static, File>>,
+    scrubber: Scrubber,
+    manifest: Manifest,
+    mtime: u64,
+}
+
+impl Report {
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        // a path may hold e.g. an account name as well
+        let name = self.scrubber.scrub(name);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        header.set_cksum();
+        self.builder
+            .append_data(&mut header, &name, data)
+            .with_context(|| format!("Failed to add {name}"))?;
+        self.manifest.files.push(name);
+        Ok(())
+    }
+
+    fn add_text(&mut self, name: &str, text: &str) -> Result<()> {
+        let text = self.scrubber.scrub(text);
+        self.add(name, text.as_bytes())
+    }
+
+    fn gap(&mut self, item: impl Into<String>, reason: impl ToString) {
+        self.manifest.missing.push(Gap {
+            item: item.into(),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Add the file at `path` as `name`, gzipped files are added unpacked so
+    /// they can be scrubbed
+    fn add_file(&mut self, name: &str, path: &Path) -> Result<()> {
+        let mut bytes = Vec::new();
+        let read = File::open(path).and_then(|mut file| {
+            if path.extension().is_some_and(|ext| ext == "gz") {
+                GzDecoder::new(file).read_to_end(&mut bytes)
+            } else {
+                file.read_to_end(&mut bytes)
+            }
+        });
+        match read {
+            Ok(_) => {
+                let name = name.strip_suffix(".gz").unwrap_or(name);
+                self.add_text(name, &String::from_utf8_lossy(&bytes))
+            }
+            Err(e) => {
+                self.gap(path.display().to_string(), e);
+                Ok(())
+            }
+        }
+    }
+
+    fn add_logs(&mut self) -> Result<()> {
+        let dir = Path::new(defs::APATCH_LOG_FOLDER);
+        if !dir.is_dir() {
+            self.gap(defs::APATCH_LOG_FOLDER, "no log dir");
+            return Ok(());
+        }
+        for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.gap(defs::APATCH_LOG_FOLDER, e);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(dir)?.to_string_lossy().into_owned();
+            self.add_file(&format!("log/{relative}"), entry.path())?;
+        }
+        Ok(())
+    }
+
+    fn add_records(&mut self) -> Result<()> {
+        for (name, path) in [
+            ("mount_decision.json", defs::MOUNT_DECISION_FILE),
+            ("mount_registry.json", defs::MOUNT_LIST_FILE),
+            ("kernel_handshake.json", defs::KERNEL_HANDSHAKE_FILE),
+            ("boot_progress.json", defs::BOOT_PROGRESS_FILE),
+            ("boot_profile.json", defs::PROFILE_REPORT_FILE),
+            ("proc/mounts", "/proc/mounts"),
+            ("proc/mountinfo", "/proc/self/mountinfo"),
+        ] {
+            if Path::new(path).exists() {
+                self.add_file(name, Path::new(path))?;
+            } else {
+                self.gap(path, "not found");
+            }
+        }
+        Ok(())
+    }
+
+    /// The timing of the last boot as `apd status --timing` shows it, the
+    /// json is part of the logs
+    fn add_timing(&mut self) -> Result<()> {
+        match timing::load() {
+            Some(boot) => self.add_text("timing.txt", &timing::render(&boot)),
+            None => {
+                self.gap(defs::BOOT_TIMING_FILE, "no boot timing recorded");
+                Ok(())
+            }
+        }
+    }
+
+    fn add_modules(&mut self) -> Result<()> {
+        let entries = match fs::read_dir(defs::MODULE_DIR) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.gap(defs::MODULE_DIR, e);
+                return Ok(());
+            }
+        };
+        let mut dirs: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        dirs.sort();
+        for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+            let id = dir.file_name().unwrap_or_default().to_string_lossy();
+            let prop = dir.join("module.prop");
+            if prop.exists() {
+                self.add_file(&format!("modules/{id}/module.prop"), &prop)?;
+            } else {
+                self.gap(prop.display().to_string(), "not found");
+            }
+            let flags: String = [
+                defs::DISABLE_FILE_NAME,
+                defs::SKIP_MOUNT_FILE_NAME,
+                defs::DEFER_MOUNT_FILE_NAME,
+                defs::REMOVE_FILE_NAME,
+                defs::UPDATE_FILE_NAME,
+            ]
+            .iter()
+            .map(|flag| format!("{flag}={}\n", flags::is_set(dir.join(flag))))
+            .collect();
+            self.add_text(&format!("modules/{id}/flags"), &flags)?;
+        }
+        let boot_id = script_history::boot_id();
+        let degraded = degraded_modules(&events::read(Some(&boot_id)), &boot_id);
+        self.add_script_history(Path::new(defs::SCRIPT_HISTORY_DIR), &degraded)
+    }
+
+    /// `<id>.jsonl` of `dir` for each of `ids`
+    fn add_script_history(&mut self, dir: &Path, ids: &BTreeSet<String>) -> Result<()> {
+        for id in ids {
+            let history = dir.join(format!("{id}.jsonl"));
+            if history.exists() {
+                self.add_file(&format!("modules/{id}/script_history.jsonl"), &history)?;
+            } else {
+                self.gap(history.display().to_string(), "no script history");
+            }
+        }
+        Ok(())
+    }
+
+    fn add_versions(&mut self) -> Result<()> {
+        let kernel = fs::read_to_string("/proc/version").unwrap_or_else(|e| {
+            self.gap("/proc/version", e);
+            String::new()
+        });
+        let kernelpatch = match Handshake::load() {
+            Ok(handshake) if !handshake.kernelpatch_version.is_empty() => {
+                handshake.kernelpatch_version
+            }
+            Ok(_) => "unknown, the kernel did not answer".to_string(),
+            Err(e) => {
+                self.gap(defs::KERNEL_HANDSHAKE_FILE, e);
+                "unknown".to_string()
+            }
+        };
+        let versions = format!(
+            "kernel: {}\nkernelpatch: {kernelpatch}\napd: {} ({})\n",
+            kernel.trim(),
+            defs::VERSION_NAME.trim(),
+            defs::VERSION_CODE.trim()
+        );
+        self.add_text("versions.txt", &versions)
+    }
+
+    fn add_props(&mut self, props: &[(String, String)]) -> Result<()> {
+        let text: String = props
+            .iter()
+            .filter(|(key, _)| !redacted_key(key))
+            .map(|(key, value)| format!("[{key}]: [{value}]\n"))
+            .collect();
+        self.add_text("props.txt", &text)
+    }
+
+    /// Add everything and finish the archive
+    fn write(mut self, props: &[(String, String)]) -> Result<()> {
+        self.add_logs()?;
+        self.add_records()?;
+        self.add_timing()?;
+        self.add_modules()?;
+        self.add_versions()?;
+        if !props.is_empty() {
+            self.add_props(props)?;
+        }
+        let manifest = serde_json::to_string_pretty(&self.manifest)?;
+        self.add("manifest.json", manifest.as_bytes())?;
+        let encoder = self.builder.into_inner().context("Failed to finish tar")?;
+        encoder.finish().context("Failed to finish zstd")?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn default_output() -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    PathBuf::from(format!("/data/local/tmp/apd-bugreport-{secs}.tar.zst"))
+}
+
+/// `apd bugreport [output]`
+pub fn bugreport(output: Option<PathBuf>) -> Result<()> {
+    let output = output.unwrap_or_else(default_output);
+    let tmp = PathBuf::from(format!("{}.tmp", output.display()));
+    let file = File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    let encoder = zstd::Encoder::new(file, ZSTD_LEVEL).context("Failed to start zstd")?;
+
+    let mut gaps = Vec::new();
+    let props = read_props().unwrap_or_else(|e| {
+        gaps.push(Gap {
+            item: "getprop".to_string(),
+            reason: format!("{e:#}"),
+        });
+        Vec::new()
+    });
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let report = Report {
+        builder: tar::Builder::new(encoder),
+        scrubber: Scrubber::new(&props),
+        manifest: Manifest {
+            created: mtime,
+            apd_version: defs::VERSION_CODE.trim().to_string(),
+            boot_id: script_history::boot_id(),
+            missing: gaps,
+            ..Default::default()
+        },
+        mtime,
+    };
+
+    if let Err(e) = report.write(&props) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    fs::rename(&tmp, &output)
+        .with_context(|| format!("Failed to rename {} to {}", tmp.display(), output.display()))?;
+    println!("{}", output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn entry(boot_id: &str, code: Code, severity: Severity, subject: Option<&str>) -> Entry {
+        Entry {
+            time: 0,
+            boot_id: boot_id.to_string(),
+            severity,
+            code,
+            subject: subject.map(String::from),
+            message_code: String::new(),
+            params: Default::default(),
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn degraded_modules_are_those_warned_about_this_boot() {
+        let entries = [
+            entry("old", Code::ModuleMountFailed, Severity::Error, Some("stale")),
+            entry("now", Code::StageDegraded, Severity::Warning, Some("services")),
+            entry("now", Code::ModuleMounted, Severity::Info, Some("fine")),
+            entry("now", Code::ModuleMountFailed, Severity::Error, Some("a")),
+            entry("now", Code::UpdateRejected, Severity::Warning, Some("b")),
+            entry("now", Code::ModulesDisabled, Severity::Warning, None),
+        ];
+        assert_eq!(
+            degraded_modules(&entries, "now").into_iter().collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+    }
+
+    #[test]
+    fn script_history_of_degraded_modules_is_added_or_listed_as_missing() {
+        let root = TempDir::new("bugreport-history");
+        let history = root.write("history/a.jsonl", "{}\n");
+        let archive = File::create(root.path().join("report.tar.zst")).unwrap();
+        let mut report = Report {
+            builder: tar::Builder::new(zstd::Encoder::new(archive, 1).unwrap()),
+            scrubber: Scrubber::new(&[]),
+            manifest: Manifest::default(),
+            mtime: 0,
+        };
+        let ids = BTreeSet::from(["a".to_string(), "b".to_string()]);
+        report.add_script_history(history.parent().unwrap(), &ids).unwrap();
+
+        assert_eq!(report.manifest.files, ["modules/a/script_history.jsonl"]);
+        assert_eq!(report.manifest.missing.len(), 1);
+        assert!(report.manifest.missing[0].item.ends_with("history/b.jsonl"));
+    }
+}
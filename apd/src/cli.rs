@@ -1,7 +1,12 @@
-use crate::{defs, event, lua, module, supercall, utils};
+use std::path::PathBuf;
+
+use crate::{
+    daemon::{self, Request},
+    defs, dispatch, event, lua, module, profile, status, supercall, utils,
+};
 #[cfg(target_os = "android")]
 use android_logger::Config;
-use anyhow::Result;
+use anyhow::{Result, ensure};
 use clap::Parser;
 #[cfg(target_os = "android")]
 use log::LevelFilter;
@@ -17,6 +22,12 @@ struct Args {
         help = "Super key for authentication root"
     )]
     superkey: Option<String>,
+    /// Read the super key from file descriptor <FD> instead
+    #[arg(long, value_name = "FD", conflicts_with = "superkey")]
+    superkey_fd: Option<i32>,
+    /// Print query results as one JSON document with a schema version
+    #[arg(long, global = true)]
+    json: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -39,7 +50,125 @@ enum Commands {
     BootCompleted,
 
     /// Start uid listener for synchronizing root list
-    UidListener,
+    UidListener {
+        /// run the listener itself instead of supervising it
+        #[arg(long, hide = true)]
+        worker: bool,
+    },
+
+    /// Capture logcat and dmesg until boot-completed, started by post-fs-data
+    #[command(hide = true)]
+    BootLog,
+
+    /// Run boot-completed if init never triggers it, started by services
+    #[command(hide = true)]
+    AwaitBootCompleted,
+
+    /// Stop the uid listener and boot log collectors apd left running
+    Shutdown {
+        /// also lazily unmount the module mounts
+        #[arg(long)]
+        unmount: bool,
+    },
+
+    /// Pack the settings and the installed modules into a .tar.zst
+    Backup {
+        /// archive to write
+        output: PathBuf,
+    },
+
+    /// Restore settings and modules of a backup, the modules are applied on the next boot
+    Restore {
+        /// archive written by `apd backup`
+        input: PathBuf,
+    },
+
+    /// Replace the installed apd by <binary> and restart the uid listener with it
+    SelfUpdate {
+        /// new apd executable
+        binary: PathBuf,
+
+        /// refuse the binary unless its sha256 is this hex digest
+        #[arg(long, value_name = "HEX")]
+        sha256: Option<String>,
+    },
+
+    /// Show the event log: stages, module mounts, safe mode and updates
+    Events {
+        /// start at the first event of this boot id, or a prefix of it
+        #[arg(long)]
+        since: Option<String>,
+    },
+
+    /// Collect logs, mount records and props into a .tar.zst for a bug report
+    Bugreport {
+        /// where to write it, /data/local/tmp/apd-bugreport-<time>.tar.zst by default
+        output: Option<PathBuf>,
+    },
+
+    /// Remove modules, settings and logs of APatch and detach the module mounts
+    Uninstall {
+        /// keep /data/adb/modules for a later reinstall
+        #[arg(long)]
+        keep_modules: bool,
+    },
+
+    /// Profile module scripts and mount phases during the next boot
+    ProfileBoot {
+        #[command(subcommand)]
+        command: ProfileBoot,
+    },
+
+    /// Inspect module mounting
+    Mount {
+        #[command(subcommand)]
+        command: Mount,
+    },
+
+    /// Re-run phases of a boot stage on a booted device
+    Stage {
+        #[command(subcommand)]
+        command: Stage,
+    },
+
+    /// Read or change settings in apd.toml
+    Config {
+        #[command(subcommand)]
+        command: ConfigCmd,
+    },
+
+    /// Systemless hosts file
+    Hosts {
+        #[command(subcommand)]
+        command: HostsCmd,
+    },
+
+    /// Show APatch status
+    Status {
+        /// print the last boot health record
+        #[arg(long)]
+        health: bool,
+
+        /// print how long the phases and scripts of the last boot took
+        #[arg(long)]
+        timing: bool,
+
+        /// print how the modules were mounted the last time and why
+        #[arg(long)]
+        mount: bool,
+
+        /// print the mount namespace mode su sessions of UID get
+        #[arg(long, value_name = "UID")]
+        su_namespace: Option<u32>,
+
+        /// print the version and health of the tools in /data/adb/ap/bin
+        #[arg(long)]
+        binaries: bool,
+
+        /// print whether the uid listener runs and how its package list refreshes went
+        #[arg(long)]
+        uid_listener: bool,
+    },
 
     /// Resetprop - Magisk-compatible system property tool
     Resetprop(crate::resetprop::Args),
@@ -52,6 +181,30 @@ enum Commands {
         #[command(subcommand)]
         command: Sepolicy,
     },
+
+    /// Read or change the SELinux context of files
+    Selinux {
+        #[command(subcommand)]
+        command: SelinuxCmd,
+    },
+
+    /// Show, force or clear safe mode
+    Safemode {
+        #[command(subcommand)]
+        command: SafemodeCmd,
+    },
+
+    /// Check what apps see of APatch
+    Hide {
+        #[command(subcommand)]
+        command: HideCmd,
+    },
+
+    /// Check or change the superkey, keys are read from stdin or the environment
+    Superkey {
+        #[command(subcommand)]
+        command: SuperkeyCmd,
+    },
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -60,12 +213,18 @@ enum Module {
     Install {
         /// module zip file path
         zip: String,
+        /// allow modules declaring install_unconfined=true to install with full access
+        #[arg(long)]
+        allow_unconfined: bool,
     },
 
     /// Uninstall module <id>
     Uninstall {
         /// module id
         id: String,
+        /// remove it right away instead of on next boot, only if nothing of it is mounted
+        #[arg(long)]
+        now: bool,
     },
 
     /// enable module <id>
@@ -85,6 +244,29 @@ enum Module {
         // module id
         id: String,
     },
+
+    /// Pack the partition dirs of module <ID> into a read-only module.img
+    Compress {
+        /// module id
+        id: String,
+    },
+
+    /// Kernel modules shipped in kernel_modules/ of a module
+    Kmod {
+        #[command(subcommand)]
+        command: KmodCmd,
+    },
+
+    /// Serve the webroot of module <ID> on 127.0.0.1 until killed, prints its URL with the
+    /// session token
+    Webui {
+        /// module id
+        id: String,
+
+        /// port to listen on, 0 picks a free one
+        #[arg(long, default_value_t = 0)]
+        port: u16,
+    },
     /// module lua runner
     Lua {
         // module id
@@ -92,8 +274,282 @@ enum Module {
         // lua function
         function: String,
     },
-    /// list all modules
+    /// list all modules, as JSON when not printing to a terminal
     List,
+
+    /// Show how much space each installed module takes
+    Du {
+        /// largest first instead of by id
+        #[arg(long, value_parser = ["size"])]
+        sort: Option<String>,
+    },
+
+    /// List files provided by more than one enabled module
+    Conflicts,
+
+    /// List the modules whose updateJson offers a newer version
+    CheckUpdates,
+
+    /// Download and install the update of module <ID> from its updateJson
+    Update {
+        /// module id
+        id: String,
+        /// allow modules declaring install_unconfined=true to install with full access
+        #[arg(long)]
+        allow_unconfined: bool,
+    },
+
+    /// List the partitions module <ID> modifies and how many files in each
+    Inspect {
+        /// module id
+        id: String,
+    },
+
+    /// Run the <STAGE> scripts of modules which depend on each other in order, started by the stage
+    #[command(hide = true)]
+    ExecChain {
+        stage: String,
+        /// module ids in the order to run their scripts
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+
+    /// Mount the files of module <ID> in the mount namespace of an app, started by the uid listener
+    #[command(hide = true)]
+    Inject {
+        /// module id
+        id: String,
+        /// process of the app
+        #[arg(long)]
+        pid: i32,
+    },
+
+    /// List staged module updates with their version change
+    StageStatus,
+
+    /// Show what the staged update of module <ID> changes
+    StageDiff {
+        /// module id
+        id: String,
+    },
+
+    /// Show what the last applied or rejected update of module <ID> changed
+    Changelog {
+        /// module id
+        id: String,
+    },
+
+    /// Apply staged module updates now instead of on next boot, those of modules with mounted
+    /// files wait for the boot
+    StageApply {
+        /// only apply the update of module <ID>
+        #[arg(long, value_name = "ID")]
+        only: Option<String>,
+
+        /// print which files would change without applying
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Enable the modules disabled by safe mode or bootloop protection again
+    ReEnableSafemode,
+
+    /// Restore module <id> as it was before its update, until a boot verified it
+    Rollback {
+        /// module id
+        id: String,
+    },
+
+    /// Remove all modules while keeping APatch settings and su grants
+    Reset {
+        /// really remove all modules
+        #[arg(long)]
+        confirm: bool,
+
+        /// only print what would be done
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Show where the files of every module are currently mounted
+    MountStatus,
+
+    /// Show how the scripts of module <id> exited during the last boots
+    History {
+        /// module id
+        id: String,
+    },
+
+    /// Allow module <id> to mount critical boot components
+    AckCritical {
+        /// module id
+        id: String,
+    },
+
+    /// Apply a batch of module state changes atomically
+    ApplyBatch {
+        /// JSON array, e.g. [{"op":"disable","id":"foo"},{"op":"set-flag","id":"bar","flag":"skip_mount","value":true}]
+        #[arg(value_name = "JSON")]
+        batch: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ProfileBoot {
+    /// Profile the next boot only
+    Enable,
+    /// Cancel a pending profiling request
+    Disable,
+    /// Show the slowest entries of the last profiled boot
+    Show {
+        /// number of entries to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Mount {
+    /// Show per partition which modules would be mounted, and why others are skipped
+    Plan,
+
+    /// List the module mounts of this boot, flagging those which are gone
+    List,
+
+    /// Detach recorded module mounts, newest first
+    Undo {
+        /// ids as shown by `apd mount list`
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        ids: Vec<u32>,
+
+        /// detach every recorded mount
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Detach module mounts and mount modules again, without a reboot
+    Remount {
+        /// mount mode to use instead of the configured one
+        #[arg(long, value_parser = ["magic", "metamodule", "disabled"])]
+        mode: Option<String>,
+
+        /// remount even though running apps keep the old mounts
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Stage {
+    /// Run phases of <STAGE>, only `post-fs-data` is supported
+    Run {
+        stage: String,
+        /// comma separated phases to run, e.g. `mount,props`
+        #[arg(long, value_delimiter = ',', required = true)]
+        only: Vec<String>,
+    },
+
+    /// Run the phases of <STAGE> which did not run in this boot, e.g. as apd
+    /// was killed half way
+    Resume { stage: String },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ConfigCmd {
+    /// Print the value of <KEY>
+    Get {
+        /// config key, e.g. mount_mode
+        key: String,
+    },
+    /// Set <KEY> to <VALUE>, remove it without a value
+    Set {
+        /// config key, e.g. mount_mode
+        key: String,
+        value: Option<String>,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum KmodCmd {
+    /// Show which kos of module <ID> are loaded and how loading them went this boot
+    Status {
+        /// module id
+        id: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum HostsCmd {
+    /// Mount /data/adb/ap/hosts over /system/etc/hosts from the next boot on
+    Enable,
+    /// Stop mounting the hosts file from the next boot on
+    Disable,
+    /// Show whether the hosts file is enabled and mounted
+    Status,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum SuperkeyCmd {
+    /// Check that the kernel accepts the superkey (APD_SUPERKEY or stdin)
+    Verify,
+    /// Replace the superkey, the new one is read from APD_NEW_SUPERKEY or stdin
+    Rotate,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum SafemodeCmd {
+    /// Print whether safe mode is active, why, and which modules it disabled
+    Status,
+    /// Skip all modules from the next boot on, until `safemode exit`
+    Enter,
+    /// Stop forcing safe mode and enable the modules it disabled
+    Exit,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum HideCmd {
+    /// Check that running processes of <PACKAGE> see no module mounts
+    Verify {
+        /// package name
+        package: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum SelinuxCmd {
+    /// Print the context of <PATH>
+    Get { path: PathBuf },
+
+    /// Set the context of <PATH>
+    Set {
+        path: PathBuf,
+        context: String,
+
+        /// label everything below <PATH> too
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// print the result of every file
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// allow paths outside /data/adb and /dev
+        #[arg(long)]
+        allow_system: bool,
+    },
+
+    /// Label everything below <PATH> as system files where the label differs
+    Restore {
+        path: PathBuf,
+
+        /// print every file which could not be labeled
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// allow paths outside /data/adb and /dev
+        #[arg(long)]
+        allow_system: bool,
+    },
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -105,15 +561,41 @@ enum Sepolicy {
     },
 }
 
+/// Tools apd stands in for when executed under their name
+#[derive(Debug, PartialEq, Eq)]
+enum Applet {
+    Su,
+    Resetprop,
+    Magiskpolicy,
+    Shim(&'static str),
+}
+
+// the kernel executes su with argv[0] = "/system/bin/kp" or "/system/bin/su" or "su" or "kp" and replace it with us
+fn applet(arg0: &str) -> Option<Applet> {
+    if arg0.ends_with("kp") || arg0.ends_with("su") {
+        Some(Applet::Su)
+    } else if arg0.ends_with("resetprop") {
+        Some(Applet::Resetprop)
+    } else if arg0.ends_with("magiskpolicy") {
+        Some(Applet::Magiskpolicy)
+    } else if arg0.ends_with("magisk") {
+        Some(Applet::Shim("magisk"))
+    } else if arg0.ends_with("ksud") {
+        Some(Applet::Shim("ksud"))
+    } else {
+        None
+    }
+}
+
 pub fn run() -> Result<()> {
     #[cfg(target_os = "android")]
     android_logger::init_once(
         Config::default()
-            .with_max_level(LevelFilter::Trace) // limit log level
+            .with_max_level(crate::config::log_level()) // limit log level
             .with_tag("APatchD")
             .with_filter(
                 android_logger::FilterBuilder::new()
-                    .filter_level(LevelFilter::Trace)
+                    .filter_level(crate::config::log_level())
                     .filter_module("notify", LevelFilter::Warn)
                     .build(),
             ),
@@ -122,34 +604,145 @@ pub fn run() -> Result<()> {
     #[cfg(not(target_os = "android"))]
     env_logger::init();
 
-    // the kernel executes su with argv[0] = "/system/bin/kp" or "/system/bin/su" or "su" or "kp" and replace it with us
     let arg0 = std::env::args().next().unwrap_or_default();
-    if arg0.ends_with("kp") || arg0.ends_with("su") {
-        return crate::apd::root_shell();
-    }
-    if arg0.ends_with("resetprop") {
-        let all_args: Vec<String> = std::env::args().collect();
-        crate::resetprop::resetprop_main(&all_args)
-    }
-    if arg0.ends_with("magiskpolicy") {
-        let all_args: Vec<String> = std::env::args().collect();
-        crate::mpolicy::policy_main(&all_args)
+    match applet(&arg0) {
+        Some(Applet::Su) => return crate::apd::root_shell(),
+        Some(Applet::Resetprop) => {
+            let all_args: Vec<String> = std::env::args().collect();
+            crate::resetprop::resetprop_main(&all_args)
+        }
+        Some(Applet::Magiskpolicy) => {
+            let all_args: Vec<String> = std::env::args().collect();
+            crate::mpolicy::policy_main(&all_args)
+        }
+        Some(Applet::Shim(name)) => {
+            let args: Vec<String> = std::env::args().skip(1).collect();
+            crate::compat::shim_main(name, &args)
+        }
+        None => {}
     }
 
     let cli = Args::parse();
+    crate::output::set_json(cli.json);
 
     log::info!("command: {:?}", cli.command);
 
-    if let Some(ref _superkey) = cli.superkey {
-        supercall::privilege_apd_profile(&cli.superkey);
+    let superkey = match cli.superkey_fd {
+        Some(fd) => Some(dispatch::read_superkey_fd(fd)?),
+        None => cli.superkey,
+    };
+    if let Some(ref _superkey) = superkey {
+        supercall::privilege_apd_profile(&superkey);
     }
 
     let result = match cli.command {
-        Commands::PostFsData => event::on_post_data_fs(cli.superkey),
+        Commands::PostFsData => {
+            dispatch::run_stage("post-fs-data", || event::on_post_data_fs(superkey, None))
+        }
 
-        Commands::BootCompleted => event::on_boot_completed(cli.superkey),
+        Commands::Stage { command } => match command {
+            Stage::Run { stage, only } => {
+                ensure!(stage == "post-fs-data", "stage {stage} has no phases to run");
+                dispatch::run_stage("post-fs-data", || {
+                    event::on_post_data_fs(superkey, Some(&only))
+                })
+            }
+            Stage::Resume { stage } => {
+                ensure!(stage == "post-fs-data", "stage {stage} has no phases to resume");
+                dispatch::run_stage("post-fs-data", || event::resume_post_fs_data(superkey))
+            }
+        },
+
+        Commands::BootCompleted => {
+            dispatch::run_stage("boot-completed", || event::on_boot_completed(superkey))
+        }
+
+        Commands::AwaitBootCompleted => {
+            if event::await_boot_completed() {
+                dispatch::run_stage("boot-completed", || event::on_boot_completed(superkey))
+            }
+            Ok(())
+        }
+
+        Commands::UidListener { worker: true } => event::start_uid_listener(),
+        Commands::UidListener { worker: false } => dispatch::run_stage("uid-listener", || {
+            event::supervise_uid_listener(superkey).map(|()| dispatch::Outcome::Ok)
+        }),
+
+        Commands::BootLog => crate::bootlog::run(),
+
+        Commands::Uninstall { keep_modules } => crate::uninstall::uninstall(keep_modules),
+
+        Commands::Backup { output } => crate::backup::backup(&output),
+
+        Commands::Restore { input } => crate::backup::restore(&input),
+
+        Commands::SelfUpdate { binary, sha256 } => {
+            crate::selfupdate::self_update(&binary, sha256.as_deref())
+        }
+
+        Commands::Events { since } => crate::events::show(since.as_deref()),
 
-        Commands::UidListener => event::start_uid_listener(),
+        Commands::Bugreport { output } => crate::bugreport::bugreport(output),
+
+        Commands::Shutdown { unmount } => {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if unmount {
+                utils::switch_mnt_ns(1)?;
+            }
+            crate::shutdown::run(unmount)
+        }
+
+        Commands::Config { command } => match command {
+            ConfigCmd::Get { key } => crate::config::get_key(&key),
+            ConfigCmd::Set { key, value } => crate::config::set_key(&key, value.as_deref()),
+        },
+
+        Commands::Hosts { command } => match command {
+            HostsCmd::Enable => crate::hosts::enable(),
+            HostsCmd::Disable => crate::hosts::disable(),
+            HostsCmd::Status => crate::hosts::status(),
+        },
+
+        Commands::Status {
+            health,
+            timing,
+            mount,
+            su_namespace,
+            binaries,
+            uid_listener,
+        } => {
+            if binaries {
+                status::print_binaries()
+            } else if uid_listener {
+                status::print_uid_listener()
+            } else if let Some(uid) = su_namespace {
+                status::print_su_namespace(uid)
+            } else if mount {
+                status::print_mount_decision()
+            } else if timing {
+                crate::timing::show()
+            } else {
+                status::print_status(health, &superkey)
+            }
+        }
+
+        Commands::Mount { command } => match command {
+            Mount::Plan => status::print_mount_plan(crate::output::json()),
+            Mount::List => crate::umount::list(),
+            Mount::Undo { ids, all } => crate::umount::undo(&ids, all),
+            Mount::Remount { mode, force } => {
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                utils::switch_mnt_ns(1)?;
+                event::remount(mode, force)
+            }
+        },
+
+        Commands::ProfileBoot { command } => match command {
+            ProfileBoot::Enable => profile::enable(),
+            ProfileBoot::Disable => profile::disable(),
+            ProfileBoot::Show { limit } => profile::show(limit),
+        },
 
         Commands::Module { command } => {
             #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -157,15 +750,65 @@ pub fn run() -> Result<()> {
                 utils::switch_mnt_ns(1)?;
             }
             match command {
-                Module::Install { zip } => module::install_module(&zip),
-                Module::Uninstall { id } => module::uninstall_module(&id),
-                Module::Action { id } => module::run_action(&id),
+                Module::Install {
+                    zip,
+                    allow_unconfined,
+                } => module::install_module(&zip, allow_unconfined),
+                Module::Uninstall { id, now: false } => module::uninstall_module(&id),
+                Module::Uninstall { id, now: true } => module::uninstall_module_now(&id),
+                Module::Action { id } => module::run_action(&id).inspect_err(|e| {
+                    if let Some(failed) = e.downcast_ref::<module::ScriptFailed>() {
+                        std::process::exit(failed.status.code().unwrap_or(1));
+                    }
+                }),
+                Module::Webui { id, port } => crate::webui::serve(&id, port),
+                Module::Compress { id } => crate::image::compress(&id),
+                Module::Kmod {
+                    command: KmodCmd::Status { id },
+                } => crate::kmod::status(&id),
                 Module::Lua { id, function } => {
-                    lua::run_lua(&id, &function, false, true).map_err(|e| anyhow::anyhow!("{}", e))
+                    lua::run_lua(&id, &function).map_err(|e| anyhow::anyhow!("{}", e))
+                }
+                Module::Enable { id } => {
+                    match daemon::call(&Request::ModuleEnable { id: id.clone() })? {
+                        Some(_) => Ok(()),
+                        None => module::enable_module(&id),
+                    }
+                }
+                Module::Disable { id } => {
+                    match daemon::call(&Request::ModuleDisable { id: id.clone() })? {
+                        Some(_) => Ok(()),
+                        None => module::disable_module(&id),
+                    }
+                }
+                Module::List => module::list_modules(crate::output::json()),
+                Module::Du { sort } => module::print_disk_usage(sort.is_some()),
+                Module::Inspect { id } => crate::magic_mount::inspect(&id),
+                Module::Inject { id, pid } => crate::targeted::inject(&id, pid),
+                Module::ExecChain { stage, ids } => module::exec_chain(&stage, &ids),
+                Module::Conflicts => crate::magic_mount::conflicts(),
+                Module::CheckUpdates => crate::update_check::check_updates(),
+                Module::Update {
+                    id,
+                    allow_unconfined,
+                } => crate::update_check::update(&id, allow_unconfined),
+                Module::Reset { confirm, dry_run } => {
+                    crate::reset::reset_modules(confirm, dry_run)
+                }
+                Module::MountStatus => {
+                    status::print_module_mount_status(crate::output::json())
+                }
+                Module::History { id } => crate::script_history::show(&id, crate::output::json()),
+                Module::AckCritical { id } => crate::critical::ack_critical(&id),
+                Module::ApplyBatch { batch } => module::apply_batch(&batch),
+                Module::StageStatus => module::stage_status(),
+                Module::StageDiff { id } => module::stage_diff(&id, crate::output::json()),
+                Module::Changelog { id } => module::changelog(&id),
+                Module::StageApply { only, dry_run } => {
+                    module::stage_apply(only.as_deref(), dry_run)
                 }
-                Module::Enable { id } => module::enable_module(&id),
-                Module::Disable { id } => module::disable_module(&id),
-                Module::List => module::list_modules(),
+                Module::Rollback { id } => module::rollback_module(&id),
+                Module::ReEnableSafemode => crate::bootloop::reenable_modules(),
             }
         }
 
@@ -173,7 +816,38 @@ pub fn run() -> Result<()> {
             Sepolicy::Check { sepolicy } => crate::sepolicy::check_rule(&sepolicy),
         },
 
-        Commands::Services => event::on_services(cli.superkey),
+        Commands::Superkey { command } => match command {
+            SuperkeyCmd::Verify => crate::superkey::verify(superkey),
+            SuperkeyCmd::Rotate => crate::superkey::rotate(superkey),
+        },
+
+        Commands::Safemode { command } => match command {
+            SafemodeCmd::Status => crate::bootloop::safemode_status(superkey),
+            SafemodeCmd::Enter => crate::bootloop::enter(),
+            SafemodeCmd::Exit => crate::bootloop::exit(),
+        },
+
+        Commands::Hide { command } => match command {
+            HideCmd::Verify { package } => crate::hide::verify(&package),
+        },
+
+        Commands::Selinux { command } => match command {
+            SelinuxCmd::Get { path } => crate::selinux::get(&path),
+            SelinuxCmd::Set {
+                path,
+                context,
+                recursive,
+                verbose,
+                allow_system,
+            } => crate::selinux::set(&path, &context, recursive, verbose, allow_system),
+            SelinuxCmd::Restore {
+                path,
+                verbose,
+                allow_system,
+            } => crate::selinux::restore(&path, verbose, allow_system),
+        },
+
+        Commands::Services => dispatch::run_stage("services", || event::on_services(superkey)),
 
         Commands::Resetprop(resetprop_args) => crate::resetprop::execute(&resetprop_args)
             .inspect_err(|e| {
@@ -192,3 +866,64 @@ pub fn run() -> Result<()> {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("apd").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn commands_are_consistent() {
+        use clap::CommandFactory;
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn argv0_selects_the_applet() {
+        assert_eq!(applet("su"), Some(Applet::Su));
+        assert_eq!(applet("/system/bin/su"), Some(Applet::Su));
+        assert_eq!(applet("/system/bin/kp"), Some(Applet::Su));
+        assert_eq!(applet("/data/adb/ap/bin/resetprop"), Some(Applet::Resetprop));
+        assert_eq!(applet("magiskpolicy"), Some(Applet::Magiskpolicy));
+        assert_eq!(applet("/system/bin/magisk"), Some(Applet::Shim("magisk")));
+        assert_eq!(applet("ksud"), Some(Applet::Shim("ksud")));
+        assert_eq!(applet("/data/adb/apd"), None);
+        assert_eq!(applet("apd"), None);
+        assert_eq!(applet(""), None);
+    }
+
+    #[test]
+    fn stage_subcommands_parse() {
+        let stage = |args: &[&str]| parse(args).unwrap().command;
+        assert!(matches!(stage(&["post-fs-data"]), Commands::PostFsData));
+        assert!(matches!(stage(&["services"]), Commands::Services));
+        assert!(matches!(stage(&["boot-completed"]), Commands::BootCompleted));
+        assert!(matches!(
+            stage(&["uid-listener"]),
+            Commands::UidListener { worker: false }
+        ));
+        assert!(matches!(
+            stage(&["uid-listener", "--worker"]),
+            Commands::UidListener { worker: true }
+        ));
+        assert!(parse(&["post-fs-data", "extra"]).is_err());
+        assert!(parse(&["post_fs_data"]).is_err());
+    }
+
+    #[test]
+    fn superkey_arguments() {
+        let args = parse(&["--superkey-fd", "5", "services"]).unwrap();
+        assert_eq!(args.superkey_fd, Some(5));
+        assert_eq!(args.superkey, None);
+
+        let args = parse(&["-s", "key", "post-fs-data"]).unwrap();
+        assert_eq!(args.superkey.as_deref(), Some("key"));
+        assert_eq!(args.superkey_fd, None);
+
+        assert!(parse(&["-s", "key", "--superkey-fd", "5", "services"]).is_err());
+        assert!(parse(&["--superkey-fd", "stdin", "services"]).is_err());
+    }
+}
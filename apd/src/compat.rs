@@ -0,0 +1,88 @@
+//! Read-only compatibility shims for scripts probing other root solutions
+//!
+//! When enabled, `magisk` and `ksud` symlinks to apd are created in a dedicated
+//! directory which is only added to PATH of module scripts. Only the commonly
+//! probed read-only subcommands are answered, anything else is refused.
+
+use std::{fs, os::unix::fs::symlink, path::Path};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::{defs, module, utils};
+
+const SHIMS: [&str; 2] = ["magisk", "ksud"];
+
+/// Compat mode is off unless explicitly enabled, to avoid masquerading as another root solution
+pub fn is_enabled() -> bool {
+    Path::new(defs::COMPAT_SHIM_FILE).exists()
+}
+
+/// Create or remove the shim directory according to the current setting
+pub fn ensure_shims() -> Result<()> {
+    let dir = Path::new(defs::COMPAT_BIN_DIR);
+    if !is_enabled() {
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        return Ok(());
+    }
+
+    utils::ensure_dir_exists(dir)?;
+    for name in SHIMS {
+        let link = dir.join(name);
+        let _ = fs::remove_file(&link);
+        symlink(defs::DAEMON_PATH, &link)?;
+    }
+    info!("compat shims installed in {}", dir.display());
+    Ok(())
+}
+
+/// Module scripts are executed with their module dir as cwd
+fn caller_module() -> Option<String> {
+    let cwd = std::env::current_dir().ok()?;
+    let rel = cwd.strip_prefix(defs::MODULE_DIR).ok()?;
+    rel.components()
+        .next()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+}
+
+pub fn shim_main(name: &str, args: &[String]) -> ! {
+    let caller = caller_module().unwrap_or_else(|| "unknown".to_string());
+    info!("[compat] {name} {:?} called by module {caller}", args);
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let code = match (name, args.as_slice()) {
+        ("magisk", ["--path"]) => {
+            println!("{}", utils::get_tmp_path());
+            0
+        }
+        ("magisk", ["-v"]) => {
+            println!("{}:APatch", defs::VERSION_NAME);
+            0
+        }
+        ("magisk", ["-V"]) => {
+            println!("{}", defs::VERSION_CODE);
+            0
+        }
+        // ksud prints the bare array
+        ("ksud", ["module", "list"]) => {
+            match serde_json::to_string_pretty(&module::_list_modules(defs::MODULE_DIR)) {
+                Ok(json) => {
+                    println!("{json}");
+                    0
+                }
+                Err(e) => {
+                    eprintln!("{name}: {e}");
+                    1
+                }
+            }
+        }
+        _ => {
+            warn!("[compat] refused {name} {:?} from module {caller}", args);
+            eprintln!("{name}: command not supported by APatch compat mode");
+            1
+        }
+    };
+    std::process::exit(code);
+}
@@ -0,0 +1,370 @@
+//! Daemon settings in `/data/adb/ap/apd.toml`
+//!
+//! ```toml
+//! mount_mode = "magic"
+//! script_timeout = 35
+//! log_level = "info"
+//! umount_packages = "/data/adb/ap/umount_packages"
+//! boot_log_duration = 300
+//! boot_log_max_kb = 8192
+//! mirror_modules = false
+//! mirror_max_mb = 64
+//! su_namespace = "inherit"
+//! su_strip_mounts = false
+//! allowed_partitions = ["system", "system_ext", "product"]
+//! script_context = "u:r:magisk:s0"
+//! script_restrict_caps = true
+//! script_umask = "022"
+//! local_overlay = false
+//! kernel_modules = true
+//! random_scratch = false
+//! package_refresh_window = 5
+//! refuse_downgrade = false
+//! ```
+//!
+//! Every key is optional. The flag files used before still work and win over
+//! the config when both are present, with a deprecation warning.
+//! `apd config get/set` edits the file for the manager.
+
+use std::{fs, path::Path, sync::OnceLock, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use log::{LevelFilter, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::defs;
+
+const DEFAULT_BOOT_LOG_MAX_KB: u64 = 8192;
+const DEFAULT_MIRROR_MAX_MB: u64 = 64;
+const DEFAULT_SCRIPT_UMASK: u32 = 0o022;
+const DEFAULT_PACKAGE_REFRESH_WINDOW: u64 = 5;
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub mount_mode: Option<String>,
+    /// seconds, `0` disables the deadline
+    pub script_timeout: Option<u64>,
+    pub log_level: Option<String>,
+    /// file listing the packages module mounts are detached from
+    pub umount_packages: Option<String>,
+    /// seconds the boot logs are captured at most, until boot-completed if unset
+    pub boot_log_duration: Option<u64>,
+    /// size at which a boot log file is rotated
+    pub boot_log_max_kb: Option<u64>,
+    /// copy module files to a tmpfs and mount them from there
+    pub mirror_modules: Option<bool>,
+    /// module files larger than this in total are mounted from /data instead
+    pub mirror_max_mb: Option<u64>,
+    /// mount namespace of su sessions: inherit, global or isolated
+    pub su_namespace: Option<String>,
+    /// detach the module mounts in isolated su sessions
+    pub su_strip_mounts: Option<bool>,
+    /// partitions modules may modify, all if unset
+    pub allowed_partitions: Option<Vec<String>>,
+    /// SELinux context stage scripts are executed in, apd's own if unset
+    pub script_context: Option<String>,
+    /// drop dangerous capabilities from the bounding set of stage scripts
+    pub script_restrict_caps: Option<bool>,
+    /// octal umask of stage scripts
+    pub script_umask: Option<String>,
+    /// mount the files of /data/adb/ap/overlay/<partition> above all modules
+    pub local_overlay: Option<bool>,
+    /// load the kernel_modules/*.ko of modules during post-fs-data
+    pub kernel_modules: Option<bool>,
+    /// create the scratch locations of a boot below a randomly named dir of /dev
+    pub random_scratch: Option<bool>,
+    /// seconds between two package list refreshes of the uid listener at least
+    pub package_refresh_window: Option<u64>,
+    /// reject module updates which lower versionCode
+    pub refuse_downgrade: Option<bool>,
+}
+
+const KEYS: &[&str] = &[
+    "mount_mode",
+    "script_timeout",
+    "log_level",
+    "umount_packages",
+    "boot_log_duration",
+    "boot_log_max_kb",
+    "mirror_modules",
+    "mirror_max_mb",
+    "su_namespace",
+    "su_strip_mounts",
+    "allowed_partitions",
+    "script_context",
+    "script_restrict_caps",
+    "script_umask",
+    "local_overlay",
+    "kernel_modules",
+    "random_scratch",
+    "package_refresh_window",
+    "refuse_downgrade",
+];
+const NUMBER_KEYS: &[&str] = &[
+    "script_timeout",
+    "boot_log_duration",
+    "boot_log_max_kb",
+    "mirror_max_mb",
+    "package_refresh_window",
+];
+const BOOL_KEYS: &[&str] = &[
+    "mirror_modules",
+    "su_strip_mounts",
+    "script_restrict_caps",
+    "local_overlay",
+    "kernel_modules",
+    "random_scratch",
+    "refuse_downgrade",
+];
+/// set as a comma separated list
+const LIST_KEYS: &[&str] = &["allowed_partitions"];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+fn load() -> Result<Config> {
+    if !Path::new(defs::CONFIG_FILE).exists() {
+        return Ok(Config::default());
+    }
+    let content = fs::read_to_string(defs::CONFIG_FILE)
+        .with_context(|| format!("Failed to read {}", defs::CONFIG_FILE))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse {}", defs::CONFIG_FILE))
+}
+
+/// The config as parsed on first use, defaults if it is broken
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        load().unwrap_or_else(|e| {
+            warn!("{e:#}, using defaults");
+            Config::default()
+        })
+    })
+}
+
+/// `flag_value` read from the deprecated `flag` file wins over `key`
+fn flag_or<T>(flag: &str, flag_value: Option<T>, key: &str, value: Option<T>) -> Option<T> {
+    match (flag_value, value) {
+        (Some(flag_value), Some(_)) => {
+            warn!("{flag} overrides {key} of apd.toml, flag files are deprecated");
+            Some(flag_value)
+        }
+        (flag_value, value) => flag_value.or(value),
+    }
+}
+
+fn read_flag(flag: &str) -> Option<String> {
+    fs::read_to_string(flag)
+        .ok()
+        .map(|content| content.trim().to_string())
+}
+
+pub fn mount_mode() -> Option<String> {
+    flag_or(
+        defs::MOUNT_MODE_FILE,
+        read_flag(defs::MOUNT_MODE_FILE),
+        "mount_mode",
+        get().mount_mode.clone(),
+    )
+}
+
+pub fn script_timeout() -> Option<u64> {
+    flag_or(
+        defs::SCRIPT_TIMEOUT_FILE,
+        read_flag(defs::SCRIPT_TIMEOUT_FILE).and_then(|secs| secs.parse().ok()),
+        "script_timeout",
+        get().script_timeout,
+    )
+}
+
+/// The list read from the default place wins over a path in the config
+pub fn umount_packages_file() -> String {
+    let flag = Path::new(defs::UMOUNT_PACKAGES_FILE)
+        .exists()
+        .then(|| defs::UMOUNT_PACKAGES_FILE.to_string());
+    flag_or(
+        defs::UMOUNT_PACKAGES_FILE,
+        flag,
+        "umount_packages",
+        get().umount_packages.clone(),
+    )
+    .unwrap_or_else(|| defs::UMOUNT_PACKAGES_FILE.to_string())
+}
+
+pub fn log_level() -> LevelFilter {
+    get()
+        .log_level
+        .as_deref()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Trace)
+}
+
+pub fn boot_log_duration() -> Option<u64> {
+    get().boot_log_duration
+}
+
+pub fn boot_log_max_kb() -> u64 {
+    get().boot_log_max_kb.unwrap_or(DEFAULT_BOOT_LOG_MAX_KB).max(1)
+}
+
+pub fn mirror_modules() -> bool {
+    get().mirror_modules.unwrap_or(false)
+}
+
+pub fn mirror_max_bytes() -> u64 {
+    get().mirror_max_mb.unwrap_or(DEFAULT_MIRROR_MAX_MB) * 1024 * 1024
+}
+
+pub fn su_namespace() -> Option<String> {
+    get().su_namespace.clone()
+}
+
+pub fn su_strip_mounts() -> bool {
+    get().su_strip_mounts.unwrap_or(false)
+}
+
+pub fn allowed_partitions() -> Option<Vec<String>> {
+    get().allowed_partitions.clone()
+}
+
+pub fn script_context() -> Option<String> {
+    get().script_context.clone()
+}
+
+pub fn script_restrict_caps() -> bool {
+    get().script_restrict_caps.unwrap_or(true)
+}
+
+pub fn local_overlay() -> bool {
+    get().local_overlay.unwrap_or(false)
+}
+
+pub fn kernel_modules() -> bool {
+    get().kernel_modules.unwrap_or(true)
+}
+
+pub fn random_scratch() -> bool {
+    get().random_scratch.unwrap_or(false)
+}
+
+pub fn refuse_downgrade() -> bool {
+    get().refuse_downgrade.unwrap_or(false)
+}
+
+pub fn package_refresh_window() -> Duration {
+    Duration::from_secs(
+        get()
+            .package_refresh_window
+            .unwrap_or(DEFAULT_PACKAGE_REFRESH_WINDOW),
+    )
+}
+
+pub fn script_umask() -> u32 {
+    get()
+        .script_umask
+        .as_deref()
+        .and_then(|umask| u32::from_str_radix(umask, 8).ok())
+        .filter(|umask| *umask <= 0o777)
+        .unwrap_or(DEFAULT_SCRIPT_UMASK)
+}
+
+fn read_table() -> Result<toml::Table> {
+    if !Path::new(defs::CONFIG_FILE).exists() {
+        return Ok(toml::Table::new());
+    }
+    let content = fs::read_to_string(defs::CONFIG_FILE)
+        .with_context(|| format!("Failed to read {}", defs::CONFIG_FILE))?;
+    content
+        .parse()
+        .with_context(|| format!("Failed to parse {}", defs::CONFIG_FILE))
+}
+
+/// The value of `key` as `set_key` takes it, `None` if it is not set
+pub fn value(key: &str) -> Result<Option<String>> {
+    if !KEYS.contains(&key) {
+        bail!("unknown config key {key}, known keys: {}", KEYS.join(", "));
+    }
+    Ok(read_table()?.get(key).map(|value| match value {
+        toml::Value::String(value) => value.clone(),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| value.as_str().map_or_else(|| value.to_string(), String::from))
+            .collect::<Vec<_>>()
+            .join(","),
+        value => value.to_string(),
+    }))
+}
+
+/// Print the value of `key`, nothing if it is not set
+pub fn get_key(key: &str) -> Result<()> {
+    if let Some(value) = value(key)? {
+        println!("{value}");
+    }
+    Ok(())
+}
+
+/// Set `key` to `value`, or remove it if no value is given
+pub fn set_key(key: &str, value: Option<&str>) -> Result<()> {
+    if !KEYS.contains(&key) {
+        bail!("unknown config key {key}, known keys: {}", KEYS.join(", "));
+    }
+    let mut table = read_table()?;
+    match value {
+        None => {
+            table.remove(key);
+        }
+        Some(value) if NUMBER_KEYS.contains(&key) => {
+            let number: i64 = value
+                .parse()
+                .ok()
+                .filter(|n| *n >= 0)
+                .with_context(|| format!("{key} must be a positive number"))?;
+            table.insert(key.to_string(), toml::Value::Integer(number));
+        }
+        Some(value) if LIST_KEYS.contains(&key) => {
+            let values = value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| toml::Value::String(item.to_string()))
+                .collect();
+            table.insert(key.to_string(), toml::Value::Array(values));
+        }
+        Some(value) if BOOL_KEYS.contains(&key) => {
+            let flag: bool = value
+                .parse()
+                .ok()
+                .with_context(|| format!("{key} must be true or false"))?;
+            table.insert(key.to_string(), toml::Value::Boolean(flag));
+        }
+        Some(value) => {
+            if key == "mount_mode"
+                && ![
+                    defs::MOUNT_MODE_MAGIC,
+                    defs::MOUNT_MODE_METAMODULE,
+                    defs::MOUNT_MODE_DISABLED,
+                ]
+                .contains(&value)
+            {
+                bail!("unknown mount mode {value}");
+            }
+            if key == "log_level" && value.parse::<LevelFilter>().is_err() {
+                bail!("unknown log level {value}");
+            }
+            if key == "script_umask"
+                && !u32::from_str_radix(value, 8).is_ok_and(|umask| umask <= 0o777)
+            {
+                bail!("script_umask must be an octal umask like 022");
+            }
+            if key == "su_namespace" {
+                value.parse::<crate::namespace::NamespaceMode>()?;
+            }
+            table.insert(key.to_string(), toml::Value::String(value.to_string()));
+        }
+    }
+    let content = toml::to_string(&table)?;
+    let tmp = format!("{}.tmp", defs::CONFIG_FILE);
+    fs::write(&tmp, content).with_context(|| format!("Failed to write {tmp}"))?;
+    fs::rename(&tmp, defs::CONFIG_FILE)
+        .with_context(|| format!("Failed to rename {tmp}"))?;
+    Ok(())
+}
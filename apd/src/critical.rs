@@ -0,0 +1,320 @@
+//! Protection of critical boot components
+//!
+//! Modules replacing init, the sepolicy or the boot jars are the leading cause
+//! of hard bricks. Paths matching one of the patterns below are only mounted for
+//! modules which were acknowledged explicitly, either by `allow_critical=true` in
+//! module.prop or with `apd module ack-critical <id>`. More patterns can be added
+//! one per line in `/data/adb/ap/critical_paths`.
+//!
+//! Patterns are relative to the root, `*` matches within one path segment and a
+//! trailing `**` matches the directory itself and everything below it. Matching
+//! ignores ASCII case and treats `system/<partition>` as `<partition>`.
+//!
+//! Symlinks are never followed, magic mount places them as they are. A symlink
+//! or file where a critical path has a parent dir, e.g. `system/bin` as a
+//! link, would replace that dir with everything in it, so it counts as critical.
+//! Only parents spelled out in a pattern count, a file in `system/framework`
+//! is not critical because of `system/framework/*/boot*`.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path},
+};
+
+use anyhow::{Result, ensure};
+
+use crate::{defs, module, utils};
+
+const BUILTIN_PATTERNS: &[&str] = &[
+    "system/bin/init",
+    "system/bin/linker*",
+    "system/bin/app_process*",
+    "system/lib*/libc.so",
+    "system/etc/selinux/**",
+    "vendor/etc/selinux/**",
+    "system/etc/init/hw/**",
+    "system/framework/services.jar",
+    "system/framework/framework.jar",
+    "system/framework/boot*",
+    "system/framework/*/boot*",
+    "system/apex/**",
+    "vendor/etc/fstab*",
+];
+
+/// Partitions which a module may carry either at its root or below `system/`
+pub const PARTITIONS: [&str; 5] = ["vendor", "system_ext", "product", "odm", "oem"];
+
+/// Patterns split into lowercase segments once, they are matched against
+/// every file of every module
+pub struct Patterns(Vec<Vec<String>>);
+
+impl Patterns {
+    pub fn load() -> Self {
+        let mut patterns: Vec<String> = BUILTIN_PATTERNS.iter().map(|p| p.to_string()).collect();
+        if let Ok(content) = fs::read_to_string(defs::CRITICAL_PATHS_FILE) {
+            patterns.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(|line| line.trim_start_matches('/').to_string()),
+            );
+        }
+        Self::new(&patterns)
+    }
+
+    fn new(patterns: &[String]) -> Self {
+        Patterns(
+            patterns
+                .iter()
+                .map(|pattern| pattern.split('/').map(str::to_ascii_lowercase).collect())
+                .collect(),
+        )
+    }
+
+    /// The patterns as one string, which changes with any of them
+    pub fn fingerprint(&self) -> String {
+        self.0
+            .iter()
+            .map(|pattern| pattern.join("/"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Whether a module entry at `path`, relative to the module root, is
+    /// critical. An entry which is not a dir replaces whatever is below it.
+    pub fn blocks_entry(&self, path: &Path, is_dir: bool) -> bool {
+        let segments = normalize(path);
+        self.0.iter().any(|pattern| {
+            match_segments(pattern, &segments) || (!is_dir && match_parent(pattern, &segments))
+        })
+    }
+
+    /// Critical paths carried by a module, relative to its root
+    ///
+    /// Only directories which may still contain a match are entered, so the
+    /// cost does not grow with the size of the module.
+    pub fn scan(&self, module_path: &Path) -> Vec<String> {
+        let mut found = Vec::new();
+        for partition in std::iter::once("system").chain(PARTITIONS) {
+            self.scan_dir(module_path, &module_path.join(partition), &mut found);
+        }
+        found
+    }
+
+    fn scan_dir(&self, module_path: &Path, dir: &Path, found: &mut Vec<String>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(rel) = path.strip_prefix(module_path) else {
+                continue;
+            };
+            // not followed if a symlink
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            if self.blocks_entry(rel, is_dir) {
+                found.push(rel.display().to_string());
+            }
+            let segments = normalize(rel);
+            if is_dir && self.0.iter().any(|pattern| match_prefix(pattern, &segments)) {
+                self.scan_dir(module_path, &path, found);
+            }
+        }
+    }
+}
+
+fn normalize(path: &Path) -> Vec<String> {
+    let mut segments: Vec<String> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(s) => segments.push(s.to_string_lossy().to_ascii_lowercase()),
+            Component::ParentDir => {
+                segments.pop();
+            }
+            _ => {}
+        }
+    }
+    if segments.len() > 1 && segments[0] == "system" && PARTITIONS.contains(&segments[1].as_str())
+    {
+        segments.remove(0);
+    }
+    segments
+}
+
+fn match_segments(pattern: &[String], path: &[String]) -> bool {
+    match (pattern.first(), path.first()) {
+        (Some(p), _) if p == "**" && pattern.len() == 1 => true,
+        (Some(p), Some(s)) => {
+            match_glob(p.as_bytes(), s.as_bytes()) && match_segments(&pattern[1..], &path[1..])
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Whether something below `path` may match `pattern`
+fn match_prefix(pattern: &[String], path: &[String]) -> bool {
+    match (pattern.first(), path.first()) {
+        (Some(p), _) if p == "**" => true,
+        (Some(p), Some(s)) => {
+            match_glob(p.as_bytes(), s.as_bytes()) && match_prefix(&pattern[1..], &path[1..])
+        }
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+/// Whether `path` is a dir named literally by `pattern` above its last segment
+fn match_parent(pattern: &[String], path: &[String]) -> bool {
+    path.len() < pattern.len()
+        && pattern.iter().zip(path).all(|(p, s)| !p.contains('*') && p == s)
+}
+
+fn match_glob(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| match_glob(rest, &s[i..])),
+        Some((c, rest)) => s.first() == Some(c) && match_glob(rest, &s[1..]),
+    }
+}
+
+pub fn is_acknowledged(module_path: &Path) -> bool {
+    module_path.join(defs::ALLOW_CRITICAL_FILE_NAME).exists()
+        || module::read_module_prop(module_path).is_ok_and(|props| acknowledged_by(&props))
+}
+
+/// Acknowledgement through an already parsed module.prop
+pub fn acknowledged_by(props: &HashMap<String, String>) -> bool {
+    props
+        .get("allow_critical")
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("true") || v.trim() == "1")
+}
+
+/// Mount planning filter for a module which was not acknowledged
+pub struct Guard<'a> {
+    pub root: &'a Path,
+    pub patterns: &'a Patterns,
+}
+
+impl Guard<'_> {
+    pub fn blocks(&self, path: &Path) -> bool {
+        let is_dir = path.symlink_metadata().is_ok_and(|m| m.is_dir());
+        let blocked = path
+            .strip_prefix(self.root)
+            .is_ok_and(|rel| self.patterns.blocks_entry(rel, is_dir));
+        if blocked {
+            log::warn!(
+                "!!! {} touches a critical boot component, skip it. Acknowledge with `apd module ack-critical` to mount it",
+                path.display()
+            );
+        }
+        blocked
+    }
+}
+
+pub fn ack_critical(id: &str) -> Result<()> {
+    let module_path = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(module_path.exists(), "module: {} not found!", id);
+    utils::ensure_file_exists(module_path.join(defs::ALLOW_CRITICAL_FILE_NAME))?;
+    println!("- Critical paths of {id} will be mounted on next boot");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn builtin() -> Patterns {
+        let patterns: Vec<String> = BUILTIN_PATTERNS.iter().map(|p| p.to_string()).collect();
+        Patterns::new(&patterns)
+    }
+
+    fn critical(path: &str) -> bool {
+        builtin().blocks_entry(Path::new(path), false)
+    }
+
+    #[test]
+    fn exact_and_glob_patterns() {
+        assert!(critical("system/bin/init"));
+        assert!(critical("system/bin/linker64"));
+        assert!(critical("system/lib64/libc.so"));
+        assert!(critical("system/framework/arm64/boot.oat"));
+        assert!(critical("system/apex/com.android.runtime.apex"));
+        assert!(!critical("system/bin/init.rc"));
+        assert!(!critical("system/bin/initx"));
+        assert!(!critical("system/binx/init"));
+        assert!(!critical("system/lib64/libcutils.so"));
+        assert!(!critical("system/framework/ext.jar"));
+    }
+
+    #[test]
+    fn nested_paths_below_a_double_star() {
+        assert!(critical("system/etc/selinux"));
+        assert!(critical("system/etc/selinux/plat_sepolicy.cil"));
+        assert!(critical("system/etc/selinux/mapping/33.0.cil"));
+        assert!(!critical("system/etc/selinuxx/file"));
+    }
+
+    #[test]
+    fn case_variants_match() {
+        assert!(critical("System/Bin/Init"));
+        assert!(critical("SYSTEM/ETC/SELINUX/plat_sepolicy.cil"));
+        assert!(critical("system/framework/Services.JAR"));
+    }
+
+    #[test]
+    fn partitions_below_system_and_odd_components() {
+        assert!(critical("system/vendor/etc/fstab.qcom"));
+        assert!(critical("vendor/etc/selinux/vendor_sepolicy.cil"));
+        assert!(critical("./system//bin/init"));
+        assert!(critical("system/xbin/../bin/init"));
+        assert!(!critical("system/bin/init/../sh"));
+    }
+
+    #[test]
+    fn entries_shadowing_a_critical_dir() {
+        let patterns = builtin();
+        // as links or files these replace the dir holding init
+        assert!(patterns.blocks_entry(Path::new("system/bin"), false));
+        assert!(patterns.blocks_entry(Path::new("system"), false));
+        assert!(patterns.blocks_entry(Path::new("vendor"), false));
+        assert!(!patterns.blocks_entry(Path::new("system/bin"), true));
+        assert!(!patterns.blocks_entry(Path::new("system/xbin"), false));
+        assert!(!patterns.blocks_entry(Path::new("product"), false));
+        // only literal parents, any file in framework would match `*`
+        assert!(!patterns.blocks_entry(Path::new("system/framework/ext.jar"), false));
+        assert!(!patterns.blocks_entry(Path::new("system/lib64"), false));
+    }
+
+    #[test]
+    fn symlinked_dirs_are_reported_and_not_followed() {
+        let root = TempDir::new("critical-symlink");
+        root.write("payload/init", "");
+        root.write("system/xbin/tool", "");
+        symlink("../payload", root.path().join("system/bin")).unwrap();
+        // a link named like a critical path is critical, where it points is not
+        fs::create_dir_all(root.path().join("system/framework")).unwrap();
+        symlink("/system/bin/init", root.path().join("system/framework/tool.jar")).unwrap();
+        fs::write(root.path().join("system/framework/arm64"), "").unwrap();
+        symlink("tool.jar", root.path().join("system/framework/services.jar")).unwrap();
+
+        let patterns = builtin();
+        let mut found = patterns.scan(root.path());
+        found.sort();
+        assert_eq!(found, ["system/bin", "system/framework/services.jar"]);
+
+        let guard = Guard {
+            root: root.path(),
+            patterns: &patterns,
+        };
+        assert!(guard.blocks(&root.path().join("system/bin")));
+        assert!(!guard.blocks(&root.path().join("system/xbin")));
+        assert!(!guard.blocks(&root.path().join("system/framework/tool.jar")));
+        assert!(guard.blocks(&root.path().join("system/framework/services.jar")));
+    }
+}
@@ -0,0 +1,381 @@
+//! The socket of the resident daemon
+//!
+//! The uid listener serves `/dev/socket/apd` so the manager can query apd
+//! without starting a process per call. Every message is a JSON document
+//! preceded by its length as a 4 byte big endian integer. A connection sends
+//! [`Request`]s, each answered with one [`Reply`]. After `subscribe` the
+//! daemon only pushes [`Event`]s on it.
+//!
+//! Peers are checked by their credentials: root, or the uid of the manager
+//! package named in `/data/adb/ap/manager_pkg`. Only root may `publish`, which
+//! is how apd processes of the boot pipeline hand their events to subscribers.
+//!
+//! The CLI asks the daemon when it runs and does the work itself otherwise.
+
+use std::{
+    fs::{self, Permissions},
+    io::{self, ErrorKind, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{info, warn};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::{bootloop, defs, events, module, package, status::MountDecision};
+
+/// Longest message accepted, a module list is far below
+const MAX_MESSAGE: usize = 1 << 20;
+/// A subscriber which does not read for this long is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    ModuleList,
+    ModuleEnable { id: String },
+    ModuleDisable { id: String },
+    /// the mount decision of this boot and where module files are mounted
+    MountStatus,
+    SafeMode,
+    /// package list refreshes of the uid listener
+    RefreshStats,
+    /// entries of the event log, from the first of boot `since` on
+    Events {
+        #[serde(default)]
+        since: Option<String>,
+    },
+    Subscribe,
+    Publish { event: Event },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PackagesRefreshed,
+    ModuleMounted { id: String },
+    ModuleMountFailed { id: String },
+    /// an entry was added to the event log
+    Logged { entry: events::Entry },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Reply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn read_message<T: DeserializeOwned>(stream: &mut UnixStream) -> io::Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "message too long"));
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    serde_json::from_slice(&buf).map(Some).map_err(io::Error::from)
+}
+
+fn frame(message: &impl Serialize) -> io::Result<Vec<u8>> {
+    let buf = serde_json::to_vec(message)?;
+    let mut frame = (buf.len() as u32).to_be_bytes().to_vec();
+    frame.extend(buf);
+    Ok(frame)
+}
+
+fn write_message(stream: &mut UnixStream, message: &impl Serialize) -> io::Result<()> {
+    stream.write_all(&frame(message)?)
+}
+
+/// Uid of the manager package, `None` if none is configured or installed
+fn manager_uid() -> Option<u32> {
+    let content = fs::read_to_string(defs::MANAGER_PKG_FILE).ok()?;
+    let package = content.trim().split('/').next()?;
+    package::package_uid(package).and_then(|uid| u32::try_from(uid).ok())
+}
+
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let cred = rustix::net::sockopt::socket_peercred(stream)?;
+    Ok(cred.uid.as_raw())
+}
+
+/// A subscribed connection, locked on its own so frames of two broadcasts do
+/// not interleave
+type Subscriber = Arc<Mutex<UnixStream>>;
+
+fn subscribers() -> &'static Mutex<Vec<Subscriber>> {
+    static SUBSCRIBERS: OnceLock<Mutex<Vec<Subscriber>>> = OnceLock::new();
+    SUBSCRIBERS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Push `event` to the subscribers of this daemon, dropping those gone
+pub fn broadcast(event: &Event) {
+    broadcast_to(subscribers(), event);
+}
+
+/// The list is only locked to copy it and to drop failed subscribers, a
+/// subscriber which does not read holds up nobody but itself, for at most
+/// [`WRITE_TIMEOUT`].
+fn broadcast_to(registry: &Mutex<Vec<Subscriber>>, event: &Event) {
+    let frame = match frame(event) {
+        Ok(frame) => frame,
+        Err(e) => {
+            warn!("[daemon] Failed to encode event: {e}");
+            return;
+        }
+    };
+    let current = registry.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let failed: Vec<Subscriber> = current
+        .into_iter()
+        .filter(|subscriber| {
+            let mut stream = subscriber.lock().unwrap_or_else(|e| e.into_inner());
+            stream.write_all(&frame).is_err()
+        })
+        .collect();
+    if !failed.is_empty() {
+        let mut subscribers = registry.lock().unwrap_or_else(|e| e.into_inner());
+        subscribers.retain(|subscriber| !failed.iter().any(|gone| Arc::ptr_eq(gone, subscriber)));
+    }
+}
+
+fn handle(request: Request, root: bool) -> Result<Option<Value>> {
+    Ok(match request {
+        Request::ModuleList => Some(serde_json::to_value(module::_list_modules(
+            defs::MODULE_DIR,
+        ))?),
+        Request::ModuleEnable { id } => {
+            module::enable_module(&id)?;
+            None
+        }
+        Request::ModuleDisable { id } => {
+            module::disable_module(&id)?;
+            None
+        }
+        Request::MountStatus => Some(serde_json::json!({
+            "decision": MountDecision::current(),
+            "modules": crate::mount::module_mounts()?,
+        })),
+        // root may use the su key, like the package refresh of the listener
+        Request::SafeMode => Some(serde_json::to_value(bootloop::status(Some(
+            "su".to_string(),
+        )))?),
+        Request::RefreshStats => Some(serde_json::to_value(crate::event::refresh_stats())?),
+        Request::Events { since } => Some(serde_json::to_value(events::read(since.as_deref()))?),
+        Request::Publish { event } => {
+            ensure!(root, "only root may publish events");
+            broadcast(&event);
+            None
+        }
+        Request::Subscribe => unreachable!("handled by serve"),
+    })
+}
+
+fn serve(mut stream: UnixStream) {
+    let uid = match peer_uid(&stream) {
+        Ok(uid) => uid,
+        Err(e) => {
+            warn!("[daemon] no peer credentials: {e}");
+            return;
+        }
+    };
+    // the manager of any user of the device, uids repeat every 100000
+    if uid != 0 && Some(uid % 100_000) != manager_uid() {
+        warn!("[daemon] refused connection of uid {uid}");
+        return;
+    }
+    loop {
+        let request = match read_message::<Request>(&mut stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("[daemon] bad request of uid {uid}: {e}");
+                return;
+            }
+        };
+        if matches!(request, Request::Subscribe) {
+            let subscribed = stream.set_write_timeout(Some(WRITE_TIMEOUT)).and_then(|()| {
+                write_message(
+                    &mut stream,
+                    &Reply {
+                        ok: true,
+                        data: None,
+                        error: None,
+                    },
+                )
+            });
+            if subscribed.is_ok() {
+                let mut subscribers = subscribers().lock().unwrap_or_else(|e| e.into_inner());
+                subscribers.push(Arc::new(Mutex::new(stream)));
+            }
+            return;
+        }
+        let reply = match handle(request, uid == 0) {
+            Ok(data) => Reply {
+                ok: true,
+                data,
+                error: None,
+            },
+            Err(e) => Reply {
+                ok: false,
+                data: None,
+                error: Some(format!("{e:#}")),
+            },
+        };
+        if write_message(&mut stream, &reply).is_err() {
+            return;
+        }
+    }
+}
+
+/// Serve the socket from a thread of the uid listener
+pub fn spawn_server() {
+    let path = Path::new(defs::DAEMON_SOCKET);
+    let _ = fs::remove_file(path);
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("[daemon] Failed to bind {}: {}", path.display(), e);
+            return;
+        }
+    };
+    // the manager must be able to connect, peers are checked by uid instead
+    if let Err(e) = fs::set_permissions(path, Permissions::from_mode(0o666)) {
+        warn!("[daemon] Failed to chmod {}: {}", path.display(), e);
+    }
+    info!("[daemon] listening on {}", path.display());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || serve(stream));
+                }
+                Err(e) => warn!("[daemon] accept failed: {e}"),
+            }
+        }
+    });
+}
+
+fn connect() -> Option<UnixStream> {
+    UnixStream::connect(defs::DAEMON_SOCKET).ok()
+}
+
+/// Send `request` to the daemon, `None` if it does not run
+pub fn call(request: &Request) -> Result<Option<Value>> {
+    let Some(mut stream) = connect() else {
+        return Ok(None);
+    };
+    write_message(&mut stream, request).context("Failed to send request to the daemon")?;
+    let reply: Reply = read_message(&mut stream)
+        .context("Failed to read reply of the daemon")?
+        .context("the daemon closed the connection")?;
+    if !reply.ok {
+        bail!("{}", reply.error.unwrap_or_default());
+    }
+    Ok(Some(reply.data.unwrap_or(Value::Null)))
+}
+
+/// Like [`call`], with the reply data deserialized
+pub fn query<T: DeserializeOwned>(request: &Request) -> Result<Option<T>> {
+    call(request)?
+        .map(|data| serde_json::from_value(data).context("Unexpected reply of the daemon"))
+        .transpose()
+}
+
+/// Hand `events` to the subscribers of the daemon, if it runs
+pub fn publish(events: impl IntoIterator<Item = Event>) {
+    for event in events {
+        match call(&Request::Publish { event }) {
+            Ok(Some(_)) => {}
+            Ok(None) => return,
+            Err(e) => {
+                warn!("[daemon] Failed to publish event: {e:#}");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscribe(registry: &Mutex<Vec<Subscriber>>) -> UnixStream {
+        let (daemon_end, client_end) = UnixStream::pair().unwrap();
+        daemon_end.set_write_timeout(Some(Duration::from_millis(100))).unwrap();
+        registry.lock().unwrap().push(Arc::new(Mutex::new(daemon_end)));
+        client_end
+    }
+
+    fn received(stream: &mut UnixStream) -> Event {
+        read_message(stream).unwrap().unwrap()
+    }
+
+    #[test]
+    fn events_reach_every_subscriber() {
+        let registry = Mutex::new(Vec::new());
+        let mut first = subscribe(&registry);
+        let mut second = subscribe(&registry);
+        broadcast_to(&registry, &Event::ModuleMounted { id: "a".to_string() });
+        broadcast_to(&registry, &Event::PackagesRefreshed);
+        for stream in [&mut first, &mut second] {
+            assert!(matches!(received(stream), Event::ModuleMounted { id } if id == "a"));
+            assert!(matches!(received(stream), Event::PackagesRefreshed));
+        }
+        assert_eq!(registry.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn closed_and_stuck_subscribers_are_dropped() {
+        let registry = Mutex::new(Vec::new());
+        let mut live = subscribe(&registry);
+        drop(subscribe(&registry));
+        // never reads, its buffer is filled up front
+        let stuck = subscribe(&registry);
+        {
+            let daemon_end = registry.lock().unwrap()[2].clone();
+            let mut daemon_end = daemon_end.lock().unwrap();
+            daemon_end.set_nonblocking(true).unwrap();
+            while daemon_end.write(&[0; 4096]).is_ok() {}
+            daemon_end.set_nonblocking(false).unwrap();
+        }
+
+        broadcast_to(&registry, &Event::PackagesRefreshed);
+        assert!(matches!(received(&mut live), Event::PackagesRefreshed));
+        assert_eq!(registry.lock().unwrap().len(), 1);
+        drop(stuck);
+    }
+
+    #[test]
+    fn a_stuck_subscriber_does_not_hold_the_list() {
+        let registry = Arc::new(Mutex::new(Vec::new()));
+        let _client = subscribe(&registry);
+        let subscriber = registry.lock().unwrap()[0].clone();
+        // a broadcast writing to it waits for its lock, not the list
+        let writing = subscriber.lock().unwrap();
+        let broadcaster = {
+            let registry = registry.clone();
+            thread::spawn(move || broadcast_to(&registry, &Event::PackagesRefreshed))
+        };
+        thread::sleep(Duration::from_millis(50));
+        let _new = subscribe(&registry);
+        assert_eq!(registry.lock().unwrap().len(), 2);
+        drop(writing);
+        broadcaster.join().unwrap();
+    }
+}
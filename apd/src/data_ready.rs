@@ -0,0 +1,62 @@
+//! Whether /data/adb can be read yet
+//!
+//! On a few devices post-fs-data starts while /data/adb is not usable yet for a
+//! moment, it reads as empty and the boot would go on as if no module and no
+//! config existed. A sentinel file written by module installs and every boot
+//! which found /data/adb tells the real dir apart. Installs older than the
+//! sentinel count as ready once the working dir lists anything.
+
+use std::{
+    fs, thread,
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+
+use crate::{defs, flags};
+
+const FIRST_DELAY: Duration = Duration::from_millis(50);
+const MAX_DELAY: Duration = Duration::from_secs(1);
+const DEADLINE: Duration = Duration::from_secs(10);
+
+fn ready() -> bool {
+    flags::is_set(defs::DATA_SENTINEL_FILE)
+        || fs::read_dir(defs::WORKING_DIR).is_ok_and(|mut dir| dir.next().is_some())
+}
+
+/// Record that /data/adb is the real dir
+pub fn mark() {
+    if !flags::is_set(defs::DATA_SENTINEL_FILE)
+        && let Err(e) = flags::set(defs::DATA_SENTINEL_FILE)
+    {
+        warn!("Failed to create {}: {:#}", defs::DATA_SENTINEL_FILE, e);
+    }
+}
+
+/// Wait with backoff for /data/adb to become readable, `false` if it did not
+/// within [`DEADLINE`]
+pub fn wait() -> bool {
+    let start = Instant::now();
+    let mut delay = FIRST_DELAY;
+    while !ready() {
+        if start.elapsed() >= DEADLINE {
+            error!(
+                "{} is still not readable after {}s, modules are not mounted this boot",
+                defs::ADB_DIR,
+                DEADLINE.as_secs()
+            );
+            return false;
+        }
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_DELAY);
+    }
+    if start.elapsed() >= FIRST_DELAY {
+        info!(
+            "{} became readable after {}ms",
+            defs::ADB_DIR,
+            start.elapsed().as_millis()
+        );
+    }
+    mark();
+    true
+}
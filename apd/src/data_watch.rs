@@ -0,0 +1,87 @@
+//! Detection of /data remounts which leave module mounts stale
+//!
+//! Storage events (adoptable storage migration, fsck triggered remounts) may
+//! remount /data while booted, module files mounted from `/data/adb/modules` then
+//! keep pointing at the old superblock and reads fail with ESTALE. post-fs-data
+//! records the device and mount id backing the module dir, the uid listener
+//! compares them periodically and reacts as configured in
+//! `/data/adb/ap/data_remount_action`:
+//!
+//! - `log` (default): only log it
+//! - `notify`: also leave a marker which `apd status` reports until next boot
+//!
+//! Module mounts can't be redone while booted, a reboot restores them.
+
+use std::{fs, path::Path, thread, time::Duration};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use rustix::fs::{AtFlags, CWD, StatxFlags, statx};
+
+use crate::{defs, utils};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Device and mount id backing the module dir, both cheap to query
+fn identity() -> Option<(u64, u64)> {
+    let stx = statx(CWD, defs::MODULE_DIR, AtFlags::empty(), StatxFlags::MNT_ID).ok()?;
+    let dev = ((stx.stx_dev_major as u64) << 32) | stx.stx_dev_minor as u64;
+    Some((dev, stx.stx_mnt_id))
+}
+
+fn recorded() -> Option<(u64, u64)> {
+    let content = fs::read_to_string(defs::DATA_IDENTITY_FILE).ok()?;
+    let (dev, mnt_id) = content.trim().split_once(':')?;
+    Some((dev.parse().ok()?, mnt_id.parse().ok()?))
+}
+
+/// Remember what backs the module dir after modules were mounted
+pub fn record() -> Result<()> {
+    let _ = fs::remove_file(defs::DATA_STALE_FILE);
+    let (dev, mnt_id) = identity().context("Failed to stat module dir")?;
+    fs::write(defs::DATA_IDENTITY_FILE, format!("{dev}:{mnt_id}"))
+        .with_context(|| format!("Failed to write {}", defs::DATA_IDENTITY_FILE))?;
+    Ok(())
+}
+
+pub fn is_stale() -> bool {
+    Path::new(defs::DATA_STALE_FILE).exists()
+}
+
+fn shutting_down() -> bool {
+    utils::getprop("sys.powerctl").is_some_and(|v| !v.is_empty())
+}
+
+fn on_remount(before: (u64, u64), after: Option<(u64, u64)>) {
+    warn!(
+        "/data remounted while booted ({:?} -> {:?}), module mounts are stale until reboot",
+        before, after
+    );
+    let action = fs::read_to_string(defs::DATA_REMOUNT_ACTION_FILE).unwrap_or_default();
+    if action.trim() == "notify"
+        && let Err(e) = utils::ensure_file_exists(defs::DATA_STALE_FILE)
+    {
+        warn!("Failed to mark module mounts stale: {}", e);
+    }
+}
+
+/// Poll the module dir identity in the background of a long running process
+pub fn spawn_watcher() {
+    let Some(before) = recorded() else {
+        info!("no recorded /data identity, skip remount watcher");
+        return;
+    };
+    thread::spawn(move || {
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            if shutting_down() {
+                return;
+            }
+            let now = identity();
+            if now != Some(before) {
+                on_remount(before, now);
+                return;
+            }
+        }
+    });
+}
@@ -6,9 +6,75 @@ pub const BINARY_DIR: &str = concatcp!(WORKING_DIR, "bin/");
 pub const APATCH_LOG_FOLDER: &str = concatcp!(WORKING_DIR, "log/");
 
 pub const AP_RC_PATH: &str = concatcp!(WORKING_DIR, ".aprc");
+pub const CONFIG_FILE: &str = concatcp!(WORKING_DIR, "apd.toml");
 pub const GLOBAL_NAMESPACE_FILE: &str = concatcp!(ADB_DIR, ".global_namespace_enable");
 pub const DAEMON_PATH: &str = concatcp!(ADB_DIR, "apd");
 pub const FACTORY_PROPS_FILE: &str = concatcp!(WORKING_DIR, "factory_props_enable");
+pub const HOSTS_FILE: &str = concatcp!(WORKING_DIR, "hosts");
+pub const HOSTS_ENABLE_FILE: &str = concatcp!(WORKING_DIR, "hosts_enable");
+pub const PROP_OVERRIDES_FILE: &str = concatcp!(WORKING_DIR, "prop_overrides");
+pub const SHELL_SU_FILE: &str = concatcp!(WORKING_DIR, "shell_su_enable");
+pub const IGNORE_MAGISK_FILE: &str = concatcp!(WORKING_DIR, "ignore_magisk");
+pub const REVOKE_ON_REINSTALL_FILE: &str = concatcp!(WORKING_DIR, "revoke_on_reinstall_enable");
+pub const HEALTH_FILE: &str = concatcp!(WORKING_DIR, "health");
+pub const COMPAT_SHIM_FILE: &str = concatcp!(WORKING_DIR, "compat_shims_enable");
+pub const COMPAT_BIN_DIR: &str = concatcp!(WORKING_DIR, "compat/");
+/// `<partition>/...` files mounted without a module, see `local_overlay` of apd.toml
+pub const LOCAL_OVERLAY_DIR: &str = concatcp!(WORKING_DIR, "overlay/");
+pub const HEALTH_PREV_FILE: &str = concatcp!(WORKING_DIR, "health.prev");
+pub const MOUNT_DECISION_FILE: &str = concatcp!(WORKING_DIR, ".last_mount_decision");
+pub const BOOT_PROGRESS_FILE: &str = concatcp!(WORKING_DIR, ".boot_progress");
+pub const KERNEL_HANDSHAKE_FILE: &str = concatcp!(WORKING_DIR, ".kernel_handshake");
+/// exists once /data/adb was found readable, see data_ready
+pub const DATA_SENTINEL_FILE: &str = concatcp!(WORKING_DIR, ".data_ready");
+pub const KMOD_RESULTS_FILE: &str = concatcp!(WORKING_DIR, ".kmod_results");
+pub const TARGETED_MOUNTS_FILE: &str = concatcp!(WORKING_DIR, ".targeted_mounts");
+/// the random scratch dir of this boot, see paths
+pub const SCRATCH_DIR_FILE: &str = concatcp!(WORKING_DIR, ".scratch_dir");
+pub const RELABEL_STAMP_FILE: &str = concatcp!(WORKING_DIR, ".relabel_stamp");
+/// module sizes, critical paths and file hashes, see scan_cache
+pub const SCAN_CACHE_FILE: &str = concatcp!(WORKING_DIR, ".scan_cache");
+pub const BOOT_COMPLETED_STAMP_FILE: &str = concatcp!(WORKING_DIR, ".boot_completed_ran");
+pub const MODULES_LOCK_FILE: &str = concatcp!(WORKING_DIR, ".modules.lock");
+pub const SEPOLICY_FAILED_FILE: &str = concatcp!(WORKING_DIR, ".sepolicy_patch_failed");
+pub const SEPOLICY_REQUIRED_FILE: &str = concatcp!(WORKING_DIR, "sepolicy_required");
+pub const SEPOLICY_RULE_RETRY_FILE: &str = concatcp!(WORKING_DIR, ".sepolicy_rule_retry");
+pub const SEPOLICY_RULE_FAILED_FILE: &str = concatcp!(WORKING_DIR, "sepolicy_rule_failed");
+pub const MODULE_CONFLICTS_LOG_FILE: &str = concatcp!(APATCH_LOG_FOLDER, "module_conflicts.log");
+pub const SEPOLICY_LOG_FILE: &str = concatcp!(APATCH_LOG_FOLDER, "sepolicy.log");
+/// kept across boots, see `events.rs`
+pub const EVENTS_FILE: &str = concatcp!(APATCH_LOG_FOLDER, "events.jsonl");
+pub const EVENTS_OLD_FILE: &str = concatcp!(APATCH_LOG_FOLDER, "events.old.jsonl");
+/// `<id>.json` per module, the report of its last update, kept across boots
+pub const UPDATE_REPORT_DIR: &str = concatcp!(APATCH_LOG_FOLDER, "updates/");
+/// `<id>.log` per module, written by its Lua script and the kernel module loader,
+/// and `<id>/<stage>.log` with the output of its stage scripts. Kept across boots.
+pub const MODULE_LOG_DIR: &str = concatcp!(APATCH_LOG_FOLDER, "modules/");
+/// `<stage>.d/<script>.log` with the output of the common scripts
+pub const COMMON_SCRIPT_LOG_DIR: &str = concatcp!(APATCH_LOG_FOLDER, "common_scripts/");
+pub const CHILDREN_FILE: &str = concatcp!(WORKING_DIR, ".children");
+pub const UID_LISTENER_PID_FILE: &str = concatcp!(WORKING_DIR, ".uid_listener.pid");
+pub const DAEMON_SOCKET: &str = "/dev/socket/apd";
+/// package of the manager, optionally followed by `/<install receiver>`
+pub const MANAGER_PKG_FILE: &str = concatcp!(WORKING_DIR, "manager_pkg");
+pub const MOUNT_LIST_FILE: &str = concatcp!(WORKING_DIR, ".mount_list");
+pub const MOUNT_FAILED_FILE: &str = concatcp!(WORKING_DIR, ".mount_failed");
+pub const UMOUNT_PACKAGES_FILE: &str = concatcp!(WORKING_DIR, "umount_packages");
+pub const DATA_IDENTITY_FILE: &str = concatcp!(WORKING_DIR, ".data_identity");
+pub const DATA_STALE_FILE: &str = concatcp!(WORKING_DIR, "stale_mounts");
+pub const DATA_REMOUNT_ACTION_FILE: &str = concatcp!(WORKING_DIR, "data_remount_action");
+pub const RESET_JOURNAL_FILE: &str = concatcp!(WORKING_DIR, ".reset_journal");
+pub const MODULE_TRASH_DIR: &str = concatcp!(WORKING_DIR, "trash/");
+pub const CRITICAL_PATHS_FILE: &str = concatcp!(WORKING_DIR, "critical_paths");
+pub const SCRIPT_HISTORY_DIR: &str = concatcp!(WORKING_DIR, "script_history/");
+pub const SCRIPT_TIMEOUT_FILE: &str = concatcp!(WORKING_DIR, "script_timeout");
+pub const FILE_CONTEXTS_FLAG_FILE: &str = concatcp!(WORKING_DIR, "file_contexts_enable");
+pub const PROFILE_BOOT_FILE: &str = concatcp!(WORKING_DIR, "profile_boot_enable");
+pub const PROFILE_ACTIVE_FILE: &str = concatcp!(WORKING_DIR, ".profile_boot_active");
+pub const PROFILE_SAMPLES_FILE: &str = concatcp!(WORKING_DIR, "boot_profile.jsonl");
+pub const PROFILE_REPORT_FILE: &str = concatcp!(WORKING_DIR, "boot_profile.json");
+pub const BOOT_TIMING_FILE: &str = concatcp!(APATCH_LOG_FOLDER, "boot_timing.json");
+pub const BOOT_LOG_INDEX_FILE: &str = concatcp!(APATCH_LOG_FOLDER, "boot_log.json");
 
 // Mount mode configuration
 pub const MOUNT_MODE_FILE: &str = concatcp!(WORKING_DIR, "mount_mode");
@@ -17,25 +83,47 @@ pub const MOUNT_MODE_METAMODULE: &str = "metamodule";
 pub const MOUNT_MODE_DISABLED: &str = "disabled";
 
 pub const MODULE_DIR: &str = concatcp!(ADB_DIR, "modules/");
+/// `<id>/` is kept for each module across updates, the installer gets it as MODDATA
+pub const MODULE_DATA_DIR: &str = concatcp!(ADB_DIR, "modules_data/");
 
 // warning: this directory should not change, or you need to change the code in module_installer.sh!!!
 pub const MODULE_UPDATE_DIR: &str = concatcp!(ADB_DIR, "modules_update/");
+pub const MODULE_BACKUP_DIR: &str = concatcp!(WORKING_DIR, "modules_backup/");
+/// updates which failed validation, kept for inspection
+pub const MODULE_REJECTED_DIR: &str = concatcp!(WORKING_DIR, "rejected_updates/");
+pub const MODULE_UPDATE_PENDING_FILE: &str = concatcp!(WORKING_DIR, "modules_update_pending");
+pub const MODULES_DISABLED_BY_SAFEMODE_FILE: &str =
+    concatcp!(WORKING_DIR, "modules_disabled_by_safemode");
+pub const SAFEMODE_FORCE_FILE: &str = concatcp!(WORKING_DIR, "safemode");
+pub const SAFEMODE_REASON_FILE: &str = concatcp!(WORKING_DIR, "safemode_reason");
+pub const INCOMPLETE_BOOTS_FILE: &str = concatcp!(WORKING_DIR, "incomplete_boots");
+pub const BOOTLOOP_THRESHOLD_FILE: &str = concatcp!(WORKING_DIR, "bootloop_threshold");
 
 pub const TEMP_DIR: &str = "/debug_ramdisk";
 pub const TEMP_DIR_LEGACY: &str = "/sbin";
+/// where the /data of a confined installer is put together
+pub const INSTALL_VIEW_DIR: &str = "/dev/apd_install_view";
 
 pub const MODULE_WEB_DIR: &str = "webroot";
 pub const MODULE_ACTION_SH: &str = "action.sh";
 pub const DISABLE_FILE_NAME: &str = "disable";
+/// next to `disable` when apd disabled the module, not the user, holds why
+pub const DISABLE_REASON_FILE_NAME: &str = "disable_reason";
 pub const UPDATE_FILE_NAME: &str = "update";
+/// erofs or squashfs image holding the partition dirs of a module
+pub const MODULE_IMAGE_FILE_NAME: &str = "module.img";
 pub const REMOVE_FILE_NAME: &str = "remove";
 pub const SKIP_MOUNT_FILE_NAME: &str = "skip_mount";
+/// mount the module at boot-completed instead of post-fs-data
+pub const DEFER_MOUNT_FILE_NAME: &str = "defer_mount";
+pub const ALLOW_CRITICAL_FILE_NAME: &str = "allow_critical";
 
 // Metamodule support
 pub const METAMODULE_MOUNT_SCRIPT: &str = "metamount.sh";
 pub const METAMODULE_METAINSTALL_SCRIPT: &str = "metainstall.sh";
 pub const METAMODULE_METAUNINSTALL_SCRIPT: &str = "metauninstall.sh";
 pub const METAMODULE_DIR: &str = concatcp!(ADB_DIR, "metamodule/");
+pub const METAMODULE_RESULT_FILE: &str = concatcp!(WORKING_DIR, ".metamount_result.json");
 
 pub const PTS_NAME: &str = "pts";
 
@@ -0,0 +1,236 @@
+//! Boot stage entry contract
+//!
+//! KernelPatch runs the stages as `apd [-s <KEY> | --superkey-fd <FD>] <stage>` with
+//! stage one of `post-fs-data`, `services`, `boot-completed` and `uid-listener`.
+//! Every stage prints a single result line to stdout
+//!
+//! ```text
+//! apd-stage: stage=<stage> result=<result> code=<code>[ reason=<text>]
+//! ```
+//!
+//! and exits with the code of its result:
+//!
+//! | code | result   | meaning                                                |
+//! |------|----------|--------------------------------------------------------|
+//! | 0    | ok       | the stage completed                                    |
+//! | 1    | degraded | the stage completed but some step failed, device boots |
+//! | 2    | fatal    | a precondition is missing, e.g. binaries not installed |
+//! | 3    | busy     | the same stage is already running                      |
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions, TryLockError},
+    io::Read,
+    os::fd::{FromRawFd, RawFd},
+};
+
+use anyhow::{Context, Result, bail};
+use log::{error, warn};
+
+use crate::{
+    defs,
+    events::{self, Code},
+    messages::Message,
+};
+
+pub enum Outcome {
+    Ok,
+    Degraded(String),
+}
+
+/// A precondition of the stage is missing, nothing sensible can be done
+#[derive(Debug)]
+pub struct Fatal(pub String);
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Fatal {}
+
+/// Read the superkey from a file descriptor so it does not show up in the process list
+///
+/// The descriptor is closed afterwards, so stdin, stdout and stderr are
+/// refused and it has to be open, owning a descriptor that is not would close
+/// whatever reuses its number later.
+pub fn read_superkey_fd(fd: RawFd) -> Result<String> {
+    if fd <= libc::STDERR_FILENO {
+        bail!("Refusing to read superkey from fd {fd}, it is not a descriptor of its own");
+    }
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to read superkey from fd {fd}"));
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let mut key = String::new();
+    file.read_to_string(&mut key)
+        .with_context(|| format!("Failed to read superkey from fd {fd}"))?;
+    Ok(key.trim_end_matches(['\n', '\0']).to_string())
+}
+
+enum Lock {
+    Held(#[allow(dead_code)] File),
+    Busy,
+    Unavailable,
+}
+
+fn lock_stage(stage: &str) -> Lock {
+    let path = format!("{}.stage-{stage}.lock", defs::WORKING_DIR);
+    let file = match OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            // never block a boot stage because the lock can't be created
+            warn!("Failed to open {path}: {e}, running {stage} unlocked");
+            return Lock::Unavailable;
+        }
+    };
+    match file.try_lock() {
+        Ok(()) => Lock::Held(file),
+        Err(TryLockError::WouldBlock) => Lock::Busy,
+        Err(TryLockError::Error(e)) => {
+            warn!("Failed to lock {path}: {e}, running {stage} unlocked");
+            Lock::Unavailable
+        }
+    }
+}
+
+/// Result name, exit code and reason of a stage which ran
+fn stage_result(stage: &str, outcome: Result<Outcome>) -> (&'static str, i32, Option<String>) {
+    match outcome {
+        Ok(Outcome::Ok) => ("ok", 0, None),
+        Ok(Outcome::Degraded(reason)) => ("degraded", 1, Some(reason)),
+        Err(e) => {
+            error!("{stage} failed: {e:?}");
+            if e.downcast_ref::<Fatal>().is_some() {
+                ("fatal", 2, Some(format!("{e:#}")))
+            } else {
+                ("degraded", 1, Some(format!("{e:#}")))
+            }
+        }
+    }
+}
+
+fn result_line(stage: &str, result: &str, code: i32, reason: Option<&str>) -> String {
+    let mut line = format!("apd-stage: stage={stage} result={result} code={code}");
+    if let Some(reason) = reason {
+        line.push_str(" reason=");
+        line.push_str(&reason.replace('\n', " "));
+    }
+    line
+}
+
+/// Run a boot stage, report its result and exit with the matching code
+pub fn run_stage(stage: &str, f: impl FnOnce() -> Result<Outcome>) -> ! {
+    let lock = lock_stage(stage);
+    let (result, code, reason) = if matches!(lock, Lock::Busy) {
+        ("busy", 3, Some("already running".to_string()))
+    } else {
+        let message = Message::StageStarted {
+            stage: stage.to_string(),
+        };
+        events::emit_for(Code::StageStarted, stage, &message);
+        stage_result(stage, f())
+    };
+
+    let line = result_line(stage, result, code, reason.as_deref());
+    let event = match (code, &reason) {
+        (0, _) => Some((
+            Code::StageFinished,
+            Message::StageFinished {
+                stage: stage.to_string(),
+            },
+        )),
+        (1, Some(reason)) => Some((
+            Code::StageDegraded,
+            Message::StageDegraded {
+                stage: stage.to_string(),
+                reason: reason.clone(),
+            },
+        )),
+        (2, Some(reason)) => Some((
+            Code::StageFailed,
+            Message::StageFailed {
+                stage: stage.to_string(),
+                reason: reason.clone(),
+            },
+        )),
+        _ => None,
+    };
+    if let Some((code, message)) = event {
+        events::emit_for(code, stage, &message);
+    }
+    log::info!("{line}");
+    println!("{line}");
+    drop(lock);
+    std::process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::fd::IntoRawFd};
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn outcomes_map_to_exit_codes() {
+        let run = |f: &dyn Fn() -> Result<Outcome>| stage_result("services", f());
+        assert_eq!(run(&|| Ok(Outcome::Ok)), ("ok", 0, None));
+        assert_eq!(
+            run(&|| Ok(Outcome::Degraded("2 scripts failed".to_string()))),
+            ("degraded", 1, Some("2 scripts failed".to_string()))
+        );
+        assert_eq!(
+            run(&|| Err(anyhow!("mount failed"))),
+            ("degraded", 1, Some("mount failed".to_string()))
+        );
+        assert_eq!(
+            run(&|| Err(anyhow::Error::new(Fatal("apd is not installed".to_string())))),
+            ("fatal", 2, Some("apd is not installed".to_string()))
+        );
+        // a fatal error keeps its code below added context
+        let (result, code, reason) = run(&|| {
+            Err(anyhow::Error::new(Fatal("no kernel patch".to_string())).context("post-fs-data"))
+        });
+        assert_eq!((result, code), ("fatal", 2));
+        assert_eq!(reason.as_deref(), Some("post-fs-data: no kernel patch"));
+    }
+
+    #[test]
+    fn result_line_is_one_line() {
+        assert_eq!(
+            result_line("post-fs-data", "ok", 0, None),
+            "apd-stage: stage=post-fs-data result=ok code=0"
+        );
+        assert_eq!(
+            result_line("services", "degraded", 1, Some("a\nb")),
+            "apd-stage: stage=services result=degraded code=1 reason=a b"
+        );
+    }
+
+    #[test]
+    fn superkey_is_read_from_an_open_fd() {
+        let root = TempDir::new("superkey-fd");
+        let key = root.write("key", "secret\n\0");
+        let fd = fs::File::open(&key).unwrap().into_raw_fd();
+        assert_eq!(read_superkey_fd(fd).unwrap(), "secret");
+    }
+
+    #[test]
+    fn superkey_fd_must_be_open_and_not_stdio() {
+        for fd in [-1, 0, 1, 2] {
+            assert!(read_superkey_fd(fd).is_err(), "fd {fd}");
+        }
+        // far above anything the test process opens
+        assert!(read_superkey_fd(1 << 20).is_err());
+    }
+}
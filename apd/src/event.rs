@@ -2,29 +2,46 @@ use std::{
     env,
     ffi::CStr,
     fs,
-    os::unix::{fs::PermissionsExt, process::CommandExt},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{ErrorKind, Write},
+    os::unix::{fs::PermissionsExt, io::FromRawFd, process::CommandExt},
     path::{Path, PathBuf},
-    process::Command,
-    sync::{Arc, Mutex},
+    process::{Command, Stdio},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use crate::mpolicy::{get_policy_main};
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, ensure};
 use libc::SIGPWR;
-use log::{info, warn};
+use log::{error, info, warn};
 use notify::{
     Config, Event, EventKind, INotifyWatcher, RecursiveMode, Watcher,
-    event::{ModifyKind, RenameMode},
+    event::ModifyKind,
 };
+use serde::{Deserialize, Serialize};
 use signal_hook::{consts::signal::*, iterator::Signals};
 
 use crate::{
-    assets, defs, lua, magic_mount, metamodule, module, package::initialize_package_baseline,
-    restorecon, supercall,
+    assets, bootlog, bootloop, compat, config, daemon, data_ready, data_watch, defs, events,
+    dispatch::Outcome,
+    kmod, lua, magic_mount,
+    messages::Message,
+    metamodule, module,
+    package::initialize_package_baseline,
+    phase::{self, BootPhase, Phase, PhaseResult, RootAccess, StageContext},
+    profile, restorecon, script_history, sepolicy, shutdown,
+    status::{self, Health, MountDecision},
+    supercall,
     supercall::{
         init_load_package_uid_config, init_load_su_path, refresh_ap_package_list,
     },
+    timing,
+    umount::MountRegistry,
     utils::{self, switch_cgroups},
 };
 
@@ -40,91 +57,257 @@ pub fn report_kernel(superkey: Option<String>, event: &str, state: &str) -> Resu
     Ok(())
 }
 
-pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
-    utils::umask(0);
-    report_kernel(superkey.clone(), "post-fs-data", "before")?;
-    use std::process::Stdio;
-    #[cfg(unix)]
-    init_load_package_uid_config(&superkey);
+/// The steps of [`prepare_root_access`] which reach the kernel, the policy or
+/// /data, a trait so tests can make any of them fail
+trait RootSteps {
+    fn validate_superkey(&self, superkey: &Option<String>) -> bool;
+    fn handshake(&self, superkey: &Option<String>) -> supercall::Handshake;
+    fn load_package_config(&self, superkey: &Option<String>);
+    fn load_su_path(&self, superkey: &Option<String>);
+    fn shell_su_requested(&self) -> bool;
+    fn grant_shell_su(&self, superkey: &Option<String>);
+    fn inject_sepolicy(&self);
+    fn sepolicy_patched(&self) -> bool;
+    /// Remember whether the policy patch failed, for `apd status`
+    fn mark_sepolicy(&self, patched: bool);
+    fn privilege_apd_profile(&self, superkey: &Option<String>);
+    fn ensure_binaries(&self) -> Vec<assets::BinaryStatus>;
+}
 
-    init_load_su_path(&superkey);
+struct Kernel;
 
-    let mut sepol = get_policy_main(&[
-        "magiskpolicy".to_string(),
-        "--live".to_string(),
-    ])?;
-    sepol.magisk_rules();
-    sepol.to_file("/sys/fs/selinux/load")
-            .context("Cannot apply policy")?;
+impl RootSteps for Kernel {
+    fn validate_superkey(&self, superkey: &Option<String>) -> bool {
+        supercall::validate_superkey(superkey)
+    }
+
+    fn handshake(&self, superkey: &Option<String>) -> supercall::Handshake {
+        let handshake = supercall::handshake(superkey);
+        if let Err(e) = handshake.store() {
+            warn!("Failed to record kernel handshake: {}", e);
+        }
+        handshake
+    }
 
+    fn load_package_config(&self, superkey: &Option<String>) {
+        init_load_package_uid_config(superkey);
+    }
 
-    info!("Re-privilege apd profile after injecting sepolicy");
-    supercall::privilege_apd_profile(&superkey);
+    fn load_su_path(&self, superkey: &Option<String>) {
+        init_load_su_path(superkey);
+    }
 
-    if utils::has_magisk() {
-        warn!("Magisk detected, skip post-fs-data!");
-        report_kernel(superkey.clone(), "post-fs-data", "after")?;
-        return Ok(());
+    fn shell_su_requested(&self) -> bool {
+        Path::new(defs::SHELL_SU_FILE).exists()
     }
 
-    // Create log environment
-    if !Path::new(defs::APATCH_LOG_FOLDER).exists() {
-        fs::create_dir(defs::APATCH_LOG_FOLDER).expect("Failed to create log folder");
-        let permissions = fs::Permissions::from_mode(0o700);
-        fs::set_permissions(defs::APATCH_LOG_FOLDER, permissions)
-            .expect("Failed to set permissions");
+    fn grant_shell_su(&self, superkey: &Option<String>) {
+        supercall::grant_shell_su(superkey);
     }
-    let command_string = format!(
-        "rm -rf {}*.old.log; for file in {}*; do mv \"$file\" \"$file.old.log\"; done",
-        defs::APATCH_LOG_FOLDER,
-        defs::APATCH_LOG_FOLDER
-    );
-    let mut args = vec!["-c", &command_string];
-    // for all file to .old
-    let result = utils::run_command("sh", &args, None)?.wait()?;
-    if result.success() {
-        info!("Successfully deleted .old files.");
+
+    fn inject_sepolicy(&self) {
+        match get_policy_main(&["magiskpolicy".to_string(), "--live".to_string()]) {
+            Ok(mut sepol) => {
+                sepol.magisk_rules();
+                if let Err(e) = sepol.to_file("/sys/fs/selinux/load") {
+                    warn!("Cannot apply policy: {:?}", e);
+                }
+            }
+            Err(e) => warn!("Cannot load live policy: {:?}", e),
+        }
+    }
+
+    fn sepolicy_patched(&self) -> bool {
+        sepolicy::is_live_patched()
+    }
+
+    fn mark_sepolicy(&self, patched: bool) {
+        let marker = Path::new(defs::SEPOLICY_FAILED_FILE);
+        if patched {
+            let _ = fs::remove_file(marker);
+        } else if let Err(e) = utils::ensure_file_exists(marker) {
+            warn!("Failed to record sepolicy patch failure: {}", e);
+        }
+    }
+
+    fn privilege_apd_profile(&self, superkey: &Option<String>) {
+        supercall::privilege_apd_profile(superkey);
+    }
+
+    fn ensure_binaries(&self) -> Vec<assets::BinaryStatus> {
+        assets::ensure_binaries()
+    }
+}
+
+/// Minimal root plumbing, runs before anything that safe mode or a failing
+/// later step could skip, so su stays usable from adb to fix a broken boot.
+/// Every step here only logs on failure.
+fn prepare_root_access(superkey: &Option<String>, inject_policy: bool) -> RootAccess {
+    prepare_root_access_with(&Kernel, superkey, inject_policy)
+}
+
+fn prepare_root_access_with(
+    steps: &dyn RootSteps,
+    superkey: &Option<String>,
+    inject_policy: bool,
+) -> RootAccess {
+    let key_accepted = steps.validate_superkey(superkey);
+    if !key_accepted {
+        warn!("superkey was not accepted by kernel, root access may be unavailable");
+    }
+
+    let handshake = steps.handshake(superkey);
+    let supercall_compatible = handshake.compatible;
+    if supercall_compatible {
+        steps.load_package_config(superkey);
+        steps.load_su_path(superkey);
+
+        if steps.shell_su_requested() {
+            info!("grant root to adb shell");
+            steps.grant_shell_su(superkey);
+        }
     } else {
-        info!("Failed to delete .old files.");
-    }
-    let logcat_path = format!("{}logcat.log", defs::APATCH_LOG_FOLDER);
-    let dmesg_path = format!("{}dmesg.log", defs::APATCH_LOG_FOLDER);
-    let bootlog = fs::File::create(dmesg_path)?;
-    args = vec![
-        "-s",
-        "9",
-        "45s",
-        "logcat",
-        "-b",
-        "main,system,crash",
-        "DrmLibFs:S",
-        "-f",
-        &logcat_path,
-        "logcatcher-bootlog:S",
-        "&",
-    ];
-    let _ = unsafe {
-        Command::new("timeout")
-            .process_group(0)
-            .pre_exec(|| {
-                switch_cgroups();
-                Ok(())
+        error!(
+            "!!! KernelPatch {} is older than {}, the minimum apd supports. Skipping su setup, modules are still mounted. Missing supercalls: {}",
+            handshake.kernelpatch_version,
+            handshake.minimum,
+            handshake.missing.join(", ")
+        );
+    }
+
+    // verify the rules landed, so later steps can be skipped instead of
+    // failing with EACCES all over the place. A load racing init's own policy
+    // load is lost, it is tried once more.
+    let sepolicy_patched = timing::measure("sepolicy inject", || {
+        if !inject_policy {
+            return steps.sepolicy_patched();
+        }
+        steps.inject_sepolicy();
+        if steps.sepolicy_patched() {
+            return true;
+        }
+        warn!("sepolicy rules did not land, injecting them again");
+        steps.inject_sepolicy();
+        steps.sepolicy_patched()
+    });
+    steps.mark_sepolicy(sepolicy_patched);
+
+    if supercall_compatible {
+        info!("Re-privilege apd profile after injecting sepolicy");
+        steps.privilege_apd_profile(superkey);
+    }
+
+    RootAccess {
+        key_accepted,
+        broken_binaries: steps.ensure_binaries(),
+        sepolicy_patched,
+        supercall_compatible,
+    }
+}
+
+fn phase_root(ctx: &mut StageContext) -> Result<PhaseResult> {
+    utils::umask(0);
+    if !ctx.partial
+        && let Err(e) = report_kernel(ctx.superkey.clone(), "post-fs-data", "before")
+    {
+        warn!("report post-fs-data to kernel failed: {}", e);
+    }
+    let magisk = utils::has_magisk();
+    let coexist = magisk && utils::coexist_with_magisk();
+    if coexist {
+        warn!(
+            "Magisk detected, running alongside it as {} exists, skip magiskpolicy --live",
+            defs::IGNORE_MAGISK_FILE
+        );
+    }
+    ctx.root_access = prepare_root_access(&ctx.superkey, !coexist);
+
+    if magisk && !coexist {
+        warn!(
+            "Magisk detected, skip post-fs-data! Create {} to run alongside it",
+            defs::IGNORE_MAGISK_FILE
+        );
+        report_kernel(ctx.superkey.clone(), "post-fs-data", "after")?;
+        return Ok(PhaseResult::Stop);
+    }
+    Ok(PhaseResult::Done)
+}
+
+/// Keep the logs of the last boot as `<name>.old.log`, dropping those of the
+/// boot before. Hidden entries are left alone, like the shell glob this
+/// replaces did, and so are the event log, which rotates by size, and the
+/// update reports and the script logs. Returns how many entries could not be
+/// rotated.
+fn rotate_logs(dir: &Path) -> usize {
+    let entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|entry| !entry.file_name().as_encoded_bytes().starts_with(b"."))
+            .map(|entry| entry.path())
+            .filter(|path| {
+                ![
+                    defs::EVENTS_FILE,
+                    defs::EVENTS_OLD_FILE,
+                    defs::UPDATE_REPORT_DIR,
+                    // script logs rotate on every run of the script
+                    defs::MODULE_LOG_DIR,
+                    defs::COMMON_SCRIPT_LOG_DIR,
+                ]
+                .iter()
+                    .any(|events| path.file_name() == Path::new(events).file_name())
             })
-            .args(args)
-            .spawn()
+            .collect(),
+        Err(e) => {
+            warn!("Failed to read {}: {}", dir.display(), e);
+            return 1;
+        }
     };
-    args = vec!["-s", "9", "120s", "dmesg", "-w"];
-    let _result = unsafe {
-        Command::new("timeout")
-            .process_group(0)
-            .pre_exec(|| {
-                switch_cgroups();
-                Ok(())
-            })
-            .args(args)
-            .stdout(Stdio::from(bootlog))
-            .spawn()
+    let remove = |path: &Path| {
+        let result = if path.is_dir() && !path.is_symlink() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        result.inspect_err(|e| warn!("Failed to remove {}: {}", path.display(), e))
     };
+    let (old, current): (Vec<PathBuf>, Vec<PathBuf>) = entries
+        .into_iter()
+        .partition(|path| path.as_os_str().as_encoded_bytes().ends_with(b".old.log"));
+    let mut failed = old.iter().filter(|path| remove(path).is_err()).count();
+    for path in current {
+        let mut old = path.clone().into_os_string();
+        old.push(".old.log");
+        let old = PathBuf::from(old);
+        // left over if removing it failed above
+        if old.symlink_metadata().is_ok() && remove(&old).is_err() {
+            failed += 1;
+            continue;
+        }
+        if let Err(e) = fs::rename(&path, &old) {
+            warn!("Failed to rename {} to {}: {}", path.display(), old.display(), e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+fn phase_logs(_ctx: &mut StageContext) -> Result<PhaseResult> {
+    shutdown::reset();
+
+    // Create log environment
+    if !Path::new(defs::APATCH_LOG_FOLDER).exists() {
+        fs::create_dir(defs::APATCH_LOG_FOLDER).context("Failed to create log folder")?;
+        let permissions = fs::Permissions::from_mode(0o700);
+        fs::set_permissions(defs::APATCH_LOG_FOLDER, permissions)
+            .context("Failed to set permissions")?;
+    }
+    match rotate_logs(Path::new(defs::APATCH_LOG_FOLDER)) {
+        0 => info!("Successfully deleted .old files."),
+        failed => info!("Failed to delete .old files, {failed} entries not rotated."),
+    }
+    if let Err(e) = bootlog::spawn() {
+        warn!("{e:#}");
+    }
 
     let key = "KERNELPATCH_VERSION";
     match env::var(key) {
@@ -137,86 +320,347 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
         Ok(value) => println!("{}: {}", key, value),
         Err(_) => println!("{} not found", key),
     }
+    Ok(PhaseResult::Done)
+}
 
-    let safe_mode = utils::is_safe_mode(superkey.clone());
+fn phase_health(ctx: &mut StageContext) -> Result<PhaseResult> {
+    let reason = utils::safe_mode_reason(ctx.superkey.clone());
+    ctx.safe_mode = reason.is_some();
+    let looping = bootloop::begin_boot();
+    ctx.disable_modules = looping || bootloop::safe_mode_requested();
+    match reason {
+        Some(reason) => {
+            warn!("safe mode triggered: {reason}");
+            bootloop::record_trigger(reason);
+        }
+        None if looping => {
+            warn!("safe mode triggered: {}", utils::SafeModeReason::Bootloop);
+            bootloop::record_trigger(utils::SafeModeReason::Bootloop);
+        }
+        None => {}
+    }
+    ctx.health = status::begin_boot(ctx.safe_mode);
+    ctx.health.sepolicy_patch_failed = !ctx.root_access.sepolicy_patched;
+    if !ctx.root_access.sepolicy_patched {
+        let message = Message::SepolicyPatchFailed {
+            skipped: sepolicy::patch_required_ops().join(", "),
+        };
+        warn!("{message}");
+        println!("{}", message.to_json());
+    }
+    Ok(PhaseResult::Done)
+}
 
-    if safe_mode {
+fn phase_common_scripts(ctx: &mut StageContext) -> Result<PhaseResult> {
+    if ctx.safe_mode {
         // we should still mount modules.img to `/data/adb/modules` in safe mode
         // becuase we may need to operate the module dir in safe mode
         warn!("safe mode, skip common post-fs-data.d scripts");
-        if let Err(e) = module::disable_all_modules() {
-            warn!("disable all modules failed: {}", e);
-        }
+    } else if ctx.disable_modules {
+        warn!("modules disabled this boot, skip common post-fs-data.d scripts");
     } else {
         // Then exec common post-fs-data scripts
         if let Err(e) = module::exec_common_scripts("post-fs-data.d", true) {
             warn!("exec common post-fs-data scripts failed: {}", e);
         }
     }
-    let module_update_dir = defs::MODULE_UPDATE_DIR; //save module place
-    let module_dir = defs::MODULE_DIR; // run modules place
-    let module_update_flag = Path::new(defs::WORKING_DIR).join(defs::UPDATE_FILE_NAME); // if update ,there will be renewed modules file
-    assets::ensure_binaries().with_context(|| "binary missing")?;
+    Ok(PhaseResult::Done)
+}
+
+fn phase_binaries(ctx: &mut StageContext) -> Result<PhaseResult> {
+    // nothing here is fatal, the phases needing a broken tool skip themselves
+    let disabled: Vec<&str> = ctx
+        .root_access
+        .broken_binaries
+        .iter()
+        .flat_map(|binary| binary.features.iter().copied())
+        .collect();
+    if !disabled.is_empty() {
+        log::error!("binaries unusable, disabled this boot: {}", disabled.join(", "));
+    }
+    ctx.health.disabled_features = disabled.join(",");
+    if let Err(e) = compat::ensure_shims() {
+        warn!("Failed to set up compat shims: {}", e);
+    }
+    Ok(PhaseResult::Done)
+}
 
+fn phase_update(_ctx: &mut StageContext) -> Result<PhaseResult> {
+    if let Err(e) = module::check_pending_updates() {
+        warn!("Failed to check unverified module updates: {}", e);
+    }
     if Path::new(defs::MODULE_UPDATE_DIR).exists() {
         module::handle_updated_modules()?;
-        fs::remove_dir_all(module_update_dir)?;
+        fs::remove_dir_all(defs::MODULE_UPDATE_DIR)?;
+        restorecon::forget_relabel_stamps();
     }
+    Ok(PhaseResult::Done)
+}
 
-    if safe_mode {
-        warn!("safe mode, skip post-fs-data scripts and disable all modules!");
-        if let Err(e) = module::disable_all_modules() {
-            warn!("disable all modules failed: {}", e);
+fn phase_safe_mode(ctx: &mut StageContext) -> Result<PhaseResult> {
+    if ctx.disable_modules {
+        let message = if ctx.safe_mode || bootloop::safe_mode_requested() {
+            Message::SafeModeModulesDisabled {}
+        } else {
+            Message::BootloopModulesDisabled {}
+        };
+        match bootloop::disable_modules(message.code()) {
+            Ok(()) => {
+                warn!("{message}");
+                events::emit(events::Code::ModulesDisabled, &message);
+            }
+            Err(e) => warn!("disable all modules failed: {}", e),
         }
-        return Ok(());
     }
+    if !ctx.safe_mode && !ctx.disable_modules {
+        return Ok(PhaseResult::Done);
+    }
+    // a detected safe mode alone only skips modules for this boot
+    warn!("safe mode, skip post-fs-data scripts and modules!");
+    if !ctx.partial
+        && let Err(e) = ctx.health.store()
+    {
+        warn!("Failed to write health record: {}", e);
+    }
+    Ok(PhaseResult::Stop)
+}
 
+fn phase_prune(_ctx: &mut StageContext) -> Result<PhaseResult> {
     if let Err(e) = module::prune_modules() {
         warn!("prune modules failed: {}", e);
     }
-
-    if let Err(e) = restorecon::restorecon() {
+    if let Err(e) = timing::measure("restorecon", restorecon::restorecon) {
         warn!("restorecon failed: {}", e);
     }
+    Ok(PhaseResult::Done)
+}
 
-    // load sepolicy.rule
-    if module::load_sepolicy_rule().is_err() {
-        warn!("load sepolicy.rule failed");
+fn phase_sepolicy(_ctx: &mut StageContext) -> Result<PhaseResult> {
+    match module::load_sepolicy_rule() {
+        Ok(results) => {
+            let failed: Vec<&str> = results
+                .iter()
+                .filter(|r| r.error.is_some())
+                .map(|r| r.id.as_str())
+                .collect();
+            if !failed.is_empty() {
+                warn!("sepolicy.rule failed for: {}, retry in service", failed.join(", "));
+            }
+        }
+        Err(e) => warn!("load sepolicy.rule failed: {e}"),
     }
+    Ok(PhaseResult::Done)
+}
 
-    // Mount modules based on configured mount mode
-    let mount_mode = utils::get_mount_mode();
+/// Mount modules the way `mount_mode` asks for, recording the strategies tried
+fn mount_modules(mount_mode: &str) -> (MountDecision, Result<()>) {
+    let mut decision = MountDecision::new(mount_mode);
+    let result = try_mount_modules(mount_mode, &mut decision);
+    if let Err(e) = decision.store() {
+        warn!("Failed to record mount decision: {:#}", e);
+    }
+    (decision, result)
+}
+
+fn try_mount_modules(mount_mode: &str, decision: &mut MountDecision) -> Result<()> {
     info!("Current mount mode: {}", mount_mode);
+    metamodule::clear_result();
 
-    match mount_mode.as_str() {
+    match mount_mode {
         defs::MOUNT_MODE_DISABLED => {
             info!("Mount disabled (lite mode), skipping all module mounts");
+            decision.selected = defs::MOUNT_MODE_DISABLED.to_string();
+        }
+        defs::MOUNT_MODE_METAMODULE if sepolicy::gated("metamodule") => {
+            decision.attempt(defs::MOUNT_MODE_METAMODULE, || {
+                anyhow::bail!("metamodule mount is not possible without the sepolicy patch")
+            })?;
         }
         defs::MOUNT_MODE_METAMODULE => {
             // Use metamodule's custom mount script
-            if let Err(e) = metamodule::exec_mount_script(module_dir) {
-                warn!("execute metamodule mount failed: {e}");
+            if let Err(e) = decision.attempt(defs::MOUNT_MODE_METAMODULE, || {
+                metamodule::exec_mount_script(defs::MODULE_DIR, mount_mode)
+            }) {
+                // a broken metamodule must not leave the device without modules
+                warn!("execute metamodule mount failed: {e:#}, fall back to magic mount");
+                for point in metamodule::reported_mounts() {
+                    if let Err(e) = crate::mount::detach(&point) {
+                        warn!("{:#}", e);
+                    }
+                }
+                metamodule::clear_result();
+                decision.attempt(defs::MOUNT_MODE_MAGIC, || {
+                    timing::measure("magic mount", magic_mount::magic_mount)
+                        .context("magic mount failed")
+                })?;
+                return Err(e.context("metamodule mount failed, fell back to magic mount"));
             }
         }
         defs::MOUNT_MODE_MAGIC | _ => {
             // Use built-in magic mount (bind mount) (default for backwards compatibility)
             info!("Using Magic Mount (bind mount) mode");
-            if let Err(e) = magic_mount::magic_mount() {
-                warn!("magic mount failed: {}", e);
+            decision.attempt(defs::MOUNT_MODE_MAGIC, || {
+                timing::measure("magic mount", magic_mount::magic_mount)
+                    .context("magic mount failed")
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Tear down the module mounts and mount again in `mode`, the configured mount
+/// mode by default. The configured mode itself is left as is.
+pub fn remount(mode: Option<String>, force: bool) -> Result<()> {
+    ensure!(
+        force || !utils::apps_started(),
+        "running apps keep the current module mounts, pass --force to remount anyway"
+    );
+    let mode = mode.unwrap_or_else(utils::get_mount_mode);
+
+    let mut failed = 0;
+    for point in crate::mount::module_mount_points()? {
+        match crate::mount::detach(&point) {
+            Ok(()) => println!("detached {}", point.display()),
+            Err(e) => {
+                warn!("{:#}", e);
+                failed += 1;
             }
         }
     }
+    ensure!(failed == 0, "{failed} module mount(s) could not be detached");
+
+    let (_, result) = mount_modules(&mode);
+    for point in crate::mount::module_mount_points()? {
+        println!("mounted {}", point.display());
+    }
+    if let Err(e) = MountRegistry::record() {
+        warn!("Failed to record module mounts: {}", e);
+    }
+    if let Err(e) = module::record_mount_failures() {
+        warn!("Failed to check module mounts: {}", e);
+    }
+    result
+}
+
+/// Whether the modules were mounted before in this boot, init runs post-fs-data
+/// again e.g. when a userdata checkpoint is rolled back
+fn mounted_this_boot() -> bool {
+    fs::read_to_string(crate::paths::mounted_stamp())
+        .is_ok_and(|boot_id| boot_id.trim() == script_history::boot_id())
+}
+
+/// Detach the mounts of the earlier run, mounting again would stack on them
+fn unwind_mounts() {
+    for point in MountRegistry::load().mounts() {
+        match crate::mount::detach(&point) {
+            Ok(()) => info!("detached {}", point.display()),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+    if let Err(e) = crate::hosts::unmount() {
+        warn!("{:#}", e);
+    }
+}
+
+fn kmods_enabled(ctx: &StageContext) -> Option<String> {
+    needs_data(ctx).or_else(|| {
+        (!config::kernel_modules()).then(|| "kernel_modules = false".to_string())
+    })
+}
+
+fn load_kmods(after_mount: bool) -> Result<PhaseResult> {
+    let failed = kmod::load(after_mount)?;
+    if failed > 0 {
+        warn!("{failed} kernel module(s) failed to load, see apd module kmod status");
+    }
+    Ok(PhaseResult::Done)
+}
+
+fn phase_kmods(_ctx: &mut StageContext) -> Result<PhaseResult> {
+    load_kmods(false)
+}
+
+fn phase_kmods_late(_ctx: &mut StageContext) -> Result<PhaseResult> {
+    load_kmods(true)
+}
+
+fn phase_mount(ctx: &mut StageContext) -> Result<PhaseResult> {
+    if mounted_this_boot() {
+        warn!("modules were already mounted this boot, detaching them first");
+        unwind_mounts();
+    }
+    match crate::mount::sweep_leftover_mounts() {
+        Ok(cleaned) if !cleaned.is_empty() => {
+            warn!("cleaned {} leftover mount(s) before mounting", cleaned.len());
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to look for leftover mounts: {:#}", e),
+    }
+    // Mount modules based on configured mount mode
+    let (decision, result) = mount_modules(&utils::get_mount_mode());
+    if let Err(e) = result {
+        warn!("{:#}", e);
+    }
+    ctx.health.mount_fallbacks += decision.fallbacks();
+    ctx.health.mount_failed |= !decision.succeeded();
+    if let Err(e) = crate::hosts::mount() {
+        warn!("{:#}", e);
+    }
+    if let Err(e) = MountRegistry::record() {
+        warn!("Failed to record module mounts: {}", e);
+    }
+    let stamp = crate::paths::mounted_stamp();
+    if let Err(e) = fs::write(&stamp, script_history::boot_id()) {
+        warn!("Failed to write {}: {}", stamp.display(), e);
+    }
+    match crate::mount::module_mount_points()
+        .and_then(crate::mount::missing_in_new_namespace)
+    {
+        Ok(missing) if missing.is_empty() => {}
+        Ok(missing) => log::error!(
+            "module mounts missing in a new mount namespace, apps will not see them: {}",
+            missing
+                .iter()
+                .map(|point| point.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Err(e) => warn!("Failed to check module mounts in a new namespace: {:#}", e),
+    }
+    if let Err(e) = module::record_mount_failures() {
+        warn!("Failed to check module mounts: {}", e);
+    }
 
-    // exec modules post-fs-data scripts
-    // TODO: Add timeout
-    if let Err(e) = module::exec_stage_script("post-fs-data", true) {
-        warn!("exec post-fs-data scripts failed: {}", e);
+    if let Err(e) = data_watch::record() {
+        warn!("Failed to record /data identity: {}", e);
     }
-    if let Err(e) = lua::exec_stage_lua("post-fs-data", true, superkey.as_deref().unwrap_or("")) {
+    Ok(PhaseResult::Done)
+}
+
+fn phase_scripts(ctx: &mut StageContext) -> Result<PhaseResult> {
+    // exec modules post-fs-data scripts, each one bounded by script_timeout
+    if let Some(reason) = needs_busybox(ctx) {
+        warn!("skip post-fs-data scripts, {reason}");
+    } else if !sepolicy::gated("scripts") {
+        match module::exec_stage_script("post-fs-data", true) {
+            Ok(failed) => ctx.health.modules_failed += failed as u32,
+            Err(e) => warn!("exec post-fs-data scripts failed: {}", e),
+        }
+    }
+    let superkey = ctx.superkey.as_deref().unwrap_or("");
+    if !sepolicy::gated("lua")
+        && let Err(e) = profile::measure("lua", "post-fs-data", || {
+            lua::exec_stage_lua("post-fs-data", true, superkey)
+        })
+    {
         warn!("Failed to exec post-fs-data lua: {}", e);
     }
+    Ok(PhaseResult::Done)
+}
+
+fn phase_props(_ctx: &mut StageContext) -> Result<PhaseResult> {
     // load system.prop
-    if let Err(e) = module::load_system_prop() {
+    if let Err(e) = module::load_system_prop("post-fs-data") {
         warn!("load system.prop failed: {}", e);
     }
 
@@ -224,62 +668,300 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
     if let Err(e) = crate::hide::hide_sensitive_props() {
         warn!("Failed to hide sensitive props: {}", e);
     }
+    if let Err(e) = crate::prop_override::apply("post-fs-data") {
+        warn!("Failed to apply prop overrides: {}", e);
+    }
+    Ok(PhaseResult::Done)
+}
+
+fn phase_record(ctx: &mut StageContext) -> Result<PhaseResult> {
+    if let Err(e) = ctx.health.store() {
+        warn!("Failed to write health record: {}", e);
+    }
 
     info!("remove update flag");
-    let _ = fs::remove_file(module_update_flag);
+    let _ = fs::remove_file(Path::new(defs::WORKING_DIR).join(defs::UPDATE_FILE_NAME));
+    Ok(PhaseResult::Done)
+}
 
-    run_stage("post-mount", superkey.clone(), true);
+fn phase_post_mount(ctx: &mut StageContext) -> Result<PhaseResult> {
+    // the common dir is created so users find where post-mount scripts go
+    if let Err(e) = utils::ensure_dir_exists(Path::new(defs::ADB_DIR).join("post-mount.d")) {
+        warn!("{e:#}");
+    }
+    let failures = run_stage("post-mount", ctx.superkey.clone(), true);
+    ctx.post_mount_failures = failures.steps;
+    ctx.health.modules_failed += failures.module_scripts as u32;
+    Ok(PhaseResult::Done)
+}
 
+fn phase_report(ctx: &mut StageContext) -> Result<PhaseResult> {
     env::set_current_dir("/").with_context(|| "failed to chdir to /")?;
-    report_kernel(superkey, "post-fs-data", "after")?;
-    Ok(())
+    report_kernel(ctx.superkey.clone(), "post-fs-data", "after")?;
+    Ok(PhaseResult::Done)
+}
+
+/// Phases reading modules or the working dir
+fn needs_data(ctx: &StageContext) -> Option<String> {
+    (!ctx.data_ready).then(|| format!("{} is not readable", defs::ADB_DIR))
+}
+
+/// Phases running scripts through busybox
+fn needs_busybox(ctx: &StageContext) -> Option<String> {
+    if let Some(reason) = needs_data(ctx) {
+        return Some(reason);
+    }
+    ctx.root_access
+        .broken_binaries
+        .iter()
+        .find(|binary| binary.name == "busybox")
+        .map(|binary| {
+            let problem = binary.problem.as_deref().unwrap_or_default();
+            format!("busybox is unusable: {problem}")
+        })
+}
+
+/// Phases which only make sense once per boot, e.g. log rotation
+fn boot_only(ctx: &StageContext) -> Option<String> {
+    ctx.partial.then(|| "only runs during boot".to_string())
+}
+
+const POST_FS_DATA: &[Phase] = &[
+    Phase::new("root", phase::always, phase_root).critical(),
+    Phase::new("logs", boot_only, phase_logs),
+    Phase::new("health", boot_only, phase_health),
+    Phase::new("common-scripts", needs_busybox, phase_common_scripts),
+    Phase::new("binaries", phase::always, phase_binaries),
+    Phase::new("update", needs_data, phase_update).critical(),
+    Phase::new("safe-mode", phase::always, phase_safe_mode),
+    Phase::new("prune", needs_data, phase_prune),
+    Phase::new("sepolicy", needs_data, phase_sepolicy),
+    Phase::new("kmods", kmods_enabled, phase_kmods),
+    Phase::new("mount", needs_data, phase_mount),
+    Phase::new("kmods-late", kmods_enabled, phase_kmods_late),
+    Phase::new("scripts", needs_data, phase_scripts),
+    Phase::new("props", needs_data, phase_props),
+    Phase::new("record", boot_only, phase_record),
+    Phase::new("post-mount", needs_busybox, phase_post_mount),
+    Phase::new("report", boot_only, phase_report),
+];
+
+/// Names of the post-fs-data phases, in the order they run
+pub fn post_fs_data_phases() -> Vec<&'static str> {
+    POST_FS_DATA.iter().map(|phase| phase.name).collect()
+}
+
+pub fn on_post_data_fs(superkey: Option<String>, only: Option<&[String]>) -> Result<Outcome> {
+    run_post_fs_data(superkey, only, false)
+}
+
+/// Run the post-fs-data phases that did not run in this boot, e.g. as the
+/// daemon was killed half way
+pub fn resume_post_fs_data(superkey: Option<String>) -> Result<Outcome> {
+    let Some(progress) = phase::Progress::current("post-fs-data") else {
+        anyhow::bail!("post-fs-data did not run in this boot, nothing to resume");
+    };
+    if progress.finished {
+        info!("post-fs-data already finished in this boot");
+        return Ok(Outcome::Ok);
+    }
+    let remaining = progress.remaining(&post_fs_data_phases());
+    info!("resuming post-fs-data with: {}", remaining.join(","));
+    run_post_fs_data(superkey, Some(&remaining), true)
 }
 
-fn run_stage(stage: &str, superkey: Option<String>, block: bool) {
+fn run_post_fs_data(
+    superkey: Option<String>,
+    only: Option<&[String]>,
+    resume: bool,
+) -> Result<Outcome> {
+    // both work on the state left by the phases which already ran
+    let restored = only.is_some();
+    let partial = restored && !resume;
+    if let Some(only) = only {
+        let known = post_fs_data_phases();
+        if let Some(unknown) = only.iter().find(|name| !known.contains(&name.as_str())) {
+            anyhow::bail!("unknown phase {unknown}, phases are: {}", known.join(","));
+        }
+    } else {
+        profile::begin_boot();
+        timing::begin_stage("post-fs-data");
+    }
+    // a re-run or resume happens on a booted device, /data is there by then
+    let data_ready = restored || data_ready::wait();
+
+    let mut ctx = StageContext {
+        root_access: RootAccess {
+            key_accepted: supercall::validate_superkey(&superkey),
+            broken_binaries: if restored {
+                assets::ensure_binaries()
+            } else {
+                Vec::new()
+            },
+            sepolicy_patched: !restored || sepolicy::is_live_patched(),
+            supercall_compatible: supercall::Handshake::current()
+                .is_none_or(|handshake| handshake.compatible),
+        },
+        safe_mode: restored && utils::is_safe_mode(superkey.clone()),
+        disable_modules: resume && bootloop::safe_mode_requested(),
+        health: if resume {
+            status::resume_boot(utils::is_safe_mode(superkey.clone()))
+        } else if partial {
+            Health::load().unwrap_or_default()
+        } else {
+            Health::default()
+        },
+        superkey,
+        post_mount_failures: 0,
+        partial,
+        resume,
+        data_ready,
+    };
+    let phases: Vec<&dyn BootPhase> = POST_FS_DATA.iter().map(|p| p as &dyn BootPhase).collect();
+    let reports = phase::run_phases("post-fs-data", &phases, &mut ctx, only);
+    if let Err(e) = timing::finish_stage() {
+        warn!("Failed to write boot timing: {}", e);
+    }
+    let reports = reports?;
+    if reports.last().is_some_and(|r| r.result == "stopped") {
+        return Ok(Outcome::Ok);
+    }
+
+    let mut problems: Vec<String> = reports
+        .iter()
+        .filter(|r| r.result == "failed")
+        .map(|r| format!("phase {} failed", r.name))
+        .collect();
+    if !ctx.root_access.sepolicy_patched {
+        problems.push("sepolicy patch failed".to_string());
+    }
+    if !ctx.root_access.key_accepted {
+        problems.push("superkey not accepted".to_string());
+    }
+    if !ctx.root_access.supercall_compatible {
+        problems.push("KernelPatch too old".to_string());
+    }
+    if !ctx.data_ready {
+        problems.push(format!("{} not readable, modules skipped", defs::ADB_DIR));
+    }
+    if ctx.health.mount_failed {
+        problems.push("module mount failed".to_string());
+    } else if ctx.health.mount_fallbacks > 0 {
+        problems.push("module mount fell back".to_string());
+    }
+    if ctx.health.modules_failed > 0 {
+        let failed = ctx.health.modules_failed;
+        problems.push(format!("{failed} module script(s) failed"));
+    }
+    if ctx.post_mount_failures > 0 {
+        let failed = ctx.post_mount_failures;
+        problems.push(format!("{failed} post-mount step(s) failed"));
+    }
+    Ok(outcome(problems))
+}
+
+fn outcome(problems: Vec<String>) -> Outcome {
+    if problems.is_empty() {
+        Outcome::Ok
+    } else {
+        Outcome::Degraded(problems.join(", "))
+    }
+}
+
+/// Failed steps of a stage run
+#[derive(Default)]
+struct StageFailures {
+    steps: usize,
+    /// module scripts, of the metamodule too, which failed to start or, if
+    /// waited for, exited unsuccessfully. They are part of `steps`.
+    module_scripts: usize,
+}
+
+/// Run scripts of a stage
+fn run_stage(stage: &str, superkey: Option<String>, block: bool) -> StageFailures {
     utils::umask(0);
 
-    if utils::has_magisk() {
+    if utils::has_magisk() && !utils::coexist_with_magisk() {
         warn!("Magisk detected, skip {stage}");
-        return;
+        return StageFailures::default();
     }
 
     if utils::is_safe_mode(superkey.clone()) {
         warn!("safe mode, skip {stage} scripts");
-        if let Err(e) = module::disable_all_modules() {
-            warn!("disable all modules failed: {}", e);
-        }
-        return;
+        return StageFailures::default();
     }
 
+    let mut failures = StageFailures::default();
+    if matches!(stage, "service" | "boot-completed")
+        && let Err(e) = timing::measure("system.prop", || module::load_system_prop(stage))
+    {
+        warn!("Failed to load {stage} system.prop: {e}");
+        failures.steps += 1;
+    }
     // execute metamodule stage script first (priority) (only in metamodule mode)
-    if utils::get_mount_mode() == defs::MOUNT_MODE_METAMODULE {
-        if let Err(e) = metamodule::exec_stage_script(stage, block) {
-            warn!("Failed to exec metamodule {stage} script: {e}");
-        }
+    if utils::get_mount_mode() == defs::MOUNT_MODE_METAMODULE && !sepolicy::gated("metamodule") {
+        let failed = timing::measure("metamodule", || metamodule::exec_stage_script(stage, block));
+        failures.steps += failed;
+        failures.module_scripts += failed;
     }
 
-    if let Err(e) = module::exec_common_scripts(&format!("{stage}.d"), block) {
-        warn!("Failed to exec common {stage} scripts: {e}");
-    }
-    if let Err(e) = module::exec_stage_script(stage, block) {
-        warn!("Failed to exec {stage} scripts: {e}");
+    if !sepolicy::gated("scripts") {
+        if let Err(e) = timing::measure("common-scripts", || {
+            module::exec_common_scripts(&format!("{stage}.d"), block)
+        }) {
+            warn!("Failed to exec common {stage} scripts: {e}");
+            failures.steps += 1;
+        }
+        match timing::measure("scripts", || module::exec_stage_script(stage, block)) {
+            Ok(failed) => {
+                failures.steps += failed;
+                failures.module_scripts += failed;
+            }
+            Err(e) => {
+                warn!("Failed to exec {stage} scripts: {e}");
+                failures.steps += 1;
+            }
+        }
     }
-    if let Err(e) = lua::exec_stage_lua(stage, block, superkey.as_deref().unwrap_or("")) {
+    if !sepolicy::gated("lua")
+        && let Err(e) = timing::measure("lua", || {
+            lua::exec_stage_lua(stage, block, superkey.as_deref().unwrap_or(""))
+        })
+    {
         warn!("Failed to exec {stage} lua: {e}");
+        failures.steps += 1;
     }
+    failures
 }
 
-pub fn on_services(superkey: Option<String>) -> Result<()> {
+pub fn on_services(superkey: Option<String>) -> Result<Outcome> {
     info!("on_services triggered!");
-    run_stage("service", superkey, false);
+    timing::begin_stage("service");
+    if let Err(e) = timing::measure("sepolicy retry", module::retry_sepolicy_rules) {
+        warn!("Failed to retry sepolicy.rule: {}", e);
+    }
+    if let Err(e) = spawn_boot_completed_watch(superkey.as_deref()) {
+        warn!("{:#}", e);
+    }
+    let failures = run_stage("service", superkey, false);
+    if let Err(e) = timing::finish_stage() {
+        warn!("Failed to write boot timing: {}", e);
+    }
+    if let Err(e) = status::add_failed_scripts(failures.module_scripts) {
+        warn!("Failed to write health record: {}", e);
+    }
 
-    Ok(())
+    let mut problems = Vec::new();
+    if failures.steps > 0 {
+        problems.push(format!("{} service step(s) failed", failures.steps));
+    }
+    Ok(outcome(problems))
 }
 
 fn run_uid_monitor() {
     info!("Trigger run_uid_monitor!");
 
-    let mut command = &mut Command::new("/data/adb/apd");
+    let mut command = &mut Command::new(defs::DAEMON_PATH);
     {
         command = command.process_group(0);
         command = unsafe {
@@ -298,13 +980,398 @@ fn run_uid_monitor() {
         .expect("[run_uid_monitor] Failed to run uid monitor");
 }
 
-pub fn on_boot_completed(superkey: Option<String>) -> Result<()> {
+const BOOT_COMPLETED_POLL: Duration = Duration::from_secs(5);
+/// Time init gets to trigger boot-completed itself once the property is set
+const BOOT_COMPLETED_GRACE: Duration = Duration::from_secs(30);
+const BOOT_COMPLETED_TIMEOUT: Duration = Duration::from_secs(600);
+
+fn boot_completed_ran() -> bool {
+    fs::read_to_string(defs::BOOT_COMPLETED_STAMP_FILE)
+        .is_ok_and(|id| id.trim() == script_history::boot_id())
+}
+
+/// Mount the modules flagged `defer_mount`, left out at post-fs-data, on top
+/// of the module mounts of this boot
+fn mount_deferred() {
+    // nothing was mounted at post-fs-data, e.g. in safe mode
+    if !mounted_this_boot()
+        || utils::get_mount_mode() != defs::MOUNT_MODE_MAGIC
+        || !magic_mount::has_deferred()
+    {
+        return;
+    }
+    // new mount namespaces inherit the mounts, existing ones do not
+    warn!("mounting deferred modules, apps already running will not see them until restarted");
+    if let Err(e) = timing::measure("deferred-mount", magic_mount::magic_mount_deferred) {
+        warn!("Failed to mount deferred modules: {:#}", e);
+    }
+    if let Err(e) = MountRegistry::record() {
+        warn!("Failed to record module mounts: {}", e);
+    }
+}
+
+/// Start `apd await-boot-completed`, which runs boot-completed in case the
+/// trigger of init never arrives. The superkey is handed over on a pipe of its
+/// own, see [`superkey_pipe`].
+fn spawn_boot_completed_watch(superkey: Option<&str>) -> Result<()> {
+    let superkey_fd = superkey.map(superkey_pipe).transpose()?;
+    let child = unsafe {
+        boot_completed_watch(superkey_fd)
+            .process_group(0)
+            .pre_exec(|| {
+                switch_cgroups();
+                Ok(())
+            })
+            .spawn()
+    };
+    // the child holds its own copy of the read end
+    if let Some(fd) = superkey_fd {
+        unsafe { libc::close(fd) };
+    }
+    let child = child.context("Failed to start boot-completed watch")?;
+    shutdown::track(child.id());
+    Ok(())
+}
+
+/// The command line of `apd await-boot-completed`, reading the superkey from
+/// `superkey_fd` if there is one
+fn boot_completed_watch(superkey_fd: Option<i32>) -> Command {
+    let mut command = Command::new(defs::DAEMON_PATH);
+    if let Some(fd) = superkey_fd {
+        command.args(["--superkey-fd", &fd.to_string()]);
+    }
+    command.arg("await-boot-completed").stdin(Stdio::null());
+    command
+}
+
+/// Wait for `sys.boot_completed`, returns whether boot-completed still has to
+/// run because init did not trigger it within [`BOOT_COMPLETED_GRACE`]
+pub fn await_boot_completed() -> bool {
+    let deadline = Instant::now() + BOOT_COMPLETED_TIMEOUT;
+    while utils::getprop("sys.boot_completed").as_deref() != Some("1") {
+        if boot_completed_ran() || Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(BOOT_COMPLETED_POLL);
+    }
+    thread::sleep(BOOT_COMPLETED_GRACE);
+    if boot_completed_ran() {
+        return false;
+    }
+    warn!("init did not trigger boot-completed, running it from the watch");
+    true
+}
+
+pub fn on_boot_completed(superkey: Option<String>) -> Result<Outcome> {
     info!("on_boot_completed triggered!");
+    // the stage lock serializes the init trigger and the watch
+    if boot_completed_ran() {
+        info!("boot-completed already ran this boot, skip");
+        return Ok(Outcome::Ok);
+    }
+    if let Err(e) = fs::write(defs::BOOT_COMPLETED_STAMP_FILE, script_history::boot_id()) {
+        warn!("Failed to write {}: {}", defs::BOOT_COMPLETED_STAMP_FILE, e);
+    }
+    timing::begin_stage("boot-completed");
 
-    run_stage("boot-completed", superkey, false);
+    mount_deferred();
+    let failures = run_stage("boot-completed", superkey, false);
+    if let Err(e) = status::add_failed_scripts(failures.module_scripts) {
+        warn!("Failed to write health record: {}", e);
+    }
+    if let Err(e) = timing::measure("props", || crate::prop_override::apply("boot-completed")) {
+        warn!("Failed to apply prop overrides: {}", e);
+    }
+    if let Err(e) = timing::finish_stage() {
+        warn!("Failed to write boot timing: {}", e);
+    }
+
+    let mut problems = Vec::new();
+    if failures.steps > 0 {
+        problems.push(format!("{} boot-completed step(s) failed", failures.steps));
+    }
+    bootloop::finish_boot();
+    if let Err(e) = module::verify_updates() {
+        warn!("Failed to verify module updates: {}", e);
+    }
+    if let Err(e) = status::finish_boot() {
+        warn!("Failed to write health record: {}", e);
+        problems.push("health record not written".to_string());
+    }
+    if let Err(e) = profile::finish_boot() {
+        warn!("Failed to write boot profile: {}", e);
+    }
 
     run_uid_monitor();
-    Ok(())
+    Ok(outcome(problems))
+}
+
+/// Coalesces a burst of events into one action once no event arrived for
+/// `quiet`, and acts at most once per `window`
+struct Debouncer {
+    quiet: Duration,
+    window: Duration,
+    /// time of the last event not yet acted upon
+    pending: Option<Instant>,
+    fired: Option<Instant>,
+}
+
+impl Debouncer {
+    fn new(quiet: Duration, window: Duration) -> Self {
+        Debouncer {
+            quiet,
+            window,
+            pending: None,
+            fired: None,
+        }
+    }
+
+    /// Time left until the action may run again
+    fn window_left(&self, now: Instant) -> Duration {
+        self.fired.map_or(Duration::ZERO, |fired| {
+            self.window.saturating_sub(now.saturating_duration_since(fired))
+        })
+    }
+
+    fn event(&mut self, now: Instant) {
+        self.pending = Some(now);
+    }
+
+    /// How long to wait for more events, `None` if nothing is pending
+    fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.pending.map(|last| {
+            let quiet = self.quiet.saturating_sub(now.saturating_duration_since(last));
+            quiet.max(self.window_left(now))
+        })
+    }
+
+    /// Whether the quiet period is over, the pending events are consumed then.
+    /// Events arriving while acting make it fire again after the next quiet period.
+    fn fire(&mut self, now: Instant) -> bool {
+        match self.pending {
+            Some(last)
+                if now.saturating_duration_since(last) >= self.quiet
+                    && self.window_left(now).is_zero() =>
+            {
+                self.pending = None;
+                self.fired = Some(now);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+enum ListenerEvent {
+    PackagesChanged,
+    /// the watch is gone, e.g. /data/system was remounted
+    Rewatch,
+}
+
+const REWATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Package list refreshes of this listener, for `apd status --uid-listener`
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct RefreshStats {
+    pub performed: u64,
+    /// refreshes left out because packages.list did not change
+    pub skipped: u64,
+    pub last_duration_ms: Option<u64>,
+}
+
+fn refresh_stats_cell() -> &'static Mutex<RefreshStats> {
+    static STATS: OnceLock<Mutex<RefreshStats>> = OnceLock::new();
+    STATS.get_or_init(|| Mutex::new(RefreshStats::default()))
+}
+
+pub fn refresh_stats() -> RefreshStats {
+    refresh_stats_cell()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+fn update_refresh_stats(update: impl FnOnce(&mut RefreshStats)) {
+    update(&mut refresh_stats_cell().lock().unwrap_or_else(|e| e.into_inner()));
+}
+
+const PACKAGES_LIST: &str = "/data/system/packages.list";
+
+/// Hash of packages.list, `None` if it cannot be read. The system server
+/// replaces the file by renaming a new one over it, a read which finds no file
+/// at all is tried once more.
+fn packages_list_hash() -> Option<u64> {
+    let content = fs::read(PACKAGES_LIST).or_else(|e| {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e);
+        }
+        thread::sleep(Duration::from_millis(100));
+        fs::read(PACKAGES_LIST)
+    });
+    match content {
+        Ok(content) => {
+            let mut hasher = DefaultHasher::new();
+            content.hash(&mut hasher);
+            Some(hasher.finish())
+        }
+        Err(e) => {
+            warn!("[uid_monitor] Failed to read {PACKAGES_LIST}: {e}");
+            None
+        }
+    }
+}
+
+/// Files in /data/system whose change may change the package list
+const PACKAGE_FILES: [&str; 3] = ["packages.list", "packages.list.tmp", "packages.xml"];
+
+/// Whether `event` touched the package list. Writers differ between releases,
+/// some rename a temp file in place, others create the file anew, and kernels
+/// report renames as one event or as a from/to pair.
+fn packages_changed(event: &Event) -> bool {
+    matches!(
+        event.kind,
+        EventKind::Modify(ModifyKind::Name(_)) | EventKind::Create(_)
+    ) && event.paths.iter().any(|path| {
+        path.file_name()
+            .is_some_and(|name| PACKAGE_FILES.iter().any(|file| name == *file))
+    })
+}
+
+fn next_event(rx: &Receiver<ListenerEvent>, timeout: Option<Duration>) -> Option<ListenerEvent> {
+    match timeout {
+        Some(timeout) => match rx.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(ListenerEvent::Rewatch),
+        },
+        None => Some(rx.recv().unwrap_or(ListenerEvent::Rewatch)),
+    }
+}
+
+/// Restarts of the listener allowed within [`RESTART_WINDOW`]
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+/// Pid of the running uid listener supervisor, if any
+pub fn uid_listener_pid() -> Option<u32> {
+    let pid: u32 = fs::read_to_string(defs::UID_LISTENER_PID_FILE)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    cmdline
+        .split(|b| *b == 0)
+        .any(|arg| arg == b"uid-listener")
+        .then_some(pid)
+}
+
+/// A pipe holding `superkey`, its read end is inherited by executed programs
+fn superkey_pipe(superkey: &str) -> Result<i32> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to create superkey pipe");
+    }
+    // keys are far below the pipe buffer, the write does not block
+    let mut writer = unsafe { fs::File::from_raw_fd(fds[1]) };
+    writer.write_all(superkey.as_bytes())?;
+    Ok(fds[0])
+}
+
+/// Replace this supervisor by the apd at [`defs::DAEMON_PATH`], e.g. after
+/// `apd self-update`. Returns only if that fails.
+fn reexec(superkey: Option<&str>) -> Result<()> {
+    let mut command = Command::new(defs::DAEMON_PATH);
+    if let Some(superkey) = superkey {
+        command.args(["--superkey-fd", &superkey_pipe(superkey)?.to_string()]);
+    }
+    command.arg("uid-listener");
+    info!("[uid_monitor] re-executing {}", defs::DAEMON_PATH);
+    let e = command.exec();
+    Err(e).with_context(|| format!("Failed to execute {}", defs::DAEMON_PATH))
+}
+
+/// `apd uid-listener`: run the listener as a worker process and start it again
+/// with exponential backoff when it dies, at most [`MAX_RESTARTS`] times a minute.
+/// On SIGUSR1 the worker is killed and the supervisor executes the apd binary
+/// anew, keeping its pid and superkey.
+pub fn supervise_uid_listener(superkey: Option<String>) -> Result<()> {
+    fs::write(defs::UID_LISTENER_PID_FILE, std::process::id().to_string())
+        .with_context(|| format!("Failed to write {}", defs::UID_LISTENER_PID_FILE))?;
+    shutdown::track(std::process::id());
+    // the worker handles power events, they must not end the supervisor
+    signal_hook::flag::register(SIGPWR, Arc::new(AtomicBool::new(false)))?;
+
+    let reexec_requested = Arc::new(AtomicBool::new(false));
+    let worker = Arc::new(AtomicI32::new(0));
+    {
+        let mut signals = Signals::new([SIGUSR1])?;
+        let (reexec_requested, worker) = (reexec_requested.clone(), worker.clone());
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("[uid_monitor] asked to re-execute, stopping the listener");
+                reexec_requested.store(true, Ordering::SeqCst);
+                // SIGTERM would make the worker stop everything apd started
+                let pid = worker.load(Ordering::SeqCst);
+                if pid > 0 {
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                }
+            }
+        });
+    }
+
+    let mut exe = env::current_exe().unwrap_or_else(|_| PathBuf::from(defs::DAEMON_PATH));
+    let mut restarts: Vec<Instant> = Vec::new();
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let started = Instant::now();
+        let mut child = Command::new(&exe)
+            .args(["uid-listener", "--worker"])
+            .spawn()
+            .with_context(|| format!("Failed to start {} uid-listener", exe.display()))?;
+        worker.store(child.id() as i32, Ordering::SeqCst);
+        if reexec_requested.load(Ordering::SeqCst) {
+            let _ = child.kill();
+        }
+        let status = child.wait()?;
+        worker.store(0, Ordering::SeqCst);
+        if reexec_requested.swap(false, Ordering::SeqCst) {
+            if let Err(e) = reexec(superkey.as_deref()) {
+                error!("[uid_monitor] {e:#}, keeping the running listener");
+                // the file this supervisor was started from may be gone now
+                exe = PathBuf::from("/proc/self/exe");
+            }
+            continue;
+        }
+        if status.success() {
+            info!("[uid_monitor] listener stopped");
+            return Ok(());
+        }
+
+        // a listener which ran for a while starts over with the shortest delay
+        if started.elapsed() > RESTART_WINDOW {
+            delay = Duration::from_secs(1);
+        }
+        restarts.retain(|at| at.elapsed() < RESTART_WINDOW);
+        if restarts.len() >= MAX_RESTARTS {
+            let wait = RESTART_WINDOW.saturating_sub(restarts[0].elapsed());
+            warn!("[uid_monitor] listener keeps dying, next restart in {}s", wait.as_secs());
+            thread::sleep(wait);
+            restarts.remove(0);
+        } else {
+            thread::sleep(delay);
+        }
+        warn!("[uid_monitor] listener exited with {status}, restarting");
+        events::emit(
+            events::Code::UidListenerRestarted,
+            &Message::UidListenerRestarted {
+                status: status.to_string(),
+            },
+        );
+        restarts.push(Instant::now());
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
 }
 
 pub fn start_uid_listener() -> Result<()> {
@@ -315,13 +1382,17 @@ pub fn start_uid_listener() -> Result<()> {
         warn!("[start_uid_listener] Failed to initialize package baseline: {}", e);
     }
 
+    shutdown::track(std::process::id());
+    data_watch::spawn_watcher();
+    crate::umount::spawn_watcher();
+    crate::targeted::spawn_watcher();
+    daemon::spawn_server();
+
     // create inotify instance
-    const SYS_PACKAGES_LIST_TMP: &str = "/data/system/packages.list.tmp";
-    let sys_packages_list_tmp = PathBuf::from(&SYS_PACKAGES_LIST_TMP);
-    let dir: PathBuf = sys_packages_list_tmp.parent().unwrap().into();
+    let dir = PathBuf::from("/data/system");
+    let watched_dir = dir.clone();
 
     let (tx, rx) = std::sync::mpsc::channel();
-    let tx_clone = tx.clone();
     let mutex = Arc::new(Mutex::new(()));
 
     {
@@ -333,6 +1404,11 @@ pub fn start_uid_listener() -> Result<()> {
                 let skey = CStr::from_bytes_with_nul(b"su\0")
                     .expect("[shutdown_listener] CStr::from_bytes_with_nul failed");
                 refresh_ap_package_list(&skey, &mutex_clone);
+                if sig != SIGPWR {
+                    // asked to stop, e.g. by `apd shutdown` before an upgrade
+                    shutdown::stop_children();
+                    std::process::exit(0);
+                }
                 break; // 执行一次后退出线程
             }
         });
@@ -340,37 +1416,494 @@ pub fn start_uid_listener() -> Result<()> {
 
     let mut watcher = INotifyWatcher::new(
         move |ev: notify::Result<Event>| match ev {
+            Ok(event) if packages_changed(&event) => {
+                info!("[uid_monitor] System packages list changed, sending to tx...");
+                let _ = tx.send(ListenerEvent::PackagesChanged);
+            }
             Ok(Event {
-                kind: EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                kind: EventKind::Remove(_) | EventKind::Other,
                 paths,
                 ..
-            }) => {
-                if paths.contains(&sys_packages_list_tmp) {
-                    info!("[uid_monitor] System packages list changed, sending to tx...");
-                    tx_clone.send(false).unwrap()
-                }
+            }) if paths.is_empty() || paths.contains(&watched_dir) => {
+                let _ = tx.send(ListenerEvent::Rewatch);
+            }
+            Err(err) => {
+                warn!("inotify error: {err}");
+                let _ = tx.send(ListenerEvent::Rewatch);
             }
-            Err(err) => warn!("inotify error: {err}"),
             _ => (),
         },
         Config::default(),
     )?;
 
     watcher.watch(dir.as_ref(), RecursiveMode::NonRecursive)?;
+    let mut watching = true;
+
+    let mut debouncer = Debouncer::new(Duration::from_secs(1), config::package_refresh_window());
+    // of packages.list at the last refresh which went through
+    let mut refreshed_hash = None;
+    loop {
+        let mut timeout = debouncer.timeout(Instant::now());
+        if !watching {
+            timeout = Some(timeout.map_or(REWATCH_INTERVAL, |t| t.min(REWATCH_INTERVAL)));
+        }
+        match next_event(&rx, timeout) {
+            Some(ListenerEvent::PackagesChanged) => debouncer.event(Instant::now()),
+            Some(ListenerEvent::Rewatch) => watching = false,
+            None => {}
+        }
+
+        if !watching {
+            let _ = watcher.unwatch(dir.as_ref());
+            match watcher.watch(dir.as_ref(), RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    info!("[uid_monitor] re-registered watch on {}", dir.display());
+                    watching = true;
+                    // changes may have been missed meanwhile
+                    debouncer.event(Instant::now());
+                }
+                Err(e) => warn!("[uid_monitor] failed to watch {}: {}", dir.display(), e),
+            }
+        }
 
-    let mut debounce = false;
-    while let Ok(delayed) = rx.recv() {
-        if delayed {
-            debounce = false;
+        if debouncer.fire(Instant::now()) {
+            let hash = packages_list_hash();
+            if hash.is_some() && hash == refreshed_hash {
+                info!("[uid_monitor] packages.list unchanged, skip refresh");
+                update_refresh_stats(|stats| stats.skipped += 1);
+                continue;
+            }
             let skey = CStr::from_bytes_with_nul(b"su\0")
                 .expect("[start_uid_listener] CStr::from_bytes_with_nul failed");
-            refresh_ap_package_list(&skey, &mutex);
-        } else if !debounce {
-            thread::sleep(Duration::from_secs(1));
-            debounce = true;
-            tx.send(true)?;
+            let started = Instant::now();
+            let refreshed = refresh_ap_package_list(&skey, &mutex);
+            refreshed_hash = if refreshed { hash } else { None };
+            update_refresh_stats(|stats| {
+                stats.performed += 1;
+                stats.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+            });
+            daemon::broadcast(&daemon::Event::PackagesRefreshed);
         }
     }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+
+    /// Records the steps taken, `patched_after` injections make the policy land
+    #[derive(Default)]
+    struct Fake {
+        key_accepted: bool,
+        compatible: bool,
+        shell_su: bool,
+        patched_after: Option<usize>,
+        injections: Cell<usize>,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl Fake {
+        fn working() -> Self {
+            Fake {
+                key_accepted: true,
+                compatible: true,
+                shell_su: true,
+                patched_after: Some(1),
+                ..Default::default()
+            }
+        }
+
+        fn call(&self, name: &str) {
+            self.calls.borrow_mut().push(name.to_string());
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.borrow().clone()
+        }
+    }
+
+    impl RootSteps for Fake {
+        fn validate_superkey(&self, _: &Option<String>) -> bool {
+            self.call("validate");
+            self.key_accepted
+        }
+
+        fn handshake(&self, _: &Option<String>) -> supercall::Handshake {
+            self.call("handshake");
+            supercall::Handshake {
+                kernelpatch_version: "0.10.0".to_string(),
+                minimum: "0.11.0".to_string(),
+                compatible: self.compatible,
+                missing: Vec::new(),
+                boot_id: String::new(),
+            }
+        }
+
+        fn load_package_config(&self, _: &Option<String>) {
+            self.call("package_config");
+        }
+
+        fn load_su_path(&self, _: &Option<String>) {
+            self.call("su_path");
+        }
+
+        fn shell_su_requested(&self) -> bool {
+            self.shell_su
+        }
+
+        fn grant_shell_su(&self, _: &Option<String>) {
+            self.call("shell_su");
+        }
+
+        fn inject_sepolicy(&self) {
+            self.injections.set(self.injections.get() + 1);
+            self.call("inject");
+        }
+
+        fn sepolicy_patched(&self) -> bool {
+            self.patched_after
+                .is_some_and(|after| self.injections.get() >= after)
+        }
+
+        fn mark_sepolicy(&self, patched: bool) {
+            self.call(if patched { "mark_ok" } else { "mark_failed" });
+        }
+
+        fn privilege_apd_profile(&self, _: &Option<String>) {
+            self.call("privilege");
+        }
+
+        fn ensure_binaries(&self) -> Vec<assets::BinaryStatus> {
+            self.call("binaries");
+            Vec::new()
+        }
+    }
+
+    const ALL_STEPS: [&str; 9] = [
+        "validate",
+        "handshake",
+        "package_config",
+        "su_path",
+        "shell_su",
+        "inject",
+        "mark_ok",
+        "privilege",
+        "binaries",
+    ];
+
+    #[test]
+    fn every_step_runs_on_a_working_kernel() {
+        let steps = Fake::working();
+        let access = prepare_root_access_with(&steps, &Some("key".to_string()), true);
+        assert_eq!(steps.calls(), ALL_STEPS);
+        assert!(access.key_accepted && access.supercall_compatible && access.sepolicy_patched);
+    }
+
+    #[test]
+    fn a_refused_key_does_not_stop_the_other_steps() {
+        let steps = Fake {
+            key_accepted: false,
+            ..Fake::working()
+        };
+        let access = prepare_root_access_with(&steps, &None, true);
+        assert_eq!(steps.calls(), ALL_STEPS);
+        assert!(!access.key_accepted);
+    }
+
+    #[test]
+    fn an_old_kernelpatch_skips_su_setup_only() {
+        let steps = Fake {
+            compatible: false,
+            ..Fake::working()
+        };
+        let access = prepare_root_access_with(&steps, &Some("key".to_string()), true);
+        assert_eq!(
+            steps.calls(),
+            ["validate", "handshake", "inject", "mark_ok", "binaries"]
+        );
+        assert!(!access.supercall_compatible);
+        assert!(access.sepolicy_patched);
+    }
+
+    #[test]
+    fn a_lost_policy_load_is_retried_once() {
+        let steps = Fake {
+            patched_after: Some(2),
+            ..Fake::working()
+        };
+        let access = prepare_root_access_with(&steps, &Some("key".to_string()), true);
+        assert_eq!(steps.injections.get(), 2);
+        assert!(access.sepolicy_patched);
+        assert!(steps.calls().contains(&"mark_ok".to_string()));
+    }
+
+    #[test]
+    fn a_failed_policy_patch_is_marked_and_root_setup_completes() {
+        let steps = Fake {
+            patched_after: None,
+            ..Fake::working()
+        };
+        let access = prepare_root_access_with(&steps, &Some("key".to_string()), true);
+        assert_eq!(steps.injections.get(), 2);
+        assert!(!access.sepolicy_patched);
+        assert_eq!(
+            steps.calls(),
+            [
+                "validate",
+                "handshake",
+                "package_config",
+                "su_path",
+                "shell_su",
+                "inject",
+                "inject",
+                "mark_failed",
+                "privilege",
+                "binaries"
+            ]
+        );
+    }
+
+    #[test]
+    fn alongside_magisk_the_policy_is_only_checked() {
+        let steps = Fake::working();
+        let access = prepare_root_access_with(&steps, &Some("key".to_string()), false);
+        assert_eq!(steps.injections.get(), 0);
+        assert!(!access.sepolicy_patched);
+        assert!(steps.calls().contains(&"mark_failed".to_string()));
+    }
+
+    fn stage_context() -> StageContext {
+        StageContext {
+            superkey: None,
+            root_access: RootAccess {
+                key_accepted: true,
+                broken_binaries: Vec::new(),
+                sepolicy_patched: true,
+                supercall_compatible: true,
+            },
+            safe_mode: false,
+            disable_modules: false,
+            health: Health::default(),
+            post_mount_failures: 0,
+            partial: false,
+            resume: false,
+            data_ready: true,
+        }
+    }
+
+    #[test]
+    fn post_fs_data_keeps_its_order() {
+        assert_eq!(
+            post_fs_data_phases(),
+            [
+                "root",
+                "logs",
+                "health",
+                "common-scripts",
+                "binaries",
+                "update",
+                "safe-mode",
+                "prune",
+                "sepolicy",
+                "kmods",
+                "mount",
+                "kmods-late",
+                "scripts",
+                "props",
+                "record",
+                "post-mount",
+                "report"
+            ]
+        );
+        let critical: Vec<_> = POST_FS_DATA
+            .iter()
+            .filter(|phase| phase.critical)
+            .map(|phase| phase.name)
+            .collect();
+        assert_eq!(critical, ["root", "update"]);
+    }
+
+    #[test]
+    fn phases_are_skipped_without_what_they_need() {
+        let unmet = |ctx: &StageContext| -> Vec<&str> {
+            POST_FS_DATA
+                .iter()
+                .filter(|phase| phase.unmet(ctx).is_some())
+                .map(|phase| phase.name)
+                .collect()
+        };
+        let mut ctx = stage_context();
+        let kmods = unmet(&ctx);
+        assert!(kmods.iter().all(|name| name.starts_with("kmods")));
+
+        ctx.partial = true;
+        let partial = unmet(&ctx);
+        assert!(partial.contains(&"logs") && partial.contains(&"record"));
+        assert!(!partial.contains(&"mount"));
+
+        ctx.partial = false;
+        ctx.data_ready = false;
+        let no_data = unmet(&ctx);
+        for name in ["common-scripts", "update", "prune", "mount", "scripts", "post-mount"] {
+            assert!(no_data.contains(&name), "{name} runs without data");
+        }
+        assert!(!no_data.contains(&"root") && !no_data.contains(&"safe-mode"));
+    }
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn a_burst_of_events_fires_once() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(SECOND, Duration::ZERO);
+        assert_eq!(debouncer.timeout(start), None);
+        assert!(!debouncer.fire(start));
+
+        debouncer.event(start);
+        debouncer.event(start + SECOND / 2);
+        assert_eq!(debouncer.timeout(start + SECOND), Some(SECOND / 2));
+        assert!(!debouncer.fire(start + SECOND));
+        assert!(debouncer.fire(start + SECOND * 3 / 2));
+
+        assert_eq!(debouncer.timeout(start + SECOND * 2), None);
+        assert!(!debouncer.fire(start + SECOND * 5));
+    }
+
+    #[test]
+    fn an_event_during_the_refresh_fires_again() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(SECOND, Duration::ZERO);
+        debouncer.event(start);
+        assert!(debouncer.fire(start + SECOND));
+        // packages.list changed again while it was being read
+        debouncer.event(start + SECOND + SECOND / 10);
+        assert!(!debouncer.fire(start + SECOND * 2));
+        assert!(debouncer.fire(start + SECOND * 2 + SECOND / 10));
+        assert!(!debouncer.fire(start + SECOND * 10));
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn logs_of_the_last_boot_become_old_logs() {
+        let root = crate::testutil::TempDir::new("rotate");
+        root.write("log/kmsg.log", "this boot");
+        root.write("log/kmsg.log.old.log", "last boot");
+        root.write("log/my module.log", "spaces");
+        root.write("log/stale.old.log", "boot before");
+        root.write("log/dmesg dir/part 1", "dir");
+        root.write("log/.keep", "hidden");
+
+        assert_eq!(rotate_logs(&root.path().join("log")), 0);
+        assert_eq!(
+            names(&root.path().join("log")),
+            [".keep", "dmesg dir.old.log", "kmsg.log.old.log", "my module.log.old.log"]
+        );
+        let read = |name: &str| fs::read_to_string(root.path().join("log").join(name)).unwrap();
+        assert_eq!(read("kmsg.log.old.log"), "this boot");
+        assert_eq!(read("my module.log.old.log"), "spaces");
+        assert_eq!(read("dmesg dir.old.log/part 1"), "dir");
+        assert_eq!(read(".keep"), "hidden");
+    }
+
+    #[test]
+    fn rotating_twice_keeps_only_the_last_boot() {
+        let root = crate::testutil::TempDir::new("rotate-twice");
+        let log = root.path().join("log");
+        root.write("log/apd.log", "first");
+        assert_eq!(rotate_logs(&log), 0);
+        root.write("log/apd.log", "second");
+        assert_eq!(rotate_logs(&log), 0);
+        assert_eq!(names(&log), ["apd.log.old.log"]);
+        assert_eq!(fs::read_to_string(log.join("apd.log.old.log")).unwrap(), "second");
+
+        assert_eq!(rotate_logs(&root.path().join("missing")), 1);
+    }
+
+    fn notify_event(kind: EventKind, paths: &[&str]) -> Event {
+        paths
+            .iter()
+            .fold(Event::new(kind), |event, path| event.add_path(PathBuf::from(path)))
+    }
+
+    #[test]
+    fn package_list_renames_and_creates_are_changes() {
+        use notify::event::{CreateKind, RenameMode};
+
+        let rename = |mode| EventKind::Modify(ModifyKind::Name(mode));
+        let changes = [
+            // one event with both paths
+            notify_event(
+                rename(RenameMode::Both),
+                &["/data/system/packages.list.tmp", "/data/system/packages.list"],
+            ),
+            // a from/to pair of a writer with its own temp name
+            notify_event(rename(RenameMode::To), &["/data/system/packages.list"]),
+            notify_event(rename(RenameMode::From), &["/data/system/packages.list.tmp"]),
+            notify_event(rename(RenameMode::Any), &["/data/system/packages.xml"]),
+            notify_event(
+                EventKind::Create(CreateKind::File),
+                &["/data/system/packages.list"],
+            ),
+        ];
+        for event in &changes {
+            assert!(packages_changed(event), "{event:?}");
+        }
+    }
+
+    #[test]
+    fn other_files_and_kinds_are_not_changes() {
+        use notify::event::{AccessKind, CreateKind, DataChange, RemoveKind, RenameMode};
+
+        let others = [
+            notify_event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["/data/system/users.tmp", "/data/system/users.xml"],
+            ),
+            notify_event(
+                EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+                &["/data/system/packages.list"],
+            ),
+            notify_event(
+                EventKind::Access(AccessKind::Any),
+                &["/data/system/packages.list"],
+            ),
+            notify_event(
+                EventKind::Remove(RemoveKind::File),
+                &["/data/system/packages.list"],
+            ),
+            notify_event(
+                EventKind::Create(CreateKind::File),
+                &["/data/system/packages.list.bak"],
+            ),
+            notify_event(EventKind::Any, &[]),
+        ];
+        for event in &others {
+            assert!(!packages_changed(event), "{event:?}");
+        }
+    }
+
+    #[test]
+    fn boot_completed_watch_reads_the_superkey_from_its_pipe() {
+        let fd = superkey_pipe("key").unwrap();
+        let command = boot_completed_watch(Some(fd));
+        let args: Vec<_> = command.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(args[0], "--superkey-fd");
+        let fd: i32 = args[1].parse().unwrap();
+        assert_eq!(crate::dispatch::read_superkey_fd(fd).unwrap(), "key");
+        assert_eq!(args[2..], ["await-boot-completed"]);
+
+        let command = boot_completed_watch(None);
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["await-boot-completed"]);
+    }
 }
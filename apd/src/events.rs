@@ -0,0 +1,255 @@
+//! Structured log of what happened during boot, for the manager
+//!
+//! apd appends one JSON document per line to `/data/adb/ap/log/events.jsonl`
+//! at the points the manager wants to tell the user about: a stage started or
+//! finished, a module was mounted or not, safe mode was entered, modules were
+//! disabled, updates were applied or rolled back, the uid listener restarted.
+//!
+//! ```json
+//! {"time":1760000000,"boot_id":"...","severity":"warning","code":"modules_disabled",
+//!  "message_code":"boot.safe_mode.modules_disabled","params":{},"message":"..."}
+//! ```
+//!
+//! `code` is stable, new codes may be added but existing ones keep their name
+//! and meaning. `subject` is the stage or module id the entry is about.
+//! `message_code` and `params` are those of the message catalog, the manager
+//! translates by them, `message` is the English rendering.
+//!
+//! Unlike the other logs the file is kept across boots. Once it grows past
+//! [`MAX_SIZE`] it is moved to `events.old.jsonl`, so at most two files are
+//! around. Entries are also pushed to subscribers of the daemon socket, and
+//! read with `apd events` or the `events` request.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::DirBuilderExt,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{daemon, defs, messages::Message, script_history};
+
+/// Size from which the log is moved aside on the next entry
+const MAX_SIZE: u64 = 512 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Code {
+    StageStarted,
+    StageFinished,
+    /// the stage completed but some step failed
+    StageDegraded,
+    StageFailed,
+    ModuleMounted,
+    ModuleMountFailed,
+    SafeModeEntered,
+    ModulesDisabled,
+    UpdateApplied,
+    /// the update failed validation, the installed version stays
+    UpdateRejected,
+    UpdatesRolledBack,
+    UidListenerRestarted,
+}
+
+impl Code {
+    fn severity(self) -> Severity {
+        match self {
+            Code::StageStarted | Code::StageFinished | Code::ModuleMounted | Code::UpdateApplied => {
+                Severity::Info
+            }
+            Code::StageDegraded
+            | Code::UpdateRejected
+            | Code::SafeModeEntered
+            | Code::ModulesDisabled
+            | Code::UpdatesRolledBack
+            | Code::UidListenerRestarted => Severity::Warning,
+            Code::StageFailed | Code::ModuleMountFailed => Severity::Error,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    /// seconds since the epoch
+    pub time: u64,
+    pub boot_id: String,
+    pub severity: Severity,
+    pub code: Code,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// code of `message` in the message catalog, empty in entries of older apd
+    #[serde(default)]
+    pub message_code: String,
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    pub message: String,
+}
+
+fn rotate_if_full() {
+    let full = fs::metadata(defs::EVENTS_FILE).is_ok_and(|meta| meta.len() >= MAX_SIZE);
+    if full && let Err(e) = fs::rename(defs::EVENTS_FILE, defs::EVENTS_OLD_FILE) {
+        warn!("Failed to rotate {}: {}", defs::EVENTS_FILE, e);
+    }
+}
+
+fn append(entry: &Entry) -> Result<()> {
+    if !Path::new(defs::APATCH_LOG_FOLDER).exists() {
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .recursive(true)
+            .create(defs::APATCH_LOG_FOLDER)
+            .context("Failed to create log folder")?;
+    }
+    rotate_if_full();
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    // one write per line, entries of concurrent stages do not interleave
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(defs::EVENTS_FILE)
+        .and_then(|mut file| file.write_all(&line))
+        .with_context(|| format!("Failed to write {}", defs::EVENTS_FILE))
+}
+
+fn entry(code: Code, subject: Option<&str>, message: &Message) -> Entry {
+    Entry {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        boot_id: script_history::boot_id(),
+        severity: code.severity(),
+        code,
+        subject: subject.map(String::from),
+        message_code: message.code().to_string(),
+        params: message
+            .params()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+        message: message.to_string(),
+    }
+}
+
+fn record(code: Code, subject: Option<&str>, message: &Message) {
+    let entry = entry(code, subject, message);
+    if let Err(e) = append(&entry) {
+        warn!("{e:#}");
+    }
+    daemon::publish([daemon::Event::Logged { entry }]);
+}
+
+/// Record an event of the boot
+pub fn emit(code: Code, message: &Message) {
+    record(code, None, message);
+}
+
+/// Record an event about a stage or module, `subject` names it
+pub fn emit_for(code: Code, subject: &str, message: &Message) {
+    record(code, Some(subject), message);
+}
+
+/// Entries oldest first, those before the first entry of boot `since`, a
+/// prefix of its id, are left out. An unknown boot id leaves out nothing.
+pub fn read(since: Option<&str>) -> Vec<Entry> {
+    let content: String = [defs::EVENTS_OLD_FILE, defs::EVENTS_FILE]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .collect();
+    let entries: Vec<Entry> = content
+        .lines()
+        // lines of a newer apd with codes this one does not know are skipped
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let start = since
+        .and_then(|boot_id| entries.iter().position(|entry| entry.boot_id.starts_with(boot_id)))
+        .unwrap_or(0);
+    entries.into_iter().skip(start).collect()
+}
+
+/// `apd events [--since <boot_id>]`
+pub fn show(since: Option<&str>) -> Result<()> {
+    let entries = read(since);
+    if crate::output::json() {
+        return crate::output::print("events", entries);
+    }
+    if entries.is_empty() {
+        println!("No events recorded");
+        return Ok(());
+    }
+
+    let mut last_boot = "";
+    for entry in &entries {
+        if entry.boot_id != last_boot {
+            last_boot = &entry.boot_id;
+            println!("boot {}", entry.boot_id.get(..8).unwrap_or(&entry.boot_id));
+        }
+        let severity = match entry.severity {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        let code = serde_json::to_value(entry.code)?;
+        let subject = entry
+            .subject
+            .as_deref()
+            .map(|subject| format!(" {subject}:"))
+            .unwrap_or_default();
+        println!(
+            "  {} {severity} {}{subject} {}",
+            entry.time,
+            code.as_str().unwrap_or_default(),
+            entry.message
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::CODES;
+
+    #[test]
+    fn entries_carry_the_catalog_code_and_parameters() {
+        let message = Message::UpdateRejected {
+            id: "test".to_string(),
+            reason: "new critical path".to_string(),
+        };
+        let entry = entry(Code::UpdateRejected, Some("test"), &message);
+        assert!(CODES.contains(&entry.message_code.as_str()));
+        assert_eq!(entry.params.len(), message.params().len());
+        assert_eq!(entry.params["reason"], "new critical path");
+        assert_eq!(entry.message, message.to_string());
+
+        let line = serde_json::to_string(&entry).unwrap();
+        let parsed: Entry = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed.message_code, "module.update.rejected");
+        assert_eq!(parsed.params, entry.params);
+    }
+
+    #[test]
+    fn entries_of_older_apd_still_parse() {
+        let line = concat!(
+            r#"{"time":1,"boot_id":"b","severity":"info","#,
+            r#""code":"stage_started","message":"x"}"#
+        );
+        let entry: Entry = serde_json::from_str(line).unwrap();
+        assert!(entry.message_code.is_empty());
+        assert!(entry.params.is_empty());
+    }
+}
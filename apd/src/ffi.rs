@@ -0,0 +1,155 @@
+//! C entry points for the manager NDK layer, declared in `include/apatch_core.h`
+//!
+//! Every function returning a string returns a NUL terminated JSON string owned
+//! by the caller, which must be released with `free_json_string`. NULL is
+//! returned on error.
+//!
+//! The header is generated from this file by cbindgen with `cbindgen.toml`, a
+//! test fails when it is out of date. `APD_BLESS_HEADER=1 cargo test header`
+//! writes it again.
+
+use std::{
+    ffi::{CStr, CString, c_char, c_int},
+    ptr,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::api;
+
+fn into_c_json<T: Serialize>(value: Result<T>) -> *mut c_char {
+    value
+        .and_then(|value| Ok(serde_json::to_string(&value)?))
+        .ok()
+        .and_then(|json| CString::new(json).ok())
+        .map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// # Safety
+/// `s` must be NULL or a NUL terminated string.
+unsafe fn from_c_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(s) }.to_str().ok()
+}
+
+/// Array of installed modules, module.prop keys plus state flags
+#[unsafe(no_mangle)]
+pub extern "C" fn list_modules_json() -> *mut c_char {
+    into_c_json(Ok(api::list_modules()))
+}
+
+/// Version, mount mode, module count and the last boot health record
+#[unsafe(no_mangle)]
+pub extern "C" fn get_status_json() -> *mut c_char {
+    into_c_json(Ok(api::get_status()))
+}
+
+/// Per module plan of the next mount, without touching anything
+#[unsafe(no_mangle)]
+pub extern "C" fn get_mount_report_json() -> *mut c_char {
+    into_c_json(api::get_mount_report())
+}
+
+/// Value of config `key` as a JSON string, JSON null if it is not set
+///
+/// # Safety
+/// `key` must be a NUL terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_config_json(key: *const c_char) -> *mut c_char {
+    let Some(key) = (unsafe { from_c_str(key) }) else {
+        return ptr::null_mut();
+    };
+    into_c_json(api::get_config(key))
+}
+
+/// Set config `key` to `value`, a NULL `value` removes the key. Returns 0 on
+/// success, -1 on error.
+///
+/// # Safety
+/// `key` must be a NUL terminated string, `value` NULL or one.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_config(key: *const c_char, value: *const c_char) -> c_int {
+    let Some(key) = (unsafe { from_c_str(key) }) else {
+        return -1;
+    };
+    let value = match unsafe { from_c_str(value) } {
+        None if !value.is_null() => return -1,
+        value => value,
+    };
+    match api::set_config(key, value) {
+        Ok(()) => 0,
+        Err(e) => {
+            log::warn!("Failed to set config {key}: {e:#}");
+            -1
+        }
+    }
+}
+
+/// Release a string returned by one of the `_json` functions
+///
+/// # Safety
+/// `json` must be NULL or a string returned by one of the `_json` functions,
+/// and must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free_json_string(json: *mut c_char) {
+    if !json.is_null() {
+        drop(unsafe { CString::from_raw(json) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    #[test]
+    fn header_matches_the_exports() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).unwrap();
+        let mut generated = Vec::new();
+        cbindgen::Builder::new()
+            .with_crate(dir)
+            .with_config(config)
+            .generate()
+            .expect("Failed to generate the header")
+            .write(&mut generated);
+
+        let path = dir.join("include/apatch_core.h");
+        if std::env::var_os("APD_BLESS_HEADER").is_some() {
+            fs::write(&path, &generated).unwrap();
+            return;
+        }
+        let header = fs::read(&path).unwrap();
+        assert!(
+            header == generated,
+            "include/apatch_core.h differs from src/ffi.rs, check the change keeps old \
+             signatures and run `APD_BLESS_HEADER=1 cargo test header`\n{}",
+            String::from_utf8_lossy(&generated)
+        );
+    }
+
+    #[test]
+    fn config_arguments_are_checked() {
+        unsafe {
+            assert!(get_config_json(ptr::null()).is_null());
+            assert_eq!(set_config(ptr::null(), ptr::null()), -1);
+            assert!(get_config_json(c"no_such_key".as_ptr()).is_null());
+            assert_eq!(set_config(c"no_such_key".as_ptr(), ptr::null()), -1);
+            let invalid = [0xffu8 as c_char, 0];
+            assert_eq!(set_config(c"mount_mode".as_ptr(), invalid.as_ptr()), -1);
+            free_json_string(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn json_is_returned_and_freed() {
+        let json = into_c_json(Ok(["a", "b"]));
+        assert_eq!(unsafe { CStr::from_ptr(json) }.to_str(), Ok(r#"["a","b"]"#));
+        unsafe { free_json_string(json) };
+        assert!(into_c_json::<()>(Err(anyhow::anyhow!("failed"))).is_null());
+    }
+}
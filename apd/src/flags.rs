@@ -0,0 +1,121 @@
+//! Flag files
+//!
+//! A flag is set when its path is a regular file. Module dirs come from zips
+//! anyone can build, so a flag which is a dir or a symlink, e.g. `skip_mount`
+//! linking to /data/adb, does not count and is never followed when created.
+
+use std::{
+    fs::{self, File, Permissions},
+    io::ErrorKind,
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt, fchown},
+    path::Path,
+};
+
+use anyhow::{Context, Result, ensure};
+
+/// Whether the flag at `path` is set, only a regular file sets it
+pub fn is_set(path: impl AsRef<Path>) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_file())
+}
+
+/// Set the flag at `path`, leaving it a root owned regular file of mode 0600.
+/// Fails if the path is a symlink or anything but a regular file.
+pub fn set(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ELOOP) => anyhow::anyhow!("{} is a symlink", path.display()),
+            _ if e.kind() == ErrorKind::IsADirectory => {
+                anyhow::anyhow!("{} is a directory", path.display())
+            }
+            _ => anyhow::Error::from(e).context(format!("Failed to create {}", path.display())),
+        })?;
+    // checked on the opened file, the path may have been swapped meanwhile
+    let metadata = file.metadata()?;
+    ensure!(
+        metadata.file_type().is_file(),
+        "{} is not a regular file",
+        path.display()
+    );
+    if metadata.uid() != 0 || metadata.gid() != 0 {
+        fchown(&file, Some(0), Some(0))
+            .with_context(|| format!("Failed to chown {}", path.display()))?;
+    }
+    if metadata.mode() & 0o7777 != 0o600 {
+        file.set_permissions(Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to chmod {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn only_regular_files_set_a_flag() {
+        let root = TempDir::new("flags-is-set");
+        let file = root.write("module/skip_mount", "");
+        fs::create_dir(root.path().join("module/disable")).unwrap();
+        symlink(&file, root.path().join("module/remove")).unwrap();
+        symlink("/nonexistent", root.path().join("module/update")).unwrap();
+
+        assert!(is_set(&file));
+        for name in ["disable", "remove", "update", "missing"] {
+            assert!(!is_set(root.path().join("module").join(name)), "{name}");
+        }
+    }
+
+    #[test]
+    fn symlinks_are_not_followed_when_setting() {
+        let root = TempDir::new("flags-symlink");
+        let target = root.write("adb/target", "keep");
+        let link = root.path().join("module/skip_mount");
+        fs::create_dir(root.path().join("module")).unwrap();
+        symlink(&target, &link).unwrap();
+        let error = set(&link).unwrap_err();
+        assert!(error.to_string().contains("is a symlink"), "{error:#}");
+        assert_eq!(fs::read_to_string(&target).unwrap(), "keep");
+
+        let dangling = root.path().join("module/update");
+        let created = root.path().join("adb/created");
+        symlink(&created, &dangling).unwrap();
+        assert!(set(&dangling).is_err());
+        assert!(!created.exists());
+    }
+
+    #[test]
+    fn a_directory_is_not_a_flag() {
+        let root = TempDir::new("flags-dir");
+        let dir = root.path().join("disable");
+        fs::create_dir(&dir).unwrap();
+        let error = set(&dir).unwrap_err();
+        assert!(error.to_string().contains("is a directory"), "{error:#}");
+        assert!(!is_set(&dir));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "privileged-tests"), ignore = "chowns the flag to root")]
+    fn set_leaves_a_private_root_owned_file() {
+        let root = TempDir::new("flags-set");
+        let flag = root.write("update", "");
+        fs::set_permissions(&flag, Permissions::from_mode(0o666)).unwrap();
+        set(&flag).unwrap();
+        let fresh = root.path().join("disable");
+        set(&fresh).unwrap();
+        for path in [flag, fresh] {
+            let metadata = fs::symlink_metadata(&path).unwrap();
+            assert!(is_set(&path));
+            assert_eq!(metadata.mode() & 0o7777, 0o600);
+            assert_eq!((metadata.uid(), metadata.gid()), (0, 0));
+        }
+    }
+}
@@ -4,8 +4,10 @@ use anyhow::{Context, Result};
 use log::info;
 use prop_rs_android::resetprop::ResetProp as InnerResetProp;
 use prop_rs_android::sys_prop;
+use serde::Serialize;
 use crate::defs;
 use std::fs;
+use std::path::PathBuf;
 
 /// Hide sensitive props like Factory Props
 pub fn hide_sensitive_props() -> Result<()> {
@@ -80,3 +82,233 @@ pub fn hide_sensitive_props() -> Result<()> {
     info!("Hiding sensitive props");
     Ok(())
 }
+
+/// A mount of a process which shows APatch module files
+#[derive(Serialize, Debug)]
+struct Leak {
+    pid: i32,
+    mount_point: PathBuf,
+    fs_type: String,
+    source: Option<String>,
+    root: String,
+    reason: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+struct VerifyReport {
+    package: String,
+    pids: Vec<i32>,
+    clean: bool,
+    leaks: Vec<Leak>,
+}
+
+/// Why `info` shows module files, `None` for mounts of the stock system
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn leak_reason(
+    info: &procfs::process::MountInfo,
+    registry: &[PathBuf],
+    module_files: Option<(&str, &std::path::Path)>,
+) -> Option<&'static str> {
+    let module_dir = std::path::Path::new(defs::MODULE_DIR);
+    if registry.iter().any(|point| info.mount_point.starts_with(point)) {
+        return Some("recorded module mount");
+    }
+    if info.fs_type == "tmpfs"
+        && matches!(
+            info.mount_source.as_deref(),
+            Some("APatch" | crate::mount::MIRROR_SOURCE)
+        )
+    {
+        return Some("APatch tmpfs");
+    }
+    if info.fs_type == "overlay"
+        && info
+            .super_options
+            .get("lowerdir")
+            .cloned()
+            .flatten()
+            .is_some_and(|dirs| {
+                dirs.split(':')
+                    .any(|dir| std::path::Path::new(dir).starts_with(module_dir))
+            })
+    {
+        return Some("overlay of module files");
+    }
+    if let Some((majmin, module_root)) = module_files
+        && info.majmin == majmin
+        && std::path::Path::new(&info.root).starts_with(module_root)
+        && !info.mount_point.starts_with(module_dir)
+    {
+        return Some("bind mount of module files");
+    }
+    None
+}
+
+/// Mounts of `pid` which show module files
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn leaks_of(pid: i32, registry: &[PathBuf]) -> Result<Vec<Leak>> {
+    let mounts: Vec<_> = procfs::process::Process::new(pid)?
+        .mountinfo()?
+        .into_iter()
+        .collect();
+    Ok(leaks_in(pid, &mounts, registry))
+}
+
+/// Entries of `mounts`, the mountinfo of `pid`, which show module files
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn leaks_in(
+    pid: i32,
+    mounts: &[procfs::process::MountInfo],
+    registry: &[PathBuf],
+) -> Vec<Leak> {
+    // module files are recognized by their root in the filesystem holding /data/adb/modules
+    let module_dir = std::path::Path::new(defs::MODULE_DIR);
+    let holder = mounts
+        .iter()
+        .filter(|info| module_dir.starts_with(&info.mount_point))
+        .max_by_key(|info| info.mount_point.as_os_str().len());
+    let module_root = holder.and_then(|holder| {
+        module_dir
+            .strip_prefix(&holder.mount_point)
+            .ok()
+            .map(|rest| (holder.majmin.clone(), std::path::Path::new(&holder.root).join(rest)))
+    });
+    let module_files = module_root
+        .as_ref()
+        .map(|(majmin, root)| (majmin.as_str(), root.as_path()));
+    mounts
+        .iter()
+        .filter_map(|info| {
+            let reason = leak_reason(info, registry, module_files)?;
+            Some(Leak {
+                pid,
+                mount_point: info.mount_point.clone(),
+                fs_type: info.fs_type.clone(),
+                source: info.mount_source.clone(),
+                root: info.root.clone(),
+                reason,
+            })
+        })
+        .collect()
+}
+
+/// Running processes of `package`, matched by name and by the uid of the package
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn package_pids(package: &str) -> Result<Vec<i32>> {
+    let app_id = crate::package::package_uid(package)
+        .with_context(|| format!("{package} is not installed"))?
+        % 100000;
+    let mut pids = Vec::new();
+    for process in procfs::process::all_processes()?.flatten() {
+        let Some(name) = crate::umount::process_name(process.pid) else {
+            continue;
+        };
+        let name_matches = name == package || name.starts_with(&format!("{package}:"));
+        if name_matches && process.uid().is_ok_and(|uid| uid as i32 % 100000 == app_id) {
+            pids.push(process.pid);
+        }
+    }
+    Ok(pids)
+}
+
+/// `apd hide verify <package>`: check that running processes of a package see
+/// none of the module mounts, exits non-zero if one does
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn verify(package: &str) -> Result<()> {
+    let pids = package_pids(package)?;
+    anyhow::ensure!(!pids.is_empty(), "no running process of {package}, start it first");
+    let registry = crate::umount::MountRegistry::load().mounts();
+    let mut leaks = Vec::new();
+    for pid in &pids {
+        match leaks_of(*pid, &registry) {
+            Ok(found) => leaks.extend(found),
+            Err(e) => log::warn!("Failed to read the mounts of {pid}: {e:#}"),
+        }
+    }
+    let report = VerifyReport {
+        package: package.to_string(),
+        pids,
+        clean: leaks.is_empty(),
+        leaks,
+    };
+    if crate::output::json() {
+        crate::output::print("hide verify", &report)?;
+    } else {
+        for leak in &report.leaks {
+            println!(
+                "{} {} {} {} {}: {}",
+                leak.pid,
+                leak.mount_point.display(),
+                leak.fs_type,
+                leak.source.as_deref().unwrap_or("none"),
+                leak.root,
+                leak.reason
+            );
+        }
+        println!("{}", if report.clean { "PASS" } else { "FAIL" });
+    }
+    anyhow::ensure!(report.clean, "{package} sees {} module mount(s)", report.leaks.len());
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn verify(_package: &str) -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use procfs::process::MountInfo;
+
+    use super::*;
+
+    /// mountinfo of an app process on a device with modules mounted
+    const APP_MOUNTINFO: &str = "\
+1 0 253:2 / / ro,relatime shared:1 - ext4 /dev/block/dm-2 ro,seclabel
+25 1 253:3 / /vendor ro,relatime shared:2 - erofs /dev/block/dm-3 ro
+30 1 0:20 / /dev rw,nosuid,relatime shared:11 - tmpfs tmpfs rw,seclabel,mode=755
+40 1 254:40 / /data rw,nosuid,nodev,noatime shared:12 - f2fs /dev/block/dm-40 rw
+41 40 254:40 /data /data/user/0 rw,nosuid,nodev,noatime shared:12 - f2fs /dev/block/dm-40 rw
+50 1 254:40 /adb/modules/foo/system/bin/tool /system/bin/tool ro - f2fs /dev/block/dm-40 rw
+51 1 0:30 / /system/etc ro,relatime - tmpfs APatch ro
+52 1 0:31 / /system/fonts ro,relatime - tmpfs APatch-mirror ro
+53 1 0:32 / /odm ro,relatime - overlay overlay ro,lowerdir=/data/adb/modules/foo/odm:/odm
+54 1 0:33 / /product ro,relatime - overlay overlay ro,lowerdir=/product:/mnt/other
+55 1 253:4 / /product/app/Foo ro,relatime - ext4 /dev/block/dm-4 ro
+";
+
+    fn mounts() -> Vec<MountInfo> {
+        APP_MOUNTINFO
+            .lines()
+            .map(|line| MountInfo::from_line(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn module_mounts_of_an_app_are_found() {
+        let registry = [PathBuf::from("/product/app/Foo")];
+        let leaks: Vec<_> = leaks_in(42, &mounts(), &registry)
+            .into_iter()
+            .map(|leak| (leak.mount_point.display().to_string(), leak.reason))
+            .collect();
+        assert_eq!(
+            leaks,
+            [
+                ("/system/bin/tool".to_string(), "bind mount of module files"),
+                ("/system/etc".to_string(), "APatch tmpfs"),
+                ("/system/fonts".to_string(), "APatch tmpfs"),
+                ("/odm".to_string(), "overlay of module files"),
+                ("/product/app/Foo".to_string(), "recorded module mount"),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_stock_namespace_is_clean() {
+        let stock: Vec<_> = mounts()
+            .into_iter()
+            .filter(|info| info.mnt_id <= 41 || info.mnt_id == 54)
+            .collect();
+        assert!(leaks_in(42, &stock, &[]).is_empty());
+    }
+}
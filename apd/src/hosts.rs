@@ -0,0 +1,97 @@
+//! Systemless hosts
+//!
+//! Once enabled, `/data/adb/ap/hosts` is bind mounted over `/system/etc/hosts`
+//! in post-fs-data. The file starts as a copy of the system hosts and is meant
+//! to be edited by ad blockers. The mount happens after the module mounts, so
+//! it wins over a module shipping its own `system/etc/hosts`.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use log::info;
+
+use crate::{
+    defs,
+    restorecon::{SYSTEM_CON, lsetfilecon},
+};
+
+const SYSTEM_HOSTS: &str = "/system/etc/hosts";
+
+pub fn is_enabled() -> bool {
+    crate::flags::is_set(defs::HOSTS_ENABLE_FILE)
+}
+
+pub fn enable() -> Result<()> {
+    if !Path::new(defs::HOSTS_FILE).exists() {
+        fs::copy(SYSTEM_HOSTS, defs::HOSTS_FILE)
+            .with_context(|| format!("Failed to copy {SYSTEM_HOSTS}"))?;
+    }
+    fs::write(defs::HOSTS_ENABLE_FILE, "")
+        .with_context(|| format!("Failed to write {}", defs::HOSTS_ENABLE_FILE))?;
+    println!("Systemless hosts enabled, edit {} and reboot", defs::HOSTS_FILE);
+    Ok(())
+}
+
+/// Stop mounting the hosts file, the file itself is kept
+pub fn disable() -> Result<()> {
+    if is_enabled() {
+        fs::remove_file(defs::HOSTS_ENABLE_FILE)
+            .with_context(|| format!("Failed to remove {}", defs::HOSTS_ENABLE_FILE))?;
+    }
+    println!("Systemless hosts disabled, reboot to unmount it");
+    Ok(())
+}
+
+/// Mount the hosts file if enabled, called once the modules are mounted
+pub fn mount() -> Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    if !Path::new(defs::HOSTS_FILE).exists() {
+        fs::copy(SYSTEM_HOSTS, defs::HOSTS_FILE)
+            .with_context(|| format!("Failed to copy {SYSTEM_HOSTS}"))?;
+    }
+    lsetfilecon(defs::HOSTS_FILE, SYSTEM_CON)?;
+    crate::mount::bind_mount(defs::HOSTS_FILE, SYSTEM_HOSTS)
+        .with_context(|| format!("Failed to mount {SYSTEM_HOSTS}"))?;
+    info!("systemless hosts mounted");
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn is_mounted() -> Result<bool> {
+    let mountinfo = procfs::process::Process::new(1)?.mountinfo()?;
+    Ok(mountinfo
+        .into_iter()
+        .any(|info| info.mount_point == Path::new(SYSTEM_HOSTS)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn is_mounted() -> Result<bool> {
+    unimplemented!()
+}
+
+/// Detach the hosts mount if there is one
+pub fn unmount() -> Result<()> {
+    if is_mounted()? {
+        crate::mount::detach(SYSTEM_HOSTS)?;
+    }
+    Ok(())
+}
+
+pub fn status() -> Result<()> {
+    if crate::output::json() {
+        return crate::output::print(
+            "hosts status",
+            serde_json::json!({
+                "enabled": is_enabled(),
+                "file": defs::HOSTS_FILE,
+                "mounted": is_mounted()?,
+            }),
+        );
+    }
+    println!("enabled: {}", is_enabled());
+    println!("file: {}", defs::HOSTS_FILE);
+    println!("mounted: {}", is_mounted()?);
+    Ok(())
+}
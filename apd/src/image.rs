@@ -0,0 +1,230 @@
+//! Modules packed into a read-only image
+//!
+//! A module may carry its partition dirs as `module.img`, an erofs or
+//! squashfs image with `system/`, `vendor/`, ... at its root. For magic mount
+//! the image is attached to a read-only loop device and mounted below a
+//! staging dir in the scratch dir, the module files are then bound from there. The
+//! staging mounts are detached once mounted, the bind mounts keep the image
+//! busy and its loop device is cleared with the last of them.
+//!
+//! module.prop, the flag files and the scripts stay loose in the module dir.
+//! An image which fails to mount leaves the module to its loose files, if any.
+//!
+//! `apd module compress <id>` packs the partition dirs of an installed module
+//! with `mkfs.erofs` from `/data/adb/ap/bin` or `PATH`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{info, warn};
+
+use crate::{critical, defs, module, restorecon};
+
+const FS_TYPES: [&str; 2] = ["erofs", "squashfs"];
+
+pub fn image_of(module_path: &Path) -> Option<PathBuf> {
+    let image = module_path.join(defs::MODULE_IMAGE_FILE_NAME);
+    image.is_file().then_some(image)
+}
+
+/// Attach `image` read-only and mount it on `target`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn mount_image(image: &Path, target: &Path) -> Result<()> {
+    use rustix::mount::{MountFlags, mount};
+
+    let device = loopdev::LoopControl::open()
+        .context("open loop-control")?
+        .next_free()
+        .context("find a free loop device")?;
+    device
+        .with()
+        .read_only(true)
+        .autoclear(true)
+        .attach(image)
+        .with_context(|| format!("attach {}", image.display()))?;
+    let path = device.path().context("loop device has no path")?;
+    let mut errors = Vec::new();
+    for fs_type in FS_TYPES {
+        match mount(&path, target, fs_type, MountFlags::RDONLY, rustix::cstr!("")) {
+            Ok(()) => return Ok(()),
+            Err(e) => errors.push(format!("{fs_type}: {e}")),
+        }
+    }
+    // autoclear only applies once mounted
+    let _ = device.detach();
+    bail!("mount {}: {}", image.display(), errors.join(", "))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn mount_image(_image: &Path, _target: &Path) -> Result<()> {
+    unimplemented!()
+}
+
+/// The staging dir of the module images of one mount run
+pub struct StagedImages {
+    dir: Option<PathBuf>,
+}
+
+impl StagedImages {
+    pub fn new() -> Self {
+        StagedImages { dir: None }
+    }
+
+    fn dir(&mut self) -> Result<&Path> {
+        if self.dir.is_none() {
+            let name = fs::read_to_string("/proc/sys/kernel/random/uuid")
+                .context("read random uuid")?
+                .trim()
+                .replace('-', "");
+            let dir = crate::paths::scratch_dir()
+                .join(format!(".img{}", &name[..8.min(name.len())]));
+            fs::create_dir(&dir).with_context(|| format!("create {}", dir.display()))?;
+            self.dir = Some(dir);
+        }
+        Ok(self.dir.as_deref().unwrap())
+    }
+
+    /// Mount the image of `module_path`, returns where its files are or
+    /// `None` if it has no image or the image does not mount
+    pub fn stage(&mut self, module_path: &Path) -> Option<PathBuf> {
+        let image = image_of(module_path)?;
+        let id = module_path.file_name()?;
+        let target = match self.dir() {
+            Ok(dir) => dir.join(id),
+            Err(e) => {
+                warn!("{e:#}, mounting loose files of {}", module_path.display());
+                return None;
+            }
+        };
+        let result = fs::create_dir(&target)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| mount_image(&image, &target));
+        match result {
+            Ok(()) => {
+                info!("mounted {} on {}", image.display(), target.display());
+                Some(target)
+            }
+            Err(e) => {
+                warn!("{e:#}, mounting loose files of {}", module_path.display());
+                fs::remove_dir(&target).ok();
+                None
+            }
+        }
+    }
+
+    /// Detach the staging mounts, what was bound from them stays
+    pub fn release(self) {
+        let Some(dir) = self.dir else {
+            return;
+        };
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if let Err(e) = crate::mount::detach(&path) {
+                warn!("{e:#}");
+            }
+            fs::remove_dir(&path).ok();
+        }
+        fs::remove_dir(&dir).ok();
+    }
+}
+
+fn mkfs_erofs() -> Option<PathBuf> {
+    let bundled = Path::new(defs::BINARY_DIR).join("mkfs.erofs");
+    if bundled.exists() {
+        return Some(bundled);
+    }
+    std::env::var_os("PATH")?
+        .to_str()?
+        .split(':')
+        .map(|dir| Path::new(dir).join("mkfs.erofs"))
+        .find(|path| path.exists())
+}
+
+/// The partition dirs of a module, those an image takes over
+fn partition_dirs(module_path: &Path) -> Vec<&'static str> {
+    std::iter::once("system")
+        .chain(critical::PARTITIONS)
+        .filter(|partition| module_path.join(partition).is_dir())
+        .collect()
+}
+
+/// Check that `image` mounts and holds `partitions`
+fn verify(image: &Path, partitions: &[&str]) -> Result<()> {
+    let mut staged = StagedImages::new();
+    let target = staged.dir()?.join("verify");
+    fs::create_dir(&target)?;
+    let result = mount_image(image, &target).map(|()| {
+        partitions
+            .iter()
+            .filter(|partition| !target.join(partition).is_dir())
+            .map(|partition| partition.to_string())
+            .collect::<Vec<_>>()
+    });
+    if result.is_err() {
+        fs::remove_dir(&target).ok();
+    }
+    staged.release();
+    let missing = result?;
+    ensure!(missing.is_empty(), "image lacks {}", missing.join(", "));
+    Ok(())
+}
+
+/// `apd module compress <id>`: pack the partition dirs of module `id` into
+/// `module.img` and delete them once the image is known to mount
+pub fn compress(id: &str) -> Result<()> {
+    let module_path = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(module_path.exists(), "module: {} not found!", id);
+    ensure!(image_of(&module_path).is_none(), "module {id} already has an image");
+    let partitions = partition_dirs(&module_path);
+    ensure!(!partitions.is_empty(), "module {id} has no files to pack");
+    let mkfs = mkfs_erofs().context("mkfs.erofs not found in /data/adb/ap/bin or PATH")?;
+    let _guard = module::lock_modules()?;
+
+    // the image keeps the labels the files have now
+    let summary = restorecon::restore_syscon(&module_path);
+    summary.check()?;
+
+    // mkfs packs a whole dir, the partition dirs are moved aside into one
+    let source = module_path.join(".image_src");
+    fs::create_dir(&source).with_context(|| format!("create {}", source.display()))?;
+    let restore = || {
+        for partition in &partitions {
+            let _ = fs::rename(source.join(partition), module_path.join(partition));
+        }
+        let _ = fs::remove_dir(&source);
+    };
+    for partition in &partitions {
+        if let Err(e) = fs::rename(module_path.join(partition), source.join(partition)) {
+            restore();
+            return Err(e).context(format!("move {partition} aside"));
+        }
+    }
+
+    let image = module_path.join(defs::MODULE_IMAGE_FILE_NAME);
+    let tmp = module_path.join(format!("{}.tmp", defs::MODULE_IMAGE_FILE_NAME));
+    println!("- Packing {} of {id}", partitions.join(", "));
+    let result = Command::new(&mkfs)
+        .arg("-zlz4hc")
+        .arg(&tmp)
+        .arg(&source)
+        .status()
+        .with_context(|| format!("run {}", mkfs.display()))
+        .and_then(|status| {
+            ensure!(status.success(), "{} exited with {status}", mkfs.display());
+            verify(&tmp, &partitions)
+        });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        restore();
+        return Err(e);
+    }
+    fs::rename(&tmp, &image)?;
+    fs::remove_dir_all(&source)
+        .with_context(|| format!("remove {}", source.display()))?;
+    println!("- {id} now mounts from {}", image.display());
+    Ok(())
+}
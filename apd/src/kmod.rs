@@ -0,0 +1,250 @@
+//! Kernel modules shipped by APatch modules
+//!
+//! A module may carry `kernel_modules/*.ko`. post-fs-data loads them with
+//! `finit_module`, those named in `kernel_modules/load_order.txt` first and in
+//! that order, the others by file name. They are loaded before the modules are
+//! mounted, or right after if the flag `kernel_modules/after_mount` is set.
+//!
+//! A ko which is loaded already counts as loaded, any other error is written
+//! to the module log and kept for `apd module kmod status <id>`. A failing ko
+//! does not keep the next ones from being tried.
+//!
+//! `kernel_modules = false` in apd.toml turns the loading off.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, ensure};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{defs, flags, module, script_history};
+
+const KMOD_DIR: &str = "kernel_modules";
+const LOAD_ORDER_FILE: &str = "load_order.txt";
+const AFTER_MOUNT_FLAG: &str = "after_mount";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KmodResult {
+    pub file: String,
+    /// the ko was loaded before, e.g. by an earlier post-fs-data run
+    pub already_loaded: bool,
+    pub error: Option<String>,
+}
+
+/// Load results of this boot by module id, in `.kmod_results`
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct Record {
+    boot_id: String,
+    modules: BTreeMap<String, Vec<KmodResult>>,
+}
+
+impl Record {
+    fn current() -> Self {
+        fs::read_to_string(defs::KMOD_RESULTS_FILE)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Record>(&content).ok())
+            .filter(|record| record.boot_id == script_history::boot_id())
+            .unwrap_or_else(|| Record {
+                boot_id: script_history::boot_id(),
+                ..Default::default()
+            })
+    }
+
+    fn store(&self) -> Result<()> {
+        fs::write(defs::KMOD_RESULTS_FILE, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write {}", defs::KMOD_RESULTS_FILE))
+    }
+}
+
+/// The ko files of the module at `module_path` in load order
+fn ko_files(module_path: &Path) -> Vec<PathBuf> {
+    let dir = module_path.join(KMOD_DIR);
+    let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ko"))
+        // a module zip may hold anything, only regular files are loaded
+        .filter(|path| flags::is_set(path))
+        .collect();
+    files.sort();
+
+    let order = fs::read_to_string(dir.join(LOAD_ORDER_FILE)).unwrap_or_default();
+    let mut ordered = Vec::new();
+    for line in order.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let name = if line.ends_with(".ko") {
+            line.to_string()
+        } else {
+            format!("{line}.ko")
+        };
+        match files.iter().position(|file| file.file_name() == Some(name.as_ref())) {
+            Some(index) => ordered.push(files.remove(index)),
+            None => warn!("{}: {name} not found", dir.join(LOAD_ORDER_FILE).display()),
+        }
+    }
+    ordered.extend(files);
+    ordered
+}
+
+/// Name the kernel knows the ko at `path` by, as listed in /proc/modules
+fn module_name(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .replace('-', "_")
+}
+
+fn loaded_modules() -> BTreeSet<String> {
+    fs::read_to_string("/proc/modules")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .collect()
+}
+
+fn finit_module(path: &Path) -> io::Result<()> {
+    let file = File::open(path)?;
+    let ret = unsafe { libc::syscall(libc::SYS_finit_module, file.as_raw_fd(), c"".as_ptr(), 0) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// What a failed `finit_module` most likely means
+fn explain(error: &io::Error) -> String {
+    let hint = match error.raw_os_error() {
+        Some(libc::ENOEXEC) => "not a module for this kernel",
+        Some(libc::ENOKEY) => "not signed with a key the kernel trusts",
+        Some(libc::EKEYREJECTED | libc::EBADMSG) => "signature rejected",
+        Some(libc::ENOENT) => "unknown symbol, a module it depends on may be missing",
+        Some(libc::EINVAL) => "version magic or parameters do not match the kernel",
+        Some(libc::EPERM) => "module loading is not permitted",
+        Some(libc::EBUSY) => "the kernel is still loading it",
+        _ => return error.to_string(),
+    };
+    format!("{hint} ({error})")
+}
+
+fn load_module(module_path: &Path, id: &str) -> Vec<KmodResult> {
+    let mut results = Vec::new();
+    for file in ko_files(module_path) {
+        let name = file.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let result = match finit_module(&file) {
+            Ok(()) => {
+                module::append_log(id, "info", &format!("loaded kernel module {name}"));
+                KmodResult {
+                    file: name,
+                    already_loaded: false,
+                    error: None,
+                }
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {
+                module::append_log(id, "info", &format!("kernel module {name} already loaded"));
+                KmodResult {
+                    file: name,
+                    already_loaded: true,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                let error = explain(&e);
+                warn!("load {} of {id}: {error}", file.display());
+                module::append_log(id, "error", &format!("load kernel module {name}: {error}"));
+                KmodResult {
+                    file: name,
+                    already_loaded: false,
+                    error: Some(error),
+                }
+            }
+        };
+        results.push(result);
+    }
+    results
+}
+
+/// Load the kos of the active modules which load them `after_mount` or before,
+/// returns how many kos failed
+pub fn load(after_mount: bool) -> Result<usize> {
+    let mut record = Record::current();
+    let mut failed = 0;
+    module::foreach_module(module::ModuleType::Active, |module_path| {
+        if !module_path.join(KMOD_DIR).is_dir()
+            || flags::is_set(module_path.join(KMOD_DIR).join(AFTER_MOUNT_FLAG)) != after_mount
+        {
+            return Ok(());
+        }
+        let id = module_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let results = load_module(module_path, &id);
+        failed += results.iter().filter(|result| result.error.is_some()).count();
+        info!("{id}: {} kernel module(s) tried", results.len());
+        record.modules.insert(id, results);
+        Ok(())
+    })?;
+    record.store()?;
+    Ok(failed)
+}
+
+#[derive(Serialize)]
+struct KmodStatus {
+    file: String,
+    name: String,
+    loaded: bool,
+    /// what loading it did this boot, `None` if it was not tried
+    result: Option<KmodResult>,
+}
+
+/// `apd module kmod status <id>`
+pub fn status(id: &str) -> Result<()> {
+    let module_path = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(module_path.exists(), "module: {} not found!", id);
+    let loaded = loaded_modules();
+    let record = Record::current();
+    let results = record.modules.get(id);
+    let status: Vec<KmodStatus> = ko_files(&module_path)
+        .iter()
+        .map(|file| {
+            let name = module_name(file);
+            let file = file.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            KmodStatus {
+                result: results
+                    .and_then(|results| results.iter().find(|result| result.file == file))
+                    .cloned(),
+                loaded: loaded.contains(&name),
+                file,
+                name,
+            }
+        })
+        .collect();
+    if crate::output::json() {
+        return crate::output::print("module kmod status", status);
+    }
+    if status.is_empty() {
+        println!("{id} has no kernel modules");
+        return Ok(());
+    }
+    for ko in &status {
+        let state = if ko.loaded { "loaded" } else { "not loaded" };
+        match ko.result.as_ref().and_then(|result| result.error.as_deref()) {
+            Some(error) => println!("{}: {state}, failed this boot: {error}", ko.file),
+            None => println!("{}: {state}", ko.file),
+        }
+    }
+    Ok(())
+}
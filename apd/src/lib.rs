@@ -0,0 +1,74 @@
+//! apatch_core, the library behind apd
+//!
+//! The `apd` binary is a thin CLI over this crate. Only the [`api`], which reads
+//! state and changes nothing but apd.toml, and its C wrappers in [`ffi`] are
+//! public, everything else stays internal.
+
+mod apd;
+pub mod api;
+mod assets;
+mod backup;
+mod bootlog;
+mod bootloop;
+mod bugreport;
+mod cli;
+mod compat;
+mod config;
+mod critical;
+mod daemon;
+mod data_ready;
+mod data_watch;
+mod defs;
+mod dispatch;
+mod event;
+mod events;
+mod flags;
+mod hosts;
+mod image;
+mod kmod;
+pub mod ffi;
+mod magic_mount;
+mod lua;
+mod messages;
+mod metamodule;
+mod module;
+mod mount;
+mod output;
+mod package;
+mod paths;
+mod phase;
+mod profile;
+mod prop_override;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod pty;
+mod reset;
+mod restorecon;
+mod sandbox;
+#[cfg(all(test, feature = "scaling-tests"))]
+mod scaling;
+mod scan_cache;
+mod script_history;
+mod script_order;
+mod selfupdate;
+mod selinux;
+mod sepolicy;
+mod shutdown;
+mod status;
+mod mpolicy;
+mod namespace;
+mod supercall;
+mod superkey;
+mod targeted;
+#[cfg(test)]
+mod testutil;
+mod timing;
+mod umount;
+mod uninstall;
+mod update_check;
+mod utils;
+mod webui;
+mod resetprop;
+mod hide;
+
+#[doc(hidden)]
+pub use cli::run;
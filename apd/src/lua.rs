@@ -1,9 +1,22 @@
 use crate::module::*;
 use crate::utils::*;
-use anyhow::Result;
-use log::{info, warn};
-use mlua::{Function, Lua, Result as LuaResult, Table};
-use std::{fs, path::Path};
+use crate::{critical, defs, module, mount, profile, resetprop};
+use anyhow::{Result, bail, ensure};
+use log::{debug, error, info, warn};
+use mlua::{AppDataRef, Function, Lua, Result as LuaResult, Table};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// What the `apatch` table tells a script about the call it is in
+struct ScriptContext {
+    id: String,
+    stage: String,
+    superkey_available: bool,
+    /// takes the log lines of the module, [`append_log`] outside of tests
+    log: fn(&str, &str, &str),
+}
 
 pub fn save_text<P: AsRef<Path>>(filename: P, content: &str) -> std::io::Result<()> {
     let _ = ensure_dir_exists("/data/adb/config");
@@ -37,6 +50,10 @@ pub fn load_all_lua_modules(lua: &Lua) -> LuaResult<()> {
             if let Ok(entry) = entry {
                 let path = entry.path();
                 if path.is_dir() {
+                    if let Err(e) = crate::module::ModuleInfo::parse(&path) {
+                        eprintln!("Skip quarantined {}: {:#}", path.display(), e);
+                        continue;
+                    }
                     let id = path.file_name().unwrap().to_string_lossy().to_string();
                     let package: Table = lua.globals().get("package")?;
                     let old_cpath: String = package.get("cpath")?;
@@ -94,7 +111,7 @@ pub fn warn_lua(lua: &Lua) -> LuaResult<Function> {
 
 pub fn install_module_lua(lua: &Lua) -> LuaResult<Function> {
     lua.create_function(|_, zip: String| {
-        install_module(&zip)
+        install_module(&zip, false)
             .map_err(|e| mlua::Error::external(format!("install_module failed: {}", e)))
     })
 }
@@ -116,13 +133,134 @@ pub fn read_text_lua(lua: &Lua) -> LuaResult<Function> {
     })
 }
 
-pub fn exec_stage_lua(stage: &str, wait: bool, superkey: &str) -> Result<()> {
-    let stage_safe = stage.replace('-', "_");
-    run_lua(&superkey, &stage_safe, true, wait).map_err(|e| anyhow::anyhow!("{}", e))?;
+fn context(lua: &Lua) -> LuaResult<AppDataRef<'_, ScriptContext>> {
+    lua.app_data_ref::<ScriptContext>()
+        .ok_or_else(|| mlua::Error::runtime("no module function is running"))
+}
+
+/// Bind `source` of module `id` on `target`, only files of the module dir may
+/// be mounted and only onto the partitions modules may change
+fn bind_module_file(id: &str, source: &str, target: &str) -> Result<()> {
+    let module_dir = Path::new(defs::MODULE_DIR).join(id).canonicalize()?;
+    // a relative source is taken from the module dir
+    let source = module_dir.join(source).canonicalize()?;
+    ensure!(
+        source.starts_with(&module_dir),
+        "{} is outside of the module dir",
+        source.display()
+    );
+    let target = Path::new(target).canonicalize()?;
+    check_bind_target(&module_dir, &target)?;
+    ensure!(
+        source.is_dir() == target.is_dir(),
+        "{} and {} are not both dirs or both files",
+        source.display(),
+        target.display()
+    );
+    if source.is_dir() {
+        mount::bind_mount(&source, &target)
+    } else {
+        mount::bind_mount_file(&source, &target)
+    }
+}
+
+/// Hold a bind of `target` to the rules the mount of `module_dir` follows: the
+/// partitions it may change and, unless acknowledged, no critical paths
+fn check_bind_target(module_dir: &Path, target: &Path) -> Result<()> {
+    let relative = target.strip_prefix("/")?;
+    let partition = relative
+        .components()
+        .next()
+        .and_then(|c| c.as_os_str().to_str())
+        .filter(|p| *p == "system" || critical::PARTITIONS.contains(p));
+    let Some(partition) = partition else {
+        bail!("{} is not on a partition modules may change", target.display());
+    };
+    ensure!(
+        module::allowed_partitions(module_dir)
+            .is_none_or(|allowed| allowed.iter().any(|p| p == partition)),
+        "{} is on /{partition}, which the module may not change",
+        target.display()
+    );
+    ensure!(
+        critical::is_acknowledged(module_dir)
+            || !critical::Patterns::load().blocks_entry(relative, target.is_dir()),
+        "{} is a critical boot component, acknowledge with `apd module ack-critical`",
+        target.display()
+    );
     Ok(())
 }
 
-pub fn run_lua(id: &str, function: &str, on_each_module: bool, _wait: bool) -> mlua::Result<()> {
+/// The `apatch` table given to module scripts
+fn apatch_table(lua: &Lua) -> LuaResult<Table> {
+    let apatch = lua.create_table()?;
+    apatch.set(
+        "log",
+        lua.create_function(|lua, (level, msg): (String, String)| {
+            let (id, log) = {
+                let context = context(lua)?;
+                (context.id.clone(), context.log)
+            };
+            match level.as_str() {
+                "error" => error!("[Lua {id}] {msg}"),
+                "warn" => warn!("[Lua {id}] {msg}"),
+                "info" => info!("[Lua {id}] {msg}"),
+                "debug" => debug!("[Lua {id}] {msg}"),
+                _ => {
+                    return Err(mlua::Error::runtime(format!(
+                        "unknown log level {level}, use error, warn, info or debug"
+                    )));
+                }
+            }
+            log(&id, &level, &msg);
+            Ok(())
+        })?,
+    )?;
+    apatch.set(
+        "getprop",
+        lua.create_function(|_, name: String| Ok(getprop(&name)))?,
+    )?;
+    apatch.set(
+        "setprop",
+        lua.create_function(|_, (name, value): (String, String)| {
+            resetprop::set_prop(&name, &value).map_err(|e| mlua::Error::external(format!("{e:#}")))
+        })?,
+    )?;
+    apatch.set(
+        "module_path",
+        lua.create_function(|lua, ()| {
+            let id = context(lua)?.id.clone();
+            Ok(Path::new(defs::MODULE_DIR).join(id).to_string_lossy().into_owned())
+        })?,
+    )?;
+    apatch.set(
+        "stage",
+        lua.create_function(|lua, ()| Ok(context(lua)?.stage.clone()))?,
+    )?;
+    apatch.set(
+        "superkey_available",
+        lua.create_function(|lua, ()| Ok(context(lua)?.superkey_available))?,
+    )?;
+    apatch.set(
+        "mount_bind",
+        lua.create_function(|lua, (source, target): (String, String)| {
+            let id = context(lua)?.id.clone();
+            bind_module_file(&id, &source, &target)
+                .map_err(|e| mlua::Error::external(format!("mount_bind failed: {e:#}")))
+        })?,
+    )?;
+    apatch.set(
+        "run",
+        lua.create_function(|lua, (program, args): (String, Option<Vec<String>>)| {
+            let module_dir: PathBuf = Path::new(defs::MODULE_DIR).join(&context(lua)?.id);
+            run_for_module(&module_dir, &program, &args.unwrap_or_default())
+                .map_err(|e| mlua::Error::external(format!("run failed: {e:#}")))
+        })?,
+    )?;
+    Ok(apatch)
+}
+
+fn new_lua() -> LuaResult<Lua> {
     let lua = unsafe { Lua::unsafe_new() };
 
     let func = install_module_lua(&lua)?;
@@ -131,22 +269,173 @@ pub fn run_lua(id: &str, function: &str, on_each_module: bool, _wait: bool) -> m
     lua.globals().set("warn", warn_lua(&lua)?)?;
     lua.globals().set("setConfig", save_text_lua(&lua)?)?;
     lua.globals().set("getConfig", read_text_lua(&lua)?)?;
+    lua.globals().set("apatch", apatch_table(&lua)?)?;
 
     load_all_lua_modules(&lua)?;
+    Ok(lua)
+}
 
-    let modules: mlua::Table = lua.globals().get("modules")?;
-    if on_each_module {
-        for pair in modules.pairs::<String, mlua::Table>() {
-            let (_, module_table) = pair?;
-            if let Ok(func_obj) = module_table.get::<mlua::Function>(function) {
-                func_obj.call::<()>(id)?;
-            }
+/// Call the `<stage>` function of every module script. A failing module is
+/// logged to its Lua log and the others still run.
+pub fn exec_stage_lua(stage: &str, _wait: bool, superkey: &str) -> Result<()> {
+    let function = stage.replace('-', "_");
+    let lua = new_lua().map_err(|e| anyhow::anyhow!("{}", e))?;
+    let failed = call_stage(&lua, stage, superkey, append_log)?;
+    ensure!(failed.is_empty(), "Lua {function} failed for: {}", failed.join(", "));
+    Ok(())
+}
+
+/// Call the `<stage>` function of every script in the `modules` table, returns
+/// the ids of the modules whose function failed
+fn call_stage(
+    lua: &Lua,
+    stage: &str,
+    superkey: &str,
+    log: fn(&str, &str, &str),
+) -> Result<Vec<String>> {
+    let function = stage.replace('-', "_");
+    let modules: Table = lua.globals().get("modules").map_err(|e| anyhow::anyhow!("{}", e))?;
+    let mut failed = Vec::new();
+    for pair in modules.pairs::<String, Table>() {
+        let Ok((id, module_table)) = pair else {
+            continue;
+        };
+        let Ok(func_obj) = module_table.get::<Function>(function.as_str()) else {
+            continue;
+        };
+        lua.set_app_data(ScriptContext {
+            id: id.clone(),
+            stage: stage.to_string(),
+            superkey_available: !superkey.is_empty(),
+            log,
+        });
+        let name = format!("{id}:{function}");
+        if let Err(e) = profile::measure("lua", &name, || func_obj.call::<()>(superkey)) {
+            warn!("[Lua] {name} failed: {e}");
+            log(&id, "error", &format!("{function} failed: {e}"));
+            failed.push(id);
         }
-    } else {
-        let module_table: mlua::Table = modules.get(id)?;
-        let func_obj: mlua::Function = module_table.get(function)?;
-        func_obj.call::<()>(())?;
     }
+    Ok(failed)
+}
 
-    Ok(())
+/// Call `function` of the script of module `id`, e.g. its `action`
+pub fn run_lua(id: &str, function: &str) -> mlua::Result<()> {
+    let lua = new_lua()?;
+    let modules: Table = lua.globals().get("modules")?;
+    let module_table: Table = modules.get(id)?;
+    let func_obj: Function = module_table.get(function)?;
+    lua.set_app_data(ScriptContext {
+        id: id.to_string(),
+        stage: function.replace('_', "-"),
+        superkey_available: false,
+        log: append_log,
+    });
+    func_obj.call::<()>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    thread_local! {
+        static LOGGED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(id: &str, level: &str, msg: &str) {
+        LOGGED.with_borrow_mut(|logged| logged.push(format!("{id} [{level}] {msg}")));
+    }
+
+    /// A Lua with the `apatch` table and `modules` defined by `script`
+    fn lua_with(script: &str) -> Lua {
+        LOGGED.with_borrow_mut(Vec::clear);
+        let lua = Lua::new();
+        lua.globals().set("apatch", apatch_table(&lua).unwrap()).unwrap();
+        lua.load(script).exec().unwrap();
+        lua
+    }
+
+    fn logged() -> Vec<String> {
+        LOGGED.with_borrow(|logged| logged.clone())
+    }
+
+    #[test]
+    fn scripts_see_the_call_they_are_in() {
+        let lua = lua_with(
+            r#"
+            modules = {
+                a = {
+                    post_fs_data = function(superkey)
+                        apatch.log("info", apatch.stage() .. " " .. superkey .. " "
+                            .. tostring(apatch.superkey_available()) .. " " .. apatch.module_path())
+                    end,
+                },
+                b = { service = function() error("not this stage") end },
+            }
+            "#,
+        );
+        let failed = call_stage(&lua, "post-fs-data", "key", record).unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(
+            logged(),
+            [format!("a [info] post-fs-data key true {}a", defs::MODULE_DIR)]
+        );
+
+        call_stage(&lua, "service", "", record).unwrap();
+        assert_eq!(logged().len(), 2);
+        assert!(logged()[1].starts_with("b [error] service failed: "));
+    }
+
+    #[test]
+    fn a_failing_module_does_not_stop_the_others() {
+        let lua = lua_with(
+            r#"
+            modules = {
+                a = { service = function() error("broken") end },
+                b = { service = function() apatch.log("warn", "still runs") end },
+                c = { service = function() apatch.log("loud", "bad level") end },
+                d = {
+                    service = function()
+                        apatch.mount_bind("/etc/hosts", "/system/etc/hosts")
+                    end,
+                },
+            }
+            "#,
+        );
+        let mut failed = call_stage(&lua, "service", "", record).unwrap();
+        failed.sort();
+        assert_eq!(failed, ["a", "c", "d"]);
+
+        let mut logged = logged();
+        logged.sort();
+        assert_eq!(logged.len(), 4);
+        assert!(logged[0].starts_with("a [error] service failed: "));
+        assert!(logged[0].contains("broken"));
+        assert_eq!(logged[1], "b [warn] still runs");
+        assert!(logged[2].contains("unknown log level loud"));
+        assert!(logged[3].contains("mount_bind failed"));
+    }
+
+    #[test]
+    fn the_apatch_table_needs_a_running_module() {
+        let lua = lua_with("");
+        let error = lua.load("return apatch.stage()").eval::<String>().unwrap_err();
+        assert!(error.to_string().contains("no module function is running"));
+    }
+
+    #[test]
+    fn binds_follow_the_partition_and_critical_rules_of_mounts() {
+        let root = crate::testutil::TempDir::new("lua-bind-target");
+        root.write("module.prop", "id=m\npartitions=system\n");
+        let check = |target: &str| check_bind_target(root.path(), Path::new(target));
+        assert!(check("/system/etc/hosts").is_ok());
+        assert!(check("/vendor/etc/hosts").is_err());
+        assert!(check("/data/adb/apd").is_err());
+        assert!(check("/system/framework/framework.jar").is_err());
+
+        root.write("module.prop", "id=m\npartitions=system\nallow_critical=true\n");
+        assert!(check("/system/framework/framework.jar").is_ok());
+    }
 }
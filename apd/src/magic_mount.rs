@@ -1,18 +1,29 @@
-use crate::defs::{DISABLE_FILE_NAME, MODULE_DIR, SKIP_MOUNT_FILE_NAME};
+use crate::defs::{
+    DEFER_MOUNT_FILE_NAME, DISABLE_FILE_NAME, LOCAL_OVERLAY_DIR, MODULE_DIR, SKIP_MOUNT_FILE_NAME,
+};
 use crate::magic_mount::NodeFileType::{Directory, RegularFile, Symlink, Whiteout};
-use crate::restorecon::{ensure_syscon, lgetfilecon, lsetfilecon, restore_syscon};
+use crate::critical::{self, Guard, Patterns};
+use crate::module;
+use crate::{config, profile};
+use crate::image::StagedImages;
+use crate::restorecon::{
+    RelabelStamps, ensure_syscon, lgetfilecon, lsetfilecon, restore_syscon,
+};
 use crate::utils::ensure_dir_exists;
-use crate::utils::get_tmp_path;
 use rustix::fs::{
     Gid, MetadataExt, Mode, Uid, chmod, chown,
 };
 use rustix::mount::{
     MountPropagationFlags, UnmountFlags, unmount
 };
-use crate::mount::{bind_mount, bind_mount_file, move_mount_path};
+use crate::mount::{
+    Propagation, bind_mount, bind_mount_file, get_mount_attrs, move_mount_path,
+    set_mount_attrs,
+};
 use rustix::mount::mount_change;
 use anyhow::{Context, Result, bail};
 use extattr::lgetxattr;
+use serde::Serialize;
 use rustix::path::Arg;
 use std::cmp::PartialEq;
 use std::collections::BTreeMap;
@@ -22,9 +33,215 @@ use std::ffi::{OsStr, OsString};
 use std::fs::{DirEntry, FileType, create_dir, create_dir_all, read_link};
 use std::os::unix::fs::{FileTypeExt, symlink};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
 
 const REPLACE_DIR_XATTR: &str = "trusted.overlay.opaque";
 
+/// Partitions modules may provide, with whether `/system/<partition>` must be a
+/// symlink for `system/<partition>` of a module to be mounted at `/<partition>`
+const PARTITIONS: [(&str, bool); 6] = [
+    ("system", false),
+    ("vendor", true),
+    ("system_ext", true),
+    ("product", true),
+    ("odm", false),
+    ("oem", false),
+];
+
+/// Mount points of `mountinfo` which look like partitions: read-only mounts of
+/// a block device directly below `/`, e.g. `/my_product` on ColorOS
+fn parse_root_partitions(mountinfo: &str) -> Vec<String> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            // `36 35 259:5 / /my_product ro,relatime shared:9 - erofs /dev/block/dm-6 ro`
+            let (mount, fs) = line.split_once(" - ")?;
+            let fields: Vec<&str> = mount.split_whitespace().collect();
+            let name = fields.get(4)?.strip_prefix('/')?;
+            let read_only = fields.get(5)?.split(',').any(|option| option == "ro");
+            let source = fs.split_whitespace().nth(1)?;
+            (!name.is_empty() && !name.contains('/') && read_only && source.starts_with("/dev/"))
+                .then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// [`PARTITIONS`] followed by the other partitions mounted on this device.
+/// `system/<partition>` of a module only moves to such a partition if
+/// `/system/<partition>` links there.
+fn partitions() -> &'static [(String, bool)] {
+    static FOUND: OnceLock<Vec<(String, bool)>> = OnceLock::new();
+    FOUND.get_or_init(|| {
+        let mut partitions: Vec<(String, bool)> = PARTITIONS
+            .iter()
+            .map(|(partition, require_symlink)| (partition.to_string(), *require_symlink))
+            .collect();
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+        for name in parse_root_partitions(&mountinfo) {
+            if !partitions.iter().any(|(partition, _)| *partition == name) {
+                log::debug!("found partition /{name}");
+                partitions.push((name, true));
+            }
+        }
+        partitions
+    })
+}
+
+/// Whether `/<partition>` links somewhere, into /system on older A-only devices
+fn root_is_symlink(partition: &str) -> bool {
+    root_is_symlink_in(Path::new("/"), partition)
+}
+
+fn root_is_symlink_in(root: &Path, partition: &str) -> bool {
+    fs::symlink_metadata(root.join(partition)).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// Whether `system/<partition>` of modules is mounted at `/<partition>`
+fn mounts_at_root(partition: &str, require_symlink: bool) -> bool {
+    mounts_at_root_in(Path::new("/"), partition, require_symlink)
+}
+
+fn mounts_at_root_in(root: &Path, partition: &str, require_symlink: bool) -> bool {
+    !root_is_symlink_in(root, partition)
+        && root.join(partition).is_dir()
+        && (!require_symlink || root.join("system").join(partition).is_symlink())
+}
+
+/// Partition a path relative to a module dir modifies, `system/<partition>`
+/// counts as `<partition>` where it is mounted at `/<partition>`
+fn partition_of(relative: &Path) -> Option<String> {
+    let mut components = relative.components().map(|c| c.as_os_str());
+    let first = components.next()?;
+    let (partition, _) = partitions().iter().find(|(p, _)| OsStr::new(p) == first)?;
+    if *partition == "system"
+        && let Some(second) = components.next()
+        && let Some((relocated, _)) = partitions()
+            .iter()
+            .skip(1)
+            .find(|(p, symlink)| OsStr::new(p) == second && mounts_at_root(p, *symlink))
+    {
+        return Some(relocated.clone());
+    }
+    Some(partition.clone())
+}
+
+/// Skips the files of a module below partitions it may not modify
+struct PartitionFilter<'a> {
+    root: &'a Path,
+    /// `None` allows every partition
+    allowed: Option<Vec<String>>,
+    warned: RefCell<BTreeSet<String>>,
+}
+
+impl PartitionFilter<'_> {
+    fn blocks(&self, path: &Path) -> bool {
+        let Some(allowed) = &self.allowed else {
+            return false;
+        };
+        let Some(partition) = path
+            .strip_prefix(self.root)
+            .ok()
+            .and_then(partition_of)
+        else {
+            return false;
+        };
+        if allowed.contains(&partition) {
+            return false;
+        }
+        if self.warned.borrow_mut().insert(partition.clone()) {
+            log::warn!(
+                "skip /{partition} of {}: partition not allowed",
+                self.root.display()
+            );
+        }
+        true
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct PartitionUsage {
+    partition: String,
+    files: usize,
+    allowed: bool,
+}
+
+/// `apd module inspect <id>`: the partitions a module would modify
+pub fn inspect(id: &str) -> Result<()> {
+    let module_path = Path::new(MODULE_DIR).join(id);
+    anyhow::ensure!(module_path.exists(), "module: {} not found!", id);
+    let allowed = module::allowed_partitions(&module_path);
+    let mut files: BTreeMap<String, usize> = BTreeMap::new();
+    for (partition, _) in partitions() {
+        for entry in jwalk::WalkDir::new(module_path.join(partition))
+            .parallelism(jwalk::Parallelism::Serial)
+            .into_iter()
+            .flatten()
+            .filter(|entry| !entry.file_type().is_dir())
+        {
+            let path = entry.path();
+            if let Some(partition) = path.strip_prefix(&module_path).ok().and_then(partition_of) {
+                *files.entry(partition).or_default() += 1;
+            }
+        }
+    }
+    let usage: Vec<PartitionUsage> = files
+        .into_iter()
+        .map(|(partition, files)| PartitionUsage {
+            allowed: allowed.as_ref().is_none_or(|allowed| allowed.contains(&partition)),
+            partition,
+            files,
+        })
+        .collect();
+    if crate::output::json() {
+        return crate::output::print("module inspect", usage);
+    }
+    if usage.is_empty() {
+        println!("{id} modifies no partition");
+    }
+    for usage in &usage {
+        println!(
+            "/{}: {} file(s){}",
+            usage.partition,
+            usage.files,
+            if usage.allowed { "" } else { ", not allowed, skipped" }
+        );
+    }
+    Ok(())
+}
+
+/// Mount points the files of a module end up below, in partition order
+pub fn mount_targets(module_path: &Path) -> Vec<String> {
+    let relocated = |name: &OsStr| {
+        partitions()
+            .iter()
+            .skip(1)
+            .any(|(p, symlink)| name == OsStr::new(p) && mounts_at_root(p, *symlink))
+    };
+    partitions()
+        .iter()
+        .filter(|(partition, require_symlink)| {
+            if *partition == "system" {
+                module_path
+                    .join(partition)
+                    .read_dir()
+                    .is_ok_and(|dir| dir.flatten().any(|e| !relocated(&e.file_name())))
+                    || partitions().iter().skip(1).any(|(p, _)| {
+                        root_is_symlink(p) && module_path.join(p).is_dir()
+                    })
+            } else if root_is_symlink(partition) {
+                false
+            } else {
+                module_path.join(partition).is_dir()
+                    || (mounts_at_root(partition, *require_symlink)
+                        && module_path.join("system").join(partition).is_dir())
+            }
+        })
+        .map(|(partition, _)| format!("/{partition}"))
+        .collect()
+}
+
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 enum NodeFileType {
     RegularFile,
@@ -58,11 +275,19 @@ struct Node {
 }
 
 impl Node {
-    fn collect_module_files<T: AsRef<Path>>(&mut self, module_dir: T) -> Result<bool> {
+    fn collect_module_files<T: AsRef<Path>>(
+        &mut self,
+        module_dir: T,
+        skip: &dyn Fn(&Path) -> bool,
+    ) -> Result<bool> {
         let dir = module_dir.as_ref();
         let mut has_file = false;
         for entry in dir.read_dir()?.flatten() {
             let name = entry.file_name();
+            // marks its dir as replaced, it is not a module file itself
+            if skip(&entry.path()) || name == ".replace" {
+                continue;
+            }
 
             let node = match self.children.entry(name.clone()) {
                 Entry::Occupied(o) => Some(o.into_mut()),
@@ -71,7 +296,7 @@ impl Node {
 
             if let Some(node) = node {
                 has_file |= if node.file_type == Directory {
-                    node.collect_module_files(dir.join(&node.name))? || node.replace
+                    node.collect_module_files(dir.join(&node.name), skip)? || node.replace
                 } else {
                     true
                 }
@@ -101,14 +326,7 @@ impl Node {
                 NodeFileType::from_file_type(metadata.file_type())
             };
             if let Some(file_type) = file_type {
-                let mut replace = false;
-                if file_type == Directory {
-                    if let Ok(v) = lgetxattr(&path, REPLACE_DIR_XATTR) {
-                        if v.as_slice() == b"y" {
-                            replace = true;
-                        }
-                    }
-                }
+                let replace = file_type == Directory && is_replace_dir(&path);
                 return Some(Node {
                     name: name.into(),
                     file_type,
@@ -124,63 +342,320 @@ impl Node {
     }
 }
 
-fn collect_module_files() -> Result<Option<Node>> {
-    let mut root = Node::new_root("");
-    let module_root = Path::new(MODULE_DIR);
-    let mut has_file = false;
-    
-    let partitions = [
-        ("system", false),
-        ("vendor", true),
-        ("system_ext", true),
-        ("product", true),
-        ("odm", false),
-        ("oem", false),
-    ];
-
-    for entry in module_root.read_dir()?.flatten() {
-        if !entry.file_type()?.is_dir() {
-            continue;
+/// How the local overlay is named in logs and mount plans
+pub const LOCAL_OVERLAY_NAME: &str = "(local overlay)";
+
+/// The local overlay dir if `local_overlay = true` and a partition dir of it
+/// has files. It is collected before every module, so its files win.
+pub fn local_overlay() -> Option<PathBuf> {
+    if !config::local_overlay() {
+        return None;
+    }
+    let dir = Path::new(LOCAL_OVERLAY_DIR);
+    partitions()
+        .iter()
+        .any(|(partition, _)| {
+            dir.join(partition)
+                .read_dir()
+                .is_ok_and(|mut entries| entries.next().is_some())
+        })
+        .then(|| dir.to_path_buf())
+}
+
+fn module_id(module_path: &Path) -> String {
+    if module_path == Path::new(LOCAL_OVERLAY_DIR) {
+        return LOCAL_OVERLAY_NAME.to_string();
+    }
+    module_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The modules whose files are mounted at post-fs-data or, if `deferred`, at
+/// boot-completed, in mount order
+fn mountable_modules(deferred: bool) -> Result<Vec<PathBuf>> {
+    let blocked = module::blocked_modules();
+    // the first module providing a file wins, so the order must not depend on readdir
+    let mut modules: Vec<PathBuf> = Path::new(MODULE_DIR)
+        .read_dir()?
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|entry| entry.path())
+        .filter(|module_path| {
+            !crate::flags::is_set(module_path.join(DISABLE_FILE_NAME))
+                && !crate::flags::is_set(module_path.join(SKIP_MOUNT_FILE_NAME))
+                && module::classify(module_path).has_payload()
+                && crate::flags::is_set(module_path.join(DEFER_MOUNT_FILE_NAME)) == deferred
+        })
+        .filter(|module_path| {
+            // mounted in the namespaces of their apps only, see targeted
+            let targeted = crate::targeted::is_targeted(module_path);
+            if targeted {
+                log::info!("{} is mounted for its target apps only", module_path.display());
+            }
+            !targeted
+        })
+        .filter(|module_path| match blocked.get(&module_id(module_path)) {
+            Some(reason) => {
+                log::warn!("skip {}: {}", module_path.display(), reason);
+                false
+            }
+            None => true,
+        })
+        .collect();
+    module::sort_by_mount_order(&mut modules);
+    Ok(modules)
+}
+
+/// A file provided by more than one module
+#[derive(Serialize, Debug)]
+pub struct Conflict {
+    pub path: PathBuf,
+    /// in mount order, the first one wins
+    pub modules: Vec<String>,
+}
+
+/// Where a path relative to a module dir ends up
+pub(crate) fn target_of(relative: &Path) -> PathBuf {
+    if let Some(first) = relative.components().next()
+        && first.as_os_str() != OsStr::new("system")
+        && root_is_symlink(&first.as_os_str().to_string_lossy())
+    {
+        // <partition>/... of a partition linking into /system
+        return Path::new("/system").join(relative);
+    }
+    match partition_of(relative) {
+        Some(partition) if !relative.starts_with(&partition) => {
+            // system/<partition>/... mounted at /<partition>/...
+            let rest: PathBuf = relative.components().skip(2).collect();
+            Path::new("/").join(partition).join(rest)
         }
+        _ => Path::new("/").join(relative),
+    }
+}
 
-        let module_path = entry.path();
-        if module_path.join(DISABLE_FILE_NAME).exists()
-            || module_path.join(SKIP_MOUNT_FILE_NAME).exists()
-        {
-            continue;
+fn is_replace_dir(path: &Path) -> bool {
+    crate::flags::is_set(path.join(".replace"))
+        || lgetxattr(path, REPLACE_DIR_XATTR).is_ok_and(|v| v.as_slice() == b"y")
+}
+
+/// Files more than one of `modules` provide. A replaced directory claims its
+/// whole subtree, directories merely present in several modules do not conflict.
+pub(crate) fn find_conflicts(modules: &[PathBuf]) -> Vec<Conflict> {
+    let mut files: BTreeMap<PathBuf, Vec<usize>> = BTreeMap::new();
+    let mut replaced: BTreeMap<PathBuf, Vec<usize>> = BTreeMap::new();
+    for (index, module_path) in modules.iter().enumerate() {
+        for (partition, _) in partitions() {
+            for entry in jwalk::WalkDir::new(module_path.join(partition))
+                .parallelism(jwalk::Parallelism::Serial)
+                .into_iter()
+                .flatten()
+            {
+                let path = entry.path();
+                let Ok(relative) = path.strip_prefix(module_path) else {
+                    continue;
+                };
+                if entry.file_type().is_dir() {
+                    if is_replace_dir(&path) {
+                        replaced.entry(target_of(relative)).or_default().push(index);
+                    }
+                } else if path.file_name() != Some(OsStr::new(".replace")) {
+                    files.entry(target_of(relative)).or_default().push(index);
+                }
+            }
         }
+    }
 
-        log::debug!("collecting {} and restoring context", module_path.display());
-        
-        // Merge restorecon walk with module discovery
-        if let Err(e) = restore_syscon(&module_path) {
-            log::warn!("Failed to restorecon for {}: {}", module_path.display(), e);
+    let mut conflicts = Vec::new();
+    // looked up by the parents of a target, not by comparing every pair
+    let claims = |target: &Path, providers: &mut Vec<usize>| {
+        for dir in target.ancestors().skip(1) {
+            if let Some(claimers) = replaced.get(dir) {
+                providers.extend(claimers);
+            }
+        }
+    };
+    for (target, mut providers) in files.into_iter().chain(replaced.clone()) {
+        claims(&target, &mut providers);
+        providers.sort_unstable();
+        providers.dedup();
+        if providers.len() > 1 {
+            conflicts.push(Conflict {
+                path: target,
+                modules: providers.iter().map(|i| module_id(&modules[*i])).collect(),
+            });
         }
+    }
+    conflicts
+}
+
+/// Warn about conflicting files and append them to the conflict log
+fn report_conflicts(modules: &[PathBuf]) {
+    let conflicts = find_conflicts(modules);
+    if conflicts.is_empty() {
+        return;
+    }
+    let mut lines = String::new();
+    for conflict in &conflicts {
+        let line = format!(
+            "{} provided by {}, {} wins",
+            conflict.path.display(),
+            conflict.modules.join(", "),
+            conflict.modules[0]
+        );
+        log::warn!("{line}");
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+    let result = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(crate::defs::MODULE_CONFLICTS_LOG_FILE)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, lines.as_bytes()));
+    if let Err(e) = result {
+        log::warn!("Failed to write {}: {}", crate::defs::MODULE_CONFLICTS_LOG_FILE, e);
+    }
+}
+
+/// `apd module conflicts`: files provided by several enabled modules
+pub fn conflicts() -> Result<()> {
+    // deferred modules are mounted on top of the others, they win
+    let mut modules = mountable_modules(true)?;
+    modules.extend(mountable_modules(false)?);
+    let conflicts = find_conflicts(&modules);
+    if crate::output::json() {
+        return crate::output::print("module conflicts", &conflicts);
+    }
+    if conflicts.is_empty() {
+        println!("no conflicts");
+    }
+    for conflict in &conflicts {
+        println!(
+            "{}: {} (winner: {})",
+            conflict.path.display(),
+            conflict.modules.join(", "),
+            conflict.modules[0]
+        );
+    }
+    Ok(())
+}
+
+fn collect_module_files(images: &mut StagedImages, deferred: bool) -> Result<Option<Node>> {
+    let mut root = Node::new_root("");
+    let mut has_file = false;
+    let critical_patterns = Patterns::load();
+    let mut stamps = RelabelStamps::load();
+    let modules = mountable_modules(deferred)?;
+    if !deferred {
+        report_conflicts(&modules);
+    }
+    let mut order: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    // the local overlay sits above all modules, deferred ones are mounted on top
+    let local = if deferred { None } else { local_overlay() };
+
+    for module_path in local.into_iter().chain(modules) {
+        let id = module_id(&module_path);
+        // files of a module image come from its mount, labelled when packed
+        let staged = images.stage(&module_path);
+        let source = staged.as_deref().unwrap_or(&module_path);
+
+        if staged.is_some() {
+            log::debug!("collecting {} from its image", module_path.display());
+        } else if stamps.is_fresh(&module_path) {
+            log::debug!("collecting {}, labels unchanged", module_path.display());
+            stamps.mark(&module_path);
+        } else {
+            log::debug!("collecting {} and restoring context", module_path.display());
+
+            // Merge restorecon walk with module discovery
+            let relabel = format!("relabel {}", module_path.display());
+            let mut summary =
+                profile::measure("phase", &relabel, || restore_syscon(&module_path));
+            if summary.raced() {
+                summary = restore_syscon(&module_path);
+            }
+            match summary.check() {
+                Ok(()) if !summary.raced() => stamps.mark(&module_path),
+                Ok(()) => {}
+                Err(e) => log::warn!(
+                    "Failed to restorecon for {}: {} ({})",
+                    module_path.display(),
+                    e,
+                    summary
+                ),
+            }
+        }
+
+        let guard = (!critical::is_acknowledged(&module_path)).then(|| Guard {
+            root: source,
+            patterns: &critical_patterns,
+        });
+        let partition_filter = PartitionFilter {
+            root: source,
+            allowed: module::allowed_partitions(&module_path),
+            warned: Default::default(),
+        };
+        let skip = |path: &Path| {
+            partition_filter.blocks(path) || guard.as_ref().is_some_and(|guard| guard.blocks(path))
+        };
 
         // Use a single read_dir for faster partition checking
-        if let Ok(dir) = module_path.read_dir() {
+        if let Ok(dir) = source.read_dir() {
             for entry in dir.flatten() {
                 let name = entry.file_name();
-                if let Some((partition, _)) = partitions.iter().find(|(p, _)| OsStr::new(p) == name) {
+                if let Some((partition, _)) = partitions().iter().find(|(p, _)| OsStr::new(p) == name) {
                     if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
-                        let mod_part = module_path.join(partition);
+                        order.entry(partition.clone()).or_default().push(id.clone());
+                        let mod_part = source.join(partition);
                         let node = root.children.entry(name)
                             .or_insert_with(|| Node::new_root(partition));
-                        has_file |= node.collect_module_files(&mod_part)?;
+                        has_file |= node.collect_module_files(&mod_part, &skip)?;
                     }
                 }
             }
         }
     }
 
+    if let Err(e) = stamps.store() {
+        log::warn!("Failed to store relabel stamps: {}", e);
+    }
+    for (partition, ids) in &order {
+        log::info!("/{partition} module order (mountorder, then id): {}", ids.join(", "));
+    }
+
     if has_file {
+        // mounting on /<partition> would follow its link, its files go below /system
+        for (partition, _) in partitions().iter().skip(1) {
+            if !root_is_symlink(partition) {
+                continue;
+            }
+            let Some(node) = root.children.remove(OsStr::new(partition)) else {
+                continue;
+            };
+            log::debug!("/{partition} is a symlink, mounting its module files below /system");
+            let system_node = root
+                .children
+                .entry(OsString::from("system"))
+                .or_insert_with(|| Node::new_root("system"));
+            match system_node.children.entry(node.name.clone()) {
+                Entry::Vacant(v) => {
+                    v.insert(node);
+                }
+                Entry::Occupied(mut o) => {
+                    let system_part = o.get_mut();
+                    if node.replace {
+                        system_part.replace = true;
+                    }
+                    system_part.children.extend(node.children);
+                }
+            }
+        }
         if let Some(mut system_node) = root.children.remove(OsStr::new("system")) {
-            for (partition, require_symlink) in partitions.iter().skip(1) { // 略过索引 0 ("system")
-                let path_of_root = Path::new("/").join(partition);
-                let path_of_system = Path::new("/system").join(partition);
-                
-                if path_of_root.is_dir() && (!require_symlink || path_of_system.is_symlink()) {
-                    let name = OsString::from(*partition);
+            for (partition, require_symlink) in partitions().iter().skip(1) { // 略过索引 0 ("system")
+                if mounts_at_root(partition, *require_symlink) {
+                    let name = OsString::from(partition);
                     if let Some(node) = system_node.children.remove(&name) {
                         match root.children.entry(name) {
                              Entry::Vacant(v) => {
@@ -263,11 +738,97 @@ fn mount_mirror<P: AsRef<Path>, WP: AsRef<Path>>(
     Ok(())
 }
 
+/// Gives the module files the label of the files they are mounted over, a file
+/// labeled `system_file` over e.g. an overlay APK of /product makes apps which
+/// read it hit denials. Files of /data or of an image are not relabeled in
+/// place, a copy on tmpfs is mounted instead.
+struct Relabel {
+    /// dir on the tmpfs of the mount run holding the relabeled copies and the
+    /// placeholders hiding whiteouts
+    scratch: PathBuf,
+    /// copies in the mirror belong to this mount run and are relabeled in place
+    mirror: Option<PathBuf>,
+    copies: usize,
+}
+
+impl Relabel {
+    fn new(tmp_dir: &Path, mirror: Option<PathBuf>) -> Self {
+        Relabel {
+            scratch: tmp_dir.join(".relabel"),
+            mirror,
+            copies: 0,
+        }
+    }
+
+    /// The file to bind over `target`, carrying the label `target` has now
+    fn source_for(&mut self, source: &Path, target: &Path) -> Result<PathBuf> {
+        // a file the module adds keeps the label of the module file
+        let Ok(wanted) = lgetfilecon(target) else {
+            return Ok(source.to_path_buf());
+        };
+        let in_mirror = self.mirror.as_deref().is_some_and(|mirror| source.starts_with(mirror));
+        let wanted = match label_plan(&lgetfilecon(source)?, wanted, in_mirror) {
+            LabelPlan::Keep => return Ok(source.to_path_buf()),
+            LabelPlan::InPlace(wanted) => {
+                lsetfilecon(source, &wanted)?;
+                return Ok(source.to_path_buf());
+            }
+            LabelPlan::Copy(wanted) => wanted,
+        };
+        create_dir_all(&self.scratch)?;
+        self.copies += 1;
+        let copy = self.scratch.join(self.copies.to_string());
+        fs::copy(source, &copy).with_context(|| format!("copy {}", source.display()))?;
+        copy_attrs(source, &copy)?;
+        lsetfilecon(&copy, &wanted)?;
+        log::debug!(
+            "relabeled copy of {} to {wanted} for {}",
+            source.display(),
+            target.display()
+        );
+        Ok(copy)
+    }
+
+    /// An empty dir or file to bind over `target` to hide it, with the owner,
+    /// mode and label of `target`
+    fn placeholder_for(&mut self, target: &Path, dir: bool) -> Result<PathBuf> {
+        create_dir_all(&self.scratch)?;
+        self.copies += 1;
+        let placeholder = self.scratch.join(self.copies.to_string());
+        if dir {
+            create_dir(&placeholder)?;
+        } else {
+            fs::File::create(&placeholder)?;
+        }
+        copy_attrs(target, &placeholder)?;
+        Ok(placeholder)
+    }
+}
+
+/// How a module file labeled `current` is made to carry the label `wanted`
+#[derive(Debug, PartialEq, Eq)]
+enum LabelPlan {
+    Keep,
+    InPlace(String),
+    Copy(String),
+}
+
+fn label_plan(current: &str, wanted: String, in_mirror: bool) -> LabelPlan {
+    if current == wanted {
+        LabelPlan::Keep
+    } else if in_mirror {
+        LabelPlan::InPlace(wanted)
+    } else {
+        LabelPlan::Copy(wanted)
+    }
+}
+
 fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
     path: P,
     work_dir_path: WP,
     current: Node,
     has_tmpfs: bool,
+    relabel: &mut Relabel,
 ) -> Result<()> {
     let mut current = current;
     let path = path.as_ref().join(&current.name);
@@ -286,7 +847,8 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                     module_path.display(),
                     work_dir_path.display()
                 );
-                bind_mount_file(module_path, target_path)?;
+                let source = relabel.source_for(module_path, &path)?;
+                bind_mount_file(&source, target_path)?;
             } else {
                 bail!("cannot mount root file {}!", path.display());
             }
@@ -311,7 +873,8 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                     let real_path = path.join(name);
                     let need = match node.file_type {
                         Symlink => true,
-                        Whiteout => real_path.exists(),
+                        // bind mounts follow symlinks, only a tmpfs can hide one
+                        Whiteout => real_path.is_symlink(),
                         _ => {
                             if let Ok(metadata) = real_path.symlink_metadata() {
                                 let file_type = NodeFileType::from_file_type(metadata.file_type())
@@ -379,7 +942,7 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                         if node.skip {
                             continue;
                         }
-                        do_magic_mount(&path, &work_dir_path, node, has_tmpfs)
+                        do_magic_mount(&path, &work_dir_path, node, has_tmpfs, relabel)
                             .with_context(|| format!("magic mount {}/{}", path.display(), name.to_string_lossy()))
                     } else if has_tmpfs {
                         mount_mirror(&path, &work_dir_path, &entry)
@@ -413,7 +976,7 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                 if node.skip {
                     continue;
                 }
-                if let Err(e) = do_magic_mount(&path, &work_dir_path, node, has_tmpfs)
+                if let Err(e) = do_magic_mount(&path, &work_dir_path, node, has_tmpfs, relabel)
                     .with_context(|| format!("magic mount {}/{}", path.display(), name.to_string_lossy()))
                 {
                     if has_tmpfs {
@@ -430,30 +993,213 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                     work_dir_path.display(),
                     path.display()
                 );
+                // the tmpfs must not look different from the stock mount it covers
+                let stock_attrs = get_mount_attrs(&path);
                 move_mount_path(&work_dir_path, &path).context("move self")?;
                 mount_change(&path, MountPropagationFlags::PRIVATE).context("make self private")?;
+                match stock_attrs {
+                    Ok((flags, shared)) => {
+                        if let Err(e) = set_mount_attrs(&path, flags) {
+                            log::warn!("cannot reproduce mount flags on {}: {:#}", path.display(), e);
+                        }
+                        if shared {
+                            log::info!(
+                                "{} is shared on stock, kept private to not leak module mounts",
+                                path.display()
+                            );
+                        }
+                    }
+                    Err(e) => log::warn!("cannot read mount flags of {}: {:#}", path.display(), e),
+                }
             }
         }
         Whiteout => {
-            log::debug!("file {} is removed", path.display());
+            if has_tmpfs {
+                // left out of the skeleton
+                log::debug!("file {} is removed", path.display());
+            } else if let Ok(metadata) = path.symlink_metadata() {
+                let placeholder = relabel.placeholder_for(&path, metadata.is_dir())?;
+                if metadata.is_dir() {
+                    bind_mount(&placeholder, &path)?;
+                } else {
+                    bind_mount_file(&placeholder, &path)?;
+                }
+                log::debug!("file {} is hidden", path.display());
+            }
         }
     }
 
     Ok(())
 }
 
+/// Total size of the module files `node` mounts
+fn mirror_size(node: &Node) -> u64 {
+    let own = match (&node.file_type, &node.module_path) {
+        (RegularFile, Some(path)) => path.metadata().map_or(0, |m| m.len()),
+        _ => 0,
+    };
+    own + node.children.values().map(mirror_size).sum::<u64>()
+}
+
+fn mirror_path(mirror: &Path, module_path: &Path) -> Option<PathBuf> {
+    Some(mirror.join(module_path.strip_prefix(MODULE_DIR).ok()?))
+}
+
+/// Copy owner, mode and label of `src` to `dst`
+fn copy_attrs(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = src.metadata()?;
+    chmod(dst, Mode::from_raw_mode(metadata.mode()))?;
+    chown(
+        dst,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+    )?;
+    lsetfilecon(dst, lgetfilecon(src)?.as_str())
+}
+
+fn copy_to_mirror(node: &Node, mirror: &Path) -> Result<()> {
+    if let Some(module_path) = &node.module_path
+        && let Some(copy) = mirror_path(mirror, module_path)
+    {
+        if let Some(parent) = copy.parent() {
+            create_dir_all(parent)?;
+        }
+        match node.file_type {
+            RegularFile => {
+                fs::copy(module_path, &copy)
+                    .with_context(|| format!("copy {}", module_path.display()))?;
+                copy_attrs(module_path, &copy)?;
+            }
+            Directory => {
+                create_dir_all(&copy)?;
+                copy_attrs(module_path, &copy)?;
+            }
+            Symlink => clone_symlink(module_path, &copy)?,
+            Whiteout => {}
+        }
+    }
+    node.children
+        .values()
+        .try_for_each(|child| copy_to_mirror(child, mirror))
+}
+
+fn point_to_mirror(node: &mut Node, mirror: &Path) {
+    if node.file_type != Whiteout
+        && let Some(copy) = node
+            .module_path
+            .as_deref()
+            .and_then(|path| mirror_path(mirror, path))
+    {
+        node.module_path = Some(copy);
+    }
+    for child in node.children.values_mut() {
+        point_to_mirror(child, mirror);
+    }
+}
+
+/// Copy the module files to a private tmpfs below a random dir of the scratch
+/// dir, so the mounts do not depend on /data and show no /data paths. Returns the
+/// mirror to detach once mounted, `None` to mount from /data instead.
+fn mirror_modules(root: &mut Node) -> Result<Option<PathBuf>> {
+    let size = mirror_size(root);
+    let cap = crate::config::mirror_max_bytes();
+    if size > cap {
+        log::warn!("module files take {size} bytes, more than the mirror cap {cap}, mount from /data");
+        return Ok(None);
+    }
+
+    let name = fs::read_to_string("/proc/sys/kernel/random/uuid")
+        .context("read random uuid")?
+        .trim()
+        .replace('-', "");
+    let mirror = crate::paths::scratch_dir().join(&name[..12.min(name.len())]);
+    create_dir(&mirror)?;
+    if let Err(e) = crate::mount::mount_tmpfs_named(&mirror, crate::mount::MIRROR_SOURCE) {
+        fs::remove_dir(&mirror).ok();
+        return Err(e.context("mount mirror tmpfs"));
+    }
+    if let Err(e) = copy_to_mirror(root, &mirror) {
+        unmount(&mirror, UnmountFlags::DETACH).ok();
+        fs::remove_dir(&mirror).ok();
+        return Err(e.context("copy modules to mirror"));
+    }
+    point_to_mirror(root, &mirror);
+    log::info!("mirrored {size} bytes of module files to {}", mirror.display());
+    Ok(Some(mirror))
+}
+
 pub fn magic_mount() -> Result<()> {
-    match collect_module_files()? {
-        Some(root) => {
+    mount_modules(false)
+}
+
+/// Whether there are modules to mount at boot-completed
+pub fn has_deferred() -> bool {
+    mountable_modules(true).is_ok_and(|modules| !modules.is_empty())
+}
+
+/// Mount the modules flagged `defer_mount` on top of the mounts of post-fs-data
+pub fn magic_mount_deferred() -> Result<()> {
+    mount_modules(true)
+}
+
+fn mount_modules(deferred: bool) -> Result<()> {
+    let mut images = StagedImages::new();
+    let collected = collect_module_files(&mut images, deferred);
+    let root = match collected {
+        Ok(root) => root,
+        Err(e) => {
+            images.release();
+            return Err(e);
+        }
+    };
+    let result = mount_collected(root);
+    images.release();
+    result
+}
+
+fn mount_collected(root: Option<Node>) -> Result<()> {
+    match root {
+        Some(mut root) => {
             log::debug!("collected: {:#?}", root);
-            let tmp_dir = PathBuf::from(get_tmp_path());
+            let mirror = if crate::config::mirror_modules() {
+                mirror_modules(&mut root).unwrap_or_else(|e| {
+                    log::warn!("{:#}, mount from /data", e);
+                    None
+                })
+            } else {
+                None
+            };
+            let tmp_dir = crate::paths::mount_work_dir();
             ensure_dir_exists(&tmp_dir)?;
             crate::mount::mount_tmpfs(&tmp_dir).context("mount tmpfs")?;
-            let result = do_magic_mount("/", &tmp_dir, root, false);
+            // module mounts must reach the namespaces zygote creates later on
+            let targets: Vec<PathBuf> = root
+                .children
+                .keys()
+                .map(|partition| Path::new("/").join(partition))
+                .filter(|target| target.exists())
+                .collect();
+            let mut relabel = Relabel::new(&tmp_dir, mirror.clone());
+            let result = crate::mount::with_propagation(&targets, Propagation::Shared, || {
+                do_magic_mount("/", &tmp_dir, root, false, &mut relabel)
+            });
+            if relabel.copies > 0 {
+                log::info!(
+                    "{} module file(s) mounted as copies labeled like their targets",
+                    relabel.copies
+                );
+            }
             if let Err(e) = unmount(&tmp_dir, UnmountFlags::DETACH) {
                 log::error!("failed to unmount tmp {}", e);
             }
             fs::remove_dir(tmp_dir).ok();
+            // the bind mounts keep the mirror tmpfs alive, its dir can go
+            if let Some(mirror) = mirror {
+                if let Err(e) = unmount(&mirror, UnmountFlags::DETACH) {
+                    log::error!("failed to unmount mirror {}", e);
+                }
+                fs::remove_dir(mirror).ok();
+            }
             result
         }
         _ => {
@@ -462,3 +1208,167 @@ pub fn magic_mount() -> Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+1 0 253:2 / / ro,relatime shared:1 - ext4 /dev/block/dm-2 ro,seclabel
+25 1 253:3 / /vendor ro,relatime shared:2 - erofs /dev/block/dm-3 ro
+36 1 259:5 / /my_product ro,relatime shared:9 - erofs /dev/block/dm-6 ro
+37 1 259:6 / /my_heytap ro,nodev,relatime shared:10 master:3 - erofs /dev/block/dm-7 ro
+40 1 0:20 / /dev rw,nosuid,relatime shared:11 - tmpfs tmpfs rw,seclabel,mode=755
+41 1 259:8 / /data rw,nosuid,nodev shared:12 - f2fs /dev/block/dm-9 rw
+42 1 259:9 / /my_stock rw,relatime shared:13 - ext4 /dev/block/dm-10 rw
+43 36 259:5 / /my_product/app ro,relatime shared:9 - erofs /dev/block/dm-6 ro
+44 1 0:30 / /my_bigball ro,relatime shared:14 - overlay overlay ro,lowerdir=/a
+not a mountinfo line
+";
+
+    #[test]
+    fn only_read_only_block_mounts_below_root_are_partitions() {
+        assert_eq!(
+            parse_root_partitions(MOUNTINFO),
+            ["vendor", "my_product", "my_heytap"]
+        );
+    }
+
+    #[test]
+    fn empty_mountinfo_has_no_partitions() {
+        assert!(parse_root_partitions("").is_empty());
+    }
+
+    fn collect(module: &Path) -> Node {
+        let mut root = Node::new_root("");
+        assert!(root.collect_module_files(module, &|_| false).unwrap());
+        root
+    }
+
+    fn child<'a>(node: &'a Node, path: &str) -> &'a Node {
+        path.split('/').fold(node, |node, name| &node.children[OsStr::new(name)])
+    }
+
+    #[test]
+    fn a_replace_file_marks_its_dir_replaced() {
+        let root = crate::testutil::TempDir::new("collect-replace");
+        root.write("module/system/app/Bloat/.replace", "");
+        root.write("module/system/app/Bloat/keep.txt", "");
+        root.write("module/system/app/Other/Other.apk", "");
+
+        let node = collect(&root.path().join("module"));
+        let bloat = child(&node, "system/app/Bloat");
+        assert!(bloat.replace);
+        assert_eq!(bloat.children.keys().collect::<Vec<_>>(), ["keep.txt"]);
+        assert!(!child(&node, "system/app/Other").replace);
+        assert!(!child(&node, "system/app").replace);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "privileged-tests"), ignore = "creates char devices")]
+    fn nested_whiteouts_inside_replaced_dirs_are_collected() {
+        let root = crate::testutil::TempDir::new("collect-whiteout");
+        let whiteout = |relative: &str| {
+            let path = root.path().join(relative);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            let path = std::ffi::CString::new(path.into_os_string().into_encoded_bytes()).unwrap();
+            assert_eq!(unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o600, 0) }, 0);
+        };
+        root.write("module/system/app/Bloat/.replace", "");
+        whiteout("module/system/app/Bloat/oat");
+        whiteout("module/system/app/Bloat/lib/arm64/libbloat.so");
+        whiteout("module/system/priv-app/Gone");
+        // a replaced dir whose only content is a whiteout further down
+        root.write("module/system/etc/empty/.replace", "");
+        whiteout("module/system/etc/empty/nested/file");
+
+        let node = collect(&root.path().join("module"));
+        let bloat = child(&node, "system/app/Bloat");
+        assert!(bloat.replace);
+        assert_eq!(child(bloat, "oat").file_type, Whiteout);
+        assert_eq!(child(bloat, "lib/arm64/libbloat.so").file_type, Whiteout);
+        assert_eq!(child(bloat, "lib").file_type, Directory);
+        assert_eq!(child(&node, "system/priv-app/Gone").file_type, Whiteout);
+        assert_eq!(child(&node, "system/etc/empty/nested/file").file_type, Whiteout);
+        assert!(!bloat.children.contains_key(OsStr::new(".replace")));
+    }
+
+    const SYSTEM: &str = "u:object_r:system_file:s0";
+    const OVERLAY_APK: &str = "u:object_r:vendor_overlay_file:s0";
+
+    #[test]
+    fn module_files_take_the_label_of_their_target() {
+        assert_eq!(label_plan(SYSTEM, SYSTEM.to_string(), false), LabelPlan::Keep);
+        assert_eq!(label_plan(SYSTEM, SYSTEM.to_string(), true), LabelPlan::Keep);
+        // files of the mirror belong to the mount run
+        assert_eq!(
+            label_plan(SYSTEM, OVERLAY_APK.to_string(), true),
+            LabelPlan::InPlace(OVERLAY_APK.to_string())
+        );
+        // module files below /data are never relabeled
+        assert_eq!(
+            label_plan(crate::restorecon::ADB_CON, OVERLAY_APK.to_string(), false),
+            LabelPlan::Copy(OVERLAY_APK.to_string())
+        );
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "privileged-tests"), ignore = "writes security.selinux")]
+    fn relabeled_copies_leave_module_files_alone() {
+        let root = crate::testutil::TempDir::new("relabel-copy");
+        let source = root.write("modules/example/product/overlay/Foo.apk", "apk");
+        let target = root.write("product/overlay/Foo.apk", "stock");
+        let added = root.path().join("product/overlay/Added.apk");
+        let mirrored = root.write("mirror/product/overlay/Bar.apk", "apk");
+        lsetfilecon(&source, SYSTEM).unwrap();
+        lsetfilecon(&mirrored, SYSTEM).unwrap();
+        lsetfilecon(&target, OVERLAY_APK).unwrap();
+
+        let mut relabel = Relabel::new(&root.path().join("tmp"), Some(root.path().join("mirror")));
+        let copy = relabel.source_for(&source, &target).unwrap();
+        assert_ne!(copy, source);
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "apk");
+        assert_eq!(lgetfilecon(&copy).unwrap(), OVERLAY_APK);
+        assert_eq!(lgetfilecon(&source).unwrap(), SYSTEM);
+
+        assert_eq!(relabel.source_for(&mirrored, &target).unwrap(), mirrored);
+        assert_eq!(lgetfilecon(&mirrored).unwrap(), OVERLAY_APK);
+
+        // nothing to match for a file the module adds
+        assert_eq!(relabel.source_for(&source, &added).unwrap(), source);
+        assert_eq!(relabel.copies, 1);
+    }
+
+    #[test]
+    fn partition_roots_are_resolved_without_following_links() {
+        use std::os::unix::fs::symlink;
+
+        let root = crate::testutil::TempDir::new("partition-roots");
+        let root_path = root.path();
+        // A-only: /vendor links into /system
+        fs::create_dir_all(root_path.join("system/vendor/lib")).unwrap();
+        symlink("system/vendor", root_path.join("vendor")).unwrap();
+        // system-as-root: /system/product links to /product
+        fs::create_dir_all(root_path.join("product")).unwrap();
+        symlink("../product", root_path.join("system/product")).unwrap();
+        // /odm is its own partition, /system/odm is a plain dir
+        fs::create_dir_all(root_path.join("odm")).unwrap();
+        fs::create_dir_all(root_path.join("system/odm")).unwrap();
+        // /system_ext only exists below /system
+        fs::create_dir_all(root_path.join("system/system_ext")).unwrap();
+
+        assert!(root_is_symlink_in(root_path, "vendor"));
+        assert!(!mounts_at_root_in(root_path, "vendor", true));
+        assert!(!mounts_at_root_in(root_path, "vendor", false));
+
+        assert!(!root_is_symlink_in(root_path, "product"));
+        assert!(mounts_at_root_in(root_path, "product", true));
+
+        assert!(!mounts_at_root_in(root_path, "odm", true));
+        assert!(mounts_at_root_in(root_path, "odm", false));
+
+        assert!(!root_is_symlink_in(root_path, "system_ext"));
+        assert!(!mounts_at_root_in(root_path, "system_ext", true));
+        assert!(!mounts_at_root_in(root_path, "oem", false));
+    }
+}
@@ -0,0 +1,206 @@
+//! User-facing message catalog
+//!
+//! Every message shown to the user carries a stable code plus typed parameters.
+//! The manager translates by code, the English rendering is only a fallback,
+//! so wording here may change freely while codes must never be renamed or reused.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::{Serialize, Serializer, ser::SerializeStruct};
+
+macro_rules! catalog {
+    ($($variant:ident { $($param:ident: $ty:ty),* } => $code:literal, $text:literal;)*) => {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum Message {
+            $($variant { $($param: $ty),* },)*
+        }
+
+        /// All codes known to the catalog, in declaration order
+        #[allow(dead_code)]
+        pub const CODES: &[&str] = &[$($code),*];
+
+        /// Code, English text and parameter names of every message
+        #[cfg(test)]
+        const TEMPLATES: &[(&str, &str, &[&str])] =
+            &[$(($code, $text, &[$(stringify!($param)),*])),*];
+
+        /// Every message once, with empty parameters
+        #[cfg(test)]
+        fn samples() -> Vec<Message> {
+            vec![$(Message::$variant { $($param: Default::default()),* }),*]
+        }
+
+        impl Message {
+            /// Stable code of this message
+            pub fn code(&self) -> &'static str {
+                match self {
+                    $(Message::$variant { .. } => $code,)*
+                }
+            }
+
+            /// Parameters of this message in declaration order
+            pub fn params(&self) -> Vec<(&'static str, String)> {
+                match self {
+                    $(Message::$variant { $($param),* } => {
+                        vec![$((stringify!($param), $param.to_string())),*]
+                    })*
+                }
+            }
+        }
+
+        impl fmt::Display for Message {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Message::$variant { $($param),* } => write!(f, $text $(, $param = $param)*),)*
+                }
+            }
+        }
+    };
+}
+
+catalog! {
+    ModuleDisabled { path: String } => "module.skip.disabled", "{path} is disabled, skip";
+    ModuleRemoved { path: String } => "module.skip.removed", "{path} is removed, skip";
+    InstallBlockedMetamoduleDisabled {} => "install.blocked.metamodule_disabled",
+        "A metamodule with custom installer is disabled, re-enable or uninstall it, then reboot";
+    InstallBlockedMetamodulePending {} => "install.blocked.metamodule_pending",
+        "A metamodule with custom installer has pending changes, reboot to apply them first";
+    InstallMetamoduleExists { existing: String } => "install.failed.metamodule_exists",
+        "Metamodule {existing} already mounts modules, uninstall it and reboot first";
+    SafeModeModulesDisabled {} => "boot.safe_mode.modules_disabled",
+        "Safe mode detected, all modules have been disabled";
+    BootloopModulesDisabled {} => "boot.bootloop.modules_disabled",
+        "Several boots in a row did not complete, all modules have been disabled";
+    SepolicyPatchFailed { skipped: String } => "boot.sepolicy.patch_failed",
+        "SELinux policy patch failed, root may be degraded. Skipped: {skipped}";
+    GrantRevokedReinstall { pkg: String } => "su.grant.revoked_reinstall",
+        "{pkg} was reinstalled, root access must be granted again";
+
+    // events of the boot, see events
+    StageStarted { stage: String } => "stage.started", "{stage} started";
+    StageFinished { stage: String } => "stage.finished", "{stage} finished";
+    StageDegraded { stage: String, reason: String } => "stage.degraded",
+        "{stage} completed with errors: {reason}";
+    StageFailed { stage: String, reason: String } => "stage.failed", "{stage} failed: {reason}";
+    ModuleMounted { id: String } => "module.mounted", "{id} mounted";
+    ModuleNotMounted { id: String } => "module.mount_failed", "{id} has no mount, scripts skipped";
+    UpdateApplied { id: String, summary: String } => "module.update.applied",
+        "{id} updated: {summary}";
+    UpdateRejected { id: String, reason: String } => "module.update.rejected",
+        "update of {id} rejected: {reason}";
+    UpdateRolledBack { id: String } => "module.update.rolled_back",
+        "the boot with the update of {id} did not complete, rolled back";
+    UidListenerRestarted { status: String } => "daemon.uid_listener.restarted",
+        "uid listener exited with {status}, restarted";
+    SafeModeEntered { trigger: String } => "boot.safe_mode.entered", "safe mode entered: {trigger}";
+
+    // why a module is not mounted, see api::get_mount_report
+    MountSkipMountDisabled {} => "mount.skip.mount_disabled", "mount disabled";
+    MountSkipDisabled {} => "mount.skip.disabled", "disabled";
+    MountSkipRemoved {} => "mount.skip.removed", "removed";
+    MountSkipSkipMount {} => "mount.skip.skip_mount", "skip_mount";
+    MountSkipScriptOnly {} => "mount.skip.script_only", "script only";
+    MountSkipEmpty {} => "mount.skip.empty", "nothing to mount or run";
+    MountSkipTargeted {} => "mount.skip.targeted", "mounted for target apps only";
+    MountSkipDeferred {} => "mount.skip.deferred", "deferred to boot-completed";
+    DependencyMissing { dep: String } => "module.blocked.dependency_missing",
+        "dependency {dep} is not installed";
+    DependencyDisabled { dep: String } => "module.blocked.dependency_disabled",
+        "dependency {dep} is disabled";
+    DependencyBlocked { dep: String } => "module.blocked.dependency_blocked",
+        "dependency {dep} is not mounted";
+    ConflictsWith { other: String } => "module.blocked.conflict", "conflicts with {other}";
+    ModuleQuarantined { reason: String } => "module.blocked.quarantined",
+        "malformed module.prop: {reason}";
+
+    // findings of `apd status` about the last boot
+    KernelPatchTooOld { version: String, minimum: String } => "status.kernelpatch.too_old",
+        "kernelpatch: {version} is older than {minimum}, su setup was skipped this boot";
+    SepolicyPatchDegraded {} => "status.sepolicy.failed",
+        "sepolicy patch: FAILED, root may be degraded";
+    FeaturesDisabled { features: String } => "status.binaries.features_disabled",
+        "disabled by broken binaries: {features}";
+    MountsStale {} => "status.mounts.stale",
+        "module mounts: STALE, /data was remounted, reboot to restore them";
+    SlowBoot { kind: String, name: String, seconds: u64 } => "status.boot.slow",
+        "slow boot: {kind} {name} took {seconds}s, see `apd profile-boot show`";
+}
+
+impl Message {
+    /// Render as a single JSON line for consumers that translate by code
+    #[allow(dead_code)]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let params: BTreeMap<&str, String> = self.params().into_iter().collect();
+        let mut state = serializer.serialize_struct("Message", 3)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("params", &params)?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// Names of the `{param}` placeholders of `text`
+    fn placeholders(text: &str) -> BTreeSet<&str> {
+        text.split('{')
+            .skip(1)
+            .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+            .collect()
+    }
+
+    #[test]
+    fn codes_are_unique() {
+        let unique: BTreeSet<&str> = CODES.iter().copied().collect();
+        assert_eq!(unique.len(), CODES.len());
+    }
+
+    #[test]
+    fn templates_use_every_parameter() {
+        for (code, text, params) in TEMPLATES {
+            let names: BTreeSet<&str> = params.iter().copied().collect();
+            assert_eq!(placeholders(text), names, "{code}");
+        }
+    }
+
+    #[test]
+    fn every_message_has_a_code_of_the_catalog() {
+        let samples = samples();
+        assert_eq!(samples.len(), CODES.len());
+        for message in samples {
+            let (_, _, params) = TEMPLATES
+                .iter()
+                .find(|(code, _, _)| *code == message.code())
+                .unwrap_or_else(|| panic!("{} is not in the catalog", message.code()));
+            assert_eq!(message.params().len(), params.len(), "{}", message.code());
+
+            let json: serde_json::Value = serde_json::from_str(&message.to_json()).unwrap();
+            assert_eq!(json["code"], message.code());
+            assert_eq!(json["params"].as_object().unwrap().len(), params.len());
+            assert_eq!(json["message"], message.to_string());
+        }
+    }
+
+    #[test]
+    fn parameters_are_rendered() {
+        let message = Message::StageFailed {
+            stage: "post-fs-data".to_string(),
+            reason: "no superkey".to_string(),
+        };
+        assert_eq!(message.to_string(), "post-fs-data failed: no superkey");
+        assert_eq!(
+            message.params(),
+            [("stage", "post-fs-data".to_string()), ("reason", "no superkey".to_string())]
+        );
+    }
+}
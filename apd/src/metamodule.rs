@@ -3,17 +3,50 @@
 //! This module handles all metamodule-related functionality.
 //! Metamodules are special modules that manage how regular modules are mounted
 //! and provide hooks for module installation/uninstallation.
+//!
+//! Several metamodules may be installed. All of them get their stage scripts
+//! run, ordered by `metapriority=` (highest first) and then by id. Only one
+//! owns the mount role, the one `/data/adb/metamodule` links to. Its
+//! metamount.sh, metainstall.sh and metauninstall.sh are the ones used.
 
 use std::{
     collections::HashMap,
+    fs,
     path::{Path, PathBuf},
     process::Command,
 };
 
-use anyhow::{Context, Result, ensure};
+use anyhow::{Context, Result, bail, ensure};
 use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{assets, defs, module::ModuleType::All, utils};
+
+/// What metamount.sh reports to have done, written as JSON to the path in
+/// `APATCH_RESULT_FILE`:
+///
+/// ```json
+/// {"mounted": ["/system/app/Foo"], "skipped": ["some_module"], "errors": []}
+/// ```
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct MountResult {
+    pub mounted: Vec<PathBuf>,
+    /// ids of modules it left out
+    pub skipped: Vec<String>,
+    pub errors: Vec<String>,
+}
 
-use crate::{assets, defs, module::ModuleType::All};
+fn read_result() -> Result<MountResult> {
+    let content = fs::read_to_string(defs::METAMODULE_RESULT_FILE)
+        .context("metamount.sh wrote no result")?;
+    serde_json::from_str(&content).context("metamount.sh wrote a malformed result")
+}
+
+/// Mount points the metamodule reported for this boot, none if it didn't mount
+pub fn reported_mounts() -> Vec<PathBuf> {
+    read_result().map(|result| result.mounted).unwrap_or_default()
+}
 
 /// Determine whether the provided module properties mark it as a metamodule
 pub fn is_metamodule(props: &HashMap<String, String>) -> bool {
@@ -23,7 +56,27 @@ pub fn is_metamodule(props: &HashMap<String, String>) -> bool {
     })
 }
 
-/// Get metamodule path if it exists
+fn metapriority(module_path: &Path) -> i64 {
+    crate::module::read_module_prop(module_path)
+        .ok()
+        .and_then(|props| props.get("metapriority")?.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// All installed metamodules in the order their stage scripts run
+pub fn metamodules() -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let _ = crate::module::foreach_module(All, |module_path| {
+        if crate::module::read_module_prop(module_path).is_ok_and(|props| is_metamodule(&props)) {
+            found.push((metapriority(module_path), module_path.to_path_buf()));
+        }
+        Ok(())
+    });
+    found.sort_by(|(a_priority, a), (b_priority, b)| b_priority.cmp(a_priority).then(a.cmp(b)));
+    found.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Get the path of the metamodule owning the mount role if it exists
 /// The metamodule is stored in /data/adb/modules/{id} with a symlink at /data/adb/metamodule
 pub fn get_metamodule_path() -> Option<PathBuf> {
     let path = Path::new(defs::METAMODULE_DIR);
@@ -48,27 +101,22 @@ pub fn get_metamodule_path() -> Option<PathBuf> {
         );
     }
 
-    // Fallback: search for metamodule=1 in modules directory
-    let mut result = None;
-    let _ = crate::module::foreach_module(All, |module_path| {
-        if let Ok(props) = crate::module::read_module_prop(module_path)
-            && is_metamodule(&props)
-        {
-            info!(
-                "Found metamodule in modules directory: {}",
-                module_path.display()
-            );
-            result = Some(module_path.to_path_buf());
-        }
-        Ok(())
-    });
-
+    // Fallback: the first metamodule able to mount, else the first one
+    let all = metamodules();
+    let result = all
+        .iter()
+        .find(|path| path.join(defs::METAMODULE_MOUNT_SCRIPT).exists())
+        .or(all.first())
+        .cloned();
+    if let Some(path) = &result {
+        info!("Found metamodule in modules directory: {}", path.display());
+    }
     result
 }
 
-/// Check if metamodule exists
-pub fn has_metamodule() -> bool {
-    get_metamodule_path().is_some()
+/// Whether `module_path` is the metamodule owning the mount role
+pub fn is_mount_owner(module_path: &Path) -> bool {
+    get_metamodule_path().is_some_and(|owner| owner.file_name() == module_path.file_name())
 }
 
 /// Check if it's safe to install a regular module
@@ -97,9 +145,9 @@ pub fn check_install_safety() -> Result<(), bool> {
     }
 
     // Check for marker files
-    let has_update = metamodule_path.join(defs::UPDATE_FILE_NAME).exists();
-    let has_remove = metamodule_path.join(defs::REMOVE_FILE_NAME).exists();
-    let has_disable = metamodule_path.join(defs::DISABLE_FILE_NAME).exists();
+    let has_update = crate::flags::is_set(metamodule_path.join(defs::UPDATE_FILE_NAME));
+    let has_remove = crate::flags::is_set(metamodule_path.join(defs::REMOVE_FILE_NAME));
+    let has_disable = crate::flags::is_set(metamodule_path.join(defs::DISABLE_FILE_NAME));
 
     // Stable state (no markers) → safe
     if !has_update && !has_remove && !has_disable {
@@ -173,7 +221,7 @@ pub fn get_install_script(
         info!("Installing metamodule, using default installer");
         install_module_script.to_string()
     } else if let Some(metamodule_path) = get_metamodule_path() {
-        if metamodule_path.join(defs::DISABLE_FILE_NAME).exists() {
+        if crate::flags::is_set(metamodule_path.join(defs::DISABLE_FILE_NAME)) {
             info!("Metamodule is disabled, using default installer");
             install_module_script.to_string()
         } else {
@@ -205,7 +253,7 @@ fn check_metamodule_script(script_name: &str) -> Option<PathBuf> {
     let metamodule_path = get_metamodule_path()?;
 
     // Check if metamodule is disabled
-    if metamodule_path.join(defs::DISABLE_FILE_NAME).exists() {
+    if crate::flags::is_set(metamodule_path.join(defs::DISABLE_FILE_NAME)) {
         info!("Metamodule is disabled, skipping {script_name}");
         return None;
     }
@@ -245,10 +293,18 @@ pub fn exec_metauninstall_script(module_id: &str) -> Result<()> {
     Ok(())
 }
 
-/// Execute metamodule mount script
-pub fn exec_mount_script(module_dir: &str) -> Result<()> {
+/// Forget the result of an earlier mount, so [`reported_mounts`] only ever
+/// describes the current one
+pub fn clear_result() {
+    let _ = fs::remove_file(defs::METAMODULE_RESULT_FILE);
+}
+
+/// Execute metamodule mount script. It must exit successfully and write its
+/// [`MountResult`], otherwise the mount is considered failed.
+pub fn exec_mount_script(module_dir: &str, mount_mode: &str) -> Result<MountResult> {
+    clear_result();
     let Some(mount_script) = check_metamodule_script(defs::METAMODULE_MOUNT_SCRIPT) else {
-        return Ok(());
+        bail!("no enabled metamodule with {}", defs::METAMODULE_MOUNT_SCRIPT);
     };
 
     info!("Executing mount script for metamodule");
@@ -257,6 +313,10 @@ pub fn exec_mount_script(module_dir: &str) -> Result<()> {
         .args(["sh", mount_script.to_str().unwrap()])
         .envs(crate::module::get_common_script_envs())
         .env("MODULE_DIR", module_dir)
+        .env("APATCH_MODULE_DIR", module_dir)
+        .env("APATCH_MOUNT_MODE", mount_mode)
+        .env("APATCH_TMP_DIR", utils::get_tmp_path())
+        .env("APATCH_RESULT_FILE", defs::METAMODULE_RESULT_FILE)
         .status()?;
 
     ensure!(
@@ -265,18 +325,42 @@ pub fn exec_mount_script(module_dir: &str) -> Result<()> {
         result
     );
 
+    let mount_result = read_result()?;
+    for point in &mount_result.mounted {
+        info!("metamodule mounted {}", point.display());
+    }
+    for id in &mount_result.skipped {
+        info!("metamodule skipped module {id}");
+    }
+    for error in &mount_result.errors {
+        warn!("metamodule mount error: {error}");
+    }
     info!("Metamodule mount script executed successfully");
-    Ok(())
+    Ok(mount_result)
 }
 
-/// Execute metamodule script for a specific stage
-pub fn exec_stage_script(stage: &str, block: bool) -> Result<()> {
-    let Some(script_path) = check_metamodule_script(&format!("{stage}.sh")) else {
-        return Ok(());
-    };
+/// Execute the script for a specific stage of every enabled metamodule in
+/// order, returns how many failed. One failing doesn't stop the others.
+pub fn exec_stage_script(stage: &str, block: bool) -> usize {
+    let mut failures = 0;
+    for metamodule in metamodules() {
+        if crate::flags::is_set(metamodule.join(defs::DISABLE_FILE_NAME)) {
+            info!("Metamodule {} is disabled, skipping {stage}.sh", metamodule.display());
+            continue;
+        }
+        let script_path = metamodule.join(format!("{stage}.sh"));
+        if !script_path.exists() {
+            continue;
+        }
 
-    info!("Executing metamodule {stage}.sh");
-    crate::module::exec_script(&script_path, block)?;
-    info!("Metamodule {stage}.sh executed successfully");
-    Ok(())
+        info!("Executing metamodule {}", script_path.display());
+        match crate::module::exec_stage_file(&script_path, block) {
+            Ok(()) => info!("Metamodule {} executed successfully", script_path.display()),
+            Err(e) => {
+                warn!("Failed to exec metamodule {}: {e}", script_path.display());
+                failures += 1;
+            }
+        }
+    }
+    failures
 }
@@ -1,13 +1,18 @@
 #[cfg(unix)]
-use std::os::unix::{prelude::PermissionsExt, process::CommandExt};
+use std::os::unix::{
+    prelude::PermissionsExt,
+    process::{CommandExt, ExitStatusExt},
+};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
     env::var as env_var,
     fs::{self, remove_dir_all},
-    io::Cursor,
+    io::{self, Cursor, IsTerminal},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Child, Command, ExitStatus},
     str::FromStr,
+    thread,
+    time::{Duration, Instant},
 };
 use crate::mpolicy::{get_policy_main};
 use crate::lua;
@@ -16,14 +21,20 @@ use const_format::concatcp;
 use is_executable::is_executable;
 use java_properties::PropertiesIter;
 use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use zip_extensions::zip_extract_file_to_memory;
 
 #[allow(clippy::wildcard_imports)]
 use crate::utils::*;
 use crate::{
-    assets,
+    assets, compat, critical,
     defs::{self, MODULE_DIR, MODULE_UPDATE_DIR},
-    metamodule, restorecon,
+    events::{self, Code},
+    messages::Message,
+    metamodule, profile, restorecon,
+    sandbox::Sandbox,
+    scan_cache::ScanCache,
+    script_history, script_order, timing,
 };
 
 const INSTALLER_CONTENT: &str = include_str!("./installer.sh");
@@ -43,7 +54,183 @@ pub enum ModuleType {
     Updated,
 }
 
-fn exec_install_script(module_file: &str, is_metamodule: bool) -> Result<()> {
+/// What a module brings along, only modules with a payload are mounted
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModuleKind {
+    Payload,
+    ScriptOnly,
+    Hybrid,
+    /// neither a payload nor scripts, e.g. a half extracted module
+    Empty,
+}
+
+impl ModuleKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ModuleKind::Payload => "payload",
+            ModuleKind::ScriptOnly => "script-only",
+            ModuleKind::Hybrid => "hybrid",
+            ModuleKind::Empty => "empty",
+        }
+    }
+
+    pub fn has_payload(self) -> bool {
+        matches!(self, ModuleKind::Payload | ModuleKind::Hybrid)
+    }
+}
+
+/// Files at the module root which are applied without mounting anything
+const ROOT_SCRIPT_FILES: [&str; 2] = ["system.prop", "sepolicy.rule"];
+
+/// Classify a module by its files. A module image or partition dirs holding
+/// anything but dirs count as payload, `.sh` and `.lua` files, `system.prop`
+/// and `sepolicy.rule` at the module root as scripts.
+pub fn classify(module_path: &Path) -> ModuleKind {
+    let has_payload = crate::image::image_of(module_path).is_some()
+        || std::iter::once("system")
+            .chain(critical::PARTITIONS)
+            .any(|partition| {
+                walkdir::WalkDir::new(module_path.join(partition))
+                    .min_depth(1)
+                    .into_iter()
+                    .flatten()
+                    .any(|entry| !entry.file_type().is_dir())
+            });
+    let has_scripts = fs::read_dir(module_path).is_ok_and(|dir| {
+        dir.flatten().any(|entry| {
+            let path = entry.path();
+            path.is_file()
+                && (path
+                    .extension()
+                    .is_some_and(|ext| ext == "sh" || ext == "lua")
+                    || ROOT_SCRIPT_FILES.iter().any(|name| entry.file_name() == *name))
+        })
+    });
+    match (has_payload, has_scripts) {
+        (true, true) => ModuleKind::Hybrid,
+        (true, false) => ModuleKind::Payload,
+        (false, true) => ModuleKind::ScriptOnly,
+        (false, false) => ModuleKind::Empty,
+    }
+}
+
+/// `dependencies=`, `conflicts=` and `priority=` of an enabled module
+struct Relations {
+    dependencies: Vec<String>,
+    conflicts: Vec<String>,
+    priority: i64,
+}
+
+fn prop_list(props: &HashMap<String, String>, key: &str) -> Vec<String> {
+    props
+        .get(key)
+        .map(|list| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Enabled modules which must not be mounted, with the reason. A module is
+/// left out if a dependency is missing, disabled or itself left out. Of two
+/// conflicting modules the one with the lower `priority=` is left out, on a
+/// tie the one whose id sorts last.
+pub fn blocked_modules() -> BTreeMap<String, Message> {
+    let mut enabled = BTreeMap::new();
+    let mut installed = BTreeSet::new();
+    let mut blocked: BTreeMap<String, Message> = BTreeMap::new();
+    let _ = foreach_module(ModuleType::All, |module| {
+        let Some(id) = module.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+            return Ok(());
+        };
+        installed.insert(id.clone());
+        if crate::flags::is_set(module.join(defs::DISABLE_FILE_NAME))
+            || crate::flags::is_set(module.join(defs::REMOVE_FILE_NAME))
+        {
+            return Ok(());
+        }
+        let props = match ModuleInfo::parse(module) {
+            Ok(info) => info.props,
+            Err(e) => {
+                // quarantined, modules depending on it are left out as well
+                let reason = format!("{e:#}");
+                blocked.insert(id.clone(), Message::ModuleQuarantined { reason });
+                HashMap::new()
+            }
+        };
+        let relations = Relations {
+            dependencies: prop_list(&props, "dependencies"),
+            conflicts: prop_list(&props, "conflicts"),
+            priority: props
+                .get("priority")
+                .and_then(|p| p.trim().parse().ok())
+                .unwrap_or(0),
+        };
+        enabled.insert(id, relations);
+        Ok(())
+    });
+
+    loop {
+        let before = blocked.len();
+        for (id, relations) in &enabled {
+            if blocked.contains_key(id) {
+                continue;
+            }
+            let reason = relations.dependencies.iter().find_map(|dep| {
+                let dep = dep.clone();
+                if !installed.contains(&dep) {
+                    Some(Message::DependencyMissing { dep })
+                } else if !enabled.contains_key(&dep) {
+                    Some(Message::DependencyDisabled { dep })
+                } else if blocked.contains_key(&dep) {
+                    Some(Message::DependencyBlocked { dep })
+                } else {
+                    None
+                }
+            });
+            if let Some(reason) = reason {
+                blocked.insert(id.clone(), reason);
+                continue;
+            }
+            // conflicts are declared by either side
+            let winner = enabled.iter().find(|(other, other_relations)| {
+                *other != id
+                    && !blocked.contains_key(*other)
+                    && (relations.conflicts.contains(*other) || other_relations.conflicts.contains(id))
+                    && (other_relations.priority, std::cmp::Reverse(*other))
+                        > (relations.priority, std::cmp::Reverse(id))
+            });
+            if let Some((other, _)) = winner {
+                let other = other.clone();
+                blocked.insert(id.clone(), Message::ConflictsWith { other });
+            }
+        }
+        if blocked.len() == before {
+            break;
+        }
+    }
+    blocked
+}
+
+const INSTALL_TIMEOUT: Duration = Duration::from_secs(300);
+/// Largest file an installer may write while confined
+const INSTALL_FSIZE_LIMIT: libc::rlim_t = 1 << 30;
+
+/// Run the installer, and with it the module's customize.sh.
+///
+/// Unless `writable` is `None` the installer runs in its own mount namespace in
+/// which /data holds nothing but `writable`, the binaries and the zip, with a
+/// file size limit and a deadline which kills its whole process group.
+fn exec_install_script(
+    module_file: &str,
+    is_metamodule: bool,
+    module_data: &Path,
+    writable: Option<Vec<PathBuf>>,
+) -> Result<()> {
     let realpath = std::fs::canonicalize(module_file)
         .with_context(|| format!("realpath: {module_file} failed"))?;
 
@@ -54,66 +241,738 @@ fn exec_install_script(module_file: &str, is_metamodule: bool) -> Result<()> {
         INSTALL_MODULE_SCRIPT.to_string()
     };
 
-    let result = Command::new(assets::BUSYBOX_PATH)
+    let mut command = Command::new(assets::BUSYBOX_PATH);
+    command
         .args(["sh", "-c", &install_script])
         .envs(get_common_script_envs())
         .env("OUTFD", "1")
-        .env("ZIPFILE", realpath)
-        .status()?;
+        .env("MODDATA", module_data)
+        .env("ZIPFILE", &realpath);
+    let Some(writable) = writable else {
+        let result = command.status()?;
+        ensure!(result.success(), "Failed to install module script");
+        return Ok(());
+    };
+
+    let mut readonly = vec![PathBuf::from(defs::BINARY_DIR)];
+    if realpath.starts_with("/data") {
+        readonly.push(realpath);
+    }
+    let confinement = crate::mount::Confinement::new(
+        Path::new("/data"),
+        Path::new(defs::INSTALL_VIEW_DIR),
+        &writable,
+        &readonly,
+    )?;
+    command.process_group(0);
+    unsafe {
+        command.pre_exec(move || {
+            confinement.enter()?;
+            let limit = libc::rlimit {
+                rlim_cur: INSTALL_FSIZE_LIMIT,
+                rlim_max: INSTALL_FSIZE_LIMIT,
+            };
+            if libc::setrlimit(libc::RLIMIT_FSIZE, &limit) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    let (result, _, timed_out) = wait_script(&child, Some(INSTALL_TIMEOUT))?;
+    ensure!(
+        !timed_out,
+        "Module installer did not finish within {}s and was killed",
+        INSTALL_TIMEOUT.as_secs()
+    );
     ensure!(result.success(), "Failed to install module script");
     Ok(())
 }
 
+/// What became of the last update of a module, in `log/updates/<id>.json`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateReport {
+    /// seconds since the epoch
+    pub time: u64,
+    pub boot_id: String,
+    /// why the update was not applied, `None` if it was
+    pub rejected: Option<String>,
+    pub diff: StageDiff,
+}
+
+fn write_update_report(report: &UpdateReport) -> Result<()> {
+    fs::create_dir_all(defs::UPDATE_REPORT_DIR)?;
+    let path = Path::new(defs::UPDATE_REPORT_DIR).join(format!("{}.json", report.diff.id));
+    fs::write(&path, serde_json::to_string_pretty(report)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Why the update staged at `staged` must not replace the installed module,
+/// `None` if it may
+fn rejection(staged: &Path, diff: &StageDiff) -> Option<String> {
+    let new_id = read_module_prop(staged)
+        .ok()
+        .and_then(|props| props.get("id").cloned());
+    if let Some(new_id) = new_id
+        && new_id != diff.id
+    {
+        return Some(format!("module id changes from {} to {new_id}", diff.id));
+    }
+    if let Err(e) = ModuleInfo::parse(staged) {
+        return Some(format!("{e:#}"));
+    }
+    let code = |code: &Option<String>| {
+        code.as_deref()
+            .and_then(|code| code.trim().parse::<i64>().ok())
+    };
+    if crate::config::refuse_downgrade()
+        && let Some(old) = code(&diff.old_version_code)
+        && let Some(new) = code(&diff.new_version_code)
+        && new < old
+    {
+        return Some(format!("versionCode goes down from {old} to {new}"));
+    }
+    None
+}
+
+/// Keep a rejected update in `rejected_updates/<id>` for inspection
+fn reject_update(staged: &Path, id: &str) -> Result<()> {
+    let target = Path::new(defs::MODULE_REJECTED_DIR).join(id);
+    if target.exists() {
+        remove_dir_all(&target)?;
+    }
+    fs::create_dir_all(defs::MODULE_REJECTED_DIR)?;
+    fs::rename(staged, &target)
+        .with_context(|| format!("Failed to move {} to {}", staged.display(), target.display()))?;
+    // a new install leaves nothing behind but the dir the installer created
+    let module_dir = Path::new(MODULE_DIR).join(id);
+    if !module_dir.join("module.prop").exists() {
+        remove_dir_all(&module_dir)?;
+    }
+    Ok(())
+}
+
+/// Move one staged module into place, shared by boot time and `stage-apply`.
+/// An update failing validation is moved aside to `rejected_updates` and the
+/// installed version stays. Returns whether the update was applied.
+fn apply_staged_module(updated_module: &Path) -> Result<bool> {
+    let Some(name) = updated_module.file_name() else {
+        return Ok(false);
+    };
+    let id = name.to_string_lossy().into_owned();
+    let diff = stage_diff_of(updated_module);
+    let rejected = rejection(updated_module, &diff);
+    let summary = diff.summary();
+    let report = UpdateReport {
+        time: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        boot_id: script_history::boot_id(),
+        rejected: rejected.clone(),
+        diff,
+    };
+    if let Err(e) = write_update_report(&report) {
+        warn!("{e:#}");
+    }
+    if let Some(reason) = rejected {
+        warn!("update of {id} rejected: {reason}");
+        reject_update(updated_module, &id)?;
+        let message = Message::UpdateRejected {
+            id: id.clone(),
+            reason,
+        };
+        events::emit_for(Code::UpdateRejected, &id, &message);
+        return Ok(false);
+    }
+
+    let module_dir = Path::new(MODULE_DIR).join(name);
+    if module_dir.exists() {
+        carry_state(&module_dir, updated_module)?;
+        swap_in(updated_module, &module_dir, &id)?;
+    } else {
+        fs::rename(updated_module, &module_dir).with_context(|| {
+            format!("Failed to move {} to {}", updated_module.display(), module_dir.display())
+        })?;
+    }
+    let message = Message::UpdateApplied {
+        id: id.clone(),
+        summary,
+    };
+    events::emit_for(Code::UpdateApplied, &id, &message);
+    Ok(true)
+}
+
+/// Flags an update takes over from the installed module, whatever the zip has
+const CARRIED_FLAGS: [&str; 3] = [
+    defs::DISABLE_FILE_NAME,
+    defs::DISABLE_REASON_FILE_NAME,
+    defs::REMOVE_FILE_NAME,
+];
+
+/// Give the update staged at `staged` the flags of the installed module at
+/// `module_dir`. They are hard linked, so owner and mode stay as they were.
+fn carry_state(module_dir: &Path, staged: &Path) -> Result<()> {
+    for name in CARRIED_FLAGS {
+        let target = staged.join(name);
+        match fs::symlink_metadata(&target) {
+            Ok(metadata) if metadata.is_dir() => remove_dir_all(&target)?,
+            Ok(_) => fs::remove_file(&target)?,
+            Err(_) => {}
+        }
+        let source = module_dir.join(name);
+        if crate::flags::is_set(&source) {
+            fs::hard_link(&source, &target).with_context(|| {
+                format!("Failed to link {} to {}", source.display(), target.display())
+            })?;
+        }
+    }
+    Ok(())
+}
+
 pub fn handle_updated_modules() -> Result<()> {
-    let modules_root = Path::new(MODULE_DIR);
+    let mut applied = false;
     foreach_module(ModuleType::Updated, |updated_module| {
         if !updated_module.is_dir() {
             return Ok(());
         }
+        applied |= apply_staged_module(updated_module)?;
+        Ok(())
+    })?;
+    if applied {
+        // this boot verifies the updates
+        fs::write(defs::MODULE_UPDATE_PENDING_FILE, script_history::boot_id())?;
+    }
+    Ok(())
+}
+
+/// Replace the installed module at `module_dir` by the update at `staged`.
+/// The installed version is renamed aside first and renamed back if the
+/// update cannot take its place, then it becomes the backup.
+fn swap_in(staged: &Path, module_dir: &Path, id: &str) -> Result<()> {
+    // dot names are skipped by the module walks
+    let aside = module_dir.with_file_name(format!(".{id}.replaced"));
+    if aside.exists() {
+        remove_dir_all(&aside)?;
+    }
+    fs::rename(module_dir, &aside)
+        .with_context(|| format!("Failed to move {} aside", module_dir.display()))?;
+    if let Err(e) = fs::rename(staged, module_dir) {
+        if let Err(back) = fs::rename(&aside, module_dir) {
+            warn!("Failed to put {} back: {back}", module_dir.display());
+        }
+        return Err(e).with_context(|| {
+            format!("Failed to move {} to {}", staged.display(), module_dir.display())
+        });
+    }
+    backup_module(&aside, id)
+}
 
-        if let Some(name) = updated_module.file_name() {
-            let module_dir = modules_root.join(name);
-            let mut disabled = false;
-            let mut removed = false;
-            if module_dir.exists() {
-                // If the old module is disabled, we need to also disable the new one
-                disabled = module_dir.join(defs::DISABLE_FILE_NAME).exists();
-                removed = module_dir.join(defs::REMOVE_FILE_NAME).exists();
-                remove_dir_all(&module_dir)?;
-            }
-            std::fs::rename(updated_module, &module_dir)?;
-            if removed {
-                let path = module_dir.join(defs::REMOVE_FILE_NAME);
-                if let Err(e) = ensure_file_exists(&path) {
-                    warn!("Failed to create {}: {e}", path.display());
-                }
-            } else if disabled {
-                let path = module_dir.join(defs::DISABLE_FILE_NAME);
-                if let Err(e) = ensure_file_exists(&path) {
-                    warn!("Failed to create {}: {e}", path.display());
-                }
-            }
+/// Keep the version of module `id` at `module_dir`, replaced by an update, as
+/// its backup. An existing backup is kept, it is the last version which
+/// booted fine.
+fn backup_module(module_dir: &Path, id: &str) -> Result<()> {
+    let backup = Path::new(defs::MODULE_BACKUP_DIR).join(id);
+    if backup.exists() {
+        remove_dir_all(module_dir)?;
+        return Ok(());
+    }
+    fs::create_dir_all(defs::MODULE_BACKUP_DIR)?;
+    fs::rename(module_dir, &backup)
+        .with_context(|| format!("Failed to back up {}", module_dir.display()))?;
+    Ok(())
+}
+
+fn restore_backup(id: &str) -> Result<()> {
+    let backup = Path::new(defs::MODULE_BACKUP_DIR).join(id);
+    let module_dir = Path::new(MODULE_DIR).join(id);
+    if module_dir.exists() {
+        remove_dir_all(&module_dir)?;
+    }
+    fs::rename(&backup, &module_dir)
+        .with_context(|| format!("Failed to restore {}", backup.display()))?;
+    info!("module {id} rolled back to its previous version");
+    Ok(())
+}
+
+/// Drop the pending marker once no backup is left
+fn finish_rollback() -> Result<()> {
+    if fs::read_dir(defs::MODULE_BACKUP_DIR).is_ok_and(|mut dir| dir.next().is_none()) {
+        fs::remove_dir(defs::MODULE_BACKUP_DIR)?;
+    }
+    if !Path::new(defs::MODULE_BACKUP_DIR).exists() {
+        let _ = fs::remove_file(defs::MODULE_UPDATE_PENDING_FILE);
+    }
+    Ok(())
+}
+
+/// Called in post-fs-data before updates are applied. The pending marker holds
+/// the boot id of the boot verifying the updates, or nothing if they were
+/// applied at runtime. If that boot never completed, all backups are restored.
+pub fn check_pending_updates() -> Result<()> {
+    let Ok(pending) = fs::read_to_string(defs::MODULE_UPDATE_PENDING_FILE) else {
+        return Ok(());
+    };
+    let boot_id = script_history::boot_id();
+    let pending = pending.trim();
+    if pending.is_empty() || pending == boot_id {
+        fs::write(defs::MODULE_UPDATE_PENDING_FILE, boot_id)?;
+        return Ok(());
+    }
+
+    warn!("the last boot with updated modules did not complete, rolling them back");
+    let ids: Vec<String> = fs::read_dir(defs::MODULE_BACKUP_DIR)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    for id in ids {
+        match restore_backup(&id) {
+            Ok(()) => events::emit_for(
+                Code::UpdatesRolledBack,
+                &id,
+                &Message::UpdateRolledBack { id: id.clone() },
+            ),
+            Err(e) => warn!("Failed to roll back {id}: {e:#}"),
+        }
+    }
+    finish_rollback()
+}
+
+/// Called at boot-completed, updated modules survived a full boot
+pub fn verify_updates() -> Result<()> {
+    let Ok(pending) = fs::read_to_string(defs::MODULE_UPDATE_PENDING_FILE) else {
+        return Ok(());
+    };
+    if pending.trim() != script_history::boot_id() {
+        return Ok(());
+    }
+    if Path::new(defs::MODULE_BACKUP_DIR).exists() {
+        remove_dir_all(defs::MODULE_BACKUP_DIR)?;
+    }
+    fs::remove_file(defs::MODULE_UPDATE_PENDING_FILE)?;
+    info!("module updates verified, backups removed");
+    Ok(())
+}
+
+/// Put back the version of module <id> from before its unverified update
+pub fn rollback_module(id: &str) -> Result<()> {
+    let _guard = lock_modules()?;
+    ensure!(
+        Path::new(defs::MODULE_BACKUP_DIR).join(id).is_dir(),
+        "module: {} has no previous version to roll back to",
+        id
+    );
+    restore_backup(id)?;
+    finish_rollback()?;
+    println!("- Rolled back {id}, reboot to apply");
+    Ok(())
+}
+
+fn staged_modules(only: Option<&str>) -> Result<Vec<PathBuf>> {
+    let mut staged = Vec::new();
+    foreach_module(ModuleType::Updated, |updated_module| {
+        let id = updated_module.file_name().map(|n| n.to_string_lossy());
+        if updated_module.is_dir() && (only.is_none() || id.as_deref() == only) {
+            staged.push(updated_module.to_path_buf());
         }
         Ok(())
     })?;
+    if let Some(id) = only {
+        ensure!(!staged.is_empty(), "module: {} has no staged update", id);
+    }
+    Ok(staged)
+}
+
+pub fn stage_status() -> Result<()> {
+    if crate::output::json() {
+        let staged = if Path::new(MODULE_UPDATE_DIR).exists() {
+            staged_modules(None)?
+        } else {
+            Vec::new()
+        };
+        let diffs: Vec<_> = staged.iter().map(stage_diff_of).collect();
+        return crate::output::print("module stage-status", diffs);
+    }
+    if !Path::new(MODULE_UPDATE_DIR).exists() {
+        println!("No staged updates");
+        return Ok(());
+    }
+    for staged in staged_modules(None)? {
+        let diff = stage_diff_of(&staged);
+        println!("{}: {}", diff.id, diff.summary());
+    }
+    Ok(())
+}
+
+/// Relative paths of all files below `dir`
+fn list_files(dir: &Path) -> BTreeSet<PathBuf> {
+    if !dir.exists() {
+        return BTreeSet::new();
+    }
+    jwalk::WalkDir::new(dir)
+        .parallelism(jwalk::Parallelism::Serial)
+        .into_iter()
+        .flatten()
+        .filter(|entry| !entry.file_type().is_dir())
+        .filter_map(|entry| entry.path().strip_prefix(dir).ok().map(Path::to_path_buf))
+        .collect()
+}
+
+fn print_stage_diff(staged: &Path) -> Result<()> {
+    let Some(name) = staged.file_name() else {
+        return Ok(());
+    };
+    let mut cache = ScanCache::load();
+    let changes = file_changes(&Path::new(MODULE_DIR).join(name), staged, &mut cache);
+    cache.store();
+    let total = changes.values().fold(FileChanges::default(), |total, dir| FileChanges {
+        added: total.added + dir.added,
+        removed: total.removed + dir.removed,
+        changed: total.changed + dir.changed,
+    });
+    println!(
+        "{}: {} added, {} removed, {} modified",
+        name.to_string_lossy(),
+        total.added,
+        total.removed,
+        total.changed
+    );
+    Ok(())
+}
+
+/// Files an update adds, removes or changes below one top-level dir
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct FileChanges {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+}
+
+/// File changes from the module at `current` to the one at `staged` by their
+/// top-level dir, files at the top level count for `.`
+fn file_changes(
+    current: &Path,
+    staged: &Path,
+    cache: &mut ScanCache,
+) -> BTreeMap<String, FileChanges> {
+    let old = list_files(current);
+    let new = list_files(staged);
+    let top = |file: &Path| match file.parent().and_then(|parent| parent.iter().next()) {
+        Some(dir) => dir.to_string_lossy().into_owned(),
+        None => ".".to_string(),
+    };
+    let mut changes: BTreeMap<String, FileChanges> = BTreeMap::new();
+    for file in new.difference(&old) {
+        changes.entry(top(file)).or_default().added += 1;
+    }
+    for file in old.difference(&new) {
+        changes.entry(top(file)).or_default().removed += 1;
+    }
+    for file in new.intersection(&old) {
+        // our own state flags are carried over, not replaced
+        if matches!(
+            file.to_str(),
+            Some(defs::DISABLE_FILE_NAME | defs::REMOVE_FILE_NAME)
+        ) {
+            continue;
+        }
+        if cache.hash(&current.join(file)) != cache.hash(&staged.join(file)) {
+            changes.entry(top(file)).or_default().changed += 1;
+        }
+    }
+    changes
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScriptChange {
+    pub path: String,
+    /// `added`, `removed` or `modified`
+    pub change: String,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+}
+
+/// What a staged update changes compared to the installed module
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StageDiff {
+    pub id: String,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    pub old_version_code: Option<String>,
+    pub new_version_code: Option<String>,
+    pub scripts: Vec<ScriptChange>,
+    pub payload_size_delta: i64,
+    pub new_partitions: Vec<String>,
+    pub new_critical_paths: Vec<String>,
+    /// by top-level dir of the module
+    #[serde(default)]
+    pub files: BTreeMap<String, FileChanges>,
+}
+
+impl StageDiff {
+    pub fn summary(&self) -> String {
+        let mut parts = vec![format!(
+            "{} -> {}",
+            self.old_version.as_deref().unwrap_or("(new)"),
+            self.new_version.as_deref().unwrap_or("-")
+        )];
+        if !self.scripts.is_empty() {
+            parts.push(format!("{} script(s) changed", self.scripts.len()));
+        }
+        parts.push(format!("{:+} bytes", self.payload_size_delta));
+        if !self.new_partitions.is_empty() {
+            parts.push(format!("new partitions: {}", self.new_partitions.join(",")));
+        }
+        if !self.new_critical_paths.is_empty() {
+            parts.push(format!(
+                "{} new critical path(s)",
+                self.new_critical_paths.len()
+            ));
+        }
+        parts.join(", ")
+    }
+}
+
+fn is_script(path: &Path) -> bool {
+    path.components().count() == 1
+        && path
+            .extension()
+            .is_some_and(|ext| ext == "sh" || ext == "lua")
+}
+
+fn payload_size(dir: &Path, cache: &mut ScanCache) -> i64 {
+    cache.disk_usage(dir).bytes as i64
+}
+
+fn partitions_of(dir: &Path) -> BTreeSet<String> {
+    std::iter::once("system")
+        .chain(critical::PARTITIONS)
+        .filter(|partition| dir.join(partition).is_dir())
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn stage_diff_of(staged: &Path) -> StageDiff {
+    let id = staged
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let current = Path::new(MODULE_DIR).join(&id);
+    let old_props = read_module_prop(&current).unwrap_or_default();
+    let new_props = read_module_prop(staged).unwrap_or_default();
+
+    let old_files = list_files(&current);
+    let new_files = list_files(staged);
+    let mut cache = ScanCache::load();
+    let mut hash = |path: PathBuf| cache.hash(&path);
+    let mut scripts = Vec::new();
+    for file in old_files.union(&new_files).filter(|file| is_script(file)) {
+        let old_hash = old_files
+            .contains(file)
+            .then(|| hash(current.join(file)))
+            .flatten();
+        let new_hash = new_files
+            .contains(file)
+            .then(|| hash(staged.join(file)))
+            .flatten();
+        let change = match (&old_hash, &new_hash) {
+            (None, Some(_)) => "added",
+            (Some(_), None) => "removed",
+            (old, new) if old != new => "modified",
+            _ => continue,
+        };
+        scripts.push(ScriptChange {
+            path: file.display().to_string(),
+            change: change.to_string(),
+            old_hash,
+            new_hash,
+        });
+    }
+
+    let patterns = critical::Patterns::load();
+    let old_critical: BTreeSet<String> =
+        cache.critical(&patterns, &current).into_iter().collect();
+    let diff = StageDiff {
+        old_version: old_props.get("version").cloned(),
+        new_version: new_props.get("version").cloned(),
+        old_version_code: old_props.get("versionCode").cloned(),
+        new_version_code: new_props.get("versionCode").cloned(),
+        scripts,
+        payload_size_delta: payload_size(staged, &mut cache)
+            - payload_size(&current, &mut cache),
+        new_partitions: partitions_of(staged)
+            .difference(&partitions_of(&current))
+            .cloned()
+            .collect(),
+        new_critical_paths: cache
+            .critical(&patterns, staged)
+            .into_iter()
+            .filter(|path| !old_critical.contains(path))
+            .collect(),
+        files: file_changes(&current, staged, &mut cache),
+        id,
+    };
+    cache.store();
+    diff
+}
+
+/// `apd module changelog <id>`: the report of the last update of module `id`
+pub fn changelog(id: &str) -> Result<()> {
+    let path = Path::new(defs::UPDATE_REPORT_DIR).join(format!("{id}.json"));
+    ensure!(path.exists(), "module: {} has no update report", id);
+    let content =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let report: UpdateReport = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    if crate::output::json() {
+        return crate::output::print("module changelog", report);
+    }
+    match &report.rejected {
+        Some(reason) => println!(
+            "rejected at {}, kept in {}{id}: {reason}",
+            report.time,
+            defs::MODULE_REJECTED_DIR
+        ),
+        None => println!("applied at {}", report.time),
+    }
+    print_diff(&report.diff);
+    Ok(())
+}
+
+pub fn stage_diff(id: &str, json: bool) -> Result<()> {
+    let staged = staged_modules(Some(id))?;
+    let diff = stage_diff_of(&staged[0]);
+    if json {
+        return crate::output::print("module stage-diff", diff);
+    }
+    print_diff(&diff);
+    Ok(())
+}
+
+fn print_diff(diff: &StageDiff) {
+    println!("{}", diff.id);
+    println!(
+        "version: {} -> {}",
+        diff.old_version.as_deref().unwrap_or("(new)"),
+        diff.new_version.as_deref().unwrap_or("-")
+    );
+    println!(
+        "versionCode: {} -> {}",
+        diff.old_version_code.as_deref().unwrap_or("(new)"),
+        diff.new_version_code.as_deref().unwrap_or("-")
+    );
+    for script in &diff.scripts {
+        println!(
+            "{} {}: {} -> {}",
+            script.change,
+            script.path,
+            script.old_hash.as_deref().unwrap_or("-"),
+            script.new_hash.as_deref().unwrap_or("-")
+        );
+    }
+    println!("payload size: {:+} bytes", diff.payload_size_delta);
+    if !diff.new_partitions.is_empty() {
+        println!("new partitions: {}", diff.new_partitions.join(", "));
+    }
+    for path in &diff.new_critical_paths {
+        println!("new critical path: {path}");
+    }
+    for (dir, changes) in &diff.files {
+        println!(
+            "{dir}: {} added, {} removed, {} modified",
+            changes.added, changes.removed, changes.changed
+        );
+    }
+}
+
+/// Apply staged updates on a booted system through the boot time code path.
+/// Updates of modules with files mounted stay staged for the next boot.
+pub fn stage_apply(only: Option<&str>, dry_run: bool) -> Result<()> {
+    ensure_boot_completed()?;
+    ensure!(Path::new(MODULE_UPDATE_DIR).exists(), "No staged updates");
+    let _guard = lock_modules()?;
+    let staged = staged_modules(only)?;
+
+    let metamodule = metamodule::get_metamodule_path();
+    for module in &staged {
+        let is_metamodule = metamodule
+            .as_ref()
+            .is_some_and(|path| path.file_name() == module.file_name())
+            || read_module_prop(module).is_ok_and(|props| metamodule::is_metamodule(&props));
+        ensure!(
+            !is_metamodule,
+            "{} is a metamodule, reboot to apply its update",
+            module.display()
+        );
+    }
+
+    // the files of a mounted module are in use, its update waits for a boot
+    let mounts = crate::mount::module_mounts()?;
+    let mut applied = false;
+    for module in &staged {
+        let id = module.file_name().unwrap_or_default().to_string_lossy();
+        let in_use = mounts.get(&*id).map_or(0, Vec::len);
+        if in_use > 0 {
+            println!("- Deferred {id}: {in_use} file mount(s) in use, applied on the next boot");
+            continue;
+        }
+        if dry_run {
+            print_stage_diff(module)?;
+        } else if apply_staged_module(module)? {
+            applied = true;
+            println!("- Applied {}", module.display());
+        } else {
+            println!("- Rejected {}, see apd module changelog", module.display());
+        }
+    }
+    if applied && !Path::new(defs::MODULE_UPDATE_PENDING_FILE).exists() {
+        // verified by the next boot
+        fs::write(defs::MODULE_UPDATE_PENDING_FILE, "")?;
+    }
+    if !dry_run && fs::read_dir(MODULE_UPDATE_DIR).is_ok_and(|mut dir| dir.next().is_none()) {
+        fs::remove_dir(MODULE_UPDATE_DIR)?;
+    }
     Ok(())
 }
 
-/// Get common environment variables for script execution
+/// PATH for scripts, with our binaries and the compat shims when enabled
+fn script_path_env() -> String {
+    let mut path = format!(
+        "{}:{}",
+        env_var("PATH").unwrap_or_default(),
+        defs::BINARY_DIR.trim_end_matches('/')
+    );
+    if compat::is_enabled() {
+        path.push(':');
+        path.push_str(defs::COMPAT_BIN_DIR.trim_end_matches('/'));
+    }
+    path
+}
+
+/// Environment of every script apd runs, stage scripts of all stages included:
+///
+/// * `APATCH=true`, `APATCH_VER`, `APATCH_VER_CODE`: the running apd
+/// * `KERNELPATCH_VERSION`: as passed to apd by the kernel, empty if unknown
+/// * `MOUNT_MODE`: the configured mount mode, `magic`, `metamodule` or `disabled`
+/// * `MOUNT_DECISION`: the strategy which mounted the modules this boot, `none`
+///   if all failed and empty before the modules are mounted
 pub fn get_common_script_envs() -> Vec<(&'static str, String)> {
+    let decision = crate::status::MountDecision::current()
+        .map(|decision| decision.selected)
+        .unwrap_or_default();
     vec![
         ("ASH_STANDALONE", "1".to_string()),
         ("APATCH", "true".to_string()),
         ("APATCH_VER", defs::VERSION_NAME.to_string()),
         ("APATCH_VER_CODE", defs::VERSION_CODE.to_string()),
         (
-            "PATH",
-            format!(
-                "{}:{}",
-                env_var("PATH").unwrap_or_default(),
-                defs::BINARY_DIR.trim_end_matches('/')
-            ),
+            "KERNELPATCH_VERSION",
+            env_var("KERNELPATCH_VERSION").unwrap_or_default(),
         ),
+        ("MOUNT_MODE", get_mount_mode()),
+        ("MOUNT_DECISION", decision),
+        ("PATH", script_path_env()),
     ]
 }
 
@@ -129,12 +988,68 @@ fn ensure_boot_completed() -> Result<()> {
     Ok(())
 }
 
-fn mark_update() -> Result<()> {
+pub fn mark_update() -> Result<()> {
     ensure_file_exists(concatcp!(defs::WORKING_DIR, defs::UPDATE_FILE_NAME))
 }
 
+/// Who disabled a module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Disabled {
+    User,
+    /// apd did, e.g. bootloop protection, with the code of the reason
+    System(String),
+}
+
+/// Who disabled the module at `module`, `None` if it is enabled
+pub fn disabled_by(module: &Path) -> Option<Disabled> {
+    if !crate::flags::is_set(module.join(defs::DISABLE_FILE_NAME)) {
+        return None;
+    }
+    let reason = module.join(defs::DISABLE_REASON_FILE_NAME);
+    if !crate::flags::is_set(&reason) {
+        return Some(Disabled::User);
+    }
+    let reason = fs::read_to_string(reason).unwrap_or_default();
+    Some(Disabled::System(reason.trim().to_string()))
+}
+
+/// Record that apd disabled the module at `module` for `reason`
+fn write_disable_reason(module: &Path, reason: &str) -> Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    let path = module.join(defs::DISABLE_REASON_FILE_NAME);
+    clear_disable_reason(module)?;
+    // created anew, a link shipped by the module is not followed
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| writeln!(file, "{reason}"))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Forget why apd disabled the module at `module`, the user decides now
+fn clear_disable_reason(module: &Path) -> Result<()> {
+    let path = module.join(defs::DISABLE_REASON_FILE_NAME);
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
 fn mark_module_state(module: &str, flag_file: &str, create_or_delete: bool) -> Result<()> {
-    let module_state_file = Path::new(defs::MODULE_DIR).join(module).join(flag_file);
+    set_module_flag(&Path::new(defs::MODULE_DIR).join(module), flag_file, create_or_delete)
+}
+
+/// Set or clear `flag_file` of the module dir `module`
+fn set_module_flag(module: &Path, flag_file: &str, create_or_delete: bool) -> Result<()> {
+    let module_state_file = module.join(flag_file);
+    if flag_file == defs::DISABLE_FILE_NAME {
+        clear_disable_reason(module)?;
+    }
     if create_or_delete {
         ensure_file_exists(module_state_file)
     } else {
@@ -155,17 +1070,31 @@ pub fn foreach_module(
     let dir = std::fs::read_dir(modules_dir)?;
     for entry in dir.flatten() {
         let path = entry.path();
+        // module ids start with a letter, these are leftovers of an extraction
+        if entry.file_name().as_encoded_bytes().starts_with(b".") {
+            continue;
+        }
         if !path.is_dir() {
             warn!("{} is not a directory, skip", path.display());
             continue;
         }
 
-        if module_type == ModuleType::Active && path.join(defs::DISABLE_FILE_NAME).exists() {
-            info!("{} is disabled, skip", path.display());
+        if module_type == ModuleType::Active
+            && crate::flags::is_set(path.join(defs::DISABLE_FILE_NAME))
+        {
+            info!("{}", Message::ModuleDisabled { path: path.display().to_string() });
+            continue;
+        }
+        if module_type == ModuleType::Active
+            && crate::flags::is_set(path.join(defs::REMOVE_FILE_NAME))
+        {
+            warn!("{}", Message::ModuleRemoved { path: path.display().to_string() });
             continue;
         }
-        if module_type == ModuleType::Active && path.join(defs::REMOVE_FILE_NAME).exists() {
-            warn!("{} is removed, skip", path.display());
+        if module_type == ModuleType::Active
+            && let Err(e) = ModuleInfo::parse(&path)
+        {
+            warn!("{} is quarantined: {e:#}", path.display());
             continue;
         }
 
@@ -179,79 +1108,469 @@ fn foreach_active_module(f: impl FnMut(&Path) -> Result<()>) -> Result<()> {
     foreach_module(ModuleType::Active, f)
 }
 
-pub fn load_sepolicy_rule() -> Result<()> {
-    foreach_active_module(|path| {
-        let rule_file = path.join("sepolicy.rule");
-        if !rule_file.exists() {
-            return Ok(());
+/// Outcome of applying the sepolicy.rule of one module
+pub struct RuleResult {
+    pub id: String,
+    pub rules: usize,
+    pub error: Option<String>,
+}
+
+impl RuleResult {
+    fn log_line(&self, stage: &str) -> String {
+        match &self.error {
+            None => format!("[{stage}] {}: {} rule(s) applied", self.id, self.rules),
+            Some(e) => format!("[{stage}] {}: {} rule(s) failed: {e}", self.id, self.rules),
         }
+    }
+}
+
+fn apply_sepolicy_rule(module: &Path) -> Option<RuleResult> {
+    let rule_file = module.join("sepolicy.rule");
+    let content = fs::read_to_string(&rule_file).ok()?;
+    let id = module.file_name()?.to_string_lossy().into_owned();
+    let rules = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .count();
+
+    info!("load policy: {}", &rule_file.display());
+    let error = get_policy_main(&[
+        "magiskpolicy".to_string(),
+        "--live".to_string(),
+        "--apply".to_string(),
+        rule_file.display().to_string(),
+    ])
+    .err()
+    .map(|e| format!("{e:#}"));
+    if let Some(e) = &error {
+        warn!("sepolicy.rule of {id} failed: {e}");
+    }
+    Some(RuleResult { id, rules, error })
+}
 
-        info!("load policy: {}", &rule_file.display());
-        let mut _sepol = get_policy_main(&[
-            "magiskpolicy".to_string(),
-            "--live".to_string(),
-            "--apply".to_string(),
-            rule_file.display().to_string()
-        ])?;
+fn write_sepolicy_log(stage: &str, results: &[RuleResult], append: bool) -> Result<()> {
+    let mut lines: Vec<String> = results.iter().map(|r| r.log_line(stage)).collect();
+    lines.push(String::new());
+    let mut content = lines.join("\n");
+    if append {
+        content = fs::read_to_string(defs::SEPOLICY_LOG_FILE).unwrap_or_default() + &content;
+    }
+    fs::create_dir_all(defs::APATCH_LOG_FOLDER)?;
+    fs::write(defs::SEPOLICY_LOG_FILE, content)
+        .with_context(|| format!("Failed to write {}", defs::SEPOLICY_LOG_FILE))
+}
 
+/// Apply the sepolicy.rule of every active module on its own, so one broken
+/// rule file doesn't take the others down. Failed modules are retried once in
+/// the service stage, see [`retry_sepolicy_rules`].
+pub fn load_sepolicy_rule() -> Result<Vec<RuleResult>> {
+    let _ = fs::remove_file(defs::SEPOLICY_RULE_FAILED_FILE);
+    let mut results = Vec::new();
+    foreach_active_module(|path| {
+        results.extend(apply_sepolicy_rule(path));
         Ok(())
     })?;
 
+    if let Err(e) = write_sepolicy_log("post-fs-data", &results, false) {
+        warn!("{e:#}");
+    }
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|r| r.error.is_some())
+        .map(|r| r.id.as_str())
+        .collect();
+    if failed.is_empty() {
+        let _ = fs::remove_file(defs::SEPOLICY_RULE_RETRY_FILE);
+    } else {
+        fs::write(defs::SEPOLICY_RULE_RETRY_FILE, failed.join("\n") + "\n")
+            .with_context(|| format!("Failed to write {}", defs::SEPOLICY_RULE_RETRY_FILE))?;
+    }
+    Ok(results)
+}
+
+/// Apply the rules which failed in post-fs-data once more. Modules failing
+/// again are listed in `sepolicy_rule_failed` for the manager.
+pub fn retry_sepolicy_rules() -> Result<()> {
+    let Ok(pending) = fs::read_to_string(defs::SEPOLICY_RULE_RETRY_FILE) else {
+        return Ok(());
+    };
+    let _ = fs::remove_file(defs::SEPOLICY_RULE_RETRY_FILE);
+
+    let results: Vec<RuleResult> = pending
+        .lines()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| Path::new(MODULE_DIR).join(id))
+        .filter(|module| {
+            !crate::flags::is_set(module.join(defs::DISABLE_FILE_NAME))
+                && !crate::flags::is_set(module.join(defs::REMOVE_FILE_NAME))
+        })
+        .filter_map(|module| apply_sepolicy_rule(&module))
+        .collect();
+    if results.is_empty() {
+        return Ok(());
+    }
+    write_sepolicy_log("service", &results, true)?;
+
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|r| r.error.is_some())
+        .map(|r| r.id.as_str())
+        .collect();
+    info!(
+        "sepolicy.rule retry: {} recovered, {} still failing",
+        results.len() - failed.len(),
+        failed.len()
+    );
+    if !failed.is_empty() {
+        fs::write(defs::SEPOLICY_RULE_FAILED_FILE, failed.join("\n") + "\n")
+            .with_context(|| format!("Failed to write {}", defs::SEPOLICY_RULE_FAILED_FILE))?;
+    }
     Ok(())
 }
 
+const DEFAULT_SCRIPT_TIMEOUT: Duration = Duration::from_secs(35);
+
+/// Deadline of scripts which block a boot stage, `0` in `script_timeout` disables it
+fn script_timeout() -> Option<Duration> {
+    match crate::config::script_timeout() {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(DEFAULT_SCRIPT_TIMEOUT),
+    }
+}
+
+/// Reap a script together with the rusage of its whole tree. Once `timeout`
+/// expires its process group is killed, so whatever it started goes too.
+pub fn wait_script(
+    child: &Child,
+    timeout: Option<Duration>,
+) -> io::Result<(ExitStatus, libc::rusage, bool)> {
+    let pid = child.id() as libc::pid_t;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let mut timed_out = false;
+    loop {
+        let flags = if deadline.is_some() && !timed_out { libc::WNOHANG } else { 0 };
+        let ret = unsafe { libc::wait4(pid, &mut status, flags, &mut usage) };
+        if ret == pid {
+            break;
+        }
+        if ret == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            unsafe { libc::killpg(pid, libc::SIGKILL) };
+            timed_out = true;
+        } else {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+    Ok((ExitStatus::from_raw(status), usage, timed_out))
+}
+
+/// A script ran to its end but exited unsuccessfully
+#[derive(Debug)]
+pub struct ScriptFailed {
+    pub path: PathBuf,
+    pub status: ExitStatus,
+}
+
+impl std::fmt::Display for ScriptFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} exited with {}", self.path.display(), self.status)
+    }
+}
+
+impl std::error::Error for ScriptFailed {}
+
+/// A script which ran to its end, `status` of it if it was waited for
+fn exit_ok(path: &Path, status: Option<ExitStatus>) -> Result<()> {
+    match status {
+        Some(status) if !status.success() => Err(ScriptFailed {
+            path: path.to_path_buf(),
+            status,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
 pub fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
-    info!("exec {}", path.as_ref().display());
+    run_script(path.as_ref(), wait, None, None, false).map(|_| ())
+}
 
-    let mut command = &mut Command::new(assets::BUSYBOX_PATH);
+/// Run a stage script outside of the module stage loop, e.g. of a metamodule.
+/// An unsuccessful exit is a [`ScriptFailed`] like for module stage scripts.
+pub fn exec_stage_file<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
+    let path = path.as_ref();
+    let status = run_script(path, wait, None, Some(Sandbox::for_script(path)), true)?;
+    exit_ok(path, status)
+}
+
+/// Run `program` for the module at `module_dir`, with the restrictions and the
+/// deadline of its stage scripts. Returns the exit code, `None` if killed by a
+/// signal, and what it wrote to stdout.
+pub fn run_for_module(
+    module_dir: &Path,
+    program: &str,
+    args: &[String],
+) -> Result<(Option<i32>, String)> {
+    use std::{io::Read, process::Stdio};
+
+    let sandbox = Sandbox::for_module(module_dir);
+    info!("exec {program} for {} ({})", module_dir.display(), sandbox.describe());
+    let mut command = Command::new(program);
+    command.process_group(0);
+    unsafe {
+        command.pre_exec(move || {
+            switch_cgroups();
+            sandbox.apply()
+        });
+    }
+    let mut child = command
+        .current_dir(module_dir)
+        .args(args)
+        .envs(get_common_script_envs())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to exec {program}"))?;
+    // read meanwhile, a full pipe would block the child until it is killed
+    let mut stdout = child.stdout.take().context("no stdout pipe")?;
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stdout.read_to_end(&mut output);
+        output
+    });
+    let start = Instant::now();
+    let (status, _, timed_out) = wait_script(&child, script_timeout())?;
+    let output = reader.join().unwrap_or_default();
+    ensure!(
+        !timed_out,
+        "{program} did not finish within {}s and was killed",
+        start.elapsed().as_secs()
+    );
+    Ok((status.code(), String::from_utf8_lossy(&output).into_owned()))
+}
+
+/// Run `path` with busybox sh, stage scripts get a `sandbox`. With `log` the
+/// output goes to the script log instead of apd's, see [`script_history`].
+/// Returns the exit status if `wait`, an unsuccessful one is not an error.
+fn run_script(
+    path: &Path,
+    wait: bool,
+    timeout: Option<Duration>,
+    sandbox: Option<Sandbox>,
+    log: bool,
+) -> Result<Option<ExitStatus>> {
+    match &sandbox {
+        Some(sandbox) => info!("exec {} ({})", path.display(), sandbox.describe()),
+        None => info!("exec {}", path.display()),
+    }
+
+    let mut command = script_command(Path::new(assets::BUSYBOX_PATH), path);
     #[cfg(unix)]
     {
-        command = command.process_group(0);
-        command = unsafe {
-            command.pre_exec(|| {
+        command.process_group(0);
+        unsafe {
+            command.pre_exec(move || {
                 // ignore the error?
                 switch_cgroups();
-                Ok(())
+                match &sandbox {
+                    Some(sandbox) => sandbox.apply(),
+                    None => Ok(()),
+                }
             })
         };
     }
-    command = command
-        .current_dir(path.as_ref().parent().unwrap())
-        .arg("sh")
-        .arg(path.as_ref())
-        .env("ASH_STANDALONE", "1")
-        .env("APATCH", "true")
-        .env("APATCH_VER", defs::VERSION_NAME)
-        .env("APATCH_VER_CODE", defs::VERSION_CODE)
-        .env(
-            "PATH",
-            format!(
-                "{}:{}",
-                env_var("PATH")?,
-                defs::BINARY_DIR.trim_end_matches('/')
-            ),
-        );
 
+    let capture = script_history::Capture::start(path, &mut command, wait, log);
+    let start = Instant::now();
+    let mut timed_out = false;
     let result = if wait {
-        command.status().map(|_| ())
+        command.spawn().and_then(|child| {
+            let (status, usage, killed) = wait_script(&child, timeout)?;
+            timing::record_script(path, start, Some(start.elapsed()));
+            if profile::is_active() {
+                profile::record_script(path, start.elapsed(), &usage);
+            }
+            timed_out = killed;
+            Ok(Some(status))
+        })
     } else {
-        command.spawn().map(|_| ())
+        timing::record_script(path, start, None);
+        command.spawn().map(|_| None)
     };
-    result.map_err(|err| anyhow!("Failed to exec {}: {}", path.as_ref().display(), err))
+    if let Some(capture) = capture {
+        capture.finish(result.as_ref().ok().and_then(Option::as_ref));
+    }
+    match result {
+        Ok(_) if timed_out => bail!(
+            "{} did not finish within {}s and was killed",
+            path.display(),
+            start.elapsed().as_secs()
+        ),
+        Ok(status) => Ok(status),
+        Err(err) => Err(anyhow!("Failed to exec {}: {}", path.display(), err)),
+    }
 }
 
-pub fn exec_stage_script(stage: &str, block: bool) -> Result<()> {
+/// `<busybox> sh <path>` in the dir of the script, with the common environment
+fn script_command(busybox: &Path, path: &Path) -> Command {
+    let mut command = Command::new(busybox);
+    command
+        .current_dir(path.parent().unwrap())
+        .arg("sh")
+        .arg(path)
+        .envs(get_common_script_envs());
+    command
+}
+
+/// Remember the modules which should have been mounted this boot but have no
+/// mount, their stage scripts are skipped
+pub fn record_mount_failures() -> Result<()> {
+    use crate::daemon::Event;
+
+    let mounted = crate::mount::module_mounts()?;
+    let failed: Vec<String> = crate::api::get_mount_report()?
+        .modules
+        .into_iter()
+        .filter(|module| module.mounted && !mounted.contains_key(&module.id))
+        .map(|module| module.id)
+        .collect();
+    crate::daemon::publish(
+        mounted
+            .keys()
+            .map(|id| Event::ModuleMounted { id: id.clone() })
+            .chain(failed.iter().map(|id| Event::ModuleMountFailed { id: id.clone() })),
+    );
+    for id in mounted.keys() {
+        events::emit_for(Code::ModuleMounted, id, &Message::ModuleMounted { id: id.clone() });
+    }
+    for id in &failed {
+        let message = Message::ModuleNotMounted { id: id.clone() };
+        events::emit_for(Code::ModuleMountFailed, id, &message);
+    }
+    if failed.is_empty() {
+        let _ = fs::remove_file(defs::MOUNT_FAILED_FILE);
+        return Ok(());
+    }
+    warn!("module(s) failed to mount: {}", failed.join(", "));
+    fs::write(defs::MOUNT_FAILED_FILE, failed.join("\n") + "\n")
+        .with_context(|| format!("Failed to write {}", defs::MOUNT_FAILED_FILE))
+}
+
+fn mount_failed_modules() -> BTreeSet<String> {
+    fs::read_to_string(defs::MOUNT_FAILED_FILE)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Run `stage.sh` of every active module. The disable and remove flags are
+/// checked right before each script, a module whose files failed to mount this
+/// boot is skipped as well.
+pub fn exec_stage_script(stage: &str, block: bool) -> Result<usize> {
+    let timeout = if block { script_timeout() } else { None };
+    let mount_failed = mount_failed_modules();
+    let mut nodes = Vec::new();
     foreach_active_module(|module| {
-        let script_path = module.join(format!("{stage}.sh"));
-        if !script_path.exists() {
+        if !module.join(format!("{stage}.sh")).exists() {
             return Ok(());
         }
-
-        exec_script(&script_path, block)
+        let id = module.file_name().unwrap_or_default().to_string_lossy();
+        if mount_failed.contains(&*id) {
+            warn!("skip {stage}.sh of {id}: its files failed to mount");
+            return Ok(());
+        }
+        let props = read_module_prop(module).unwrap_or_default();
+        nodes.push(script_order::Node::new(
+            id.into_owned(),
+            props.get("after"),
+            props.get("before"),
+        ));
+        Ok(())
     })?;
+    let order = script_order::resolve(&nodes);
+    if !order.cyclic.is_empty() {
+        warn!(
+            "{stage}.sh: after=/before= of {} form a cycle, they run last in id order",
+            order.cyclic.join(", ")
+        );
+    }
+    info!("{stage}.sh order: {}", order.ids.join(", "));
+
+    let mut failed = 0;
+    if block {
+        for id in &order.ids {
+            if let Err(e) = run_module_script(id, stage, true, timeout) {
+                warn!("{e}");
+                failed += 1;
+            }
+        }
+        return Ok(failed);
+    }
+    for chain in script_order::chains(&nodes, &order.ids) {
+        let result = match chain.as_slice() {
+            [id] => run_module_script(id, stage, false, None),
+            _ => spawn_chain(stage, &chain),
+        };
+        if let Err(e) = result {
+            warn!("{e}");
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+/// Run `<stage>.sh` of module `id`, an unsuccessful exit counts as failed
+fn run_module_script(id: &str, stage: &str, wait: bool, timeout: Option<Duration>) -> Result<()> {
+    let script_path = Path::new(MODULE_DIR).join(id).join(format!("{stage}.sh"));
+    let sandbox = Sandbox::for_script(&script_path);
+    let status = run_script(&script_path, wait, timeout, Some(sandbox), true)?;
+    exit_ok(&script_path, status)
+}
+
+/// Start `apd module exec-chain` for modules whose scripts depend on each
+/// other, the stage does not wait for it
+fn spawn_chain(stage: &str, ids: &[String]) -> Result<()> {
+    let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from(defs::DAEMON_PATH));
+    info!("exec {stage}.sh of {} one after another", ids.join(", "));
+    let mut command = Command::new(&exe);
+    #[cfg(unix)]
+    command.process_group(0);
+    command
+        .args(["module", "exec-chain", stage])
+        .args(ids)
+        .spawn()
+        .with_context(|| format!("Failed to start {} module exec-chain", exe.display()))?;
+    Ok(())
+}
+
+/// `apd module exec-chain <stage> <id>...`: run the `stage` scripts of the
+/// modules `ids` one after another, a failing one does not stop the others
+pub fn exec_chain(stage: &str, ids: &[String]) -> Result<()> {
+    let mut failed = 0;
+    for id in ids {
+        if let Err(e) = run_module_script(id, stage, true, None) {
+            warn!("{e}");
+            failed += 1;
+        }
+    }
+    ensure!(failed == 0, "{failed} {stage}.sh script(s) failed");
     Ok(())
 }
 
+/// Run the scripts of `/data/adb/<dir>` in name order. A script without the
+/// executable bit is made executable rather than skipped.
 pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
     let script_dir = Path::new(defs::ADB_DIR).join(dir);
     if !script_dir.exists() {
@@ -259,78 +1578,211 @@ pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
         return Ok(());
     }
 
-    let dir = fs::read_dir(&script_dir)?;
-    for entry in dir.flatten() {
-        let path = entry.path();
-
-        if !is_executable(&path) {
-            warn!("{} is not executable, skip", path.display());
-            continue;
+    let timeout = if wait { script_timeout() } else { None };
+    for path in common_scripts(&script_dir)? {
+        let sandbox = Sandbox::for_script(&path);
+        if let Err(e) = run_script(&path, wait, timeout, Some(sandbox), true) {
+            warn!("{e}");
         }
-
-        exec_script(path, wait)?;
     }
 
     Ok(())
 }
 
-pub fn load_system_prop() -> Result<()> {
-    foreach_active_module(|module| {
-        let system_prop = module.join("system.prop");
-        if !system_prop.exists() {
-            return Ok(());
+/// Scripts of `script_dir` in name order, made executable where needed
+fn common_scripts(script_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut scripts: Vec<PathBuf> = fs::read_dir(script_dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    scripts.sort();
+    scripts.retain(|path| {
+        if is_executable(path) {
+            return true;
+        }
+        warn!("{} is not executable, chmod 0755", path.display());
+        match fs::set_permissions(path, fs::Permissions::from_mode(0o755)) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Failed to chmod {}: {}", path.display(), e);
+                false
+            }
         }
-        info!("load {} system.prop", module.display());
+    });
+    Ok(scripts)
+}
+
+/// Props of a system.prop which belong to `stage`. Lines prefixed with
+/// `@service` or `@boot-completed` are set in that stage, plain lines in
+/// post-fs-data.
+pub fn system_props_of_stage(content: &str, stage: &str) -> Vec<(String, String)> {
+    let mut props = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (line_stage, prop) = match line.strip_prefix('@') {
+            Some(directive) => match directive.split_once(char::is_whitespace) {
+                Some((line_stage, prop)) => (line_stage, prop.trim_start()),
+                None => {
+                    warn!("system.prop: ignore directive without prop: {line}");
+                    continue;
+                }
+            },
+            None => ("post-fs-data", line),
+        };
+        if line_stage != stage {
+            continue;
+        }
+        if let Some((key, value)) = prop.split_once('=') {
+            props.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    props
+}
 
-        crate::resetprop::load_system_prop_file(&system_prop)?;
-        
+/// Set the system.prop entries of active modules meant for `stage`, modules
+/// are applied in id order so the last one wins deterministically
+pub fn load_system_prop(stage: &str) -> Result<()> {
+    let mut modules = Vec::new();
+    foreach_active_module(|module| {
+        if module.join("system.prop").exists() {
+            modules.push(module.to_path_buf());
+        }
         Ok(())
     })?;
+    modules.sort();
 
+    let mut props = Vec::new();
+    for module in &modules {
+        let system_prop = module.join("system.prop");
+        let content = fs::read_to_string(&system_prop)
+            .with_context(|| format!("Failed to read {}", system_prop.display()))?;
+        let id = module
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for (key, value) in system_props_of_stage(&content, stage) {
+            props.push((id.clone(), key, value));
+        }
+    }
+    if props.is_empty() {
+        return Ok(());
+    }
+    crate::resetprop::set_module_props(&props)
+}
+
+/// Run the uninstall scripts of a module and remove its dir
+fn remove_module(module: &Path) -> Result<()> {
+    info!("remove module: {}", module.display());
+
+    // Execute metamodule's metauninstall.sh first
+    let module_id = module.file_name().and_then(|n| n.to_str()).unwrap_or("");
+
+    // Check if this is a metamodule
+    let is_metamodule = read_module_prop(module)
+        .map(|props| metamodule::is_metamodule(&props))
+        .unwrap_or(false);
+
+    if get_mount_mode() == defs::MOUNT_MODE_METAMODULE {
+        if is_metamodule {
+            if metamodule::is_mount_owner(module) {
+                info!("Removing metamodule symlink");
+                if let Err(e) = metamodule::remove_symlink() {
+                    warn!("Failed to remove metamodule symlink: {e}");
+                }
+            }
+        } else if let Err(e) = metamodule::exec_metauninstall_script(module_id) {
+            warn!("Failed to exec metamodule uninstall for {module_id}: {e}",);
+        }
+    }
+
+    // Then execute module's own uninstall.sh
+    let uninstaller = module.join("uninstall.sh");
+    if uninstaller.exists()
+        && let Err(e) = exec_script(uninstaller, true)
+    {
+        warn!("Failed to exec uninstaller: {e}");
+    }
+
+    // Finally remove the module directory and its data
+    let mut failed = force_remove_dir(module);
+    if !module_id.is_empty() {
+        failed += force_remove_dir(&Path::new(defs::MODULE_DATA_DIR).join(module_id));
+    }
+    ensure!(
+        failed == 0,
+        "Failed to remove {} entries of {}",
+        failed,
+        module.display()
+    );
     Ok(())
 }
 
+/// Remove `path` recursively. If that fails, e.g. on a read-only dir or a
+/// dir a previous attempt partially removed, dirs are made writable and every
+/// entry is removed on its own. Returns how many entries are left, each is
+/// logged.
+fn force_remove_dir(path: &Path) -> usize {
+    match remove_dir_all(path) {
+        Ok(()) => return 0,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return 0,
+        Err(e) => info!("{}: {}, removing entry by entry", path.display(), e),
+    }
+    let mut failed = 0;
+    force_remove(path, &mut failed);
+    failed
+}
+
+fn force_remove(path: &Path, failed: &mut usize) {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return;
+    };
+    let result = if metadata.is_dir() {
+        let _ = fs::set_permissions(path, fs::Permissions::from_mode(0o700));
+        for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+            force_remove(&entry.path(), failed);
+        }
+        fs::remove_dir(path)
+    } else {
+        fs::remove_file(path)
+    };
+    if let Err(e) = result
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        warn!("Failed to remove {}: {}", path.display(), e);
+        *failed += 1;
+    }
+}
+
+/// Whether prune removes `module`. Only the user's `remove` counts, a module
+/// apd disabled is kept however long it stays disabled.
+fn prunable(module: &Path) -> bool {
+    crate::flags::is_set(module.join(defs::REMOVE_FILE_NAME))
+}
+
 pub fn prune_modules() -> Result<()> {
     foreach_module(ModuleType::All, |module| {
         fs::remove_file(module.join(defs::UPDATE_FILE_NAME)).ok();
-        if !module.join(defs::REMOVE_FILE_NAME).exists() {
-            return Ok(());
-        }
-
-        info!("remove module: {}", module.display());
-
-        // Execute metamodule's metauninstall.sh first
-        let module_id = module.file_name().and_then(|n| n.to_str()).unwrap_or("");
-
-        // Check if this is a metamodule
-        let is_metamodule = read_module_prop(module)
-            .map(|props| metamodule::is_metamodule(&props))
-            .unwrap_or(false);
-
-        if get_mount_mode() == defs::MOUNT_MODE_METAMODULE {
-            if is_metamodule {
-                info!("Removing metamodule symlink");
-                if let Err(e) = metamodule::remove_symlink() {
-                    warn!("Failed to remove metamodule symlink: {e}");
+        if !prunable(module) {
+            match disabled_by(module) {
+                Some(Disabled::System(reason)) => {
+                    info!("{} stays disabled by apd: {reason}", module.display());
+                }
+                // enabled again without apd, the reason is stale
+                None => {
+                    if let Err(e) = clear_disable_reason(module) {
+                        warn!("{e:#}");
+                    }
                 }
-            } else if let Err(e) = metamodule::exec_metauninstall_script(module_id) {
-                warn!("Failed to exec metamodule uninstall for {module_id}: {e}",);
+                Some(Disabled::User) => {}
             }
+            return Ok(());
         }
-
-        // Then execute module's own uninstall.sh
-        let uninstaller = module.join("uninstall.sh");
-        if uninstaller.exists()
-            && let Err(e) = exec_script(uninstaller, true)
-        {
-            warn!("Failed to exec uninstaller: {e}");
-        }
-
-        // Finally remove the module directory
-        if let Err(e) = remove_dir_all(module) {
-            warn!("Failed to remove {}: {e}", module.display());
+        if let Err(e) = remove_module(module) {
+            warn!("{e:#}");
         }
-
         Ok(())
     })?;
 
@@ -343,21 +1795,101 @@ pub fn prune_modules() -> Result<()> {
     if remaining_modules.is_empty() {
         info!("no remaining modules.");
     }
+    for (id, reason) in blocked_modules() {
+        warn!("module {id} will not be mounted: {reason}");
+    }
+
+    Ok(())
+}
+
+/// Extract a module zip to `dest`, printing progress as `- Extracting: <n>%`.
+/// The archive is unpacked next to `dest` first and renamed into place, so an
+/// invalid entry leaves no half extracted module behind. Entries escaping the
+/// module dir are rejected, file modes are normalized to 0644 and 0755 for
+/// dirs, the installer sets the real ones.
+fn extract_module_zip(zip: &Path, dest: &Path) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(fs::File::open(zip)?)?;
+    let name = dest.file_name().context("no module dir name")?;
+    let tmp = dest.with_file_name(format!(".{}.extracting", name.to_string_lossy()));
+    if tmp.exists() {
+        remove_dir_all(&tmp)?;
+    }
+    fs::create_dir_all(&tmp)?;
+
+    let result = (|| -> Result<()> {
+        let total: u64 = (0..archive.len())
+            .filter_map(|i| archive.by_index_raw(i).ok().map(|file| file.size()))
+            .sum();
+        let mut done = 0;
+        let mut shown = None;
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let Some(name) = file.enclosed_name() else {
+                bail!("zip entry {} escapes the module dir", file.name());
+            };
+            let path = tmp.join(&name);
+            if file.is_dir() {
+                fs::create_dir_all(&path)?;
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if file.is_symlink() {
+                let mut target = String::new();
+                io::Read::read_to_string(&mut file, &mut target)?;
+                let escapes = target.starts_with('/')
+                    || Path::new(&target)
+                        .components()
+                        .any(|c| c == std::path::Component::ParentDir);
+                ensure!(!escapes, "zip entry {} links outside the module dir", name.display());
+                std::os::unix::fs::symlink(&target, &path)?;
+                continue;
+            }
+            let mut out = fs::File::create(&path)?;
+            done += io::copy(&mut file, &mut out)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+
+            let percent = (done * 100).checked_div(total).unwrap_or(100);
+            if shown != Some(percent) {
+                println!("- Extracting: {percent}%");
+                shown = Some(percent);
+            }
+        }
+        ensure!(tmp.join("module.prop").is_file(), "module.prop not found in zip");
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = remove_dir_all(&tmp);
+        return Err(e.context(format!("Failed to extract {}", zip.display())));
+    }
 
+    if dest.exists() {
+        remove_dir_all(dest)?;
+    }
+    fs::rename(&tmp, dest)
+        .with_context(|| format!("Failed to move {} to {}", tmp.display(), dest.display()))?;
     Ok(())
 }
 
-fn _install_module(zip: &str) -> Result<()> {
+fn _install_module(zip: &str, allow_unconfined: bool) -> Result<()> {
     ensure_boot_completed()?;
 
     // print banner
     println!(include_str!("banner"));
 
-    assets::ensure_binaries().with_context(|| "binary missing")?;
+    let broken = assets::ensure_binaries();
+    ensure!(
+        broken.is_empty(),
+        "binary missing: {}",
+        broken.iter().map(|b| b.name).collect::<Vec<_>>().join(", ")
+    );
 
     // first check if workding dir is usable
     ensure_dir_exists(defs::WORKING_DIR).with_context(|| "Failed to create working dir")?;
     ensure_dir_exists(defs::BINARY_DIR).with_context(|| "Failed to create bin dir")?;
+    crate::data_ready::mark();
 
     // read the module_id from zip
     let mut buffer: Vec<u8> = Vec::new();
@@ -365,29 +1897,25 @@ fn _install_module(zip: &str) -> Result<()> {
     let zip_path = PathBuf::from_str(zip)?;
     let zip_path = zip_path.canonicalize()?;
     zip_extract_file_to_memory(&zip_path, &entry_path, &mut buffer)?;
-    let mut module_prop = HashMap::new();
-    PropertiesIter::new_with_encoding(Cursor::new(buffer), encoding_rs::UTF_8).read_into(
-        |k, v| {
-            module_prop.insert(k, v);
-        },
-    )?;
+    let module_prop = parse_props(&buffer)?;
     info!("module prop: {:?}", module_prop);
 
-    let Some(module_id) = module_prop.get("id") else {
-        bail!("module id not found in module.prop!");
-    };
-    let module_id = module_id.trim();
+    let module_id = validate_module_prop(&module_prop)?;
+    let module_id = module_id.as_str();
 
     // Check if this module is a metamodule
     let is_metamodule = metamodule::is_metamodule(&module_prop);
 
     // Check if module needs mounting (has system/ dir and no skip_mount file)
-    let needs_mount = {
+    let (needs_mount, mounts_modules) = {
         let zip_file = fs::File::open(&zip_path)?;
         let archive = zip::ZipArchive::new(zip_file)?;
         let has_system = archive.file_names().any(|name| name.starts_with("system/"));
         let has_skip_mount = archive.file_names().any(|name| name == "skip_mount");
-        has_system && !has_skip_mount
+        let has_metamount = archive
+            .file_names()
+            .any(|name| name == defs::METAMODULE_MOUNT_SCRIPT);
+        (has_system && !has_skip_mount, has_metamount)
     };
 
     // Check if it's safe to install regular module (only in metamodule mode)
@@ -396,19 +1924,17 @@ fn _install_module(zip: &str) -> Result<()> {
         && needs_mount
         && let Err(is_disabled) = metamodule::check_install_safety()
     {
+        let message = if is_disabled {
+            Message::InstallBlockedMetamoduleDisabled {}
+        } else {
+            Message::InstallBlockedMetamodulePending {}
+        };
         println!("\n❌ Installation Blocked");
         println!("┌────────────────────────────────");
-        println!("│ A metamodule with custom installer is active");
-        println!("│");
-        if is_disabled {
-            println!("│ Current state: Disabled");
-            println!("│ Action required: Re-enable or uninstall it, then reboot");
-        } else {
-            println!("│ Current state: Pending changes");
-            println!("│ Action required: Reboot to apply changes first");
-        }
+        println!("│ {message}");
         println!("└─────────────────────────────────\n");
-        bail!("Metamodule installation blocked");
+        println!("{}", message.to_json());
+        bail!("Metamodule installation blocked: {}", message.code());
     }
 
     let modules_dir = Path::new(defs::MODULE_DIR);
@@ -422,9 +1948,10 @@ fn _install_module(zip: &str) -> Result<()> {
     if is_metamodule {
         info!("Installing metamodule: {module_id}");
 
-        // Check if there's already a metamodule installed
-        if metamodule::has_metamodule()
+        // Only one metamodule may mount modules, others just add stage hooks
+        if mounts_modules
             && let Some(existing_path) = metamodule::get_metamodule_path()
+            && existing_path.join(defs::METAMODULE_MOUNT_SCRIPT).exists()
         {
             let existing_id = read_module_prop(&existing_path)
                 .ok()
@@ -432,19 +1959,16 @@ fn _install_module(zip: &str) -> Result<()> {
                 .unwrap_or_else(|| "unknown".to_string());
 
             if existing_id != module_id {
+                let message = Message::InstallMetamoduleExists {
+                    existing: existing_id,
+                };
                 println!("\n❌ Installation Failed");
                 println!("┌────────────────────────────────");
-                println!("│ A metamodule is already installed");
-                println!("│   Current metamodule: {existing_id}");
-                println!("│");
-                println!("│ Only one metamodule can be active at a time.");
-                println!("│");
-                println!("│ To install this metamodule:");
-                println!("│   1. Uninstall the current metamodule");
-                println!("│   2. Reboot your device");
-                println!("│   3. Install the new metamodule");
+                println!("│ {message}");
+                println!("│ Only one metamodule can mount modules at a time.");
                 println!("└─────────────────────────────────\n");
-                bail!("Cannot install multiple metamodules");
+                println!("{}", message.to_json());
+                bail!("Cannot install multiple metamodules: {}", message.code());
             }
         }
     }
@@ -458,23 +1982,68 @@ fn _install_module(zip: &str) -> Result<()> {
         fs::set_permissions(module_dir.clone(), permissions).expect("Failed to set permissions");
     }
     // unzip the image and move it to modules_update/<id> dir
-    let file = fs::File::open(zip)?;
-    let mut archive = zip::ZipArchive::new(file)?;
-    archive.extract(&_module_update_dir)?;
+    fs::create_dir_all(modules_update_dir)?;
+    extract_module_zip(&zip_path, Path::new(&_module_update_dir))?;
+
+    let module_data = Path::new(defs::MODULE_DATA_DIR).join(module_id);
+    fs::create_dir_all(&module_data)
+        .and_then(|()| fs::set_permissions(&module_data, fs::Permissions::from_mode(0o700)))
+        .with_context(|| format!("Failed to create {}", module_data.display()))?;
+
+    let unconfined = module_prop
+        .get("install_unconfined")
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("true") || v.trim() == "1");
+    let writable = if unconfined {
+        ensure!(
+            allow_unconfined,
+            "{module_id} asks to install with full access (install_unconfined), pass --allow-unconfined to allow it"
+        );
+        warn!("{module_id} installs unconfined");
+        println!("- Warning: installer runs with full access to /data");
+        None
+    } else {
+        let mut writable = vec![
+            modules_update_dir.to_path_buf(),
+            PathBuf::from(&module_dir),
+            module_data.clone(),
+        ];
+        // a metamodule installer keeps its own state next to the metamodule
+        writable.extend(metamodule::get_metamodule_path());
+        Some(writable)
+    };
 
     println!("- Running module installer");
-    exec_install_script(zip, is_metamodule)?;
+    exec_install_script(zip, is_metamodule, &module_data, writable)?;
+
+    let update_path = Path::new(&_module_update_dir);
+    let critical_paths = critical::Patterns::load().scan(update_path);
+    if !critical_paths.is_empty() && !critical::is_acknowledged(update_path) {
+        println!("\n⚠️ This module modifies critical boot components:");
+        for path in &critical_paths {
+            println!("│ {path}");
+        }
+        println!("│ They will not be mounted until acknowledged with");
+        println!("│ `apd module ack-critical {module_id}` or allow_critical=true in module.prop\n");
+    }
 
     // set permission and selinux context for $MOD/system
     let module_system_dir = PathBuf::from(module_dir.clone()).join("system");
     if module_system_dir.exists() {
         #[cfg(unix)]
         fs::set_permissions(&module_system_dir, fs::Permissions::from_mode(0o755))?;
-        restorecon::restore_syscon(&module_system_dir)?;
+        let mut summary = restorecon::restore_syscon(&module_system_dir);
+        if summary.raced() {
+            summary = restorecon::restore_syscon(&module_system_dir);
+        }
+        summary.check()?;
     }
 
-    // Create symlink for metamodule
-    if is_metamodule {
+    // Create symlink for the metamodule owning the mount role
+    if is_metamodule
+        && (mounts_modules
+            || metamodule::get_metamodule_path()
+                .is_none_or(|owner| owner == Path::new(&module_dir)))
+    {
         println!("- Creating metamodule symlink");
         metamodule::ensure_symlink(&module_dir)?;
     }
@@ -483,8 +2052,37 @@ fn _install_module(zip: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn install_module(zip: &str) -> Result<()> {
-    let result = _install_module(zip);
+/// Whether `id` is a valid module id, same rule as Magisk. It also keeps the
+/// id usable as a dir name.
+pub fn valid_module_id(id: &str) -> bool {
+    let mut chars = id.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.clone().next().is_some()
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// Check the keys every module.prop must have, returns the module id
+fn validate_module_prop(module_prop: &HashMap<String, String>) -> Result<String> {
+    let Some(id) = module_prop.get("id").map(|id| id.trim()) else {
+        bail!("module id not found in module.prop!");
+    };
+    ensure!(valid_module_id(id), "invalid module id: {id}");
+    for key in ["name", "version", "versionCode"] {
+        ensure!(
+            module_prop.get(key).is_some_and(|v| !v.trim().is_empty()),
+            "{key} not found in module.prop!"
+        );
+    }
+    let version_code = &module_prop["versionCode"];
+    ensure!(
+        version_code.trim().parse::<i64>().is_ok(),
+        "versionCode must be a number, got {version_code}"
+    );
+    Ok(id.to_string())
+}
+
+pub fn install_module(zip: &str, allow_unconfined: bool) -> Result<()> {
+    let result = _install_module(zip, allow_unconfined);
     result
 }
 
@@ -530,11 +2128,85 @@ pub fn _uninstall_module(id: &str, update_dir: &str) -> Result<()> {
     Ok(())
 }
 pub fn uninstall_module(id: &str) -> Result<()> {
+    let _guard = lock_modules()?;
     _uninstall_module(id, defs::MODULE_DIR)?;
     mark_update()?;
     Ok(())
 }
 
+/// Remove a module right away instead of on next boot, only possible while
+/// none of its files are mounted
+pub fn uninstall_module_now(id: &str) -> Result<()> {
+    let module = Path::new(MODULE_DIR).join(id);
+    ensure!(module.join("module.prop").exists(), "module: {} not found!", id);
+    let mounts = crate::mount::module_mounts()?.remove(id).unwrap_or_default();
+    ensure!(
+        mounts.is_empty(),
+        "module: {} has {} file mount(s), uninstall it without --now and reboot",
+        id,
+        mounts.len()
+    );
+    let _guard = lock_modules()?;
+    remove_module(&module)?;
+    let staged = Path::new(MODULE_UPDATE_DIR).join(id);
+    if staged.exists() {
+        remove_dir_all(&staged)?;
+    }
+    println!("- Removed {id}");
+    Ok(())
+}
+
+/// `mountorder=` of a module.prop, `priority=` as an alias. When two modules
+/// provide the same file the one with the lower number wins, modules without
+/// a valid value come after all others and then go by id.
+pub fn mount_order(module_path: &Path) -> Option<i64> {
+    let prop = read_module_prop(module_path).ok()?;
+    let value = prop.get("mountorder").or_else(|| prop.get("priority"))?;
+    match value.trim().parse() {
+        Ok(order) => Some(order),
+        Err(_) => {
+            warn!("{}: invalid mount order {value}, ignored", module_path.display());
+            None
+        }
+    }
+}
+
+/// Sort module dirs in the order their files are mounted, see [`mount_order`]
+pub fn sort_by_mount_order(modules: &mut [PathBuf]) {
+    modules.sort_by_cached_key(|module| {
+        (
+            mount_order(module).unwrap_or(i64::MAX),
+            module.file_name().map(|id| id.to_os_string()),
+        )
+    });
+}
+
+/// Partitions the module at `module_path` may modify, `None` if any. The
+/// `partitions=` of its module.prop can only narrow `allowed_partitions` of
+/// apd.toml, a module cannot grant itself a partition the user denied.
+pub fn allowed_partitions(module_path: &Path) -> Option<Vec<String>> {
+    let declared: Option<Vec<String>> = read_module_prop(module_path)
+        .ok()
+        .and_then(|prop| prop.get("partitions").cloned())
+        .map(|partitions| {
+            partitions
+                .split(',')
+                .map(str::trim)
+                .filter(|partition| !partition.is_empty())
+                .map(String::from)
+                .collect()
+        });
+    match (crate::config::allowed_partitions(), declared) {
+        (Some(global), Some(declared)) => Some(
+            declared
+                .into_iter()
+                .filter(|partition| global.contains(partition))
+                .collect(),
+        ),
+        (global, declared) => global.or(declared),
+    }
+}
+
 /// Read module.prop from the given module path and return as a HashMap
 pub fn read_module_prop(module_path: &Path) -> Result<HashMap<String, String>> {
     let module_prop = module_path.join("module.prop");
@@ -546,24 +2218,74 @@ pub fn read_module_prop(module_path: &Path) -> Result<HashMap<String, String>> {
 
     let content = std::fs::read(&module_prop)
         .with_context(|| format!("Failed to read module.prop: {}", module_prop.display()))?;
+    parse_props(&content)
+        .with_context(|| format!("Failed to parse module.prop: {}", module_prop.display()))
+}
 
-    let mut prop_map: HashMap<String, String> = HashMap::new();
-    PropertiesIter::new_with_encoding(Cursor::new(content), encoding_rs::UTF_8)
+/// Parse the content of a module.prop, a UTF-8 BOM and CRLF line ends are
+/// tolerated
+fn parse_props(content: &[u8]) -> Result<HashMap<String, String>> {
+    let content = content.strip_prefix(b"\xef\xbb\xbf").unwrap_or(content);
+    let content = String::from_utf8_lossy(content).replace("\r\n", "\n");
+    let mut props = HashMap::new();
+    PropertiesIter::new_with_encoding(Cursor::new(content.into_bytes()), encoding_rs::UTF_8)
         .read_into(|k, v| {
-            prop_map.insert(k, v);
-        })
-        .with_context(|| format!("Failed to parse module.prop: {}", module_prop.display()))?;
+            props.insert(k, v);
+        })?;
+    Ok(props)
+}
+
+/// The checked module.prop of an installed module
+pub struct ModuleInfo {
+    pub id: String,
+    pub props: HashMap<String, String>,
+}
 
-    Ok(prop_map)
+impl ModuleInfo {
+    /// Parse the module.prop of the module at `module_path` and check it as on
+    /// install. A module failing this is quarantined: it is neither mounted nor
+    /// are its scripts run.
+    pub fn parse(module_path: &Path) -> Result<ModuleInfo> {
+        let props = read_module_prop(module_path)?;
+        let id = validate_module_prop(&props)?;
+        let dir_name = module_path.file_name().unwrap_or_default();
+        ensure!(
+            dir_name == id.as_str(),
+            "module id {id} does not match its dir {}",
+            dir_name.to_string_lossy()
+        );
+        Ok(ModuleInfo { id, props })
+    }
 }
 
+/// Run the action of module `id`, its `action.sh` like a stage script with
+/// the output going to stdout as it is written. A failing `action.sh` makes
+/// the error a [`ScriptFailed`] carrying its exit status.
 pub fn run_action(id: &str) -> Result<()> {
-    let action_script_path = format!("/data/adb/modules/{}/action.sh", id);
-    if Path::new(&action_script_path).exists() {
-        let _ = exec_script(&action_script_path, true);
+    let module_path = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(module_path.exists(), "module: {} not found!", id);
+    ensure!(
+        !crate::flags::is_set(module_path.join(defs::DISABLE_FILE_NAME)),
+        "module {id} is disabled"
+    );
+    ensure!(
+        !crate::flags::is_set(module_path.join(defs::UPDATE_FILE_NAME))
+            && !Path::new(defs::MODULE_UPDATE_DIR).join(id).exists(),
+        "module {id} has an update pending, reboot first"
+    );
+    let action_script_path = module_path.join("action.sh");
+    if action_script_path.exists() {
+        let status = run_script(
+            &action_script_path,
+            true,
+            script_timeout(),
+            Some(Sandbox::for_script(&action_script_path)),
+            false,
+        )?;
+        exit_ok(&action_script_path, status)?;
     } else {
         //if no action.sh, try to run lua action
-        lua::run_lua(&id, "action", false, true).map_err(|e| anyhow::anyhow!("{}", e))?;
+        lua::run_lua(&id, "action").map_err(|e| anyhow::anyhow!("{}", e))?;
     }
     Ok(())
 }
@@ -584,7 +2306,7 @@ fn _change_module_state(module_dir: &str, mid: &str, enable: bool) -> Result<()>
         ensure_file_exists(disable_path)?;
     }
 
-    let _ = mark_module_state(mid, defs::DISABLE_FILE_NAME, !enable);
+    mark_module_state(mid, defs::DISABLE_FILE_NAME, !enable)?;
 
     Ok(())
 }
@@ -599,6 +2321,7 @@ pub fn _enable_module(id: &str, update_dir: &Path) -> Result<()> {
 }
 
 pub fn enable_module(id: &str) -> Result<()> {
+    let _guard = lock_modules()?;
     let update_dir = Path::new(defs::MODULE_DIR);
     _enable_module(id, update_dir)?;
     Ok(())
@@ -614,36 +2337,261 @@ pub fn _disable_module(id: &str, update_dir: &Path) -> Result<()> {
 }
 
 pub fn disable_module(id: &str) -> Result<()> {
+    let _guard = lock_modules()?;
     let module_dir = Path::new(defs::MODULE_DIR);
     _disable_module(id, module_dir)?;
 
     Ok(())
 }
 
-pub fn _disable_all_modules(dir: &str) -> Result<()> {
+/// A module log beyond this size is moved to `<id>.log.old`
+const MAX_MODULE_LOG_SIZE: u64 = 256 * 1024;
+
+/// Append `msg` to the log of module `id` in `/data/adb/ap/log/modules`
+pub fn append_log(id: &str, level: &str, msg: &str) {
+    use std::io::Write;
+
+    let path = Path::new(defs::MODULE_LOG_DIR).join(format!("{id}.log"));
+    let write = || -> io::Result<()> {
+        fs::create_dir_all(defs::MODULE_LOG_DIR)?;
+        if fs::metadata(&path).is_ok_and(|metadata| metadata.len() > MAX_MODULE_LOG_SIZE) {
+            fs::rename(&path, path.with_extension("log.old"))?;
+        }
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{secs} [{level}] {msg}")
+    };
+    if let Err(e) = write() {
+        warn!("Failed to write {}: {}", path.display(), e);
+    }
+}
+
+/// Serialize writers of module flag files, released when the returned file is dropped
+pub fn lock_modules() -> Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(defs::MODULES_LOCK_FILE)
+        .with_context(|| format!("Failed to open {}", defs::MODULES_LOCK_FILE))?;
+    file.lock()
+        .with_context(|| format!("Failed to lock {}", defs::MODULES_LOCK_FILE))?;
+    Ok(file)
+}
+
+/// A single desired state change of a batch
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum BatchOp {
+    Enable { id: String },
+    Disable { id: String },
+    Remove { id: String },
+    SetFlag { id: String, flag: String, value: bool },
+}
+
+impl BatchOp {
+    fn id(&self) -> &str {
+        match self {
+            BatchOp::Enable { id }
+            | BatchOp::Disable { id }
+            | BatchOp::Remove { id }
+            | BatchOp::SetFlag { id, .. } => id,
+        }
+    }
+
+    /// The flag file this op writes and whether it ends up present
+    fn flag(&self) -> (&str, bool) {
+        match self {
+            BatchOp::Enable { .. } => (defs::DISABLE_FILE_NAME, false),
+            BatchOp::Disable { .. } => (defs::DISABLE_FILE_NAME, true),
+            BatchOp::Remove { .. } => (defs::REMOVE_FILE_NAME, true),
+            BatchOp::SetFlag { flag, value, .. } => (flag, *value),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchError {
+    pub index: usize,
+    pub id: String,
+    pub error: String,
+}
+
+const BATCH_FLAGS: [&str; 3] = [
+    defs::DISABLE_FILE_NAME,
+    defs::REMOVE_FILE_NAME,
+    defs::SKIP_MOUNT_FILE_NAME,
+];
+
+/// Validate a batch as a whole, any error rejects every item
+pub fn validate_batch(ops: &[BatchOp]) -> Vec<BatchError> {
+    let metamodule_id = metamodule::get_metamodule_path()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()));
+    let enables_others = ops
+        .iter()
+        .any(|op| matches!(op, BatchOp::Enable { id } if Some(id) != metamodule_id.as_ref()));
+    let mut seen: HashMap<(&str, &str), bool> = HashMap::new();
+    let mut errors = Vec::new();
+
+    for (index, op) in ops.iter().enumerate() {
+        let id = op.id();
+        let (flag, value) = op.flag();
+        let error = if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            Some("invalid module id".to_string())
+        } else if !Path::new(MODULE_DIR).join(id).join("module.prop").exists() {
+            Some("unknown module".to_string())
+        } else if !BATCH_FLAGS.contains(&flag) {
+            Some(format!("unsupported flag: {flag}"))
+        } else if seen.insert((id, flag), value).is_some_and(|v| v != value) {
+            Some(format!("conflicting changes to {flag}"))
+        } else if value
+            && (flag == defs::DISABLE_FILE_NAME || flag == defs::REMOVE_FILE_NAME)
+            && enables_others
+            && metamodule_id.as_deref() == Some(id)
+        {
+            Some(
+                "metamodule cannot be disabled or removed while enabling other modules"
+                    .to_string(),
+            )
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            errors.push(BatchError {
+                index,
+                id: id.to_string(),
+                error,
+            });
+        }
+    }
+    errors
+}
+
+/// Flag files as they were before a batch changed them, `None` if absent
+#[derive(Default)]
+struct FlagSnapshot(Vec<(PathBuf, Option<Vec<u8>>)>);
+
+impl FlagSnapshot {
+    /// Remember `path` unless it already is, the first state is the one kept
+    fn save(&mut self, path: PathBuf) {
+        if self.0.iter().any(|(saved, _)| *saved == path) {
+            return;
+        }
+        let content = crate::flags::is_set(&path)
+            .then(|| fs::read(&path).ok())
+            .flatten();
+        self.0.push((path, content));
+    }
+
+    /// Put every saved file back as it was, newest change first
+    fn restore(&self) {
+        use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+        for (path, content) in self.0.iter().rev() {
+            let restore = || -> io::Result<()> {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+                let Some(content) = content else {
+                    return Ok(());
+                };
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)?
+                    .write_all(content)
+            };
+            if let Err(e) = restore() {
+                warn!("Failed to restore {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Apply validated `ops` to the modules below `modules`. On the first failure
+/// every change made so far is undone.
+fn apply_ops(modules: &Path, ops: &[BatchOp]) -> Result<()> {
+    let mut snapshot = FlagSnapshot::default();
+    for op in ops {
+        let module = modules.join(op.id());
+        let (flag, value) = op.flag();
+        snapshot.save(module.join(flag));
+        if flag == defs::DISABLE_FILE_NAME {
+            snapshot.save(module.join(defs::DISABLE_REASON_FILE_NAME));
+        }
+        if let Err(e) = set_module_flag(&module, flag, value) {
+            snapshot.restore();
+            return Err(e.context(format!("Failed to apply {op:?}, the batch was rolled back")));
+        }
+    }
+    Ok(())
+}
+
+/// Apply a batch of flag changes atomically under the modules lock
+pub fn apply_batch(json: &str) -> Result<()> {
+    let ops: Vec<BatchOp> = serde_json::from_str(json).context("Invalid batch")?;
+    let _guard = lock_modules()?;
+
+    let errors = validate_batch(&ops);
+    if !errors.is_empty() {
+        println!("{}", serde_json::json!({ "applied": 0, "errors": errors }));
+        bail!("batch rejected: {} invalid item(s)", errors.len());
+    }
+
+    // removals take effect on next boot, mark it once for the whole batch. A
+    // mark left by a batch rolled back later does no harm.
+    if ops.iter().any(|op| matches!(op, BatchOp::Remove { .. })) {
+        mark_update()?;
+    }
+    apply_ops(Path::new(MODULE_DIR), &ops)?;
+
+    info!(
+        "batch applied: {}",
+        ops.iter()
+            .map(|op| format!("{}:{}={}", op.id(), op.flag().0, op.flag().1))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    println!("{}", serde_json::json!({ "applied": ops.len(), "errors": [] }));
+    Ok(())
+}
+
+/// Disable every module of `dir` for `reason`, modules the user disabled
+/// stay disabled by the user
+pub fn _disable_all_modules(dir: &str, reason: &str) -> Result<()> {
     let dir = fs::read_dir(dir)?;
     for entry in dir.flatten() {
         let path = entry.path();
-        let disable_flag = path.join(defs::DISABLE_FILE_NAME);
-        if let Err(e) = ensure_file_exists(disable_flag) {
+        if disabled_by(&path).is_some() {
+            continue;
+        }
+        let disable = || -> Result<()> {
+            write_disable_reason(&path, reason)?;
+            ensure_file_exists(path.join(defs::DISABLE_FILE_NAME))
+        };
+        if let Err(e) = disable() {
             warn!("Failed to disable module: {}: {}", path.display(), e);
         }
     }
     Ok(())
 }
 
-pub fn disable_all_modules() -> Result<()> {
+pub fn disable_all_modules(reason: &str) -> Result<()> {
     // Skip disabling modules since boot completed
     if getprop("sys.boot_completed").as_deref() == Some("1") {
         info!("System boot completed, no need to disable all modules");
         return Ok(());
     }
     mark_update()?;
-    _disable_all_modules(defs::MODULE_DIR)?;
+    _disable_all_modules(defs::MODULE_DIR, reason)?;
     Ok(())
 }
 
-fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
+pub fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
     // first check enabled modules
     let dir = fs::read_dir(path);
     let Ok(dir) = dir else {
@@ -651,6 +2599,9 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
     };
 
     let mut modules: Vec<HashMap<String, String>> = Vec::new();
+    let critical_patterns = critical::Patterns::load();
+    let mut cache = ScanCache::load();
+    let blocked = blocked_modules();
 
     for entry in dir.flatten() {
         let path = entry.path();
@@ -664,12 +2615,8 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
             warn!("Failed to read file: {}", module_prop.display());
             continue;
         };
-        let mut module_prop_map: HashMap<String, String> = HashMap::new();
-        let encoding = encoding_rs::UTF_8;
-        let result =
-            PropertiesIter::new_with_encoding(Cursor::new(content), encoding).read_into(|k, v| {
-                module_prop_map.insert(k, v);
-            });
+        // a module which does not parse is listed as quarantined, not hidden
+        let mut module_prop_map = parse_props(&content).unwrap_or_default();
 
         if !module_prop_map.contains_key("id") || module_prop_map["id"].is_empty() {
             match entry.file_name().to_str() {
@@ -684,33 +2631,568 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
             }
         }
 
+        let quarantined = match ModuleInfo::parse(&path) {
+            Ok(info) => {
+                module_prop_map.insert("id".to_owned(), info.id);
+                String::new()
+            }
+            Err(e) => format!("{e:#}"),
+        };
+
         // Add enabled, update, remove flags
-        let enabled = !path.join(defs::DISABLE_FILE_NAME).exists();
-        let update = path.join(defs::UPDATE_FILE_NAME).exists();
-        let remove = path.join(defs::REMOVE_FILE_NAME).exists();
+        let enabled = !crate::flags::is_set(path.join(defs::DISABLE_FILE_NAME));
+        let update = crate::flags::is_set(path.join(defs::UPDATE_FILE_NAME));
+        let remove = crate::flags::is_set(path.join(defs::REMOVE_FILE_NAME));
         let web = path.join(defs::MODULE_WEB_DIR).exists();
         let id = module_prop_map.get("id").map(|s| s.as_str()).unwrap_or("");
         let id_lua_file = format!("{}.lua", id);
         let action = path.join(defs::MODULE_ACTION_SH).exists() || path.join(&id_lua_file).exists();
 
         module_prop_map.insert("enabled".to_owned(), enabled.to_string());
+        let disable_reason = match disabled_by(&path) {
+            Some(Disabled::System(reason)) => reason,
+            _ => String::new(),
+        };
+        module_prop_map.insert("disable_reason".to_owned(), disable_reason);
         module_prop_map.insert("update".to_owned(), update.to_string());
         module_prop_map.insert("remove".to_owned(), remove.to_string());
         module_prop_map.insert("web".to_owned(), web.to_string());
         module_prop_map.insert("action".to_owned(), action.to_string());
+        module_prop_map.insert("kind".to_owned(), classify(&path).as_str().to_owned());
+        module_prop_map.insert(
+            "critical".to_owned(),
+            cache.critical(&critical_patterns, &path).join(","),
+        );
+        let critical_ack = path.join(defs::ALLOW_CRITICAL_FILE_NAME).exists()
+            || critical::acknowledged_by(&module_prop_map);
+        module_prop_map.insert("critical_ack".to_owned(), critical_ack.to_string());
+        let dir_name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+        let blocked_by = dir_name.and_then(|name| blocked.get(&name));
+        module_prop_map.insert(
+            "blocked".to_owned(),
+            blocked_by.map(Message::to_string).unwrap_or_default(),
+        );
+        module_prop_map.insert(
+            "blocked_code".to_owned(),
+            blocked_by.map(|message| message.code().to_owned()).unwrap_or_default(),
+        );
+        module_prop_map.insert("quarantined".to_owned(), quarantined);
+        modules.push(module_prop_map);
+    }
+    cache.store();
+
+    modules
+}
+
+#[derive(Serialize)]
+struct ModuleUsage {
+    id: String,
+    /// everything in the module dir but its image
+    loose: DiskUsage,
+    /// size of `module.img`, 0 without one
+    image_bytes: u64,
+}
+
+impl ModuleUsage {
+    fn total(&self) -> u64 {
+        self.loose.bytes + self.image_bytes
+    }
+}
+
+#[derive(Serialize)]
+struct UsageReport {
+    modules: Vec<ModuleUsage>,
+    total_bytes: u64,
+    total_entries: u64,
+}
 
-        if result.is_err() {
-            warn!("Failed to parse module.prop: {}", module_prop.display());
+/// `apd module du`: what each installed module takes below the module dir
+pub fn print_disk_usage(sort_by_size: bool) -> Result<()> {
+    let mut modules = Vec::new();
+    let mut cache = ScanCache::load();
+    for entry in fs::read_dir(MODULE_DIR)?.flatten() {
+        let path = entry.path();
+        // module ids start with a letter, these are leftovers of an extraction
+        if !path.is_dir() || entry.file_name().as_encoded_bytes().starts_with(b".") {
             continue;
         }
-        modules.push(module_prop_map);
+        let mut loose = cache.disk_usage(&path);
+        let image_bytes = crate::image::image_of(&path)
+            .and_then(|image| image.metadata().ok())
+            .map_or(0, |metadata| metadata.len());
+        if image_bytes > 0 {
+            loose.bytes = loose.bytes.saturating_sub(image_bytes);
+            loose.entries -= 1;
+        }
+        modules.push(ModuleUsage {
+            id: entry.file_name().to_string_lossy().into_owned(),
+            loose,
+            image_bytes,
+        });
+    }
+    cache.store();
+    if sort_by_size {
+        modules.sort_by_key(|module| std::cmp::Reverse(module.total()));
+    } else {
+        modules.sort_by(|a, b| a.id.cmp(&b.id));
     }
+    let report = UsageReport {
+        total_bytes: modules.iter().map(ModuleUsage::total).sum(),
+        total_entries: modules.iter().map(|module| module.loose.entries).sum(),
+        modules,
+    };
 
-    modules
+    if crate::output::json() {
+        return crate::output::print("module du", report);
+    }
+    let kib = |bytes: u64| bytes.div_ceil(1024);
+    println!("{:>10} {:>10} {:>8}  id", "loose KiB", "image KiB", "entries");
+    for module in &report.modules {
+        println!(
+            "{:>10} {:>10} {:>8}  {}",
+            kib(module.loose.bytes),
+            kib(module.image_bytes),
+            module.loose.entries,
+            module.id
+        );
+    }
+    println!(
+        "{:>21} {:>8}  total",
+        kib(report.total_bytes),
+        report.total_entries
+    );
+    Ok(())
 }
 
-pub fn list_modules() -> Result<()> {
-    let modules = _list_modules(defs::MODULE_DIR);
-    println!("{}", serde_json::to_string_pretty(&modules)?);
+/// Print the modules, as JSON unless a terminal asked for them without `--json`.
+/// The manager reads the JSON through a pipe.
+pub fn list_modules(json: bool) -> Result<()> {
+    let mut modules = match crate::daemon::query(&crate::daemon::Request::ModuleList)? {
+        Some(modules) => modules,
+        None => _list_modules(defs::MODULE_DIR),
+    };
+    if json {
+        return crate::output::print("module list", modules);
+    }
+    if !io::stdout().is_terminal() {
+        println!("{}", serde_json::to_string_pretty(&modules)?);
+        return Ok(());
+    }
+    modules.sort_by(|a, b| a.get("id").cmp(&b.get("id")));
+    let field = |module: &HashMap<String, String>, key: &str| {
+        module.get(key).cloned().unwrap_or_default()
+    };
+    for module in &modules {
+        let mut state = Vec::new();
+        if field(module, "enabled") != "true" {
+            state.push("disabled");
+        }
+        if field(module, "update") == "true" {
+            state.push("update pending");
+        }
+        if field(module, "remove") == "true" {
+            state.push("removed on reboot");
+        }
+        let blocked = field(module, "blocked");
+        let quarantined = format!("quarantined: {}", field(module, "quarantined"));
+        if !blocked.is_empty() {
+            state.push(&blocked);
+        } else if !field(module, "quarantined").is_empty() {
+            // enabled modules carry it as the reason they are blocked
+            state.push(&quarantined);
+        }
+        let state = if state.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", state.join(", "))
+        };
+        println!(
+            "{} {} ({}){}",
+            field(module, "id"),
+            field(module, "version"),
+            field(module, "versionCode"),
+            state
+        );
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// A module `id` below `root`, disabled by `disabled`
+    fn module(root: &TempDir, id: &str, disabled: Option<&Disabled>) -> PathBuf {
+        root.write(&format!("{id}/module.prop"), &format!("id={id}\n"));
+        match disabled {
+            Some(Disabled::User) => {
+                root.write(&format!("{id}/{}", defs::DISABLE_FILE_NAME), "");
+            }
+            Some(Disabled::System(reason)) => {
+                root.write(&format!("{id}/{}", defs::DISABLE_FILE_NAME), "");
+                root.write(&format!("{id}/{}", defs::DISABLE_REASON_FILE_NAME), reason);
+            }
+            None => {}
+        }
+        root.path().join(id)
+    }
+
+    fn states() -> [Disabled; 2] {
+        [
+            Disabled::User,
+            Disabled::System("boot.bootloop.modules_disabled".to_string()),
+        ]
+    }
+
+    #[test]
+    fn untouched_disabled_modules_are_kept() {
+        for state in states() {
+            let root = TempDir::new("untouched");
+            let path = module(&root, "test", Some(&state));
+            assert_eq!(disabled_by(&path), Some(state));
+            assert!(!prunable(&path));
+        }
+    }
+
+    #[test]
+    fn remove_flag_prunes_whoever_disabled() {
+        for state in states() {
+            let root = TempDir::new("remove");
+            let path = module(&root, "test", Some(&state));
+            root.write(&format!("test/{}", defs::REMOVE_FILE_NAME), "");
+            assert!(prunable(&path));
+        }
+    }
+
+    #[test]
+    fn update_keeps_disabled_state() {
+        for state in states() {
+            let root = TempDir::new("update");
+            let installed = module(&root, "installed", Some(&state));
+            // the zip ships flags of its own, they must not win
+            let staged = module(&root, "staged", None);
+            root.write(&format!("staged/{}", defs::DISABLE_REASON_FILE_NAME), "zip");
+            carry_state(&installed, &staged).unwrap();
+            assert_eq!(disabled_by(&staged), Some(state));
+            assert!(!prunable(&staged));
+        }
+    }
+
+    #[test]
+    fn update_keeps_remove_flag() {
+        for state in states() {
+            let root = TempDir::new("update-remove");
+            let installed = module(&root, "installed", Some(&state));
+            root.write(&format!("installed/{}", defs::REMOVE_FILE_NAME), "");
+            let staged = module(&root, "staged", None);
+            carry_state(&installed, &staged).unwrap();
+            assert_eq!(disabled_by(&staged), Some(state));
+            assert!(prunable(&staged));
+        }
+    }
+
+    #[test]
+    fn update_does_not_disable_enabled_module() {
+        let root = TempDir::new("update-enabled");
+        let installed = module(&root, "installed", None);
+        let staged = module(&root, "staged", Some(&Disabled::User));
+        carry_state(&installed, &staged).unwrap();
+        assert_eq!(disabled_by(&staged), None);
+    }
+
+    /// `id` is a module whose `disable` flag cannot be set
+    fn broken_module(root: &TempDir, id: &str) {
+        module(root, id, None);
+        fs::create_dir(root.path().join(id).join(defs::DISABLE_FILE_NAME)).unwrap();
+    }
+
+    #[test]
+    fn failed_batch_restores_enabled_modules() {
+        let root = TempDir::new("batch-enable");
+        let reason = Disabled::System("boot.safe_mode.modules_disabled".to_string());
+        let user = module(&root, "user", Some(&Disabled::User));
+        let system = module(&root, "system", Some(&reason));
+        broken_module(&root, "broken");
+        let ops = [
+            BatchOp::Enable { id: "user".into() },
+            BatchOp::Enable { id: "system".into() },
+            BatchOp::Disable { id: "broken".into() },
+        ];
+        assert!(apply_ops(root.path(), &ops).is_err());
+        assert_eq!(disabled_by(&user), Some(Disabled::User));
+        assert_eq!(disabled_by(&system), Some(reason));
+    }
+
+    #[test]
+    fn failed_batch_restores_every_flag() {
+        let root = TempDir::new("batch-flags");
+        let first = module(&root, "first", None);
+        root.write(&format!("first/{}", defs::SKIP_MOUNT_FILE_NAME), "");
+        broken_module(&root, "broken");
+        let ops = [
+            BatchOp::SetFlag {
+                id: "first".into(),
+                flag: defs::SKIP_MOUNT_FILE_NAME.into(),
+                value: false,
+            },
+            BatchOp::Disable { id: "first".into() },
+            BatchOp::Remove { id: "first".into() },
+            BatchOp::Disable { id: "broken".into() },
+        ];
+        assert!(apply_ops(root.path(), &ops).is_err());
+        assert!(crate::flags::is_set(first.join(defs::SKIP_MOUNT_FILE_NAME)));
+        assert_eq!(disabled_by(&first), None);
+        assert!(!prunable(&first));
+    }
+
+    #[test]
+    fn failing_first_op_changes_nothing() {
+        let root = TempDir::new("batch-first");
+        broken_module(&root, "broken");
+        let other = module(&root, "other", Some(&Disabled::User));
+        let ops = [
+            BatchOp::Disable { id: "broken".into() },
+            BatchOp::Enable { id: "other".into() },
+        ];
+        assert!(apply_ops(root.path(), &ops).is_err());
+        assert_eq!(disabled_by(&other), Some(Disabled::User));
+    }
+
+    #[test]
+    fn failed_swap_puts_installed_module_back() {
+        let root = TempDir::new("swap");
+        let installed = module(&root, "test", Some(&Disabled::User));
+        let missing = root.path().join("staged");
+        assert!(swap_in(&missing, &installed, "test").is_err());
+        assert_eq!(disabled_by(&installed), Some(Disabled::User));
+        assert!(installed.join("module.prop").exists());
+        assert!(!root.path().join(".test.replaced").exists());
+    }
+
+    #[test]
+    fn classifies_modules_by_their_files() {
+        let root = TempDir::new("classify");
+        let kind = |id: &str| classify(&root.path().join(id));
+
+        root.write("payload/system/bin/tool", "");
+        assert_eq!(kind("payload"), ModuleKind::Payload);
+        root.write("vendor-only/vendor/etc/tool.conf", "");
+        assert_eq!(kind("vendor-only"), ModuleKind::Payload);
+        root.write("hybrid/system/bin/tool", "");
+        root.write("hybrid/service.sh", "");
+        assert_eq!(kind("hybrid"), ModuleKind::Hybrid);
+        root.write("image/service.lua", "");
+        root.write(&format!("image/{}", defs::MODULE_IMAGE_FILE_NAME), "");
+        assert_eq!(kind("image"), ModuleKind::Hybrid);
+
+        root.write("scripts/post-fs-data.sh", "");
+        assert_eq!(kind("scripts"), ModuleKind::ScriptOnly);
+        root.write("props/system.prop", "ro.example=1");
+        assert_eq!(kind("props"), ModuleKind::ScriptOnly);
+        root.write("rules/sepolicy.rule", "allow a b c d");
+        assert_eq!(kind("rules"), ModuleKind::ScriptOnly);
+    }
+
+    #[test]
+    fn modules_without_payload_or_scripts_are_empty() {
+        let root = TempDir::new("classify-empty");
+        let kind = |id: &str| classify(&root.path().join(id));
+
+        root.write("bare/module.prop", "id=bare");
+        assert_eq!(kind("bare"), ModuleKind::Empty);
+        fs::create_dir_all(root.path().join("empty-system/system")).unwrap();
+        assert_eq!(kind("empty-system"), ModuleKind::Empty);
+        fs::create_dir_all(root.path().join("empty-dirs/system/bin")).unwrap();
+        fs::create_dir_all(root.path().join("empty-dirs/vendor/lib64")).unwrap();
+        assert_eq!(kind("empty-dirs"), ModuleKind::Empty);
+        // a script dir is not a script at the module root
+        root.write("nested/common/functions.sh", "");
+        assert_eq!(kind("nested"), ModuleKind::Empty);
+        // a replace marker is a payload even in an otherwise empty dir
+        root.write("replace/system/app/Example/.replace", "");
+        assert_eq!(kind("replace"), ModuleKind::Payload);
+        assert!(!ModuleKind::Empty.has_payload());
+        assert!(!ModuleKind::ScriptOnly.has_payload());
+    }
+
+    #[test]
+    fn disable_reason_alone_does_not_disable() {
+        let root = TempDir::new("stale-reason");
+        let path = module(&root, "test", None);
+        write_disable_reason(&path, "boot.safe_mode.modules_disabled").unwrap();
+        assert_eq!(disabled_by(&path), None);
+        clear_disable_reason(&path).unwrap();
+        assert!(!path.join(defs::DISABLE_REASON_FILE_NAME).exists());
+        // clearing twice is fine
+        clear_disable_reason(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only_module_dirs_are_removed() {
+        let root = TempDir::new("force-remove");
+        let module = root.path().join("test");
+        root.write("test/system/bin/tool", "");
+        root.write("test/system/lib64/libfoo.so", "");
+        std::os::unix::fs::symlink("/nonexistent", module.join("system/bin/link")).unwrap();
+        for dir in ["test/system/bin", "test/system", "test"] {
+            fs::set_permissions(root.path().join(dir), fs::Permissions::from_mode(0o500))
+                .unwrap();
+        }
+        assert_eq!(force_remove_dir(&module), 0);
+        assert!(!module.exists());
+        // a module already gone leaves nothing to remove
+        assert_eq!(force_remove_dir(&module), 0);
+    }
+
+    #[test]
+    fn a_partially_removed_module_is_finished() {
+        let root = TempDir::new("force-partial");
+        let module = root.path().join("test");
+        root.write("test/system/bin/tool", "");
+        root.write("test/service.sh", "");
+        fs::remove_file(module.join("system/bin/tool")).unwrap();
+        fs::set_permissions(module.join("system"), fs::Permissions::from_mode(0o500)).unwrap();
+        let mut failed = 0;
+        force_remove(&module, &mut failed);
+        assert_eq!(failed, 0);
+        assert!(!module.exists());
+    }
+
+    const VALID_PROP: &str = "id=example\nname=Example\nversion=v1\nversionCode=1\n";
+
+    /// `content` as the module.prop of a module `example`
+    fn parse_fixture(content: &[u8]) -> Result<ModuleInfo> {
+        let root = TempDir::new("module-prop");
+        let module = root.path().join("example");
+        fs::create_dir(&module).unwrap();
+        fs::write(module.join("module.prop"), content).unwrap();
+        ModuleInfo::parse(&module)
+    }
+
+    #[test]
+    fn well_formed_module_props_parse() {
+        let info = parse_fixture(VALID_PROP.as_bytes()).unwrap();
+        assert_eq!(info.id, "example");
+        assert_eq!(info.props["versionCode"], "1");
+
+        // as written by Windows editors
+        let windows = format!("\u{feff}{}", VALID_PROP.replace('\n', "\r\n"));
+        let info = parse_fixture(windows.as_bytes()).unwrap();
+        assert_eq!(info.id, "example");
+        assert_eq!(info.props["name"], "Example");
+
+        let padded = "# comment\n\n  id = example \nname=A\nversion=1\nversionCode= 7 \n";
+        assert_eq!(parse_fixture(padded.as_bytes()).unwrap().id, "example");
+    }
+
+    #[test]
+    fn malformed_module_props_are_rejected() {
+        let fixtures: [(&str, &[u8]); 9] = [
+            ("missing id", b"name=Example\nversion=v1\nversionCode=1\n"),
+            ("empty id", b"id=\nname=Example\nversion=v1\nversionCode=1\n"),
+            ("path id", b"id=../example\nname=Example\nversion=v1\nversionCode=1\n"),
+            ("digit id", b"id=1example\nname=Example\nversion=v1\nversionCode=1\n"),
+            ("missing name", b"id=example\nversion=v1\nversionCode=1\n"),
+            ("blank version", b"id=example\nname=Example\nversion=  \nversionCode=1\n"),
+            ("text versionCode", b"id=example\nname=Example\nversion=v1\nversionCode=one\n"),
+            ("other dir", b"id=other\nname=Example\nversion=v1\nversionCode=1\n"),
+            ("bad escape", b"id=example\nname=\\u12\nversion=v1\nversionCode=1\n"),
+        ];
+        for (case, content) in fixtures {
+            assert!(parse_fixture(content).is_err(), "{case} was accepted");
+        }
+    }
+
+    #[test]
+    fn a_module_without_module_prop_is_rejected() {
+        let root = TempDir::new("module-prop-missing");
+        fs::create_dir(root.path().join("example")).unwrap();
+        let error = ModuleInfo::parse(&root.path().join("example")).unwrap_err();
+        assert!(error.to_string().contains("module.prop not found"));
+    }
+
+    #[test]
+    fn modules_are_sorted_by_mount_order_then_id() {
+        let root = TempDir::new("mount-order");
+        for (id, prop) in [
+            ("e", ""),
+            ("d", "mountorder=abc"),
+            ("c", "priority=5"),
+            ("b", "mountorder=5"),
+            ("a", "mountorder= 10 "),
+            ("z", "mountorder=-1\npriority=99"),
+        ] {
+            root.write(&format!("{id}/module.prop"), &format!("id={id}\n{prop}\n"));
+        }
+        let mut modules: Vec<PathBuf> = ["a", "b", "c", "d", "e", "z", "missing"]
+            .iter()
+            .map(|id| root.path().join(id))
+            .collect();
+        sort_by_mount_order(&mut modules);
+        let ids: Vec<_> = modules
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        // invalid and missing values go last, ties go by id
+        assert_eq!(ids, ["z", "b", "c", "a", "d", "e", "missing"]);
+        assert_eq!(mount_order(&root.path().join("d")), None);
+        assert_eq!(mount_order(&root.path().join("c")), Some(5));
+    }
+
+    #[test]
+    fn common_scripts_run_in_name_order_and_get_made_executable() {
+        let root = TempDir::new("common-scripts");
+        for name in ["20-b.sh", "10-a.sh", "3-c.sh", "a b.sh"] {
+            let script = root.write(&format!("post-mount.d/{name}"), "");
+            fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let plain = root.write("post-mount.d/15-plain.sh", "");
+        fs::create_dir(root.path().join("post-mount.d/30-dir")).unwrap();
+
+        let scripts = common_scripts(&root.path().join("post-mount.d")).unwrap();
+        let names: Vec<_> = scripts
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["10-a.sh", "15-plain.sh", "20-b.sh", "3-c.sh", "a b.sh"]);
+        assert_eq!(plain.metadata().unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    #[test]
+    fn scripts_get_the_common_environment() {
+        let root = TempDir::new("script-env");
+        // stands in for busybox, `sh <script>` reports what the script would see
+        let busybox = root.write(
+            "busybox",
+            "#!/bin/sh\n\
+             echo \"$1|${2##*/}|$(pwd)|$APATCH|$APATCH_VER|$MOUNT_MODE|\
+             ${MOUNT_DECISION-unset}|${KERNELPATCH_VERSION-unset}\" >> ../log\n",
+        );
+        fs::set_permissions(&busybox, fs::Permissions::from_mode(0o755)).unwrap();
+        root.write("service.d/b.sh", "");
+        root.write("service.d/a.sh", "");
+
+        for script in common_scripts(&root.path().join("service.d")).unwrap() {
+            let status = script_command(&busybox, &script).status().unwrap();
+            assert!(status.success());
+        }
+        let envs: HashMap<_, _> = get_common_script_envs().into_iter().collect();
+        let dir = root.path().join("service.d");
+        let expected: Vec<String> = ["a.sh", "b.sh"]
+            .iter()
+            .map(|name| {
+                format!(
+                    "sh|{name}|{}|true|{}|{}|{}|{}",
+                    dir.display(),
+                    defs::VERSION_NAME,
+                    envs["MOUNT_MODE"],
+                    envs["MOUNT_DECISION"],
+                    envs["KERNELPATCH_VERSION"],
+                )
+            })
+            .collect();
+        let log = fs::read_to_string(root.path().join("log")).unwrap();
+        assert_eq!(log.lines().collect::<Vec<_>>(), expected);
+    }
+}
@@ -114,13 +114,20 @@ pub fn mount_devpts(_dest: impl AsRef<Path>) -> Result<()> {
     unimplemented!()
 }
 
-#[cfg(any(target_os = "linux", target_os = "android"))]
+/// Source of the tmpfs holding copies of module files, see `mirror_modules`
+pub const MIRROR_SOURCE: &str = "APatch-mirror";
+
 pub fn mount_tmpfs(dest: impl AsRef<Path>) -> Result<()> {
+    mount_tmpfs_named(dest, "APatch")
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn mount_tmpfs_named(dest: impl AsRef<Path>, source: &str) -> Result<()> {
     debug!("mount tmpfs on {}", dest.as_ref().display());
     match fsopen("tmpfs", FsOpenFlags::FSOPEN_CLOEXEC) {
         Result::Ok(fs) => {
             let fs = fs.as_fd();
-            fsconfig_set_string(fs, "source", "APatch")?;
+            fsconfig_set_string(fs, "source", source)?;
             fsconfig_create(fs)?;
             let mount = fsmount(fs, FsMountFlags::FSMOUNT_CLOEXEC, MountAttrFlags::empty())?;
             move_mount(
@@ -133,7 +140,7 @@ pub fn mount_tmpfs(dest: impl AsRef<Path>) -> Result<()> {
         }
         _ => {
             mount(
-                "APatch",
+                source,
                 dest.as_ref(),
                 "tmpfs",
                 MountFlags::empty(),
@@ -147,6 +154,814 @@ pub fn mount_tmpfs(dest: impl AsRef<Path>) -> Result<()> {
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
-pub fn mount_tmpfs(_dest: impl AsRef<Path>) -> Result<()> {
+pub fn mount_tmpfs_named(_dest: impl AsRef<Path>, _source: &str) -> Result<()> {
     unimplemented!()
 }
+
+/// Per-mount flags of the mount holding `path` and whether it has shared propagation
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_mount_attrs(path: impl AsRef<Path>) -> Result<(MountFlags, bool)> {
+    use procfs::process::MountOptFields;
+
+    let mount_info = holder(path.as_ref())?;
+
+    let mut flags = MountFlags::empty();
+    for (option, flag) in [
+        ("ro", MountFlags::RDONLY),
+        ("nosuid", MountFlags::NOSUID),
+        ("nodev", MountFlags::NODEV),
+        ("noexec", MountFlags::NOEXEC),
+        ("noatime", MountFlags::NOATIME),
+        ("nodiratime", MountFlags::NODIRATIME),
+        ("relatime", MountFlags::RELATIME),
+    ] {
+        if mount_info.mount_options.contains_key(option) {
+            flags |= flag;
+        }
+    }
+    let shared = mount_info
+        .opt_fields
+        .iter()
+        .any(|field| matches!(field, MountOptFields::Shared(_)));
+    Ok((flags, shared))
+}
+
+/// The mount holding `path`, the topmost one if mounts are stacked
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn holder(path: &Path) -> Result<procfs::process::MountInfo> {
+    let mounts = procfs::process::Process::myself()?.mountinfo()?;
+    holder_of(mounts, path).with_context(|| format!("no mount holds {}", path.display()))
+}
+
+/// The mount of `mounts` holding `path`, of stacked mounts the last one
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn holder_of(
+    mounts: impl IntoIterator<Item = procfs::process::MountInfo>,
+    path: &Path,
+) -> Option<procfs::process::MountInfo> {
+    mounts
+        .into_iter()
+        .filter(|info| path.starts_with(&info.mount_point))
+        .max_by_key(|info| info.mount_point.as_os_str().len())
+}
+
+/// Propagation type of a mount as shown by the optional fields of mountinfo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    Shared,
+    Slave,
+    Private,
+    Unbindable,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Propagation {
+    fn of(info: &procfs::process::MountInfo) -> Self {
+        use procfs::process::MountOptFields;
+
+        let fields = &info.opt_fields;
+        if fields.iter().any(|f| matches!(f, MountOptFields::Shared(_))) {
+            Propagation::Shared
+        } else if fields.iter().any(|f| matches!(f, MountOptFields::Master(_))) {
+            Propagation::Slave
+        } else if fields.iter().any(|f| matches!(f, MountOptFields::Unbindable)) {
+            Propagation::Unbindable
+        } else {
+            Propagation::Private
+        }
+    }
+
+    fn flags(self) -> MountPropagationFlags {
+        match self {
+            Propagation::Shared => MountPropagationFlags::SHARED,
+            Propagation::Slave => MountPropagationFlags::DOWNSTREAM,
+            Propagation::Private => MountPropagationFlags::PRIVATE,
+            Propagation::Unbindable => MountPropagationFlags::UNBINDABLE,
+        }
+    }
+}
+
+/// Run `f` with the mounts holding `targets` switched to `mode`, so mounts made
+/// by `f` propagate as wanted, and restore their propagation afterwards
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn with_propagation<T>(
+    targets: &[impl AsRef<Path>],
+    mode: Propagation,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let mut changed: Vec<(std::path::PathBuf, Propagation)> = Vec::new();
+    for target in targets {
+        let info = match holder(target.as_ref()) {
+            Result::Ok(info) => info,
+            Err(e) => {
+                log::warn!("{:#}", e);
+                continue;
+            }
+        };
+        let original = Propagation::of(&info);
+        if original == mode || changed.iter().any(|(point, _)| *point == info.mount_point) {
+            continue;
+        }
+        match mount_change(&info.mount_point, mode.flags()) {
+            Result::Ok(()) => {
+                debug!("{} {:?} -> {:?}", info.mount_point.display(), original, mode);
+                changed.push((info.mount_point, original));
+            }
+            Err(e) => log::warn!(
+                "cannot make {} {:?}: {}",
+                info.mount_point.display(),
+                mode,
+                e
+            ),
+        }
+    }
+
+    let result = f();
+
+    for (point, original) in changed.into_iter().rev() {
+        if let Err(e) = mount_change(&point, original.flags()) {
+            log::warn!("cannot restore {:?} on {}: {}", original, point.display(), e);
+        }
+    }
+    result
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn with_propagation<T>(
+    _targets: &[impl AsRef<Path>],
+    _mode: Propagation,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    f()
+}
+
+/// `points` which are missing in a mount namespace created right now, as apps
+/// get one from zygote
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn missing_in_new_namespace(
+    points: Vec<std::path::PathBuf>,
+) -> Result<Vec<std::path::PathBuf>> {
+    // the namespace of this thread goes away with it
+    let mountinfo = std::thread::spawn(|| -> Result<String> {
+        if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("unshare mount namespace");
+        }
+        std::fs::read_to_string("/proc/thread-self/mountinfo").context("read mountinfo")
+    })
+    .join()
+    .map_err(|_| anyhow::anyhow!("namespace check panicked"))??;
+
+    let mounted = mount_points(&mountinfo);
+    Ok(points
+        .into_iter()
+        .filter(|point| !mounted.contains(&*point.to_string_lossy()))
+        .collect())
+}
+
+/// Mount points listed in `mountinfo`, with escaped spaces restored
+fn mount_points(mountinfo: &str) -> std::collections::HashSet<String> {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .map(|point| point.replace("\\040", " "))
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn missing_in_new_namespace(
+    _points: Vec<std::path::PathBuf>,
+) -> Result<Vec<std::path::PathBuf>> {
+    unimplemented!()
+}
+
+/// Apply per-mount flags to the mount at `path`, flags not given are cleared
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_mount_attrs(path: impl AsRef<Path>, flags: MountFlags) -> Result<()> {
+    mount_remount(path.as_ref(), MountFlags::BIND | flags, "")
+        .with_context(|| format!("remount {} with {:?}", path.as_ref().display(), flags))?;
+    Ok(())
+}
+
+/// One place where files of a module are visible
+#[derive(serde::Serialize, Debug)]
+pub struct ModuleMount {
+    pub target: std::path::PathBuf,
+    /// mounted as an overlay layer by a metamodule rather than bind mounted
+    pub overlay: bool,
+}
+
+/// Mounts of module files in the init mount namespace, by module id
+///
+/// Bind mounts are recognized by their root inside the filesystem holding the
+/// module dir or the module mirror, or by the loop device of a module image,
+/// overlays by their lowerdirs.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn module_mounts() -> Result<std::collections::BTreeMap<String, Vec<ModuleMount>>> {
+    use procfs::process::Process;
+    use std::path::Component;
+
+    let mounts = Process::new(1)?.mountinfo()?.into_iter().collect::<Vec<_>>();
+    let module_dir = Path::new(crate::defs::MODULE_DIR);
+    let holder = mounts
+        .iter()
+        .filter(|info| module_dir.starts_with(&info.mount_point))
+        .max_by_key(|info| info.mount_point.as_os_str().len())
+        .with_context(|| format!("no mount holds {}", module_dir.display()))?;
+    let module_root = Path::new(&holder.root).join(module_dir.strip_prefix(&holder.mount_point)?);
+
+    let module_of = |path: &Path, root: &Path| {
+        match path.strip_prefix(root).ok()?.components().next() {
+            Some(Component::Normal(id)) => Some(id.to_string_lossy().into_owned()),
+            _ => None,
+        }
+    };
+
+    let mut modules = std::collections::BTreeMap::<String, Vec<ModuleMount>>::new();
+    for info in &mounts {
+        let mut ids = Vec::new();
+        if info.fs_type == "overlay" {
+            let lowerdirs = info.super_options.get("lowerdir").cloned().flatten();
+            for lowerdir in lowerdirs.iter().flat_map(|dirs| dirs.split(':')) {
+                ids.extend(module_of(Path::new(lowerdir), module_dir));
+            }
+        } else if info.majmin == holder.majmin {
+            ids.extend(module_of(Path::new(&info.root), &module_root));
+        } else if info.mount_source.as_deref() == Some(MIRROR_SOURCE) {
+            // the mirror holds the modules by id at its root
+            ids.extend(module_of(Path::new(&info.root), Path::new("/")));
+        } else if let Result::Ok(image) =
+            std::fs::read_to_string(format!("/sys/dev/block/{}/loop/backing_file", info.majmin))
+        {
+            ids.extend(module_of(Path::new(image.trim()), module_dir));
+        }
+        ids.dedup();
+        for id in ids {
+            modules.entry(id).or_default().push(ModuleMount {
+                target: info.mount_point.clone(),
+                overlay: info.fs_type == "overlay",
+            });
+        }
+    }
+    Ok(modules)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn module_mounts() -> Result<std::collections::BTreeMap<String, Vec<ModuleMount>>> {
+    unimplemented!()
+}
+
+/// Outermost mount points created by module mounting: magic mount tmpfs dirs
+/// and the bind mounts or overlays of module files
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn module_mount_points() -> Result<Vec<std::path::PathBuf>> {
+    use procfs::process::Process;
+
+    let mut points: Vec<_> = module_mounts()?
+        .into_values()
+        .flatten()
+        .map(|mount| mount.target)
+        .collect();
+    points.extend(
+        Process::new(1)?
+            .mountinfo()?
+            .into_iter()
+            .filter(|info| {
+                info.fs_type == "tmpfs"
+                    && matches!(info.mount_source.as_deref(), Some("APatch" | MIRROR_SOURCE))
+            })
+            .map(|info| info.mount_point),
+    );
+    Ok(outermost(points))
+}
+
+/// Drop the mount points below others, a detaching unmount takes them along
+pub fn outermost(mut points: Vec<std::path::PathBuf>) -> Vec<std::path::PathBuf> {
+    points.sort();
+    points.dedup();
+    let mut outermost: Vec<std::path::PathBuf> = Vec::new();
+    for point in points {
+        if !outermost.iter().any(|parent| point.starts_with(parent)) {
+            outermost.push(point);
+        }
+    }
+    outermost
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn module_mount_points() -> Result<Vec<std::path::PathBuf>> {
+    unimplemented!()
+}
+
+/// Whether `point` is on a partition modules are mounted on
+fn on_module_partition(point: &Path) -> bool {
+    std::iter::once("system")
+        .chain(crate::critical::PARTITIONS)
+        .any(|partition| point.starts_with(Path::new("/").join(partition)))
+}
+
+/// Why `info`, found on a partition before the modules are mounted, is left
+/// over from an earlier mount run, `None` if it must be left alone.
+///
+/// Only overlays with lowerdirs in `/data/adb` or gone, the tmpfs of apd,
+/// bind mounts of `/data/adb` or of deleted dirs of `/data`, and mounts of
+/// loop devices whose image is in `/data/adb`, deleted or detached count. Any
+/// other mount, everything the ROM mounts in particular, is never touched.
+/// `adb_majmin` and `adb_root` describe the filesystem holding `/data/adb`,
+/// `exists` and `loop_backing`, the backing file of a loop device by its
+/// `major:minor`, look at the system.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn leftover_reason(
+    info: &procfs::process::MountInfo,
+    adb_majmin: &str,
+    adb_root: &Path,
+    exists: &impl Fn(&Path) -> bool,
+    loop_backing: &impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    if !on_module_partition(&info.mount_point) {
+        return None;
+    }
+    let adb_dir = Path::new(crate::defs::ADB_DIR);
+    match info.fs_type.as_str() {
+        "overlay" => {
+            let lowerdirs = info.super_options.get("lowerdir").cloned().flatten();
+            lowerdirs
+                .iter()
+                .flat_map(|dirs| dirs.split(':'))
+                .map(Path::new)
+                .find_map(|dir| {
+                    if dir.starts_with(adb_dir) {
+                        Some(format!("overlay of {}", dir.display()))
+                    } else if !exists(dir) {
+                        Some(format!("overlay of missing {}", dir.display()))
+                    } else {
+                        None
+                    }
+                })
+        }
+        "tmpfs" => matches!(info.mount_source.as_deref(), Some("APatch" | MIRROR_SOURCE))
+            .then(|| "tmpfs of an earlier mount run".to_string()),
+        _ if info.majmin == adb_majmin => {
+            // the root of a bind of a deleted dir ends in `//deleted`
+            let root = Path::new(&info.root);
+            if let Result::Ok(relative) = root.strip_prefix(adb_root) {
+                Some(format!("bind of {}", adb_dir.join(relative).display()))
+            } else if info.root.ends_with("//deleted") {
+                Some(format!("bind of deleted dir {}", info.root))
+            } else {
+                None
+            }
+        }
+        _ if info
+            .mount_source
+            .as_deref()
+            .is_some_and(|source| source.starts_with("/dev/block/loop")) =>
+        {
+            match loop_backing(&info.majmin) {
+                None => Some("mount of a detached loop device".to_string()),
+                Some(image) if image.trim().ends_with("(deleted)") => {
+                    Some(format!("mount of deleted image {}", image.trim()))
+                }
+                Some(image) if Path::new(image.trim()).starts_with(adb_dir) => {
+                    Some(format!("mount of image {}", image.trim()))
+                }
+                Some(_) => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The mounts of `mounts` left over from an earlier mount run along with
+/// why, deepest first, see [`leftover_reason`].
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn leftovers<'a>(
+    mounts: &'a [procfs::process::MountInfo],
+    exists: impl Fn(&Path) -> bool,
+    loop_backing: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<(&'a procfs::process::MountInfo, String)>> {
+    let adb_dir = Path::new(crate::defs::ADB_DIR);
+    let holder = holder_of(mounts.iter().cloned(), adb_dir)
+        .with_context(|| format!("no mount holds {}", adb_dir.display()))?;
+    let adb_root = Path::new(&holder.root).join(adb_dir.strip_prefix(&holder.mount_point)?);
+
+    let mut leftovers: Vec<_> = mounts
+        .iter()
+        .filter_map(|info| {
+            let reason =
+                leftover_reason(info, &holder.majmin, &adb_root, &exists, &loop_backing)?;
+            Some((info, reason))
+        })
+        .collect();
+    // of mounts stacked on one point the topmost goes first
+    leftovers.sort_by_key(|(info, _)| {
+        std::cmp::Reverse((info.mount_point.components().count(), info.mnt_id))
+    });
+    Ok(leftovers)
+}
+
+/// Lazily unmount mounts left on the partitions by an earlier mount run, e.g.
+/// of an apd which crashed before it recorded them, deepest first. Returns the
+/// mount points cleaned.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn sweep_leftover_mounts() -> Result<Vec<std::path::PathBuf>> {
+    use procfs::process::Process;
+
+    let mounts = Process::new(1)?.mountinfo()?.into_iter().collect::<Vec<_>>();
+    let leftovers = leftovers(
+        &mounts,
+        |dir| dir.exists(),
+        |majmin| {
+            std::fs::read_to_string(format!("/sys/dev/block/{majmin}/loop/backing_file")).ok()
+        },
+    )?;
+    let mut cleaned = Vec::new();
+    for (info, reason) in leftovers {
+        match detach(&info.mount_point) {
+            Result::Ok(()) => {
+                log::info!("cleaned leftover mount {}: {reason}", info.mount_point.display());
+                cleaned.push(info.mount_point.clone());
+            }
+            Err(e) => log::warn!("{e:#}"),
+        }
+    }
+    Ok(cleaned)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn sweep_leftover_mounts() -> Result<Vec<std::path::PathBuf>> {
+    unimplemented!()
+}
+
+/// Lazily unmount `path` along with its submounts
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn detach(path: impl AsRef<Path>) -> Result<()> {
+    unmount(path.as_ref(), UnmountFlags::DETACH)
+        .with_context(|| format!("detach {}", path.as_ref().display()))?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn detach(_path: impl AsRef<Path>) -> Result<()> {
+    unimplemented!()
+}
+
+/// A view of `root`, usually /data, holding nothing but a few of its paths
+///
+/// Everything is prepared up front, [`Confinement::enter`] runs between fork
+/// and exec and only makes raw syscalls with the C strings kept here. It moves
+/// the process into a private mount namespace, builds a read-only tmpfs at
+/// `staging` with the writable and read-only paths bound in at their place
+/// below `root`, and moves that tmpfs over `root`. Everything outside `root`
+/// stays as it is.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub struct Confinement {
+    root: std::ffi::CString,
+    staging: std::ffi::CString,
+    /// mount points to create in the tmpfs, parents first
+    dirs: Vec<std::ffi::CString>,
+    files: Vec<std::ffi::CString>,
+    /// source, target in the tmpfs and whether it is read-only, parents first
+    binds: Vec<(std::ffi::CString, std::ffi::CString, bool)>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Confinement {
+    /// `writable` and `readonly` must exist below `root`, `staging` is an
+    /// empty dir outside of it
+    pub fn new(
+        root: &Path,
+        staging: &Path,
+        writable: &[std::path::PathBuf],
+        readonly: &[std::path::PathBuf],
+    ) -> Result<Self> {
+        use std::{collections::BTreeSet, ffi::CString, os::unix::ffi::OsStrExt};
+
+        let c_path = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .with_context(|| format!("{} contains NUL", path.display()))
+        };
+        let mut dirs = BTreeSet::new();
+        let mut files = BTreeSet::new();
+        let mut binds = Vec::new();
+        let paths = writable.iter().map(|path| (path, false));
+        for (path, readonly) in paths.chain(readonly.iter().map(|path| (path, true))) {
+            let rel = path
+                .strip_prefix(root)
+                .with_context(|| format!("{} is not below {}", path.display(), root.display()))?;
+            let metadata = path
+                .metadata()
+                .with_context(|| format!("Failed to stat {}", path.display()))?;
+            let target = staging.join(rel);
+            let parents = target.ancestors().skip(1);
+            dirs.extend(parents.take_while(|dir| *dir != staging).map(Path::to_path_buf));
+            if metadata.is_dir() {
+                dirs.insert(target.clone());
+            } else {
+                files.insert(target.clone());
+            }
+            binds.push((path.clone(), target, readonly));
+        }
+        binds.sort_by(|(_, a, _), (_, b, _)| a.cmp(b));
+        std::fs::create_dir_all(staging)
+            .with_context(|| format!("Failed to create {}", staging.display()))?;
+        Ok(Confinement {
+            root: c_path(root)?,
+            staging: c_path(staging)?,
+            dirs: dirs.iter().map(|dir| c_path(dir)).collect::<Result<_>>()?,
+            files: files.iter().map(|file| c_path(file)).collect::<Result<_>>()?,
+            binds: binds
+                .iter()
+                .map(|(source, target, readonly)| {
+                    std::result::Result::Ok((c_path(source)?, c_path(target)?, *readonly))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Move the calling process into the view, meant for `pre_exec` and so
+    /// free of allocations
+    pub fn enter(&self) -> std::io::Result<()> {
+        use std::{ffi::CStr, io, ptr};
+
+        fn check(result: libc::c_int) -> io::Result<()> {
+            if result < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                io::Result::Ok(())
+            }
+        }
+        fn sys_mount(
+            source: Option<&CStr>,
+            target: &CStr,
+            fstype: Option<&CStr>,
+            flags: libc::c_ulong,
+            data: Option<&CStr>,
+        ) -> io::Result<()> {
+            let ptr_of = |s: Option<&CStr>| s.map_or(ptr::null(), CStr::as_ptr);
+            check(unsafe {
+                libc::mount(
+                    ptr_of(source),
+                    target.as_ptr(),
+                    ptr_of(fstype),
+                    flags,
+                    ptr_of(data).cast(),
+                )
+            })
+        }
+
+        let sealed = libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV;
+        check(unsafe { libc::unshare(libc::CLONE_NEWNS) })?;
+        sys_mount(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+        let tmpfs = Some(c"tmpfs");
+        let flags = libc::MS_NOSUID | libc::MS_NODEV;
+        sys_mount(tmpfs, &self.staging, tmpfs, flags, Some(c"mode=0755"))?;
+        for dir in &self.dirs {
+            check(unsafe { libc::mkdir(dir.as_ptr(), 0o755) })?;
+        }
+        for file in &self.files {
+            let flags = libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC;
+            let fd = unsafe { libc::open(file.as_ptr(), flags, 0o644) };
+            check(fd)?;
+            unsafe { libc::close(fd) };
+        }
+        for (source, target, readonly) in &self.binds {
+            sys_mount(Some(source), target, None, libc::MS_BIND | libc::MS_REC, None)?;
+            if *readonly {
+                sys_mount(None, target, None, libc::MS_BIND | libc::MS_REMOUNT | sealed, None)?;
+            }
+        }
+        sys_mount(None, &self.staging, None, libc::MS_REMOUNT | sealed, None)?;
+        sys_mount(Some(&self.staging), &self.root, None, libc::MS_MOVE, None)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub struct Confinement;
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+impl Confinement {
+    pub fn new(
+        _root: &Path,
+        _staging: &Path,
+        _writable: &[std::path::PathBuf],
+        _readonly: &[std::path::PathBuf],
+    ) -> Result<Self> {
+        unimplemented!()
+    }
+
+    pub fn enter(&self) -> std::io::Result<()> {
+        unimplemented!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use procfs::process::MountInfo;
+
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+1 0 253:2 / / ro,relatime shared:1 - ext4 /dev/block/dm-2 ro
+25 1 253:3 / /vendor ro,relatime master:2 - erofs /dev/block/dm-3 ro
+30 1 0:20 / /debug_ramdisk rw,relatime - tmpfs tmpfs rw
+31 1 0:21 / /system/bin/app_process64 ro,relatime shared:5 - tmpfs APatch ro
+32 1 0:22 / /system/bin/app_process64 ro,relatime unbindable - tmpfs APatch ro
+33 1 0:23 / /data/local/my\\040dir rw,relatime shared:7 - tmpfs tmpfs rw
+";
+
+    fn mounts() -> Vec<MountInfo> {
+        MOUNTINFO
+            .lines()
+            .map(|line| MountInfo::from_line(line).unwrap())
+            .collect()
+    }
+
+    fn holder_in(path: &str) -> (PathBuf, Propagation) {
+        let info = holder_of(mounts(), Path::new(path)).unwrap();
+        let propagation = Propagation::of(&info);
+        (info.mount_point, propagation)
+    }
+
+    #[test]
+    fn the_deepest_mount_holds_a_path() {
+        assert_eq!(holder_in("/vendor/lib64/libc.so"), ("/vendor".into(), Propagation::Slave));
+        assert_eq!(holder_in("/vendors"), ("/".into(), Propagation::Shared));
+        assert_eq!(holder_in("/debug_ramdisk"), ("/debug_ramdisk".into(), Propagation::Private));
+        assert!(holder_of(Vec::new(), Path::new("/system")).is_none());
+    }
+
+    #[test]
+    fn the_last_of_stacked_mounts_holds_a_path() {
+        assert_eq!(
+            holder_in("/system/bin/app_process64"),
+            ("/system/bin/app_process64".into(), Propagation::Unbindable)
+        );
+    }
+
+    #[test]
+    fn mount_points_restore_escaped_spaces() {
+        let points = mount_points(MOUNTINFO);
+        assert!(points.contains("/data/local/my dir"));
+        assert!(points.contains("/system/bin/app_process64"));
+        assert_eq!(points.len(), 5);
+    }
+
+    /// Captured from a device with apd killed mid mount run, trimmed
+    const LEFTOVERS: &str = "\
+1 0 253:2 / / ro,relatime shared:1 - ext4 /dev/block/dm-2 ro
+25 1 253:3 / /vendor ro,relatime master:2 - erofs /dev/block/dm-3 ro
+40 1 253:5 / /data rw,nosuid,nodev,noatime shared:10 - f2fs /dev/block/dm-5 rw
+41 25 253:3 /etc/audio /vendor/etc/audio ro,relatime - erofs /dev/block/dm-3 ro
+50 1 0:30 / /system/etc ro,relatime - overlay overlay ro,lowerdir=/data/adb/modules/a/system/etc
+51 25 0:31 / /vendor/lib ro,relatime - overlay overlay ro,lowerdir=/mnt/gone/lib:/vendor/lib
+52 1 0:32 / /product/overlay ro,relatime - overlay overlay ro,lowerdir=/product/rro:/product/overlay
+53 1 0:33 / /system/bin/app_process64 ro,relatime - tmpfs APatch ro
+54 1 0:34 / /odm/etc ro,relatime - tmpfs tmpfs rw
+55 1 253:5 /adb/modules/b/system/fonts /system/fonts ro,relatime - f2fs /dev/block/dm-5 rw
+56 55 253:5 /local/tmp/x//deleted /system/fonts/x ro,relatime - f2fs /dev/block/dm-5 rw
+57 1 7:56 / /system_ext/app ro,relatime - ext4 /dev/block/loop7 ro
+58 1 7:64 / /product/app ro,relatime - ext4 /dev/block/loop8 ro
+59 1 7:72 / /oem/app ro,relatime - ext4 /dev/block/loop9 ro
+60 1 7:80 / /odm/app ro,relatime - ext4 /dev/block/loop10 ro
+61 40 253:5 /adb/modules /data/adb/modules rw,relatime - f2fs /dev/block/dm-5 rw
+62 1 0:35 / /apex/com.android.art ro,relatime - overlay overlay ro,lowerdir=/data/adb/x
+";
+
+    fn loop_backing(majmin: &str) -> Option<String> {
+        match majmin {
+            "7:56" => Some("/data/adb/ap/modules.img\n".to_string()),
+            "7:64" => Some("/data/local/tmp/x.img (deleted)\n".to_string()),
+            "7:80" => Some("/system/odm.img\n".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn only_leftovers_of_earlier_runs_are_cleaned_deepest_first() {
+        let mounts: Vec<MountInfo> =
+            LEFTOVERS.lines().map(|line| MountInfo::from_line(line).unwrap()).collect();
+        let exists = |dir: &Path| !dir.starts_with("/mnt/gone");
+        let found: Vec<(String, String)> = leftovers(&mounts, exists, loop_backing)
+            .unwrap()
+            .into_iter()
+            .map(|(info, reason)| (info.mount_point.display().to_string(), reason))
+            .collect();
+        let expected = [
+            ("/system/fonts/x", "bind of deleted dir /local/tmp/x//deleted"),
+            ("/system/bin/app_process64", "tmpfs of an earlier mount run"),
+            ("/oem/app", "mount of a detached loop device"),
+            ("/product/app", "mount of deleted image /data/local/tmp/x.img (deleted)"),
+            ("/system_ext/app", "mount of image /data/adb/ap/modules.img"),
+            ("/system/fonts", "bind of /data/adb/modules/b/system/fonts"),
+            ("/vendor/lib", "overlay of missing /mnt/gone/lib"),
+            ("/system/etc", "overlay of /data/adb/modules/a/system/etc"),
+        ];
+        assert_eq!(
+            found,
+            expected.map(|(point, reason)| (point.to_string(), reason.to_string()))
+        );
+    }
+
+    #[test]
+    fn nothing_is_cleaned_without_a_mount_holding_adb() {
+        assert!(leftovers(&[], |_| true, loop_backing).is_err());
+    }
+}
+
+#[cfg(all(test, feature = "privileged-tests"))]
+mod privileged_tests {
+    use std::{
+        fs,
+        os::unix::process::CommandExt,
+        path::PathBuf,
+        process::Command,
+    };
+
+    use super::Confinement;
+    use crate::testutil::TempDir;
+
+    /// Run `script` inside `confinement`, whether it exits with 0
+    fn run_confined(confinement: Confinement, script: &str) -> bool {
+        let mut command = Command::new("/bin/sh");
+        command.args(["-c", script]);
+        unsafe {
+            command.pre_exec(move || confinement.enter());
+        }
+        command.status().unwrap().success()
+    }
+
+    fn setup(root: &TempDir) -> (PathBuf, Confinement) {
+        let data = root.path().join("data");
+        root.write("data/adb/modules_update/example/module.prop", "id=example");
+        root.write("data/adb/ap/bin/busybox", "");
+        root.write("data/adb/ap/package_config", "secret");
+        root.write("data/local/tmp/module.zip", "zip");
+        root.write("data/data/com.example/grant", "secret");
+        let confinement = Confinement::new(
+            &data,
+            &root.path().join("view"),
+            &[data.join("adb/modules_update")],
+            &[data.join("adb/ap/bin"), data.join("local/tmp/module.zip")],
+        )
+        .unwrap();
+        (data, confinement)
+    }
+
+    #[test]
+    fn only_listed_paths_are_visible() {
+        let root = TempDir::new("confine-visible");
+        let (data, confinement) = setup(&root);
+        let d = data.display();
+        let script = format!(
+            "test -f {d}/adb/modules_update/example/module.prop \
+             && test -f {d}/adb/ap/bin/busybox \
+             && test -f {d}/local/tmp/module.zip \
+             && test ! -e {d}/adb/ap/package_config \
+             && test ! -e {d}/data/com.example"
+        );
+        assert!(run_confined(confinement, &script));
+        // nothing changes outside the process
+        assert!(data.join("adb/ap/package_config").exists());
+    }
+
+    #[test]
+    fn only_writable_paths_can_be_written() {
+        let root = TempDir::new("confine-writable");
+        let (data, confinement) = setup(&root);
+        let d = data.display();
+        let script = format!(
+            "echo new > {d}/adb/modules_update/example/service.sh \
+             && ! touch {d}/adb/ap/bin/busybox 2>/dev/null \
+             && ! echo x > {d}/local/tmp/module.zip 2>/dev/null \
+             && ! mkdir {d}/adb/ap/new 2>/dev/null \
+             && ! touch {d}/leak 2>/dev/null"
+        );
+        assert!(run_confined(confinement, &script));
+        assert!(data.join("adb/modules_update/example/service.sh").exists());
+        assert_eq!(fs::read_to_string(data.join("local/tmp/module.zip")).unwrap(), "zip");
+        assert!(!data.join("leak").exists());
+    }
+
+    #[test]
+    fn paths_outside_the_root_are_refused() {
+        let root = TempDir::new("confine-outside");
+        root.write("data/a", "");
+        root.write("other/b", "");
+        let result = Confinement::new(
+            &root.path().join("data"),
+            &root.path().join("view"),
+            &[root.path().join("other")],
+            &[],
+        );
+        assert!(result.is_err());
+        let missing = Confinement::new(
+            &root.path().join("data"),
+            &root.path().join("view"),
+            &[root.path().join("data/missing")],
+            &[],
+        );
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Mount namespace of su sessions
+//!
+//! * `inherit` keeps the namespace of the caller, the default
+//! * `global` joins the namespace of init, like `su -M`
+//! * `isolated` unshares a private namespace, with `su_strip_mounts = true`
+//!   the module mounts of the mount registry are detached in it, giving the
+//!   view of an unmodified system
+//!
+//! The mode is `su_namespace` of apd.toml, the `.global_namespace_enable` flag
+//! file still selects `global`. The `namespace` column of the package config
+//! overrides it for the uid of a package. A mode the kernel does not allow,
+//! e.g. `setns` denied by the policy, falls back to `inherit` with a warning.
+
+use std::{fmt, fs, str::FromStr};
+
+use anyhow::{Result, bail};
+use log::warn;
+
+use crate::{config, defs, package};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NamespaceMode {
+    Inherit,
+    Global,
+    Isolated,
+}
+
+impl FromStr for NamespaceMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "inherit" => Ok(NamespaceMode::Inherit),
+            "global" => Ok(NamespaceMode::Global),
+            "isolated" => Ok(NamespaceMode::Isolated),
+            _ => bail!("unknown namespace mode {mode}, use inherit, global or isolated"),
+        }
+    }
+}
+
+impl fmt::Display for NamespaceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NamespaceMode::Inherit => "inherit",
+            NamespaceMode::Global => "global",
+            NamespaceMode::Isolated => "isolated",
+        })
+    }
+}
+
+/// The mode of sessions without a per-package setting
+pub fn default_mode() -> NamespaceMode {
+    let flag = fs::read_to_string(defs::GLOBAL_NAMESPACE_FILE).unwrap_or_default();
+    if flag.trim() == "1" {
+        return NamespaceMode::Global;
+    }
+    config::su_namespace()
+        .and_then(|mode| {
+            mode.parse()
+                .inspect_err(|e| warn!("su_namespace of apd.toml: {e}"))
+                .ok()
+        })
+        .unwrap_or(NamespaceMode::Inherit)
+}
+
+/// The mode a su session requested by `uid` gets
+pub fn mode_for_uid(uid: u32) -> NamespaceMode {
+    let configured = package::read_ap_package_config()
+        .into_iter()
+        .find(|config| config.uid as u32 == uid && !config.namespace.is_empty());
+    match configured {
+        Some(config) => config.namespace.parse().unwrap_or_else(|e| {
+            warn!("package config of {}: {e}", config.pkg);
+            default_mode()
+        }),
+        None => default_mode(),
+    }
+}
+
+/// The uid which ran su, the owner of the parent process
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn caller_uid() -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+
+    let ppid = std::os::unix::process::parent_id();
+    fs::metadata(format!("/proc/{ppid}"))
+        .ok()
+        .map(|metadata| metadata.uid())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn caller_uid() -> Option<u32> {
+    None
+}
+
+/// Move the calling process into the namespace of `mode`, must be called
+/// while it is single threaded. Failures leave it where it was.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn enter(mode: NamespaceMode) {
+    let result = match mode {
+        NamespaceMode::Inherit => return,
+        NamespaceMode::Global => crate::utils::switch_mnt_ns(1),
+        NamespaceMode::Isolated => isolate(),
+    };
+    if let Err(e) = result {
+        warn!("Failed to enter the {mode} mount namespace, keeping the current one: {e:#}");
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn enter(_mode: NamespaceMode) {}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn isolate() -> Result<()> {
+    use anyhow::Context;
+    use rustix::mount::{MountPropagationFlags, mount_change};
+
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("unshare mount namespace");
+    }
+    // nothing done in here may leak back into the namespace of the caller
+    mount_change("/", MountPropagationFlags::REC | MountPropagationFlags::PRIVATE)
+        .context("make / private")?;
+    if !config::su_strip_mounts() {
+        return Ok(());
+    }
+    // the registry holds the outermost mounts, detaching takes the inner ones along
+    for point in crate::umount::MountRegistry::load().mounts() {
+        if let Err(e) = crate::mount::detach(&point) {
+            warn!("{e:#}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! Machine readable output of the query commands
+//!
+//! With the global `--json` flag a query command prints exactly one document
+//! on stdout and nothing else, logging goes to logcat or stderr:
+//!
+//! ```json
+//! {"schema_version": 1, "command": "status", "data": { ... }}
+//! ```
+//!
+//! `schema_version` is raised whenever a field of `data` is renamed, removed or
+//! changes its meaning. Added fields keep the version, readers must ignore
+//! fields they do not know.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::status::Health;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Debug)]
+pub struct Document<'a, T: Serialize> {
+    pub schema_version: u32,
+    pub command: &'a str,
+    pub data: T,
+}
+
+/// `apd status`
+#[derive(Serialize, Debug)]
+pub struct StatusOutput {
+    pub version_name: String,
+    pub version_code: String,
+    pub mount_mode: String,
+    /// mount namespace of su sessions without a per-package mode
+    pub su_namespace: String,
+    pub modules: usize,
+    pub safe_mode: bool,
+    /// `None` if no superkey was given
+    pub superkey_valid: Option<bool>,
+    pub kernel_version: String,
+    /// value of KERNELPATCH_VERSION, empty if unknown
+    pub kernelpatch_version: String,
+    /// version handshake of this boot, `None` before post-fs-data
+    pub kernel_handshake: Option<crate::supercall::Handshake>,
+    /// pid of the uid listener, `None` if it is not running
+    pub uid_listener: Option<u32>,
+    /// what `apd status` warns about, from the message catalog
+    pub findings: Vec<crate::messages::Message>,
+    /// record of the last boot, `None` before the first boot with apd
+    pub health: Option<Health>,
+}
+
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+/// Whether `--json` was given
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Print `data` as the single document of `command`
+pub fn print<T: Serialize>(command: &str, data: T) -> Result<()> {
+    let document = Document {
+        schema_version: SCHEMA_VERSION,
+        command,
+        data,
+    };
+    println!("{}", serde_json::to_string_pretty(&document)?);
+    Ok(())
+}
@@ -1,7 +1,8 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{self, BufRead},
+    os::unix::fs::MetadataExt,
     path::Path,
     sync::{Mutex, OnceLock},
     thread,
@@ -11,7 +12,9 @@ use std::{
 use log::warn;
 use serde::{Deserialize, Serialize};
 
-static KNOWN_PACKAGES: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+use crate::defs;
+
+static KNOWN_PACKAGES: OnceLock<Mutex<HashMap<String, PackageIdentity>>> = OnceLock::new();
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct PackageConfig {
@@ -21,10 +24,78 @@ pub struct PackageConfig {
     pub uid: i32,
     pub to_uid: i32,
     pub sctx: String,
+    /// mount namespace of su sessions of this uid, the global mode if empty
+    #[serde(default)]
+    pub namespace: String,
+}
+
+/// What stays the same across updates of one install of a package
+///
+/// An update keeps the uid, the seinfo and the data dir, a reinstall gives at
+/// least one of them up. The seinfo is derived from the signing certificate,
+/// `platform`, `media` or `default` for any other key, followed by flags like
+/// `privapp`. Its `targetSdkVersion` field is dropped, it changes on plain
+/// updates like the installer and version fields, which are ignored. Android
+/// refuses to update an app with a key other than the installed one, so a
+/// change between app keys always comes with a new data dir.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PackageIdentity {
+    uid: i32,
+    seinfo: String,
+    data_ino: Option<u64>,
+}
+
+impl PackageIdentity {
+    fn is_reinstall_of(&self, old: &PackageIdentity) -> bool {
+        self.uid != old.uid
+            || self.seinfo != old.seinfo
+            || matches!((self.data_ino, old.data_ino), (Some(new), Some(old)) if new != old)
+    }
+}
+
+/// Parse one packages.list line:
+/// `name uid debuggable data_dir seinfo gids ...`
+fn parse_package_line(line: &str) -> Option<(String, i32, String, String)> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let [name, uid, _, data_dir, seinfo, ..] = words[..] else {
+        return None;
+    };
+    // `platform:privapp:targetSdkVersion=34`
+    let seinfo: Vec<&str> = seinfo
+        .split(':')
+        .filter(|field| !field.starts_with("targetSdkVersion="))
+        .collect();
+    Some((name.to_string(), uid.parse().ok()?, data_dir.to_string(), seinfo.join(":")))
+}
+
+fn package_identities(lines: impl Iterator<Item = String>) -> HashMap<String, PackageIdentity> {
+    lines
+        .filter_map(|line| parse_package_line(&line))
+        .map(|(name, uid, data_dir, seinfo)| {
+            let data_ino = std::fs::metadata(&data_dir).ok().map(|m| m.ino());
+            (name, PackageIdentity { uid, seinfo, data_ino })
+        })
+        .collect()
+}
+
+fn read_package_identities() -> io::Result<HashMap<String, PackageIdentity>> {
+    Ok(package_identities(
+        read_lines("/data/system/packages.list")?.filter_map(|line| line.ok()),
+    ))
+}
+
+/// The uid of `package` as listed in packages.list
+pub fn package_uid(package: &str) -> Option<i32> {
+    read_lines("/data/system/packages.list")
+        .ok()?
+        .filter_map(|line| line.ok())
+        .filter_map(|line| parse_package_line(&line))
+        .find(|(name, ..)| name == package)
+        .map(|(_, uid, ..)| uid)
 }
 
-fn get_known_packages() -> &'static Mutex<HashSet<String>> {
-    KNOWN_PACKAGES.get_or_init(|| Mutex::new(HashSet::new()))
+fn get_known_packages() -> &'static Mutex<HashMap<String, PackageIdentity>> {
+    KNOWN_PACKAGES.get_or_init(|| Mutex::new(HashMap::new()))
 }
 
 fn read_lines<P: AsRef<Path>>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>> {
@@ -81,11 +152,8 @@ pub fn write_ap_package_config(package_configs: &[PackageConfig]) -> io::Result<
 
 pub fn initialize_package_baseline() -> io::Result<()> {
     retry_operation(5, || {
-        let packages: HashSet<String> = read_lines("/data/system/packages.list")?
-            .filter_map(|line| line.ok())
-            .filter_map(|line| line.split_whitespace().next().map(String::from))
-            .collect();
-        
+        let packages = read_package_identities()?;
+
         if let Ok(mut guard) = get_known_packages().lock() {
             *guard = packages;
         }
@@ -94,30 +162,75 @@ pub fn initialize_package_baseline() -> io::Result<()> {
     })
 }
 
-pub fn get_package_changes() -> (Vec<String>, Vec<String>) {
+#[derive(Default)]
+pub struct PackageChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// still installed, but not the same install as in the baseline
+    pub reinstalled: Vec<String>,
+}
+
+fn package_changes(
+    known: &HashMap<String, PackageIdentity>,
+    current: &HashMap<String, PackageIdentity>,
+) -> PackageChanges {
+    let mut changes = PackageChanges::default();
+    for (name, identity) in current {
+        match known.get(name) {
+            None => changes.added.push(name.clone()),
+            Some(old) if identity.is_reinstall_of(old) => changes.reinstalled.push(name.clone()),
+            Some(_) => {}
+        }
+    }
+    changes.removed = known
+        .keys()
+        .filter(|name| !current.contains_key(*name))
+        .cloned()
+        .collect();
+    changes
+}
+
+pub fn get_package_changes() -> PackageChanges {
     retry_operation(5, || {
-        let current_packages: HashSet<String> = read_lines("/data/system/packages.list")?
-            .filter_map(|line| line.ok())
-            .filter_map(|line| line.split_whitespace().next().map(String::from))
-            .collect();
-        
-        let mut added_packages = Vec::new();
-        let mut removed_packages = Vec::new();
-        
+        let current_packages = read_package_identities()?;
+
+        let mut changes = PackageChanges::default();
+
         if let Ok(mut guard) = get_known_packages().lock() {
-            added_packages = current_packages.difference(&*guard).cloned().collect();
-            removed_packages = guard.difference(&current_packages).cloned().collect();
+            changes = package_changes(&guard, &current_packages);
             *guard = current_packages;
         }
-        
-        Ok((added_packages, removed_packages))
+
+        Ok(changes)
     })
     .unwrap_or_else(|e| {
         warn!("Failed to get package changes: {}", e);
-        (Vec::new(), Vec::new())
+        PackageChanges::default()
     })
 }
 
+pub fn revoke_on_reinstall_enabled() -> bool {
+    Path::new(defs::REVOKE_ON_REINSTALL_FILE).exists()
+}
+
+/// Drop the root grant of reinstalled packages so they must be approved again,
+/// returns the packages which actually lost a grant
+pub fn revoke_reinstalled(reinstalled: &[String]) -> io::Result<Vec<String>> {
+    let mut package_configs = read_ap_package_config();
+    let mut revoked = Vec::new();
+    for config in package_configs
+        .iter_mut()
+        .filter(|c| c.allow == 1 && reinstalled.contains(&c.pkg))
+    {
+        config.allow = 0;
+        revoked.push(config.pkg.clone());
+    }
+    if !revoked.is_empty() {
+        write_ap_package_config(&package_configs)?;
+    }
+    Ok(revoked)
+}
+
 pub fn synchronize_package_uid() -> io::Result<Vec<String>> {
     retry_operation(5, || {
         let lines: Vec<_> = read_lines("/data/system/packages.list")?
@@ -164,3 +277,115 @@ pub fn synchronize_package_uid() -> io::Result<Vec<String>> {
         Ok(removed_packages)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// packages.list as written by the package manager, data dirs below `root`
+    fn packages_list(root: &Path, entries: &[(&str, i32, &str)]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|(name, uid, seinfo)| {
+                let data_dir = root.join(name);
+                fs::create_dir_all(&data_dir).unwrap();
+                format!("{name} {uid} 0 {} {seinfo} 3003,3002 0 34 1", data_dir.display())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_package_lines() {
+        assert_eq!(
+            parse_package_line(
+                "com.example.app 10123 0 /data/user/0/com.example.app \
+                 default:targetSdkVersion=34 3003 0 1234 1"
+            ),
+            Some((
+                "com.example.app".to_string(),
+                10123,
+                "/data/user/0/com.example.app".to_string(),
+                "default".to_string()
+            ))
+        );
+        let (.., seinfo) = parse_package_line(
+            "com.android.shell 2000 0 /data/user_de/0/com.android.shell \
+             platform:privapp:targetSdkVersion=34 none 0 34 1",
+        )
+        .unwrap();
+        assert_eq!(seinfo, "platform:privapp");
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        assert_eq!(parse_package_line(""), None);
+        assert_eq!(parse_package_line("com.example.app 10123 0"), None);
+        assert_eq!(
+            parse_package_line("com.example.app uid 0 /data/user/0/com.example.app default"),
+            None
+        );
+        let lines = [
+            "com.example.broken".to_string(),
+            "com.example.app 10123 0 /nonexistent default:targetSdkVersion=34 none".to_string(),
+        ];
+        let identities = package_identities(lines.into_iter());
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities["com.example.app"].data_ino, None);
+    }
+
+    #[test]
+    fn updates_are_not_reinstalls() {
+        let root = TempDir::new("packages-update");
+        let known = package_identities(
+            packages_list(root.path(), &[("com.example.app", 10123, "default:targetSdkVersion=33")])
+                .into_iter(),
+        );
+        // a new version raising the target sdk, installed over the old one
+        let current = package_identities(
+            packages_list(root.path(), &[("com.example.app", 10123, "default:targetSdkVersion=34")])
+                .into_iter(),
+        );
+        let changes = package_changes(&known, &current);
+        assert!(changes.added.is_empty() && changes.removed.is_empty());
+        assert!(changes.reinstalled.is_empty());
+    }
+
+    #[test]
+    fn reinstalls_and_key_changes_are_detected() {
+        let root = TempDir::new("packages-reinstall");
+        let entries = [
+            ("com.example.kept", 10100, "default:targetSdkVersion=34"),
+            ("com.example.resigned", 10101, "default:targetSdkVersion=34"),
+            ("com.example.cleared", 10102, "default:targetSdkVersion=34"),
+            ("com.example.moved", 10103, "default:targetSdkVersion=34"),
+            ("com.example.removed", 10104, "default:targetSdkVersion=34"),
+        ];
+        let known = package_identities(packages_list(root.path(), &entries).into_iter());
+
+        // the data dir is created again on a reinstall or when data is cleared
+        let cleared = root.path().join("com.example.cleared");
+        fs::create_dir(root.path().join("new")).unwrap();
+        fs::remove_dir(&cleared).unwrap();
+        fs::rename(root.path().join("new"), &cleared).unwrap();
+        let entries = [
+            ("com.example.kept", 10100, "default:targetSdkVersion=34"),
+            ("com.example.resigned", 10101, "platform:targetSdkVersion=34"),
+            ("com.example.cleared", 10102, "default:targetSdkVersion=34"),
+            ("com.example.moved", 10203, "default:targetSdkVersion=34"),
+            ("com.example.added", 10105, "default:targetSdkVersion=34"),
+        ];
+        let current = package_identities(packages_list(root.path(), &entries).into_iter());
+
+        let mut changes = package_changes(&known, &current);
+        changes.reinstalled.sort();
+        assert_eq!(
+            changes.reinstalled,
+            ["com.example.cleared", "com.example.moved", "com.example.resigned"]
+        );
+        assert_eq!(changes.added, ["com.example.added"]);
+        assert_eq!(changes.removed, ["com.example.removed"]);
+    }
+}
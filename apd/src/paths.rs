@@ -0,0 +1,103 @@
+//! Per-boot scratch locations
+//!
+//! A mount run needs places of its own on a tmpfs: the dir the tmpfs
+//! skeletons of magic mount are built in, the module mirror and the staging
+//! mounts of module images. They are resolved here instead of by constants.
+//!
+//! With `random_scratch = true` in apd.toml all of them live below one dir of
+//! `/dev` named by 8 random characters, so an app cannot find them by stating
+//! well-known names. The name is drawn once a boot and recorded in
+//! `/data/adb/ap/.scratch_dir`, every apd process of the boot reads it from
+//! there. Without it the fixed locations are used. Paths which outlive the
+//! boot, /data/adb/modules or the config, never move.
+
+use std::{
+    fs,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{config, defs, script_history, utils};
+
+const MOUNTED_STAMP_NAME: &str = ".apd_modules_mounted";
+
+/// The random scratch dir of this boot, in `.scratch_dir`
+#[derive(Serialize, Deserialize)]
+struct Record {
+    boot_id: String,
+    dir: PathBuf,
+}
+
+fn random_name() -> Result<String> {
+    let uuid = fs::read_to_string("/proc/sys/kernel/random/uuid").context("read random uuid")?;
+    Ok(uuid.trim().replace('-', "")[..8].to_string())
+}
+
+/// The dir recorded for this boot, drawn and recorded if there is none
+fn random_dir() -> Result<PathBuf> {
+    let boot_id = script_history::boot_id();
+    let recorded = fs::read_to_string(defs::SCRATCH_DIR_FILE)
+        .ok()
+        .and_then(|content| serde_json::from_str::<Record>(&content).ok())
+        .filter(|record| record.boot_id == boot_id);
+    let dir = match recorded {
+        Some(record) => record.dir,
+        None => {
+            let dir = Path::new("/dev").join(random_name()?);
+            let record = Record {
+                boot_id,
+                dir: dir.clone(),
+            };
+            fs::write(defs::SCRATCH_DIR_FILE, serde_json::to_string(&record)?)
+                .with_context(|| format!("Failed to write {}", defs::SCRATCH_DIR_FILE))?;
+            dir
+        }
+    };
+    if !dir.is_dir() {
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("create {}", dir.display()))?;
+    }
+    Ok(dir)
+}
+
+/// Where the scratch locations of this boot are created, `/dev` unless they
+/// are randomized
+pub fn scratch_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        if !config::random_scratch() {
+            return PathBuf::from("/dev");
+        }
+        random_dir().unwrap_or_else(|e| {
+            warn!("{e:#}, using fixed scratch locations");
+            PathBuf::from("/dev")
+        })
+    })
+}
+
+/// Whether the scratch locations of this boot are randomized
+fn randomized() -> bool {
+    scratch_dir() != Path::new("/dev")
+}
+
+/// The dir magic mount builds its tmpfs skeletons in
+pub fn mount_work_dir() -> PathBuf {
+    if randomized() {
+        scratch_dir().join("w")
+    } else {
+        PathBuf::from(utils::get_tmp_path())
+    }
+}
+
+/// Stamp holding the boot id once the modules were mounted, on tmpfs so it
+/// cannot outlive the boot
+pub fn mounted_stamp() -> PathBuf {
+    scratch_dir().join(MOUNTED_STAMP_NAME)
+}
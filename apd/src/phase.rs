@@ -0,0 +1,494 @@
+//! Boot stages as a pipeline of named phases
+//!
+//! A stage is a list of [`BootPhase`]s sharing one [`StageContext`]. The driver
+//! runs them in order, records the result and wall time of every phase and
+//! feeds the timings to the boot profiler. `apd stage run <stage> --only a,b`
+//! re-runs selected phases on a booted device, the context then describes the
+//! current state instead of the one built up by the earlier phases.
+//!
+//! A failing phase is recorded and the stage goes on, unless the phase is
+//! critical. The phases that ran in this boot are kept in `.boot_progress`,
+//! rewritten after every phase, so `apd stage resume` can run the rest of a
+//! stage whose daemon died half way.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{assets, defs, profile, script_history, status::Health, timing};
+
+/// What prepare_root_access achieved, later phases degrade instead of failing
+pub struct RootAccess {
+    pub key_accepted: bool,
+    /// tools of /data/adb/ap/bin which are unusable even after a repair
+    pub broken_binaries: Vec<assets::BinaryStatus>,
+    pub sepolicy_patched: bool,
+    /// the KernelPatch version is at least the supported minimum
+    pub supercall_compatible: bool,
+}
+
+/// State shared by the phases of one stage run
+pub struct StageContext {
+    pub superkey: Option<String>,
+    pub root_access: RootAccess,
+    pub safe_mode: bool,
+    /// safe mode was requested or too many boots never completed
+    pub disable_modules: bool,
+    pub health: Health,
+    /// failed steps of the post-mount stage run inside post-fs-data
+    pub post_mount_failures: usize,
+    /// only some phases are re-run, the boot record must not be touched
+    pub partial: bool,
+    /// the phases a dead daemon did not get to are run
+    pub resume: bool,
+    /// /data/adb became readable, modules are skipped otherwise
+    pub data_ready: bool,
+}
+
+pub enum PhaseResult {
+    Done,
+    Skipped(String),
+    /// the stage is complete, remaining phases are not run
+    Stop,
+}
+
+pub trait BootPhase {
+    fn name(&self) -> &'static str;
+
+    /// Why the phase can't run in this context, it is skipped then
+    fn unmet(&self, _ctx: &StageContext) -> Option<String> {
+        None
+    }
+
+    fn run(&self, ctx: &mut StageContext) -> Result<PhaseResult>;
+
+    /// Whether an error of the phase ends the stage
+    fn critical(&self) -> bool {
+        false
+    }
+}
+
+/// A phase made of plain functions, which is all the stages need so far
+pub struct Phase {
+    pub name: &'static str,
+    pub unmet: fn(&StageContext) -> Option<String>,
+    pub run: fn(&mut StageContext) -> Result<PhaseResult>,
+    pub critical: bool,
+}
+
+impl Phase {
+    pub const fn new(
+        name: &'static str,
+        unmet: fn(&StageContext) -> Option<String>,
+        run: fn(&mut StageContext) -> Result<PhaseResult>,
+    ) -> Self {
+        Phase {
+            name,
+            unmet,
+            run,
+            critical: false,
+        }
+    }
+
+    /// Make an error of the phase end the stage
+    pub const fn critical(mut self) -> Self {
+        self.critical = true;
+        self
+    }
+}
+
+impl BootPhase for Phase {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn unmet(&self, ctx: &StageContext) -> Option<String> {
+        (self.unmet)(ctx)
+    }
+
+    fn run(&self, ctx: &mut StageContext) -> Result<PhaseResult> {
+        (self.run)(ctx)
+    }
+
+    fn critical(&self) -> bool {
+        self.critical
+    }
+}
+
+pub fn always(_ctx: &StageContext) -> Option<String> {
+    None
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PhaseReport {
+    pub name: String,
+    /// `done`, `skipped`, `stopped` or `failed`
+    pub result: String,
+    pub wall_ms: u64,
+}
+
+/// The phases of a stage that ran in this boot
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Progress {
+    pub boot_id: String,
+    pub stage: String,
+    pub phases: Vec<PhaseReport>,
+    /// the stage reached its end, was stopped or a critical phase failed
+    pub finished: bool,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Progress {
+    fn new(path: &Path, stage: &str) -> Self {
+        Progress {
+            boot_id: script_history::boot_id(),
+            stage: stage.to_string(),
+            path: path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// The progress of `stage` in this boot, `None` if it did not start
+    pub fn current(stage: &str) -> Option<Self> {
+        Self::current_in(Path::new(defs::BOOT_PROGRESS_FILE), stage)
+    }
+
+    fn current_in(path: &Path, stage: &str) -> Option<Self> {
+        let content = fs::read_to_string(path).ok()?;
+        let mut progress = serde_json::from_str::<Progress>(&content)
+            .ok()
+            .filter(|progress| {
+                progress.stage == stage && progress.boot_id == script_history::boot_id()
+            })?;
+        progress.path = path.to_path_buf();
+        Some(progress)
+    }
+
+    /// Names of `phases` which did not run yet, in order
+    pub fn remaining(&self, phases: &[&str]) -> Vec<String> {
+        phases
+            .iter()
+            .filter(|name| !self.phases.iter().any(|report| report.name == **name))
+            .map(|name| name.to_string())
+            .collect()
+    }
+
+    fn record(&mut self, report: &PhaseReport) {
+        match self.phases.iter_mut().find(|r| r.name == report.name) {
+            Some(known) => *known = report.clone(),
+            None => self.phases.push(report.clone()),
+        }
+        if let Err(e) = self.store() {
+            warn!("{e:#}");
+        }
+    }
+
+    fn store(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path).with_context(|| {
+            format!("Failed to rename {} to {}", tmp.display(), self.path.display())
+        })
+    }
+}
+
+/// Run `phases` in order, `only` restricts the run to the named phases.
+/// An error of a critical phase ends the stage with that error, others are
+/// logged and recorded as `failed`.
+pub fn run_phases(
+    stage: &str,
+    phases: &[&dyn BootPhase],
+    ctx: &mut StageContext,
+    only: Option<&[String]>,
+) -> Result<Vec<PhaseReport>> {
+    let progress_file = Path::new(defs::BOOT_PROGRESS_FILE);
+    run_phases_in(progress_file, stage, phases, ctx, only)
+}
+
+fn run_phases_in(
+    progress_file: &Path,
+    stage: &str,
+    phases: &[&dyn BootPhase],
+    ctx: &mut StageContext,
+    only: Option<&[String]>,
+) -> Result<Vec<PhaseReport>> {
+    // a full run starts over, partial ones add to the record of the boot
+    let mut progress = match only {
+        None => Progress::new(progress_file, stage),
+        Some(_) => Progress::current_in(progress_file, stage)
+            .unwrap_or_else(|| Progress::new(progress_file, stage)),
+    };
+    let mut reports = Vec::new();
+    for phase in phases {
+        let name = phase.name();
+        if only.is_some_and(|only| !only.iter().any(|n| n == name)) {
+            continue;
+        }
+        let start = Instant::now();
+        let result = match phase.unmet(ctx) {
+            Some(reason) => Ok(PhaseResult::Skipped(reason)),
+            None => profile::measure("phase", name, || phase.run(ctx)),
+        };
+        let elapsed = start.elapsed();
+        timing::record(name, start, elapsed);
+        let wall_ms = elapsed.as_millis() as u64;
+        let (label, stop) = match &result {
+            Ok(PhaseResult::Done) => ("done", false),
+            Ok(PhaseResult::Skipped(reason)) => {
+                info!("{stage}: phase {name} skipped: {reason}");
+                ("skipped", false)
+            }
+            Ok(PhaseResult::Stop) => ("stopped", true),
+            Err(_) => ("failed", phase.critical()),
+        };
+        let report = PhaseReport {
+            name: name.to_string(),
+            result: label.to_string(),
+            wall_ms,
+        };
+        progress.finished |= stop;
+        progress.record(&report);
+        reports.push(report);
+        match result {
+            Err(e) if stop => {
+                log_reports(stage, &reports);
+                return Err(e);
+            }
+            Err(e) => error!("{stage}: phase {name} failed: {e:#}"),
+            Ok(_) if stop => break,
+            Ok(_) => {}
+        }
+    }
+    if only.is_none() || ctx.resume {
+        progress.finished = true;
+        if let Err(e) = progress.store() {
+            warn!("{e:#}");
+        }
+    }
+    log_reports(stage, &reports);
+    Ok(reports)
+}
+
+fn log_reports(stage: &str, reports: &[PhaseReport]) {
+    let summary: Vec<String> = reports
+        .iter()
+        .map(|r| format!("{}={}/{}ms", r.name, r.result, r.wall_ms))
+        .collect();
+    info!("{stage} phases: {}", summary.join(" "));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use anyhow::bail;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// A phase which logs its runs to `ran` and ends with `result`
+    struct Fake<'a> {
+        name: &'static str,
+        unmet: Option<&'static str>,
+        result: fn() -> Result<PhaseResult>,
+        critical: bool,
+        ran: &'a RefCell<Vec<&'static str>>,
+    }
+
+    impl BootPhase for Fake<'_> {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn unmet(&self, _ctx: &StageContext) -> Option<String> {
+            self.unmet.map(str::to_string)
+        }
+
+        fn run(&self, _ctx: &mut StageContext) -> Result<PhaseResult> {
+            self.ran.borrow_mut().push(self.name);
+            (self.result)()
+        }
+
+        fn critical(&self) -> bool {
+            self.critical
+        }
+    }
+
+    fn done() -> Result<PhaseResult> {
+        Ok(PhaseResult::Done)
+    }
+
+    fn stop() -> Result<PhaseResult> {
+        Ok(PhaseResult::Stop)
+    }
+
+    fn fail() -> Result<PhaseResult> {
+        bail!("broken")
+    }
+
+    fn fake<'a>(name: &'static str, ran: &'a RefCell<Vec<&'static str>>) -> Fake<'a> {
+        Fake {
+            name,
+            unmet: None,
+            result: done,
+            critical: false,
+            ran,
+        }
+    }
+
+    fn context() -> StageContext {
+        StageContext {
+            superkey: None,
+            root_access: RootAccess {
+                key_accepted: true,
+                broken_binaries: Vec::new(),
+                sepolicy_patched: true,
+                supercall_compatible: true,
+            },
+            safe_mode: false,
+            disable_modules: false,
+            health: Health::default(),
+            post_mount_failures: 0,
+            partial: false,
+            resume: false,
+            data_ready: true,
+        }
+    }
+
+    fn results(reports: &[PhaseReport]) -> Vec<(&str, &str)> {
+        reports
+            .iter()
+            .map(|r| (r.name.as_str(), r.result.as_str()))
+            .collect()
+    }
+
+    fn run(
+        root: &TempDir,
+        phases: &[&dyn BootPhase],
+        only: Option<&[String]>,
+    ) -> Result<Vec<PhaseReport>> {
+        run_phases_in(&root.path().join("progress"), "stage", phases, &mut context(), only)
+    }
+
+    #[test]
+    fn phases_run_in_order_and_are_recorded() {
+        let root = TempDir::new("phase-order");
+        let ran = RefCell::new(Vec::new());
+        let skipped = Fake {
+            unmet: Some("no data"),
+            ..fake("b", &ran)
+        };
+        let phases: [&dyn BootPhase; 3] = [&fake("a", &ran), &skipped, &fake("c", &ran)];
+        let reports = run(&root, &phases, None).unwrap();
+
+        assert_eq!(*ran.borrow(), ["a", "c"]);
+        assert_eq!(results(&reports), [("a", "done"), ("b", "skipped"), ("c", "done")]);
+        let progress = Progress::current_in(&root.path().join("progress"), "stage").unwrap();
+        assert!(progress.finished);
+        assert_eq!(results(&progress.phases), results(&reports));
+    }
+
+    #[test]
+    fn only_reruns_the_named_phases() {
+        let root = TempDir::new("phase-only");
+        let ran = RefCell::new(Vec::new());
+        let phases: [&dyn BootPhase; 3] = [&fake("a", &ran), &fake("b", &ran), &fake("c", &ran)];
+        run(&root, &phases, None).unwrap();
+        ran.borrow_mut().clear();
+
+        let reports = run(&root, &phases, Some(&["c".to_string(), "a".to_string()])).unwrap();
+        // the stage order is kept, not the one of `only`
+        assert_eq!(*ran.borrow(), ["a", "c"]);
+        assert_eq!(results(&reports), [("a", "done"), ("c", "done")]);
+        let progress = Progress::current_in(&root.path().join("progress"), "stage").unwrap();
+        assert_eq!(progress.phases.len(), 3);
+    }
+
+    #[test]
+    fn stop_ends_the_stage() {
+        let root = TempDir::new("phase-stop");
+        let ran = RefCell::new(Vec::new());
+        let stopping = Fake {
+            result: stop,
+            ..fake("b", &ran)
+        };
+        let phases: [&dyn BootPhase; 3] = [&fake("a", &ran), &stopping, &fake("c", &ran)];
+        let reports = run(&root, &phases, None).unwrap();
+
+        assert_eq!(*ran.borrow(), ["a", "b"]);
+        assert_eq!(results(&reports), [("a", "done"), ("b", "stopped")]);
+    }
+
+    #[test]
+    fn progress_of_another_stage_is_not_current() {
+        let root = TempDir::new("phase-stage");
+        let ran = RefCell::new(Vec::new());
+        run(&root, &[&fake("a", &ran)], None).unwrap();
+        assert!(Progress::current_in(&root.path().join("progress"), "service").is_none());
+    }
+
+    #[test]
+    fn a_failing_phase_does_not_stop_the_stage() {
+        let root = TempDir::new("phase-fail");
+        let ran = RefCell::new(Vec::new());
+        let failing = Fake {
+            result: fail,
+            ..fake("b", &ran)
+        };
+        let phases: [&dyn BootPhase; 3] = [&fake("a", &ran), &failing, &fake("c", &ran)];
+        let reports = run(&root, &phases, None).unwrap();
+
+        assert_eq!(*ran.borrow(), ["a", "b", "c"]);
+        assert_eq!(results(&reports), [("a", "done"), ("b", "failed"), ("c", "done")]);
+    }
+
+    #[test]
+    fn a_failing_critical_phase_ends_the_stage() {
+        let root = TempDir::new("phase-critical");
+        let ran = RefCell::new(Vec::new());
+        let failing = Fake {
+            result: fail,
+            critical: true,
+            ..fake("b", &ran)
+        };
+        let phases: [&dyn BootPhase; 3] = [&fake("a", &ran), &failing, &fake("c", &ran)];
+        let error = run(&root, &phases, None).unwrap_err();
+
+        assert_eq!(error.to_string(), "broken");
+        assert_eq!(*ran.borrow(), ["a", "b"]);
+        let progress = Progress::current_in(&root.path().join("progress"), "stage").unwrap();
+        assert!(progress.finished);
+        assert_eq!(results(&progress.phases), [("a", "done"), ("b", "failed")]);
+    }
+
+    #[test]
+    fn resume_runs_what_a_dead_daemon_left() {
+        let root = TempDir::new("phase-resume");
+        let progress_file = root.path().join("progress");
+        let ran = RefCell::new(Vec::new());
+        let phases: [&dyn BootPhase; 3] = [&fake("a", &ran), &fake("b", &ran), &fake("c", &ran)];
+        // the daemon died after the first phase
+        run(&root, &phases[..1], Some(&["a".to_string()])).unwrap();
+        let progress = Progress::current_in(&progress_file, "stage").unwrap();
+        assert!(!progress.finished);
+        let remaining = progress.remaining(&["a", "b", "c"]);
+        assert_eq!(remaining, ["b", "c"]);
+
+        ran.borrow_mut().clear();
+        let mut ctx = context();
+        ctx.resume = true;
+        run_phases_in(&progress_file, "stage", &phases, &mut ctx, Some(&remaining)).unwrap();
+        assert_eq!(*ran.borrow(), ["b", "c"]);
+        let progress = Progress::current_in(&progress_file, "stage").unwrap();
+        assert!(progress.finished);
+        assert!(progress.remaining(&["a", "b", "c"]).is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+//! One-shot boot profiling
+//!
+//! `apd profile-boot enable` arms profiling for the next boot only. During that
+//! boot every stage script apd waits for, every lua stage callback and the mount
+//! and relabel phases are measured and appended to `boot_profile.jsonl`. At
+//! boot-completed the samples are ranked by wall time into `boot_profile.json`.
+//!
+//! When profiling is not armed nothing is measured and no extra process is spawned.
+//!
+//! The flag of the profiled boot holds its boot id. A boot which never reaches
+//! boot-completed leaves it behind, the next post-fs-data ranks what was
+//! measured and clears it, so the boot after a bootloop is not profiled again.
+//! `apd status` reports the slowest sample of the last report if it took
+//! longer than [`SLOW_SAMPLE_MS`].
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::defs;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sample {
+    /// `script`, `lua` or `phase`
+    pub kind: String,
+    pub name: String,
+    pub wall_ms: u64,
+    pub cpu_ms: u64,
+    pub max_rss_kb: u64,
+    pub read_blocks: u64,
+    pub write_blocks: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Report {
+    pub samples: Vec<Sample>,
+}
+
+/// Samples at least this slow are a finding of `apd status`
+pub const SLOW_SAMPLE_MS: u64 = 5000;
+
+impl Report {
+    fn load() -> Option<Self> {
+        let content = fs::read_to_string(defs::PROFILE_REPORT_FILE).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// The slowest sample if it is slow enough to mention
+    pub fn slow_sample(&self) -> Option<&Sample> {
+        self.samples
+            .iter()
+            .max_by_key(|sample| sample.wall_ms)
+            .filter(|sample| sample.wall_ms >= SLOW_SAMPLE_MS)
+    }
+}
+
+/// The slowest sample of the last profiled boot, see [`Report::slow_sample`]
+pub fn slow_sample() -> Option<Sample> {
+    Report::load()?.slow_sample().cloned()
+}
+
+pub fn is_active() -> bool {
+    static ACTIVE: OnceLock<bool> = OnceLock::new();
+    *ACTIVE.get_or_init(|| {
+        fs::read_to_string(defs::PROFILE_ACTIVE_FILE)
+            .is_ok_and(|id| id.trim() == crate::script_history::boot_id())
+    })
+}
+
+fn cpu_ms(usage: &libc::rusage) -> u64 {
+    let ms = |tv: libc::timeval| tv.tv_sec as u64 * 1000 + tv.tv_usec as u64 / 1000;
+    ms(usage.ru_utime) + ms(usage.ru_stime)
+}
+
+fn self_usage() -> libc::rusage {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    usage
+}
+
+fn record(sample: Sample) {
+    let result = serde_json::to_string(&sample)
+        .map_err(io::Error::from)
+        .and_then(|line| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(defs::PROFILE_SAMPLES_FILE)?
+                .write_all(format!("{line}\n").as_bytes())
+        });
+    if let Err(e) = result {
+        warn!("Failed to record boot profile sample: {}", e);
+    }
+}
+
+/// Record a script apd reaped itself, `usage` covers its whole tree
+pub fn record_script(name: &Path, wall: Duration, usage: &libc::rusage) {
+    record(Sample {
+        kind: "script".to_string(),
+        name: name.display().to_string(),
+        wall_ms: wall.as_millis() as u64,
+        cpu_ms: cpu_ms(usage),
+        max_rss_kb: usage.ru_maxrss as u64,
+        read_blocks: usage.ru_inblock as u64,
+        write_blocks: usage.ru_oublock as u64,
+    });
+}
+
+/// Measure an in-process phase, a plain call when profiling is off
+pub fn measure<T>(kind: &str, name: &str, f: impl FnOnce() -> T) -> T {
+    if !is_active() {
+        return f();
+    }
+    let before = self_usage();
+    let start = Instant::now();
+    let result = f();
+    let after = self_usage();
+    record(Sample {
+        kind: kind.to_string(),
+        name: name.to_string(),
+        wall_ms: start.elapsed().as_millis() as u64,
+        cpu_ms: cpu_ms(&after).saturating_sub(cpu_ms(&before)),
+        max_rss_kb: after.ru_maxrss as u64,
+        read_blocks: (after.ru_inblock - before.ru_inblock) as u64,
+        write_blocks: (after.ru_oublock - before.ru_oublock) as u64,
+    });
+    result
+}
+
+/// Arm profiling for this boot if it was requested, called first in post-fs-data
+pub fn begin_boot() {
+    if Path::new(defs::PROFILE_ACTIVE_FILE).exists() {
+        info!("the profiled boot did not complete, ranking what it measured");
+        if let Err(e) = finish_boot() {
+            warn!("Failed to write boot profile: {}", e);
+            let _ = fs::remove_file(defs::PROFILE_ACTIVE_FILE);
+        }
+    }
+    if !Path::new(defs::PROFILE_BOOT_FILE).exists() {
+        return;
+    }
+    let _ = fs::remove_file(defs::PROFILE_SAMPLES_FILE);
+    let result = fs::write(defs::PROFILE_ACTIVE_FILE, crate::script_history::boot_id())
+        .and_then(|()| fs::remove_file(defs::PROFILE_BOOT_FILE));
+    match result {
+        Ok(()) => info!("boot profiling enabled for this boot"),
+        Err(e) => warn!("Failed to arm boot profiling: {}", e),
+    }
+}
+
+/// Rank the samples of this boot into the report and disarm profiling
+pub fn finish_boot() -> Result<()> {
+    if !Path::new(defs::PROFILE_ACTIVE_FILE).exists() {
+        return Ok(());
+    }
+    let content = fs::read_to_string(defs::PROFILE_SAMPLES_FILE).unwrap_or_default();
+    let mut samples: Vec<Sample> = content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    samples.sort_by(|a, b| b.wall_ms.cmp(&a.wall_ms));
+
+    let report = Report { samples };
+    fs::write(defs::PROFILE_REPORT_FILE, serde_json::to_string_pretty(&report)?)
+        .with_context(|| format!("Failed to write {}", defs::PROFILE_REPORT_FILE))?;
+    let _ = fs::remove_file(defs::PROFILE_SAMPLES_FILE);
+    fs::remove_file(defs::PROFILE_ACTIVE_FILE)?;
+    info!("boot profile written to {}", defs::PROFILE_REPORT_FILE);
+    Ok(())
+}
+
+pub fn enable() -> Result<()> {
+    crate::utils::ensure_file_exists(defs::PROFILE_BOOT_FILE)?;
+    println!("Boot profiling will run on the next boot");
+    Ok(())
+}
+
+pub fn disable() -> Result<()> {
+    if Path::new(defs::PROFILE_BOOT_FILE).exists() {
+        fs::remove_file(defs::PROFILE_BOOT_FILE)?;
+    }
+    Ok(())
+}
+
+pub fn show(limit: usize) -> Result<()> {
+    let content = fs::read_to_string(defs::PROFILE_REPORT_FILE)
+        .with_context(|| "No boot profile yet, run `apd profile-boot enable` and reboot")?;
+    let report: Report = serde_json::from_str(&content)?;
+    println!(
+        "{:>9} {:>9} {:>9} {:<6} NAME",
+        "WALL(ms)", "CPU(ms)", "RSS(KB)", "KIND"
+    );
+    for sample in report.samples.iter().take(limit) {
+        println!(
+            "{:>9} {:>9} {:>9} {:<6} {}",
+            sample.wall_ms, sample.cpu_ms, sample.max_rss_kb, sample.kind, sample.name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str, wall_ms: u64) -> Sample {
+        Sample {
+            kind: "script".to_string(),
+            name: name.to_string(),
+            wall_ms,
+            cpu_ms: 0,
+            max_rss_kb: 0,
+            read_blocks: 0,
+            write_blocks: 0,
+        }
+    }
+
+    #[test]
+    fn only_slow_samples_are_findings() {
+        let report = Report {
+            samples: vec![sample("fast.sh", 1200), sample("slow.sh", 7400), sample("b.sh", 10)],
+        };
+        assert_eq!(report.slow_sample().map(|s| s.name.as_str()), Some("slow.sh"));
+
+        let report = Report {
+            samples: vec![sample("fast.sh", SLOW_SAMPLE_MS - 1)],
+        };
+        assert!(report.slow_sample().is_none());
+        assert!(Report::default().slow_sample().is_none());
+    }
+}
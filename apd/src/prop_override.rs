@@ -0,0 +1,180 @@
+//! Optional overrides of read-only props, e.g. the build fingerprint
+//!
+//! Off unless `/data/adb/ap/prop_overrides` exists. It holds `ro.*=value` lines,
+//! grouped by the stage they are applied at:
+//!
+//! ```text
+//! # read early by the framework
+//! [post-fs-data]
+//! ro.build.fingerprint=google/husky/husky:14/AP1A.240305.019.A1/11445699:user/release-keys
+//! # read late, e.g. by attestation apps
+//! [boot-completed]
+//! ro.build.version.security_patch=2024-03-05
+//! ```
+//!
+//! Lines before any section belong to post-fs-data. Overrides are applied after
+//! module system.prop files and factory prop hiding, so the configured value always
+//! wins. A module providing the same prop is logged as overridden.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use prop_rs_android::{resetprop::ResetProp, sys_prop};
+
+use crate::{defs, module};
+
+/// Overrides of one stage, in file order
+fn load(stage: &str) -> Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(defs::PROP_OVERRIDES_FILE)
+        .with_context(|| format!("Failed to read {}", defs::PROP_OVERRIDES_FILE))?;
+    Ok(parse(&content, stage))
+}
+
+fn parse(content: &str, stage: &str) -> Vec<(String, String)> {
+    let mut current = "post-fs-data";
+    let mut overrides = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = section.trim();
+            if current != "post-fs-data" && current != "boot-completed" {
+                warn!("prop_overrides: unknown stage [{current}], its props are ignored");
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            warn!("prop_overrides: ignore malformed line: {line}");
+            continue;
+        };
+        let key = key.trim();
+        if !key.starts_with("ro.") {
+            warn!("prop_overrides: only ro.* props are supported, ignore {key}");
+            continue;
+        }
+        if current == stage {
+            overrides.push((key.to_string(), value.trim().to_string()));
+        }
+    }
+    overrides
+}
+
+/// Module props replaced by a different override, as (key, module value, module id,
+/// override)
+fn overridden<'a>(
+    overrides: &'a [(String, String)],
+    from_modules: &'a HashMap<String, (String, String)>,
+) -> Vec<(&'a str, &'a str, &'a str, &'a str)> {
+    overrides
+        .iter()
+        .filter_map(|(key, value)| {
+            let (module_value, id) = from_modules.get(key)?;
+            (module_value != value).then_some((
+                key.as_str(),
+                module_value.as_str(),
+                id.as_str(),
+                value.as_str(),
+            ))
+        })
+        .collect()
+}
+
+/// Props set by system.prop of active modules at `stage`, with the module setting them
+fn module_props(stage: &str) -> HashMap<String, (String, String)> {
+    let mut props = HashMap::new();
+    let _ = module::foreach_module(module::ModuleType::Active, |module| {
+        let Ok(content) = fs::read_to_string(module.join("system.prop")) else {
+            return Ok(());
+        };
+        let id = module
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for (key, value) in module::system_props_of_stage(&content, stage) {
+            props.insert(key, (value, id.clone()));
+        }
+        Ok(())
+    });
+    props
+}
+
+/// Apply the overrides configured for `stage`, a no-op when none are configured
+pub fn apply(stage: &str) -> Result<()> {
+    if !Path::new(defs::PROP_OVERRIDES_FILE).exists() {
+        return Ok(());
+    }
+    let overrides = load(stage)?;
+    if overrides.is_empty() {
+        return Ok(());
+    }
+
+    sys_prop::init().context("Failed to initialize system property API")?;
+    let rp = ResetProp {
+        skip_svc: true,
+        persistent: false,
+        persist_only: false,
+        verbose: false,
+        show_context: false,
+    };
+    let from_modules = module_props(stage);
+    for (key, module_value, id, value) in overridden(&overrides, &from_modules) {
+        warn!("prop_overrides: {key}={module_value} of module {id} is overridden with {value}");
+    }
+    for (key, value) in &overrides {
+        if let Err(e) = rp.set(key, value) {
+            warn!("prop_overrides: failed to set {key}: {e}");
+        }
+    }
+    info!("Applied {} prop override(s) at {}", overrides.len(), stage);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OVERRIDES: &str = "\
+ro.product.model=Pixel 8 Pro
+# comment
+[boot-completed]
+ro.build.version.security_patch = 2024-03-05
+persist.sys.foo=1
+not a prop
+[post-fs-data]
+ro.build.fingerprint=google/husky
+[late_start]
+ro.ignored=1
+";
+
+    #[test]
+    fn overrides_are_grouped_by_stage_in_file_order() {
+        assert_eq!(
+            parse(OVERRIDES, "post-fs-data"),
+            [
+                ("ro.product.model".to_string(), "Pixel 8 Pro".to_string()),
+                ("ro.build.fingerprint".to_string(), "google/husky".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse(OVERRIDES, "boot-completed"),
+            [("ro.build.version.security_patch".to_string(), "2024-03-05".to_string())]
+        );
+        assert!(parse(OVERRIDES, "late_start").is_empty());
+    }
+
+    #[test]
+    fn differing_module_props_are_reported_as_overridden() {
+        let overrides = parse(OVERRIDES, "post-fs-data");
+        let from_modules = HashMap::from([
+            ("ro.product.model".to_string(), ("Pixel 8 Pro".to_string(), "same".to_string())),
+            ("ro.build.fingerprint".to_string(), ("other/fp".to_string(), "pif".to_string())),
+            ("ro.debuggable".to_string(), ("0".to_string(), "unrelated".to_string())),
+        ]);
+        assert_eq!(
+            overridden(&overrides, &from_modules),
+            [("ro.build.fingerprint", "other/fp", "pif", "google/husky")]
+        );
+    }
+}
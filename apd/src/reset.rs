@@ -0,0 +1,167 @@
+//! Factory reset of the module subsystem
+//!
+//! Runs every module's uninstaller, moves the module tree into one trash bundle
+//! and leaves an empty module dir behind. APatch settings, the su allowlist and
+//! the superkey are never touched. Progress is journaled in
+//! `/data/adb/ap/.reset_journal`, so an interrupted reset resumes where it stopped
+//! when run again.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, ensure};
+use const_format::concatcp;
+use log::{info, warn};
+
+use crate::{
+    defs, metamodule, module,
+    restorecon::{self, ADB_CON},
+    utils::{self, ensure_dir_exists},
+};
+
+/// Module related state outside of the module dirs, archived with them
+const MODULE_CONFIG_DIR: &str = concatcp!(defs::ADB_DIR, "config");
+
+struct Journal(Vec<String>);
+
+impl Journal {
+    fn load() -> Self {
+        let content = fs::read_to_string(defs::RESET_JOURNAL_FILE).unwrap_or_default();
+        Journal(content.lines().map(str::to_string).collect())
+    }
+
+    fn done(&self, step: &str) -> bool {
+        self.0.iter().any(|s| s == step)
+    }
+
+    fn mark(&mut self, step: String) -> Result<()> {
+        self.0.push(step);
+        fs::write(defs::RESET_JOURNAL_FILE, self.0.join("\n") + "\n")
+            .with_context(|| format!("Failed to write {}", defs::RESET_JOURNAL_FILE))
+    }
+}
+
+pub fn module_ids() -> Vec<String> {
+    let mut ids = Vec::new();
+    let _ = module::foreach_module(module::ModuleType::All, |path| {
+        if path.join("module.prop").exists()
+            && let Some(name) = path.file_name()
+        {
+            ids.push(name.to_string_lossy().into_owned());
+        }
+        Ok(())
+    });
+    ids.sort();
+    ids
+}
+
+fn bundle_dir() -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    Path::new(defs::MODULE_TRASH_DIR).join(format!("reset-{now}"))
+}
+
+fn archived_dirs() -> Vec<&'static str> {
+    [defs::MODULE_DIR, defs::MODULE_UPDATE_DIR, MODULE_CONFIG_DIR]
+        .into_iter()
+        .map(|dir| dir.trim_end_matches('/'))
+        .filter(|dir| Path::new(dir).exists())
+        .collect()
+}
+
+fn print_plan(ids: &[String]) {
+    println!("- Modules to uninstall: {}", ids.len());
+    for id in ids {
+        println!("  {id}");
+    }
+    for dir in archived_dirs() {
+        println!("- Archive {dir} into {}", defs::MODULE_TRASH_DIR);
+    }
+    println!("- Clear metamodule link and pending update flag");
+    println!("- Create an empty {}", defs::MODULE_DIR);
+    println!("- Keep APatch settings, su allowlist and superkey");
+}
+
+/// Run the uninstall scripts of module `id`, failures are only logged
+pub fn uninstall(id: &str) {
+    let path = Path::new(defs::MODULE_DIR).join(id);
+    let is_metamodule =
+        module::read_module_prop(&path).is_ok_and(|props| metamodule::is_metamodule(&props));
+    if !is_metamodule
+        && utils::get_mount_mode() == defs::MOUNT_MODE_METAMODULE
+        && let Err(e) = metamodule::exec_metauninstall_script(id)
+    {
+        warn!("Failed to exec metamodule uninstall for {id}: {e}");
+    }
+    let uninstaller = path.join("uninstall.sh");
+    if uninstaller.exists()
+        && let Err(e) = module::exec_script(&uninstaller, true)
+    {
+        warn!("Failed to exec uninstaller of {id}: {e}");
+    }
+}
+
+pub fn reset_modules(confirm: bool, dry_run: bool) -> Result<()> {
+    let ids = module_ids();
+    if dry_run {
+        print_plan(&ids);
+        return Ok(());
+    }
+    ensure!(confirm, "This removes all modules, pass --confirm to proceed");
+
+    let _guard = module::lock_modules()?;
+    let mut journal = Journal::load();
+    if !journal.0.is_empty() {
+        println!("- Resuming interrupted reset");
+    }
+
+    for id in &ids {
+        let step = format!("uninstall:{id}");
+        if journal.done(&step) {
+            continue;
+        }
+        println!("- Uninstalling {id}");
+        uninstall(id);
+        journal.mark(step)?;
+    }
+
+    if !journal.done("archive") {
+        let bundle = bundle_dir();
+        ensure_dir_exists(&bundle)?;
+        for dir in archived_dirs() {
+            let name = Path::new(dir).file_name().unwrap_or_default();
+            println!("- Archiving {dir}");
+            fs::rename(dir, bundle.join(name))
+                .with_context(|| format!("Failed to archive {dir}"))?;
+        }
+        println!("- Modules archived in {}", bundle.display());
+        journal.mark("archive".to_string())?;
+    }
+
+    if !journal.done("clear") {
+        println!("- Clearing module state");
+        metamodule::remove_symlink()?;
+        let update_flag = Path::new(defs::WORKING_DIR).join(defs::UPDATE_FILE_NAME);
+        if update_flag.exists() {
+            fs::remove_file(update_flag)?;
+        }
+        journal.mark("clear".to_string())?;
+    }
+
+    ensure_dir_exists(defs::MODULE_DIR)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(defs::MODULE_DIR, fs::Permissions::from_mode(0o700))?;
+    }
+    restorecon::ensure_con(defs::MODULE_DIR, ADB_CON)?;
+
+    fs::remove_file(defs::RESET_JOURNAL_FILE)?;
+    info!("module subsystem reset, {} module(s) archived", ids.len());
+    println!("- Done, reboot to unmount the old modules");
+    Ok(())
+}
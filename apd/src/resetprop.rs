@@ -1,13 +1,12 @@
 use anyhow::{Context, Result, bail};
 use clap::Parser;
 use clap::error::ErrorKind;
-use log::info;
+use log::{info, warn};
 use prop_rs_android::resetprop::ResetProp;
 use prop_rs_android::sys_prop;
 use std::fmt;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
 use std::time::Duration;
 
 #[derive(Debug)]
@@ -223,10 +222,26 @@ pub fn execute(cli: &Args) -> Result<()> {
     Ok(())
 }
 
-/// Load system.prop file using internal resetprop API.
+/// Set `name` to `value` through the property service, like `resetprop name value`
+pub fn set_prop(name: &str, value: &str) -> Result<()> {
+    sys_prop::init().context("Failed to initialize system property API")?;
+
+    let rp = ResetProp {
+        skip_svc: false,
+        persistent: false,
+        persist_only: false,
+        verbose: false,
+        show_context: false,
+    };
+    rp.set(name, value)
+        .with_context(|| format!("Failed to set {name}"))?;
+    Ok(())
+}
+
+/// Set props collected from module system.prop files using internal resetprop API.
 ///
-/// Equivalent to `resetprop -n --file <path>`.
-pub fn load_system_prop_file(path: &Path) -> Result<()> {
+/// Equivalent to `resetprop -n <name> <value>` for each `(module id, name, value)`.
+pub fn set_module_props(props: &[(String, String, String)]) -> Result<()> {
     sys_prop::init().context("Failed to initialize system property API")?;
 
     let rp = ResetProp {
@@ -237,11 +252,11 @@ pub fn load_system_prop_file(path: &Path) -> Result<()> {
         show_context: false,
     };
 
-    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
-    let reader = BufReader::new(file);
-    rp.load_props(reader.lines())
-        .with_context(|| format!("Failed to load properties from {}", path.display()))?;
-
-    info!("Loaded system.prop from {}", path.display());
+    for (id, name, value) in props {
+        match rp.set(name, value) {
+            Ok(_) => info!("system.prop of {id}: {name}={value}"),
+            Err(e) => warn!("system.prop of {id}: failed to set {name}: {e}"),
+        }
+    }
     Ok(())
 }
\ No newline at end of file
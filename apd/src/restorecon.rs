@@ -1,11 +1,22 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+    thread,
+};
 
 use anyhow::Result;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use anyhow::{Context, Ok};
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use extattr::{Flags as XattrFlags, lsetxattr};
-use jwalk::{Parallelism::Serial, WalkDir};
+use jwalk::{
+    Parallelism::{self, RayonNewPool, Serial},
+    WalkDir,
+};
+use regex::Regex;
 
 use crate::defs;
 
@@ -69,17 +80,524 @@ pub fn ensure_syscon<P: AsRef<Path>>(path: P) -> Result<()> {
     ensure_con(path, SYSTEM_CON)
 }
 
-pub fn restore_syscon<P: AsRef<Path>>(dir: P) -> Result<()> {
-    for dir_entry in WalkDir::new(dir).parallelism(Serial) {
-        if let Some(path) = dir_entry.ok().map(|dir_entry| dir_entry.path()) {
-            ensure_syscon(&path)?;
+/// Skipping more files than this usually means the walk raced a module update
+const RACE_SKIP_THRESHOLD: usize = 16;
+/// Upper bound of threads walking and relabeling, post-fs-data has other work to do
+const RELABEL_THREADS: usize = 4;
+
+/// Outcome of a relabel walk, failures are collected instead of aborting the walk
+#[derive(Debug, Default)]
+pub struct RelabelSummary {
+    pub checked: usize,
+    /// entries which vanished (deleted or renamed) while walking
+    pub skipped: usize,
+    /// entries on a filesystem without xattr support, e.g. vfat
+    pub unsupported: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl RelabelSummary {
+    /// The walk likely raced a module update and should be retried once
+    pub fn raced(&self) -> bool {
+        self.skipped > RACE_SKIP_THRESHOLD
+    }
+
+    /// Turn persistent failures into an error
+    pub fn check(&self) -> Result<()> {
+        if let Some((path, e)) = self.failed.first() {
+            anyhow::bail!(
+                "Failed to relabel {} file(s), first: {}: {}",
+                self.failed.len(),
+                path.display(),
+                e
+            );
         }
+        Ok(())
     }
-    Ok(())
 }
 
+impl fmt::Display for RelabelSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checked {}, skipped {}, failed {}",
+            self.checked,
+            self.skipped,
+            self.failed.len()
+        )?;
+        if self.unsupported > 0 {
+            write!(f, ", no xattr support {}", self.unsupported)?;
+        }
+        if self.raced() {
+            write!(f, " (many files vanished during the walk, retry once)")?;
+        }
+        Ok(())
+    }
+}
+
+/// file_contexts of the platform and vendor policy, in the order they are loaded
+const FILE_CONTEXTS: &[&str] = &[
+    "/system/etc/selinux/plat_file_contexts",
+    "/system_ext/etc/selinux/system_ext_file_contexts",
+    "/product/etc/selinux/product_file_contexts",
+    "/vendor/etc/selinux/vendor_file_contexts",
+    "/odm/etc/selinux/odm_file_contexts",
+];
+
+struct Spec {
+    /// literal start of the regex, checked before compiling it
+    stem: String,
+    pattern: String,
+    regex: OnceLock<Option<Regex>>,
+    file_type: Option<char>,
+    context: String,
+}
+
+impl Spec {
+    fn matches(&self, path: &str, file_type: char) -> bool {
+        self.file_type.is_none_or(|t| t == file_type)
+            && path.starts_with(&self.stem)
+            && self
+                .regex
+                .get_or_init(|| Regex::new(&format!("^(?:{})$", self.pattern)).ok())
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(path))
+    }
+}
+
+/// Labels of the device's file_contexts, looked up by the path a module file
+/// is mounted at. Like libselinux, specs without regex meta characters take
+/// precedence and otherwise the last matching spec wins.
+pub struct FileContexts {
+    exact: HashMap<String, (Option<char>, String)>,
+    specs: Vec<Spec>,
+}
+
+impl FileContexts {
+    fn load() -> Option<Self> {
+        let mut contexts = FileContexts {
+            exact: HashMap::new(),
+            specs: Vec::new(),
+        };
+        for file in FILE_CONTEXTS {
+            let Ok(content) = fs::read_to_string(file) else {
+                continue;
+            };
+            for line in content.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let (pattern, file_type, context) = match fields[..] {
+                    [pattern, context] => (pattern, None, context),
+                    [pattern, file_type, context] => {
+                        (pattern, file_type.strip_prefix('-')?.chars().next(), context)
+                    }
+                    _ => return None,
+                };
+                if context == "<<none>>" {
+                    continue;
+                }
+                let stem_len = pattern
+                    .find(|c| ".^$?*+|[({\\".contains(c))
+                    .unwrap_or(pattern.len());
+                if stem_len == pattern.len() {
+                    contexts
+                        .exact
+                        .insert(pattern.to_string(), (file_type, context.to_string()));
+                } else {
+                    contexts.specs.push(Spec {
+                        stem: pattern[..stem_len].to_string(),
+                        pattern: pattern.to_string(),
+                        regex: OnceLock::new(),
+                        file_type,
+                        context: context.to_string(),
+                    });
+                }
+            }
+        }
+        (!contexts.specs.is_empty()).then_some(contexts)
+    }
+
+    fn lookup(&self, target: &str, file_type: char) -> Option<&str> {
+        if let Some((t, context)) = self.exact.get(target)
+            && t.is_none_or(|t| t == file_type)
+        {
+            return Some(context);
+        }
+        self.specs
+            .iter()
+            .rev()
+            .find(|spec| spec.matches(target, file_type))
+            .map(|spec| spec.context.as_str())
+    }
+}
+
+/// The device's file_contexts if labeling by them is enabled and they parse
+fn file_contexts() -> Option<&'static FileContexts> {
+    static CONTEXTS: OnceLock<Option<FileContexts>> = OnceLock::new();
+    CONTEXTS
+        .get_or_init(|| {
+            if !Path::new(defs::FILE_CONTEXTS_FLAG_FILE).exists() {
+                return None;
+            }
+            let contexts = FileContexts::load();
+            if contexts.is_none() {
+                log::warn!("Failed to parse file_contexts, label module files as system_file");
+            }
+            contexts
+        })
+        .as_ref()
+}
+
+/// Path a module file is mounted at, for files in a partition dir of a module
+fn mount_target(path: &Path) -> Option<String> {
+    let rel = path
+        .strip_prefix(defs::MODULE_DIR)
+        .or_else(|_| path.strip_prefix(defs::MODULE_UPDATE_DIR))
+        .ok()?;
+    let mut components = rel.components().skip(1);
+    let partition = match components.next()? {
+        Component::Normal(partition) => partition.to_str()?,
+        _ => return None,
+    };
+    if partition != "system" && !crate::critical::PARTITIONS.contains(&partition) {
+        return None;
+    }
+    let target = Path::new("/").join(partition).join(components.as_path());
+    Some(target.to_string_lossy().into_owned())
+}
+
+/// file_contexts type letter of a file
+fn type_letter(path: &Path) -> char {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return '-';
+    };
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else {
+        '-'
+    }
+}
+
+/// Label a module file should get, `SYSTEM_CON` unless file_contexts say otherwise
+fn module_file_con(path: &Path) -> &'static str {
+    file_contexts()
+        .and_then(|contexts| {
+            let target = mount_target(path)?;
+            contexts.lookup(&target, type_letter(path))
+        })
+        .unwrap_or(SYSTEM_CON)
+}
+
+/// All paths below `dir` and how many entries could not be read
+fn walk(dir: &Path, parallelism: Parallelism) -> (Vec<PathBuf>, usize) {
+    let mut paths = Vec::new();
+    let mut errors = 0;
+    for dir_entry in WalkDir::new(dir).parallelism(parallelism) {
+        match dir_entry.ok() {
+            Some(dir_entry) => paths.push(dir_entry.path()),
+            // directory removed or renamed under us
+            None => errors += 1,
+        }
+    }
+    (paths, errors)
+}
+
+/// Whether the filesystem of `path` cannot store labels at all
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn xattr_unsupported(path: &Path) -> bool {
+    matches!(
+        rustix::fs::lgetxattr(path, SELINUX_XATTR, &mut [0u8; 0][..]),
+        Err(rustix::io::Errno::OPNOTSUPP)
+    )
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn xattr_unsupported(_path: &Path) -> bool {
+    false
+}
+
+fn relabel(paths: &[PathBuf]) -> RelabelSummary {
+    let mut summary = RelabelSummary::default();
+    for path in paths {
+        summary.checked += 1;
+        // ENOENT/ESTALE are detected by the file being gone, anything else
+        // (EBUSY mostly) gets a single retry
+        let con = module_file_con(path);
+        let Err(e) = ensure_con(path, con).or_else(|_| {
+            if path.symlink_metadata().is_err() {
+                return Ok(());
+            }
+            ensure_con(path, con)
+        }) else {
+            continue;
+        };
+        if path.symlink_metadata().is_err() {
+            log::debug!("{} vanished during relabel, skip", path.display());
+            summary.skipped += 1;
+        } else if xattr_unsupported(path) {
+            log::debug!("{} has no xattr support, skip", path.display());
+            summary.unsupported += 1;
+        } else {
+            summary.failed.push((path.clone(), format!("{e:#}")));
+        }
+    }
+    summary
+}
+
+/// Label everything below `dir` as system files, only where the label differs.
+/// With `file_contexts_enable` module files get the label of the path they are
+/// mounted at instead.
+///
+/// The tree is walked and relabeled by up to [`RELABEL_THREADS`] threads. If
+/// the parallel walk could not read some directory it is redone serially.
+pub fn restore_syscon<P: AsRef<Path>>(dir: P) -> RelabelSummary {
+    let dir = dir.as_ref();
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(RELABEL_THREADS);
+    let (mut paths, mut skipped) = if threads > 1 {
+        walk(dir, RayonNewPool(threads))
+    } else {
+        walk(dir, Serial)
+    };
+    if skipped > 0 && threads > 1 {
+        (paths, skipped) = walk(dir, Serial);
+    }
+
+    let mut summary = relabel_parallel(&paths, threads, relabel);
+    summary.skipped += skipped;
+    summary
+}
+
+/// Run `relabel` over `paths` split between `threads` threads. The paths of a
+/// thread which panicked count as failed, it is unknown how far it got.
+fn relabel_parallel(
+    paths: &[PathBuf],
+    threads: usize,
+    relabel: impl Fn(&[PathBuf]) -> RelabelSummary + Sync,
+) -> RelabelSummary {
+    let chunk_size = paths.len().div_ceil(threads).max(1);
+    let parts: Vec<RelabelSummary> = thread::scope(|scope| {
+        let workers: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| (chunk, scope.spawn(|| relabel(chunk))))
+            .collect();
+        workers
+            .into_iter()
+            .map(|(chunk, worker)| {
+                worker.join().unwrap_or_else(|panic| {
+                    let payload = panic
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    let reason = format!("relabel thread panicked: {payload}");
+                    log::error!("{reason}");
+                    RelabelSummary {
+                        checked: chunk.len(),
+                        failed: chunk.iter().map(|path| (path.clone(), reason.clone())).collect(),
+                        ..Default::default()
+                    }
+                })
+            })
+            .collect()
+    });
+
+    let mut summary = RelabelSummary::default();
+    for part in parts {
+        summary.checked += part.checked;
+        summary.skipped += part.skipped;
+        summary.unsupported += part.unsupported;
+        summary.failed.extend(part.failed);
+    }
+    summary
+}
+
+/// Module dirs whose labels were checked, keyed by module id. A module is only
+/// walked again once the ctime of its dir changed, which happens when entries
+/// are added, removed or renamed directly in it or the dir itself was moved.
+/// Deeper changes are not noticed, removing `.relabel_stamp` forces a full walk.
+#[derive(Default)]
+pub struct RelabelStamps {
+    file_contexts: bool,
+    old: HashMap<String, String>,
+    new: BTreeMap<String, String>,
+    path: PathBuf,
+}
+
+fn module_stamp(module_path: &Path) -> Option<(String, String)> {
+    let id = module_path.file_name()?.to_string_lossy().into_owned();
+    let metadata = module_path.metadata().ok()?;
+    Some((id, format!("{}.{}", metadata.ctime(), metadata.ctime_nsec())))
+}
+
+impl RelabelStamps {
+    pub fn load() -> Self {
+        Self::load_from(Path::new(defs::RELABEL_STAMP_FILE), file_contexts().is_some())
+    }
+
+    fn load_from(path: &Path, file_contexts: bool) -> Self {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        let mut lines = content.lines();
+        // labels of another labeling scheme are all stale
+        let old = if lines.next() == Some(Self::header(file_contexts)) {
+            lines
+                .filter_map(|line| line.split_once(' '))
+                .map(|(id, stamp)| (id.to_string(), stamp.to_string()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        RelabelStamps {
+            file_contexts,
+            old,
+            new: BTreeMap::new(),
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn header(file_contexts: bool) -> &'static str {
+        if file_contexts {
+            "file_contexts=1"
+        } else {
+            "file_contexts=0"
+        }
+    }
+
+    /// Whether `module_path` is unchanged since its labels were last checked
+    pub fn is_fresh(&self, module_path: &Path) -> bool {
+        !crate::flags::is_set(module_path.join(defs::UPDATE_FILE_NAME))
+            && module_stamp(module_path)
+                .is_some_and(|(id, stamp)| self.old.get(&id) == Some(&stamp))
+    }
+
+    /// Remember `module_path` as checked, read after relabeling as that
+    /// changes the ctime of the dir itself
+    pub fn mark(&mut self, module_path: &Path) {
+        if let Some((id, stamp)) = module_stamp(module_path) {
+            self.new.insert(id, stamp);
+        }
+    }
+
+    pub fn store(&self) -> Result<()> {
+        let mut content = format!("{}\n", Self::header(self.file_contexts));
+        for (id, stamp) in &self.new {
+            content += &format!("{id} {stamp}\n");
+        }
+        fs::write(&self.path, content)?;
+        Ok(())
+    }
+}
+
+/// Walk every module again on the next mount, e.g. after module updates
+pub fn forget_relabel_stamps() {
+    if let Err(e) = fs::remove_file(defs::RELABEL_STAMP_FILE)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        log::warn!("Failed to remove {}: {}", defs::RELABEL_STAMP_FILE, e);
+    }
+}
 
 pub fn restorecon() -> Result<()> {
     ensure_con(defs::DAEMON_PATH, ADB_CON)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn paths(count: usize) -> Vec<PathBuf> {
+        (0..count).map(|i| PathBuf::from(format!("/x/{i}"))).collect()
+    }
+
+    #[test]
+    fn parallel_relabel_sums_the_threads() {
+        let summary = relabel_parallel(&paths(10), 4, |chunk| RelabelSummary {
+            checked: chunk.len(),
+            unsupported: 1,
+            ..Default::default()
+        });
+        assert_eq!(summary.checked, 10);
+        assert_eq!(summary.unsupported, 4);
+        assert!(summary.check().is_ok());
+    }
+
+    #[test]
+    fn a_panicked_thread_fails_its_paths() {
+        let all = paths(8);
+        let summary = relabel_parallel(&all, 4, |chunk| {
+            if chunk.contains(&PathBuf::from("/x/5")) {
+                panic!("boom");
+            }
+            RelabelSummary {
+                checked: chunk.len(),
+                ..Default::default()
+            }
+        });
+        assert_eq!(summary.checked, 8);
+        let failed: Vec<_> = summary.failed.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(failed, all[4..6]);
+        assert!(summary.failed.iter().all(|(_, reason)| reason.ends_with("panicked: boom")));
+        assert!(summary.check().is_err());
+    }
+
+    #[test]
+    fn vanished_paths_are_skipped_not_failed() {
+        let root = TempDir::new("relabel-vanished");
+        let gone = root.write("module/service.sh", "");
+        fs::remove_file(&gone).unwrap();
+        let summary = relabel(&[gone, root.path().join("module/never-existed")]);
+        assert_eq!(summary.checked, 2);
+        assert_eq!(summary.skipped, 2);
+        assert!(summary.failed.is_empty());
+        assert!(summary.check().is_ok());
+    }
+
+    #[test]
+    fn stamps_keep_unchanged_modules_fresh() {
+        let root = TempDir::new("relabel-stamps");
+        let stamp_file = root.path().join("stamp");
+        let module = root.path().join("module");
+        root.write("module/system/bin/tool", "");
+
+        let mut stamps = RelabelStamps::load_from(&stamp_file, false);
+        assert!(!stamps.is_fresh(&module));
+        stamps.mark(&module);
+        stamps.store().unwrap();
+        assert!(RelabelStamps::load_from(&stamp_file, false).is_fresh(&module));
+
+        // stamps of the other labeling scheme are all stale
+        assert!(!RelabelStamps::load_from(&stamp_file, true).is_fresh(&module));
+
+        // an update flag forces a walk, even with a current stamp
+        let update = root.write(&format!("module/{}", defs::UPDATE_FILE_NAME), "");
+        let mut stamps = RelabelStamps::load_from(&stamp_file, false);
+        stamps.mark(&module);
+        stamps.store().unwrap();
+        assert!(!RelabelStamps::load_from(&stamp_file, false).is_fresh(&module));
+
+        fs::remove_file(update).unwrap();
+        let mut stamps = RelabelStamps::load_from(&stamp_file, false);
+        stamps.mark(&module);
+        stamps.store().unwrap();
+        assert!(RelabelStamps::load_from(&stamp_file, false).is_fresh(&module));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        root.write("module/service.sh", "");
+        assert!(!RelabelStamps::load_from(&stamp_file, false).is_fresh(&module));
+    }
+}
@@ -0,0 +1,94 @@
+//! Restrictions stage scripts run under
+//!
+//! Before a stage script is executed its process
+//!
+//! * drops `CAP_SYS_MODULE`, `CAP_SYS_BOOT` and `CAP_SYS_RAWIO` from its
+//!   capability bounding set, unless `script_restrict_caps = false` or the
+//!   module.prop of the script declares `needs_full_caps=true`
+//! * transitions to `script_context` on exec if set, the domain of apd otherwise
+//! * runs with `script_umask`, `022` by default
+//!
+//! The effective restrictions are logged per script, so module authors can
+//! tell a permission failure from a bug.
+
+use std::{ffi::CString, io, path::Path};
+
+use crate::{config, module};
+
+const DROPPED_CAPS: &[(libc::c_ulong, &str)] = &[
+    (16, "CAP_SYS_MODULE"),
+    (17, "CAP_SYS_RAWIO"),
+    (22, "CAP_SYS_BOOT"),
+];
+
+pub struct Sandbox {
+    context: Option<CString>,
+    restrict_caps: bool,
+    umask: u32,
+}
+
+impl Sandbox {
+    pub fn for_script(script: &Path) -> Self {
+        Self::for_module(script.parent().unwrap_or(script))
+    }
+
+    /// The restrictions of anything run for the module at `module_dir`
+    pub fn for_module(module_dir: &Path) -> Self {
+        let full_caps = module::read_module_prop(module_dir)
+            .ok()
+            .and_then(|prop| prop.get("needs_full_caps").cloned())
+            .is_some_and(|value| value.trim() == "true");
+        Sandbox {
+            context: config::script_context().and_then(|context| CString::new(context).ok()),
+            restrict_caps: config::script_restrict_caps() && !full_caps,
+            umask: config::script_umask(),
+        }
+    }
+
+    /// One line for the log naming every restriction
+    pub fn describe(&self) -> String {
+        let context = self
+            .context
+            .as_ref()
+            .map_or("inherited".into(), |context| context.to_string_lossy());
+        let caps = if self.restrict_caps {
+            let dropped: Vec<&str> = DROPPED_CAPS.iter().map(|(_, name)| *name).collect();
+            format!("without {}", dropped.join(","))
+        } else {
+            "full".to_string()
+        };
+        format!("context {context}, caps {caps}, umask {:03o}", self.umask)
+    }
+
+    /// Apply the restrictions to the calling process, meant for `pre_exec` and
+    /// so free of allocations
+    pub fn apply(&self) -> io::Result<()> {
+        unsafe { libc::umask(self.umask as libc::mode_t) };
+        if self.restrict_caps {
+            for (cap, _) in DROPPED_CAPS {
+                if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, *cap, 0, 0, 0) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        if let Some(context) = &self.context {
+            let fd = unsafe {
+                libc::open(
+                    c"/proc/thread-self/attr/exec".as_ptr(),
+                    libc::O_WRONLY | libc::O_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let bytes = context.as_bytes();
+            let written = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+            let error = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            if written < 0 {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+}
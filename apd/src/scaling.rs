@@ -0,0 +1,84 @@
+//! Timed runs over generated module sets, `cargo test --features scaling-tests`
+//!
+//! Planning and listing are run over 50, 200 and 500 synthetic modules. The
+//! time per module at 500 must stay within a few times the time per module
+//! at 50, a walk comparing every pair of modules fails it.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{magic_mount, module, testutil::TempDir};
+
+const SIZES: [usize; 3] = [50, 200, 500];
+/// allowed growth of the time per module from the smallest to the largest set
+const MAX_GROWTH: f64 = 3.0;
+
+/// `count` modules with files of their own, one file all of them carry and a
+/// replaced dir each
+fn generate(root: &TempDir, count: usize) -> Vec<PathBuf> {
+    (0..count)
+        .map(|i| {
+            let id = format!("module_{i:04}");
+            let prop = format!(
+                "id={id}\nname={id}\nversion=1\nversionCode=1\nauthor=apd\ndescription=generated\n"
+            );
+            root.write(&format!("{id}/module.prop"), &prop);
+            for file in 0..8 {
+                root.write(&format!("{id}/system/bin/{id}_{file}"), "#!/system/bin/sh\n");
+            }
+            root.write(&format!("{id}/system/etc/shared.conf"), &id);
+            root.write(&format!("{id}/system/app/{id}/.replace"), "");
+            root.write(&format!("{id}/system/app/{id}/{id}.apk"), "apk");
+            root.write(&format!("{id}/service.sh"), "#!/system/bin/sh\n");
+            root.path().join(id)
+        })
+        .collect()
+}
+
+/// Best of three runs, divided by the number of modules
+fn per_module(count: usize, run: &dyn Fn()) -> Duration {
+    (0..3)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+        / count as u32
+}
+
+fn assert_linear(name: &str, run: &dyn Fn(&Path, &[PathBuf])) {
+    let times: Vec<Duration> = SIZES
+        .iter()
+        .map(|&count| {
+            let root = TempDir::new(&format!("scaling-{count}"));
+            let modules = generate(&root, count);
+            per_module(count, &|| run(root.path(), &modules))
+        })
+        .collect();
+    let growth = times[2].as_secs_f64() / times[0].as_secs_f64();
+    println!("{name}: per module {times:?}, growth {growth:.2}");
+    assert!(
+        growth < MAX_GROWTH,
+        "{name} grows faster than linear: per module {times:?}"
+    );
+}
+
+#[test]
+fn conflict_detection_scales_linearly() {
+    assert_linear("find_conflicts", &|_, modules| {
+        let conflicts = magic_mount::find_conflicts(modules);
+        assert_eq!(conflicts.len(), 1);
+    });
+}
+
+#[test]
+fn module_list_scales_linearly() {
+    assert_linear("list_modules", &|root, modules| {
+        let listed = module::_list_modules(&root.to_string_lossy());
+        assert_eq!(listed.len(), modules.len());
+    });
+}
@@ -0,0 +1,269 @@
+//! Results of walks over module files, kept across runs
+//!
+//! `apd module list`, `apd module du` and the stage diff would otherwise walk
+//! every module tree on each call. Sizes and critical paths are kept per dir
+//! and reused while the dir keeps its inode and mtime, like the relabel stamps
+//! only changes directly in the dir are noticed. Installing or updating a
+//! module moves a new dir into place, which always misses. Hashes are kept per
+//! file and reused while the file keeps its inode, size and mtime.
+//!
+//! Removing `.scan_cache` forces every walk again.
+
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    critical::Patterns,
+    defs,
+    utils::{self, DiskUsage},
+};
+
+#[derive(Serialize, Deserialize, Default)]
+struct DirScan {
+    stamp: String,
+    usage: Option<DiskUsage>,
+    critical: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileHash {
+    stamp: String,
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ScanCache {
+    /// the critical patterns the cached critical paths were found with
+    patterns: String,
+    dirs: HashMap<PathBuf, DirScan>,
+    files: HashMap<PathBuf, FileHash>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+fn dir_stamp(dir: &Path) -> Option<String> {
+    let metadata = dir.metadata().ok()?;
+    Some(format!(
+        "{}.{}.{}",
+        metadata.ino(),
+        metadata.mtime(),
+        metadata.mtime_nsec()
+    ))
+}
+
+fn file_stamp(file: &Path) -> Option<String> {
+    let metadata = file.metadata().ok()?;
+    Some(format!(
+        "{}.{}.{}.{}",
+        metadata.ino(),
+        metadata.len(),
+        metadata.mtime(),
+        metadata.mtime_nsec()
+    ))
+}
+
+impl ScanCache {
+    pub fn load() -> Self {
+        Self::load_from(Path::new(defs::SCAN_CACHE_FILE))
+    }
+
+    /// A cache which does not parse is started over
+    fn load_from(path: &Path) -> Self {
+        let mut cache: ScanCache = fs::read(path)
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+        cache.path = path.to_path_buf();
+        cache
+    }
+
+    /// The entry of `dir` if it is unchanged, otherwise a fresh one
+    fn dir(&mut self, dir: &Path) -> Option<&mut DirScan> {
+        let stamp = dir_stamp(dir)?;
+        let scan = self.dirs.entry(dir.to_path_buf()).or_default();
+        if scan.stamp != stamp {
+            *scan = DirScan {
+                stamp,
+                ..Default::default()
+            };
+        }
+        Some(scan)
+    }
+
+    /// [`utils::disk_usage`] of `dir`
+    pub fn disk_usage(&mut self, dir: &Path) -> DiskUsage {
+        let Some(scan) = self.dir(dir) else {
+            return DiskUsage::default();
+        };
+        if let Some(usage) = scan.usage {
+            return usage;
+        }
+        let usage = utils::disk_usage(dir);
+        scan.usage = Some(usage);
+        self.dirty = true;
+        usage
+    }
+
+    /// [`Patterns::scan`] of `module_path`
+    pub fn critical(&mut self, patterns: &Patterns, module_path: &Path) -> Vec<String> {
+        let fingerprint = patterns.fingerprint();
+        if self.patterns != fingerprint {
+            self.dirs.values_mut().for_each(|scan| scan.critical = None);
+            self.patterns = fingerprint;
+            self.dirty = true;
+        }
+        let Some(scan) = self.dir(module_path) else {
+            return Vec::new();
+        };
+        if let Some(critical) = &scan.critical {
+            return critical.clone();
+        }
+        let critical = patterns.scan(module_path);
+        scan.critical = Some(critical.clone());
+        self.dirty = true;
+        critical
+    }
+
+    /// [`utils::hash_file`] of `file`, `None` if it cannot be read
+    pub fn hash(&mut self, file: &Path) -> Option<String> {
+        let stamp = file_stamp(file)?;
+        if let Some(cached) = self.files.get(file)
+            && cached.stamp == stamp
+        {
+            return Some(cached.hash.clone());
+        }
+        let hash = utils::hash_file(file).ok()?;
+        self.files.insert(
+            file.to_path_buf(),
+            FileHash {
+                stamp,
+                hash: hash.clone(),
+            },
+        );
+        self.dirty = true;
+        Some(hash)
+    }
+
+    /// Write the cache if anything was added, entries of paths which are gone
+    /// are dropped. Failing to store only costs the next run a walk.
+    pub fn store(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirs.retain(|dir, _| dir.exists());
+        self.files.retain(|file, _| file.exists());
+        if let Err(e) = self.write() {
+            log::debug!("Failed to store the scan cache: {e:#}");
+        }
+        self.dirty = false;
+    }
+
+    fn write(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path).with_context(|| {
+            format!("Failed to rename {} to {}", tmp.display(), self.path.display())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn reuses_sizes_until_the_dir_changes() {
+        let root = TempDir::new("scan-size");
+        let module = root.path().join("module");
+        root.write("module/system/bin/tool", "1234");
+        let cache_file = root.path().join("cache");
+
+        let mut cache = ScanCache::load_from(&cache_file);
+        assert_eq!(cache.disk_usage(&module).bytes, 4);
+        cache.store();
+
+        // a cached size is returned without walking again
+        let mut cache = ScanCache::load_from(&cache_file);
+        cache.dirs.get_mut(&module).unwrap().usage.as_mut().unwrap().bytes = 99;
+        assert_eq!(cache.disk_usage(&module).bytes, 99);
+
+        // an entry added to the dir itself changes its mtime
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        root.write("module/service.sh", "12");
+        assert_eq!(cache.disk_usage(&module).bytes, 6);
+    }
+
+    #[test]
+    fn a_replaced_dir_misses() {
+        let root = TempDir::new("scan-replaced");
+        let module = root.path().join("module");
+        root.write("module/system/bin/tool", "1234");
+        let mut cache = ScanCache::load_from(&root.path().join("cache"));
+        assert_eq!(cache.disk_usage(&module).bytes, 4);
+
+        let staged = root.path().join("staged");
+        root.write("staged/system/bin/tool", "12345678");
+        fs::rename(&module, root.path().join("old")).unwrap();
+        fs::rename(&staged, &module).unwrap();
+        assert_eq!(cache.disk_usage(&module).bytes, 8);
+    }
+
+    #[test]
+    fn critical_paths_are_found_again_with_other_patterns() {
+        let root = TempDir::new("scan-critical");
+        let module = root.path().join("module");
+        root.write("module/system/bin/init", "");
+        let patterns = Patterns::load();
+        let mut cache = ScanCache::load_from(&root.path().join("cache"));
+        assert_eq!(cache.critical(&patterns, &module), ["system/bin/init"]);
+
+        cache.dirs.get_mut(&module).unwrap().critical = Some(Vec::new());
+        assert!(cache.critical(&patterns, &module).is_empty());
+        cache.patterns = "system/bin/other".to_string();
+        assert_eq!(cache.critical(&patterns, &module), ["system/bin/init"]);
+    }
+
+    #[test]
+    fn hashes_follow_file_changes() {
+        let root = TempDir::new("scan-hash");
+        let file = root.write("service.sh", "echo one");
+        let mut cache = ScanCache::load_from(&root.path().join("cache"));
+        let first = cache.hash(&file).unwrap();
+        assert_eq!(first, utils::hash_file(&file).unwrap());
+
+        cache.files.get_mut(&file).unwrap().hash = "cached".to_string();
+        assert_eq!(cache.hash(&file).as_deref(), Some("cached"));
+
+        fs::write(&file, "echo two, longer").unwrap();
+        assert_eq!(cache.hash(&file), Some(utils::hash_file(&file).unwrap()));
+        assert_eq!(cache.hash(&root.path().join("missing")), None);
+    }
+
+    #[test]
+    fn store_drops_entries_of_removed_paths() {
+        let root = TempDir::new("scan-store");
+        let cache_file = root.path().join("cache");
+        let kept = root.write("kept/a", "a");
+        let gone = root.write("gone/b", "b");
+        let mut cache = ScanCache::load_from(&cache_file);
+        cache.hash(&kept);
+        cache.hash(&gone);
+        fs::remove_file(&gone).unwrap();
+        cache.store();
+
+        let cache = ScanCache::load_from(&cache_file);
+        assert!(cache.files.contains_key(&kept));
+        assert!(!cache.files.contains_key(&gone));
+    }
+}
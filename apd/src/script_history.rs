@@ -0,0 +1,340 @@
+//! Per module history of script outcomes
+//!
+//! Every script apd runs from a module dir leaves a [`ScriptOutcome`] in
+//! `/data/adb/ap/script_history/<id>.jsonl`. Only the last [`KEPT_BOOTS`] boots
+//! are kept, so the files stay small. `apd module history <id>` prints them,
+//! which is what module authors ask their users for.
+//!
+//! The output of stage scripts goes to a log of their own instead of the
+//! stream of apd: `log/modules/<id>/<stage>.log` for modules and
+//! `log/common_scripts/<stage>.d/<script>.log` for common scripts. The log of
+//! the previous run is moved to `<stage>.log.old` first. A waited script's log
+//! ends with its exit status and duration, and its lines are passed on to the
+//! apd log prefixed with the module id.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use rustix::fs::{MemfdFlags, memfd_create};
+use serde::{Deserialize, Serialize};
+
+use crate::defs;
+
+/// Boots kept in a history file
+pub const KEPT_BOOTS: usize = 5;
+/// Trailing stderr lines kept per run
+const STDERR_LINES: usize = 8;
+const STDERR_LINE_MAX: usize = 200;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScriptOutcome {
+    pub boot_id: String,
+    pub timestamp: u64,
+    /// script name without `.sh`, e.g. `post-fs-data`
+    pub stage: String,
+    /// whether apd waited for the script, the exit code is unknown otherwise
+    pub waited: bool,
+    /// `None` if not waited for or killed by a signal
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    /// last lines of stderr, of stdout and stderr for scripts with a log
+    pub stderr: Vec<String>,
+}
+
+pub fn boot_id() -> String {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .map(|id| id.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn history_file(id: &str) -> std::path::PathBuf {
+    Path::new(defs::SCRIPT_HISTORY_DIR).join(format!("{id}.jsonl"))
+}
+
+/// Module id of a script directly in a module dir
+pub fn module_id(script: &Path) -> Option<String> {
+    let module = script.parent()?;
+    if module.parent()? != Path::new(defs::MODULE_DIR) {
+        return None;
+    }
+    Some(module.file_name()?.to_string_lossy().into_owned())
+}
+
+/// Where the output of `script` goes, `None` if it is neither a script of a
+/// module nor a common script
+pub fn log_file(script: &Path) -> Option<PathBuf> {
+    let name = script.file_stem()?.to_string_lossy();
+    if let Some(id) = module_id(script) {
+        return Some(Path::new(defs::MODULE_LOG_DIR).join(id).join(format!("{name}.log")));
+    }
+    let dir = script.parent()?;
+    let dir_name = dir.file_name()?.to_string_lossy();
+    if dir.parent()? != Path::new(defs::ADB_DIR) || !dir_name.ends_with(".d") {
+        return None;
+    }
+    Some(Path::new(defs::COMMON_SCRIPT_LOG_DIR).join(&*dir_name).join(format!("{name}.log")))
+}
+
+/// Move the log of the last run aside and open a new one at `path`
+fn open_log(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut old = path.as_os_str().to_owned();
+    old.push(".old");
+    match fs::rename(path, &old) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+/// Last line of a script log, e.g. `exited with 127 after 0.3s`
+fn result_line(status: Option<&ExitStatus>, elapsed: Duration) -> String {
+    let result = match status {
+        Some(status) => match status.code() {
+            Some(code) => format!("exited with {code}"),
+            None => format!("killed ({status})"),
+        },
+        None => "failed to start".to_string(),
+    };
+    format!("{result} after {:.1}s", elapsed.as_secs_f64())
+}
+
+fn tail_lines(output: &str) -> Vec<String> {
+    let lines: Vec<&str> = output.lines().filter(|l| !l.trim().is_empty()).collect();
+    lines[lines.len().saturating_sub(STDERR_LINES)..]
+        .iter()
+        .map(|line| line.chars().take(STDERR_LINE_MAX).collect())
+        .collect()
+}
+
+pub fn load(id: &str) -> Vec<ScriptOutcome> {
+    fs::read_to_string(history_file(id))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn append(id: &str, outcome: ScriptOutcome) -> Result<()> {
+    let mut history = load(id);
+    history.push(outcome);
+
+    let mut boots: Vec<&str> = Vec::new();
+    for entry in history.iter().rev() {
+        if !boots.contains(&entry.boot_id.as_str()) {
+            boots.push(&entry.boot_id);
+        }
+    }
+    boots.truncate(KEPT_BOOTS);
+    let kept: Vec<String> = history
+        .iter()
+        .filter(|entry| boots.contains(&entry.boot_id.as_str()))
+        .filter_map(|entry| serde_json::to_string(entry).ok())
+        .collect();
+
+    fs::create_dir_all(defs::SCRIPT_HISTORY_DIR)?;
+    let path = history_file(id);
+    let tmp = path.with_extension("jsonl.tmp");
+    fs::write(&tmp, kept.join("\n") + "\n")
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, &path).with_context(|| format!("Failed to rename {}", tmp.display()))?;
+    Ok(())
+}
+
+/// Output capture of one script run, outcomes are only recorded for modules
+pub struct Capture {
+    /// module id, `None` for a common script
+    id: Option<String>,
+    stage: String,
+    waited: bool,
+    /// the script log, holding its stdout and stderr
+    log: Option<File>,
+    /// stderr collected in memory when there is no log
+    stderr: Option<File>,
+    start: Instant,
+}
+
+impl Capture {
+    /// Start capturing if `script` belongs to a module or is a common script.
+    /// With `log` its output goes to its script log, see [`log_file`].
+    /// Otherwise the stderr of waited scripts is collected in memory and
+    /// passed through once it exited, stdout stays with apd.
+    pub fn start(script: &Path, command: &mut Command, wait: bool, log: bool) -> Option<Capture> {
+        let id = module_id(script);
+        let log_path = log.then(|| log_file(script)).flatten();
+        if id.is_none() && log_path.is_none() {
+            return None;
+        }
+        let stage = script.file_stem()?.to_string_lossy().into_owned();
+        let log = log_path.and_then(|path| {
+            open_log(&path)
+                .inspect_err(|e| warn!("Failed to open {}: {}", path.display(), e))
+                .ok()
+        });
+        let mut stderr = None;
+        match log.as_ref().map(|log| (log.try_clone(), log.try_clone())) {
+            Some((Ok(out), Ok(err))) => {
+                command.stdout(Stdio::from(out)).stderr(Stdio::from(err));
+            }
+            _ => {
+                stderr = wait
+                    .then(|| memfd_create("script-stderr", MemfdFlags::CLOEXEC).ok())
+                    .flatten()
+                    .map(File::from);
+                if let Some(file) = stderr.as_ref().and_then(|f| f.try_clone().ok()) {
+                    command.stderr(Stdio::from(file));
+                }
+            }
+        }
+        Some(Capture {
+            id,
+            stage,
+            waited: wait,
+            log,
+            stderr,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn finish(self, status: Option<&ExitStatus>) {
+        let mut output = String::new();
+        let prefix = self.id.as_deref().unwrap_or(&self.stage);
+        if let Some(mut log) = self.log.filter(|_| self.waited) {
+            let mut buf = Vec::new();
+            if log.rewind().and_then(|()| log.read_to_end(&mut buf)).is_ok() {
+                output = String::from_utf8_lossy(&buf).into_owned();
+                for line in output.lines().filter(|line| !line.trim().is_empty()) {
+                    info!("[{prefix}] {line}");
+                }
+            }
+            let result = result_line(status, self.start.elapsed());
+            info!("[{prefix}] {}.sh {result}", self.stage);
+            if let Err(e) = writeln!(log, "[apd] {result}") {
+                warn!("Failed to finish the log of {prefix}: {e}");
+            }
+        }
+        if let Some(mut file) = self.stderr {
+            let mut buf = Vec::new();
+            if file.rewind().and_then(|()| file.read_to_end(&mut buf)).is_ok() {
+                let _ = io::stderr().write_all(&buf);
+                output = String::from_utf8_lossy(&buf).into_owned();
+            }
+        }
+        let Some(id) = self.id else {
+            return;
+        };
+        let outcome = ScriptOutcome {
+            boot_id: boot_id(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            stage: self.stage,
+            waited: self.waited,
+            exit_code: status.and_then(ExitStatus::code),
+            duration_ms: if self.waited {
+                self.start.elapsed().as_millis() as u64
+            } else {
+                0
+            },
+            stderr: tail_lines(&output),
+        };
+        if let Err(e) = append(&id, outcome) {
+            warn!("Failed to record script history of {}: {}", id, e);
+        }
+    }
+}
+
+/// Print the history of a module, oldest first
+pub fn show(id: &str, json: bool) -> Result<()> {
+    let history = load(id);
+    if json {
+        return crate::output::print("module history", history);
+    }
+    if history.is_empty() {
+        println!("No script history for {id}");
+        return Ok(());
+    }
+
+    let mut last_boot = "";
+    for entry in &history {
+        if entry.boot_id != last_boot {
+            last_boot = &entry.boot_id;
+            println!("boot {}", entry.boot_id.get(..8).unwrap_or(&entry.boot_id));
+        }
+        let result = match (entry.waited, entry.exit_code) {
+            (false, _) => "started".to_string(),
+            (true, Some(code)) => format!("exit {code}"),
+            (true, None) => "killed".to_string(),
+        };
+        println!(
+            "  {} {}.sh: {} ({} ms)",
+            entry.timestamp, entry.stage, result, entry.duration_ms
+        );
+        for line in &entry.stderr {
+            println!("    | {line}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn log_file_of_module_and_common_scripts() {
+        let module = Path::new(defs::MODULE_DIR).join("test/service.sh");
+        assert_eq!(
+            log_file(&module),
+            Some(Path::new(defs::MODULE_LOG_DIR).join("test/service.log"))
+        );
+        let common = Path::new(defs::ADB_DIR).join("post-fs-data.d/10-fix.sh");
+        assert_eq!(
+            log_file(&common),
+            Some(Path::new(defs::COMMON_SCRIPT_LOG_DIR).join("post-fs-data.d/10-fix.log"))
+        );
+        // deeper in a module dir or elsewhere in /data/adb
+        assert_eq!(log_file(&Path::new(defs::MODULE_DIR).join("test/bin/x.sh")), None);
+        assert_eq!(log_file(&Path::new(defs::ADB_DIR).join("ap/x.sh")), None);
+    }
+
+    #[test]
+    fn open_log_keeps_last_run() {
+        let dir = TempDir::new("script-log");
+        let path = dir.path().join("test/service.log");
+        writeln!(open_log(&path).unwrap(), "first").unwrap();
+        writeln!(open_log(&path).unwrap(), "second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert_eq!(fs::read_to_string(dir.path().join("test/service.log.old")).unwrap(), "first\n");
+    }
+
+    #[test]
+    fn result_lines() {
+        let elapsed = Duration::from_millis(300);
+        let exited = ExitStatus::from_raw(127 << 8);
+        assert_eq!(result_line(Some(&exited), elapsed), "exited with 127 after 0.3s");
+        let killed = ExitStatus::from_raw(libc::SIGKILL);
+        assert!(result_line(Some(&killed), elapsed).starts_with("killed"));
+        assert_eq!(result_line(None, elapsed), "failed to start after 0.3s");
+    }
+}
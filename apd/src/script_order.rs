@@ -0,0 +1,224 @@
+//! Order of the stage scripts of modules
+//!
+//! module.prop may list modules whose script of a stage must run before the
+//! own one with `after=<id>,<id>`, and those which must run after it with
+//! `before=`. Ids of modules without a script of the stage are ignored. The
+//! scripts run in dependency order, ties are broken by id. Modules caught in a
+//! cycle are run after all others in id order.
+//!
+//! apd does not wait for the scripts of the service stage. There the modules
+//! linked by these keys are run one after another by a process of their own,
+//! `apd module exec-chain`, unrelated modules are started at once as before.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct Node {
+    pub id: String,
+    pub after: Vec<String>,
+    pub before: Vec<String>,
+}
+
+fn split_ids(value: Option<&String>) -> Vec<String> {
+    value
+        .map(String::as_str)
+        .unwrap_or_default()
+        .split([',', ' '])
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl Node {
+    /// The node of module `id` with `after=` and `before=` of its module.prop
+    pub fn new(id: String, after: Option<&String>, before: Option<&String>) -> Self {
+        Node {
+            id,
+            after: split_ids(after),
+            before: split_ids(before),
+        }
+    }
+}
+
+pub struct Order {
+    /// every id once, in the order the scripts run
+    pub ids: Vec<String>,
+    /// ids of modules in a cycle, at the end of `ids`
+    pub cyclic: Vec<String>,
+}
+
+/// Edges `(first, then)` between the ids of `nodes`
+fn edges(nodes: &[Node]) -> BTreeSet<(&str, &str)> {
+    let ids: BTreeSet<&str> = nodes.iter().map(|node| node.id.as_str()).collect();
+    let mut edges = BTreeSet::new();
+    for node in nodes {
+        for after in &node.after {
+            if let Some(first) = ids.get(after.as_str())
+                && *first != node.id
+            {
+                edges.insert((*first, node.id.as_str()));
+            }
+        }
+        for before in &node.before {
+            if let Some(then) = ids.get(before.as_str())
+                && *then != node.id
+            {
+                edges.insert((node.id.as_str(), *then));
+            }
+        }
+    }
+    edges
+}
+
+pub fn resolve(nodes: &[Node]) -> Order {
+    let edges = edges(nodes);
+    let mut waiting_for: BTreeMap<&str, usize> =
+        nodes.iter().map(|node| (node.id.as_str(), 0)).collect();
+    for (_, then) in &edges {
+        *waiting_for.entry(*then).or_default() += 1;
+    }
+    let mut ready: BTreeSet<&str> = waiting_for
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(id, _)| *id)
+        .collect();
+    let mut ids = Vec::new();
+    while let Some(id) = ready.pop_first() {
+        waiting_for.remove(id);
+        ids.push(id.to_string());
+        for (_, then) in edges.iter().filter(|(first, _)| *first == id) {
+            if let Some(count) = waiting_for.get_mut(then) {
+                *count -= 1;
+                if *count == 0 {
+                    ready.insert(*then);
+                }
+            }
+        }
+    }
+    // what is left waits on a cycle
+    let cyclic: Vec<String> = waiting_for.keys().map(|id| id.to_string()).collect();
+    ids.extend(cyclic.iter().cloned());
+    Order { ids, cyclic }
+}
+
+/// `order` split into groups of modules linked by `after=` or `before=`,
+/// each in `order`, groups by their first module
+pub fn chains(nodes: &[Node], order: &[String]) -> Vec<Vec<String>> {
+    let mut group: BTreeMap<&str, usize> = order
+        .iter()
+        .enumerate()
+        .map(|(index, id)| (id.as_str(), index))
+        .collect();
+    // merge the groups of every edge until nothing changes, the graphs are tiny
+    let edges = edges(nodes);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (first, then) in &edges {
+            let (Some(&a), Some(&b)) = (group.get(first), group.get(then)) else {
+                continue;
+            };
+            if a != b {
+                let merged = a.min(b);
+                for value in group.values_mut().filter(|value| **value == a || **value == b) {
+                    *value = merged;
+                }
+                changed = true;
+            }
+        }
+    }
+    let mut chains: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for id in order {
+        if let Some(index) = group.get(id.as_str()) {
+            chains.entry(*index).or_default().push(id.clone());
+        }
+    }
+    chains.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str, after: &str, before: &str) -> Node {
+        Node::new(
+            id.to_string(),
+            Some(&after.to_string()),
+            Some(&before.to_string()),
+        )
+    }
+
+    fn ids(order: &Order) -> Vec<&str> {
+        order.ids.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn linear_chain_runs_in_dependency_order() {
+        let nodes = [node("a", "b", ""), node("b", "c", ""), node("c", "", "")];
+        let order = resolve(&nodes);
+        assert_eq!(ids(&order), ["c", "b", "a"]);
+        assert!(order.cyclic.is_empty());
+        assert_eq!(chains(&nodes, &order.ids), [["c", "b", "a"]]);
+    }
+
+    #[test]
+    fn before_is_the_inverse_of_after() {
+        let with_before = [node("a", "", ""), node("b", "", "a")];
+        let with_after = [node("a", "b", ""), node("b", "", "")];
+        assert_eq!(ids(&resolve(&with_before)), ["b", "a"]);
+        assert_eq!(ids(&resolve(&with_after)), ["b", "a"]);
+    }
+
+    #[test]
+    fn unrelated_modules_run_by_id() {
+        let nodes = [node("c", "", ""), node("a", "", ""), node("b", "", "")];
+        let order = resolve(&nodes);
+        assert_eq!(ids(&order), ["a", "b", "c"]);
+        assert_eq!(chains(&nodes, &order.ids), [["a"], ["b"], ["c"]]);
+    }
+
+    #[test]
+    fn unknown_and_own_ids_are_ignored() {
+        let nodes = [node("a", "missing, a", "gone"), node("b", "", "unknown")];
+        let order = resolve(&nodes);
+        assert_eq!(ids(&order), ["a", "b"]);
+        assert!(order.cyclic.is_empty());
+        assert_eq!(chains(&nodes, &order.ids), [["a"], ["b"]]);
+    }
+
+    #[test]
+    fn two_cycle_runs_last_and_the_rest_in_order() {
+        let nodes = [
+            node("x", "y", ""),
+            node("y", "x", ""),
+            node("a", "", "b"),
+            node("b", "", ""),
+            node("c", "", ""),
+        ];
+        let order = resolve(&nodes);
+        assert_eq!(ids(&order), ["a", "b", "c", "x", "y"]);
+        assert_eq!(order.cyclic, ["x", "y"]);
+        assert_eq!(
+            chains(&nodes, &order.ids),
+            [vec!["a", "b"], vec!["c"], vec!["x", "y"]]
+        );
+    }
+
+    #[test]
+    fn three_cycle_runs_last_and_the_rest_in_order() {
+        let nodes = [
+            node("p", "r", ""),
+            node("q", "p", ""),
+            node("r", "q", ""),
+            node("a", "b", ""),
+            node("b", "", ""),
+        ];
+        let order = resolve(&nodes);
+        assert_eq!(ids(&order), ["b", "a", "p", "q", "r"]);
+        assert_eq!(order.cyclic, ["p", "q", "r"]);
+        assert_eq!(
+            chains(&nodes, &order.ids),
+            [vec!["b", "a"], vec!["p", "q", "r"]]
+        );
+    }
+}
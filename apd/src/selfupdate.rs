@@ -0,0 +1,126 @@
+//! `apd self-update <binary>`: replace the installed apd without a reboot
+//!
+//! The new binary is checked to be an ELF executable for the machine the
+//! running apd was built for, and to match `--sha256` if given. It is written
+//! next to [`defs::DAEMON_PATH`], labeled and renamed over it, so the path
+//! always holds either the old or the new binary. Nothing is replaced when a
+//! check fails.
+//!
+//! Stages already running keep the old binary they were started from. The uid
+//! listener runs for the whole boot, its supervisor is sent SIGUSR1 and
+//! executes the new binary in its place, keeping pid and superkey.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::info;
+
+use crate::{defs, event, restorecon, utils};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+/// Class, byte order and machine of an ELF header
+#[derive(PartialEq, Eq, Debug)]
+struct Target {
+    class: u8,
+    data: u8,
+    machine: u16,
+}
+
+/// The target of the executable `header` is for
+fn elf_target(header: &[u8]) -> Result<Target> {
+    ensure!(header.len() >= 20 && header.starts_with(ELF_MAGIC), "not an ELF file");
+    let data = header[5];
+    let half = |at: usize| {
+        let bytes = [header[at], header[at + 1]];
+        if data == 2 {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    };
+    let kind = half(16);
+    ensure!(kind == ET_EXEC || kind == ET_DYN, "not an executable, ELF type {kind}");
+    Ok(Target {
+        class: header[4],
+        data,
+        machine: half(18),
+    })
+}
+
+fn check_binary(binary: &[u8]) -> Result<()> {
+    let target = elf_target(binary)?;
+    let own = fs::read("/proc/self/exe").context("Failed to read /proc/self/exe")?;
+    let own = elf_target(&own).context("Failed to parse the running apd")?;
+    ensure!(
+        target == own,
+        "built for another machine ({target:?}), this device needs {own:?}"
+    );
+    Ok(())
+}
+
+/// Write `binary` to `tmp` as it is installed, verified against `sha256`
+fn write_tmp(tmp: &Path, binary: &[u8], sha256: Option<&str>) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o755)
+        .open(tmp)
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(binary)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    if let Some(expected) = sha256 {
+        // hashes what landed on disk, not the buffer
+        let actual = utils::hash_file(tmp)?;
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            bail!("sha256 mismatch, expected {expected}, got {actual}");
+        }
+    }
+    restorecon::lsetfilecon(tmp, restorecon::ADB_CON)
+}
+
+/// `apd self-update <binary> [--sha256 <hex>]`
+pub fn self_update(binary: &Path, sha256: Option<&str>) -> Result<()> {
+    let content = fs::read(binary).with_context(|| format!("Failed to read {}", binary.display()))?;
+    check_binary(&content).with_context(|| format!("{} cannot replace apd", binary.display()))?;
+
+    let tmp = PathBuf::from(format!("{}.new", defs::DAEMON_PATH));
+    if let Err(e) = write_tmp(&tmp, &content, sha256) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&tmp, defs::DAEMON_PATH) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e).with_context(|| {
+            format!("Failed to rename {} to {}", tmp.display(), defs::DAEMON_PATH)
+        });
+    }
+    if let Some(dir) = Path::new(defs::DAEMON_PATH).parent() {
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("Failed to sync {}", dir.display()))?;
+    }
+    info!("installed {} as {}", binary.display(), defs::DAEMON_PATH);
+    println!("- apd replaced by {}", binary.display());
+
+    match event::uid_listener_pid() {
+        Some(pid) => {
+            if unsafe { libc::kill(pid as i32, libc::SIGUSR1) } != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("Failed to signal the uid listener {pid}"));
+            }
+            println!("- uid listener {pid} is restarting with the new binary");
+        }
+        None => println!("- uid listener not running, the new binary is used from the next start"),
+    }
+    Ok(())
+}
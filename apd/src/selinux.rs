@@ -0,0 +1,85 @@
+//! `apd selinux`: inspect and fix file labels
+//!
+//! Busybox `chcon` differs between devices, so module developers get the
+//! labeling apd itself uses. Paths outside `/data/adb` and `/dev` are refused
+//! unless `--allow-system` is given.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail, ensure};
+use jwalk::WalkDir;
+
+use crate::restorecon::{self, lgetfilecon, lsetfilecon};
+
+const ALLOWED_ROOTS: &[&str] = &["/data/adb", "/dev"];
+
+fn checked_path(path: &Path, allow_system: bool) -> Result<PathBuf> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", path.display()))?;
+    ensure!(
+        allow_system || ALLOWED_ROOTS.iter().any(|root| path.starts_with(root)),
+        "{} is outside {}, pass --allow-system to change it anyway",
+        path.display(),
+        ALLOWED_ROOTS.join(" and ")
+    );
+    Ok(path)
+}
+
+pub fn get(path: &Path) -> Result<()> {
+    println!("{}", lgetfilecon(path)?);
+    Ok(())
+}
+
+pub fn set(
+    path: &Path,
+    context: &str,
+    recursive: bool,
+    verbose: bool,
+    allow_system: bool,
+) -> Result<()> {
+    let path = checked_path(path, allow_system)?;
+    if !recursive {
+        return lsetfilecon(&path, context);
+    }
+
+    let mut failed = 0;
+    for entry in WalkDir::new(&path) {
+        let (entry, result) = match entry {
+            Ok(entry) => {
+                let entry = entry.path();
+                let result = lsetfilecon(&entry, context);
+                (entry, result)
+            }
+            Err(e) => (
+                e.path().map_or_else(|| path.clone(), Path::to_path_buf),
+                Err(e.into()),
+            ),
+        };
+        match result {
+            Ok(()) if verbose => println!("{}: ok", entry.display()),
+            Ok(()) => {}
+            Err(e) => {
+                failed += 1;
+                println!("{}: {:#}", entry.display(), e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("Failed to label {failed} file(s)");
+    }
+    Ok(())
+}
+
+/// Label everything below `path` as system files where the label differs
+pub fn restore(path: &Path, verbose: bool, allow_system: bool) -> Result<()> {
+    let path = checked_path(path, allow_system)?;
+    let summary = restorecon::restore_syscon(&path);
+    if verbose {
+        for (path, e) in &summary.failed {
+            println!("{}: {}", path.display(), e);
+        }
+    }
+    println!("{summary}");
+    summary.check()
+}
@@ -702,3 +702,34 @@ pub fn check_rule(policy: &str) -> Result<()> {
     parse_sepolicy(policy.trim(), true)?;
     Ok(())
 }
+
+const LIVE_PATCH_PROBE: &str = "u:r:magisk:s0";
+const DEFAULT_REQUIRES_PATCH: &[&str] = &["metamodule", "scripts"];
+
+/// Whether our live patch is in effect, the kernel only accepts contexts of loaded types
+pub fn is_live_patched() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/sys/fs/selinux/context")
+        .and_then(|mut file| std::io::Write::write_all(&mut file, LIVE_PATCH_PROBE.as_bytes()))
+        .is_ok()
+}
+
+/// Operations which need the live patch, configurable in `/data/adb/ap/sepolicy_required`
+pub fn patch_required_ops() -> Vec<String> {
+    match std::fs::read_to_string(crate::defs::SEPOLICY_REQUIRED_FILE) {
+        Ok(content) => content.split_whitespace().map(str::to_string).collect(),
+        Err(_) => DEFAULT_REQUIRES_PATCH.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+/// Whether `op` has to be skipped because the live patch failed this boot
+pub fn gated(op: &str) -> bool {
+    let gated = Path::new(crate::defs::SEPOLICY_FAILED_FILE).exists()
+        && patch_required_ops().iter().any(|o| o == op);
+    if gated {
+        log::info!("skip {op}, sepolicy patch failed");
+    }
+    gated
+}
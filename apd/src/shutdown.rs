@@ -0,0 +1,82 @@
+//! Stopping what apd left running
+//!
+//! The boot log collectors and the uid listener outlive the apd process which
+//! started them. They are tracked in `/data/adb/ap/.children`, one
+//! `<pid> <comm>` line each, so a SIGTERM of the uid listener or `apd shutdown`,
+//! e.g. from the installer before it replaces the binary, can stop them again.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::{defs, umount::MountRegistry};
+
+fn comm(pid: u32) -> Option<String> {
+    fs::read_to_string(format!("/proc/{pid}/comm"))
+        .ok()
+        .map(|comm| comm.trim().to_string())
+}
+
+/// Forget the processes of the previous boot
+pub fn reset() {
+    let _ = fs::remove_file(defs::CHILDREN_FILE);
+}
+
+/// Track a process, it should lead its own process group
+pub fn track(pid: u32) {
+    let Some(comm) = comm(pid) else {
+        return;
+    };
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(defs::CHILDREN_FILE)
+        .and_then(|mut file| writeln!(file, "{pid} {comm}"));
+    if let Err(e) = result {
+        warn!("Failed to track process {pid}: {}", e);
+    }
+}
+
+/// Send SIGTERM to the tracked process groups but our own. A pid is skipped if
+/// its comm changed, the process is gone and the pid was reused then.
+pub fn stop_children() {
+    let content = fs::read_to_string(defs::CHILDREN_FILE).unwrap_or_default();
+    for line in content.lines() {
+        let Some((pid, tracked)) = line.split_once(' ') else {
+            continue;
+        };
+        let Some(pid) = pid.parse::<u32>().ok() else {
+            continue;
+        };
+        if pid == std::process::id() || comm(pid).as_deref() != Some(tracked) {
+            continue;
+        }
+        let pid = pid as libc::pid_t;
+        // a process started by KernelPatch may not lead a group
+        if unsafe { libc::kill(-pid, libc::SIGTERM) } != 0 {
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+        }
+        info!("stopped {tracked} ({pid})");
+    }
+    reset();
+}
+
+/// `apd shutdown`: stop everything apd left running, optionally detach the
+/// module mounts as well
+pub fn run(unmount: bool) -> Result<()> {
+    stop_children();
+    if !unmount {
+        return Ok(());
+    }
+    for point in MountRegistry::load().mounts() {
+        match crate::mount::detach(&point) {
+            Ok(()) => println!("detached {}", point.display()),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,613 @@
+//! Boot health and status reporting
+//!
+//! `/data/adb/ap/health` is a small key=value file meant to be pulled over adb
+//! by fleet monitoring. Its format is stable: keys are only ever added, never
+//! renamed or removed, and unknown keys must be ignored by readers.
+//!
+//! ```text
+//! boot_count=<n>            increased by one on every post-fs-data
+//! boot_ok=<0|1>             1 once boot-completed was reached
+//! safe_mode=<0|1>           safe mode was active during this boot
+//! mount_fallbacks=<n>       times another mount strategy was tried after one failed
+//! modules_failed=<n>        module scripts of any stage that failed this boot
+//! apd_version=<code>        apd version code
+//! kernelpatch_version=<v>   value of KERNELPATCH_VERSION, empty if unknown
+//! timestamp=<secs>          unix time of the last update
+//! sepolicy_patch_failed=<0|1> the live sepolicy patch did not land this boot
+//! disabled_features=<list>  comma separated, lost to unusable binaries this boot
+//! mount_failed=<0|1>        no mount strategy succeeded this boot
+//! ```
+//!
+//! The record of the previous boot is kept as `health.prev`.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{api, defs, messages::Message, output::StatusOutput};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Health {
+    pub boot_count: u64,
+    pub boot_ok: bool,
+    pub safe_mode: bool,
+    pub mount_fallbacks: u32,
+    pub modules_failed: u32,
+    pub apd_version: String,
+    pub kernelpatch_version: String,
+    pub timestamp: u64,
+    pub sepolicy_patch_failed: bool,
+    pub disabled_features: String,
+    pub mount_failed: bool,
+}
+
+fn field<T: FromStr + Default>(map: &HashMap<&str, &str>, key: &str) -> T {
+    map.get(key).and_then(|v| v.parse().ok()).unwrap_or_default()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Health {
+    pub fn serialize(&self) -> String {
+        format!(
+            "boot_count={}\nboot_ok={}\nsafe_mode={}\nmount_fallbacks={}\nmodules_failed={}\napd_version={}\nkernelpatch_version={}\ntimestamp={}\nsepolicy_patch_failed={}\ndisabled_features={}\nmount_failed={}\n",
+            self.boot_count,
+            u8::from(self.boot_ok),
+            u8::from(self.safe_mode),
+            self.mount_fallbacks,
+            self.modules_failed,
+            self.apd_version,
+            self.kernelpatch_version,
+            self.timestamp,
+            u8::from(self.sepolicy_patch_failed),
+            self.disabled_features,
+            u8::from(self.mount_failed),
+        )
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let map: HashMap<&str, &str> = content
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(k, v)| (k.trim(), v.trim()))
+            .collect();
+        let flag = |key: &str| map.get(key).is_some_and(|v| *v == "1");
+        Health {
+            boot_count: field(&map, "boot_count"),
+            boot_ok: flag("boot_ok"),
+            safe_mode: flag("safe_mode"),
+            mount_fallbacks: field(&map, "mount_fallbacks"),
+            modules_failed: field(&map, "modules_failed"),
+            apd_version: field(&map, "apd_version"),
+            kernelpatch_version: field(&map, "kernelpatch_version"),
+            timestamp: field(&map, "timestamp"),
+            sepolicy_patch_failed: flag("sepolicy_patch_failed"),
+            disabled_features: field(&map, "disabled_features"),
+            mount_failed: flag("mount_failed"),
+        }
+    }
+
+    pub fn load() -> Option<Self> {
+        fs::read_to_string(defs::HEALTH_FILE)
+            .ok()
+            .map(|content| Self::parse(&content))
+    }
+
+    /// Write the record atomically so a pull never sees a truncated file
+    pub fn store(&mut self) -> Result<()> {
+        self.timestamp = now();
+        let tmp = format!("{}.tmp", defs::HEALTH_FILE);
+        fs::write(&tmp, self.serialize()).with_context(|| format!("Failed to write {tmp}"))?;
+        fs::rename(&tmp, defs::HEALTH_FILE)
+            .with_context(|| format!("Failed to rename {tmp} to {}", defs::HEALTH_FILE))?;
+        Ok(())
+    }
+}
+
+/// Start the record of a new boot, the previous one is kept as `health.prev`
+pub fn begin_boot(safe_mode: bool) -> Health {
+    let previous = Health::load();
+    if previous.is_some()
+        && let Err(e) = fs::rename(defs::HEALTH_FILE, defs::HEALTH_PREV_FILE)
+    {
+        warn!("Failed to keep previous health record: {}", e);
+    }
+    Health {
+        boot_count: previous.map_or(0, |h| h.boot_count) + 1,
+        boot_ok: false,
+        safe_mode,
+        apd_version: defs::VERSION_CODE.to_string(),
+        kernelpatch_version: std::env::var("KERNELPATCH_VERSION").unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+/// The record of the current boot for a resumed stage. Between begin_boot and
+/// the first store there is no `health`, it is rebuilt from `health.prev` then.
+pub fn resume_boot(safe_mode: bool) -> Health {
+    if let Some(health) = Health::load() {
+        return health;
+    }
+    let previous = fs::read_to_string(defs::HEALTH_PREV_FILE)
+        .ok()
+        .map(|content| Health::parse(&content));
+    Health {
+        boot_count: previous.map_or(0, |h| h.boot_count) + 1,
+        boot_ok: false,
+        safe_mode,
+        apd_version: defs::VERSION_CODE.to_string(),
+        kernelpatch_version: std::env::var("KERNELPATCH_VERSION").unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+/// Mark the current boot as healthy once boot-completed is reached
+pub fn finish_boot() -> Result<()> {
+    let mut health = Health::load().unwrap_or_else(|| begin_boot(false));
+    health.boot_ok = true;
+    health.store()?;
+    info!("boot health recorded: boot #{}", health.boot_count);
+    Ok(())
+}
+
+/// Count module scripts of a stage after post-fs-data which failed
+pub fn add_failed_scripts(failed: usize) -> Result<()> {
+    if failed == 0 {
+        return Ok(());
+    }
+    let mut health = Health::load().unwrap_or_else(|| begin_boot(false));
+    health.modules_failed += failed as u32;
+    health.store()
+}
+
+/// What the user should know about the state of root after the last boot
+fn findings(health: &Health) -> Vec<Message> {
+    let mut findings = Vec::new();
+    if let Some(handshake) = crate::supercall::Handshake::current()
+        && !handshake.compatible
+    {
+        findings.push(Message::KernelPatchTooOld {
+            version: handshake.kernelpatch_version,
+            minimum: handshake.minimum,
+        });
+    }
+    if health.sepolicy_patch_failed {
+        findings.push(Message::SepolicyPatchDegraded {});
+    }
+    if !health.disabled_features.is_empty() {
+        findings.push(Message::FeaturesDisabled {
+            features: health.disabled_features.clone(),
+        });
+    }
+    if crate::data_watch::is_stale() {
+        findings.push(Message::MountsStale {});
+    }
+    if let Some(sample) = crate::profile::slow_sample() {
+        findings.push(Message::SlowBoot {
+            kind: sample.kind,
+            name: sample.name,
+            seconds: sample.wall_ms / 1000,
+        });
+    }
+    findings
+}
+
+pub fn print_status(health_only: bool, superkey: &Option<String>) -> Result<()> {
+    if crate::output::json() {
+        if health_only {
+            return crate::output::print("status --health", Health::load());
+        }
+        let status = api::get_status();
+        let output = StatusOutput {
+            version_name: status.version_name,
+            version_code: status.version_code,
+            mount_mode: status.mount_mode,
+            su_namespace: crate::namespace::default_mode().to_string(),
+            modules: status.modules,
+            safe_mode: crate::utils::is_safe_mode(superkey.clone()),
+            superkey_valid: superkey
+                .is_some()
+                .then(|| crate::supercall::validate_superkey(superkey)),
+            kernel_version: rustix::system::uname()
+                .release()
+                .to_string_lossy()
+                .into_owned(),
+            kernelpatch_version: std::env::var("KERNELPATCH_VERSION").unwrap_or_default(),
+            kernel_handshake: crate::supercall::Handshake::current(),
+            uid_listener: crate::event::uid_listener_pid(),
+            findings: findings(&status.health.clone().unwrap_or_default()),
+            health: status.health,
+        };
+        return crate::output::print("status", output);
+    }
+
+    let health = Health::load().unwrap_or_default();
+    if health_only {
+        print!("{}", health.serialize());
+        return Ok(());
+    }
+
+    let status = api::get_status();
+    println!("apd: {} ({})", status.version_name, status.version_code);
+    println!("mount mode: {}", status.mount_mode);
+    println!("su namespace: {}", crate::namespace::default_mode());
+    println!("modules: {}", status.modules);
+    match crate::event::uid_listener_pid() {
+        Some(pid) => println!("uid listener: running ({pid})"),
+        None => println!("uid listener: not running"),
+    }
+    for finding in findings(&health) {
+        println!("{finding}");
+    }
+    if !Path::new(defs::HEALTH_FILE).exists() {
+        println!("last boot: unknown");
+        return Ok(());
+    }
+    println!(
+        "last boot: #{} {}",
+        health.boot_count,
+        if health.boot_ok { "ok" } else { "incomplete" }
+    );
+    println!("safe mode: {}", health.safe_mode);
+    println!("mount fallbacks: {}", health.mount_fallbacks);
+    if health.mount_failed {
+        println!("module mount: FAILED");
+    }
+    println!("modules failed: {}", health.modules_failed);
+    Ok(())
+}
+
+/// `apd status --su-namespace <uid>`
+pub fn print_su_namespace(uid: u32) -> Result<()> {
+    let mode = crate::namespace::mode_for_uid(uid);
+    if crate::output::json() {
+        return crate::output::print(
+            "status --su-namespace",
+            serde_json::json!({ "uid": uid, "mode": mode.to_string() }),
+        );
+    }
+    println!("{mode}");
+    Ok(())
+}
+
+/// `apd status --binaries`
+pub fn print_binaries() -> Result<()> {
+    let binaries = crate::assets::binaries();
+    if crate::output::json() {
+        return crate::output::print("status --binaries", binaries);
+    }
+    for binary in &binaries {
+        match &binary.problem {
+            None => println!(
+                "{}: ok, {}",
+                binary.name,
+                binary.version.as_deref().unwrap_or("unknown version")
+            ),
+            Some(problem) => println!(
+                "{}: {problem}, disables {}",
+                binary.name,
+                binary.features.join(", ")
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct UidListenerStatus {
+    pid: Option<u32>,
+    /// `None` if the listener does not run or does not serve the socket
+    refreshes: Option<crate::event::RefreshStats>,
+}
+
+/// `apd status --uid-listener`
+pub fn print_uid_listener() -> Result<()> {
+    let pid = crate::event::uid_listener_pid();
+    let refreshes = match crate::daemon::query(&crate::daemon::Request::RefreshStats) {
+        Ok(stats) => stats,
+        Err(e) => {
+            warn!("{e:#}");
+            None
+        }
+    };
+    let status = UidListenerStatus { pid, refreshes };
+    if crate::output::json() {
+        return crate::output::print("status --uid-listener", status);
+    }
+    let Some(pid) = status.pid else {
+        println!("uid listener: not running");
+        return Ok(());
+    };
+    println!("uid listener: running ({pid})");
+    match status.refreshes {
+        Some(stats) => {
+            println!("package list refreshes: {}", stats.performed);
+            println!("skipped, packages.list unchanged: {}", stats.skipped);
+            match stats.last_duration_ms {
+                Some(ms) => println!("last refresh took: {ms} ms"),
+                None => println!("last refresh took: -"),
+            }
+        }
+        None => println!("refresh counters: unavailable, the daemon socket does not answer"),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MountAttempt {
+    pub strategy: String,
+    pub started: u64,
+    pub finished: u64,
+    /// `None` if the strategy succeeded
+    pub error: Option<String>,
+}
+
+/// How the modules were mounted, kept in `.last_mount_decision` so a fallback
+/// can still be explained once the boot log is gone
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MountDecision {
+    pub configured: String,
+    /// the strategy that succeeded, `none` if all failed
+    pub selected: String,
+    pub attempts: Vec<MountAttempt>,
+    pub started: u64,
+    pub finished: u64,
+    /// boot the decision was made in, empty in records of older versions
+    #[serde(default)]
+    pub boot_id: String,
+}
+
+impl MountDecision {
+    pub fn new(configured: &str) -> Self {
+        MountDecision {
+            configured: configured.to_string(),
+            selected: "none".to_string(),
+            started: now(),
+            boot_id: crate::script_history::boot_id(),
+            ..Default::default()
+        }
+    }
+
+    pub fn load() -> Result<Self> {
+        let content = fs::read_to_string(defs::MOUNT_DECISION_FILE)
+            .with_context(|| format!("Failed to read {}", defs::MOUNT_DECISION_FILE))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", defs::MOUNT_DECISION_FILE))
+    }
+
+    /// The decision of this boot, `None` until the modules are mounted
+    pub fn current() -> Option<Self> {
+        Self::load()
+            .ok()
+            .filter(|decision| decision.boot_id == crate::script_history::boot_id())
+    }
+
+    /// Run `strategy`, recording when it ran and how it failed
+    pub fn attempt<T>(&mut self, strategy: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let started = now();
+        let result = f();
+        self.attempts.push(MountAttempt {
+            strategy: strategy.to_string(),
+            started,
+            finished: now(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+        });
+        if result.is_ok() {
+            self.selected = strategy.to_string();
+        }
+        result
+    }
+
+    /// Strategies tried after the first one failed
+    pub fn fallbacks(&self) -> u32 {
+        self.attempts.len().saturating_sub(1) as u32
+    }
+
+    /// Whether a strategy succeeded, or mounting was disabled
+    pub fn succeeded(&self) -> bool {
+        self.selected != "none"
+    }
+
+    /// Written atomically, a crash mid-boot must not leave a truncated record
+    pub fn store(&mut self) -> Result<()> {
+        self.finished = now();
+        let tmp = format!("{}.tmp", defs::MOUNT_DECISION_FILE);
+        fs::write(&tmp, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("Failed to write {tmp}"))?;
+        fs::rename(&tmp, defs::MOUNT_DECISION_FILE)
+            .with_context(|| format!("Failed to rename {tmp} to {}", defs::MOUNT_DECISION_FILE))?;
+        Ok(())
+    }
+}
+
+/// Print how the modules were mounted the last time
+pub fn print_mount_decision() -> Result<()> {
+    if !Path::new(defs::MOUNT_DECISION_FILE).exists() {
+        println!("no mount decision recorded yet");
+        return Ok(());
+    }
+    let decision = MountDecision::load()?;
+    if crate::output::json() {
+        return crate::output::print("status --mount", decision);
+    }
+
+    println!("configured: {}", decision.configured);
+    println!("selected: {}", decision.selected);
+    println!(
+        "took: {}s (at {})",
+        decision.finished.saturating_sub(decision.started),
+        decision.started
+    );
+    for attempt in &decision.attempts {
+        let took = attempt.finished.saturating_sub(attempt.started);
+        match &attempt.error {
+            None => println!("- {}: ok ({took}s)", attempt.strategy),
+            Some(error) => println!("- {}: failed ({took}s): {error}", attempt.strategy),
+        }
+    }
+    Ok(())
+}
+
+/// Print what the next mount would do, without mounting anything
+pub fn print_mount_plan(json: bool) -> Result<()> {
+    let report = api::get_mount_report()?;
+    if json {
+        return crate::output::print("mount plan", report);
+    }
+
+    println!("mount mode: {}", report.mode);
+    for target in &report.partitions {
+        println!("{}: {}", target.target, target.modules.join(" > "));
+    }
+    for module in &report.modules {
+        if let Some(reason) = &module.reason {
+            println!("skip {}: {}", module.id, reason);
+        }
+        for path in &module.blocked_critical {
+            println!("block {}: {} (critical, not acknowledged)", module.id, path);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ModuleMountStatus {
+    id: String,
+    enabled: bool,
+    /// mounted at boot-completed instead of post-fs-data
+    deferred: bool,
+    mounts: Vec<crate::mount::ModuleMount>,
+    /// mounts in the namespaces of target apps
+    targeted: Vec<crate::targeted::TargetedMount>,
+}
+
+#[derive(Serialize)]
+struct MountStatus {
+    mode: String,
+    modules: Vec<ModuleMountStatus>,
+}
+
+/// Print where the files of every module are actually mounted right now
+pub fn print_module_mount_status(json: bool) -> Result<()> {
+    let mut mounts = crate::mount::module_mounts()?;
+    let mut targeted = crate::targeted::mounts();
+    let mut modules = Vec::new();
+    for entry in fs::read_dir(defs::MODULE_DIR)?.flatten() {
+        let path = entry.path();
+        if !path.join("module.prop").exists() {
+            continue;
+        }
+        let id = entry.file_name().to_string_lossy().into_owned();
+        modules.push(ModuleMountStatus {
+            enabled: !crate::flags::is_set(path.join(defs::DISABLE_FILE_NAME)),
+            deferred: crate::flags::is_set(path.join(defs::DEFER_MOUNT_FILE_NAME)),
+            mounts: mounts.remove(&id).unwrap_or_default(),
+            targeted: targeted.remove(&id).unwrap_or_default(),
+            id,
+        });
+    }
+    modules.sort_by(|a, b| a.id.cmp(&b.id));
+    let status = MountStatus {
+        mode: crate::utils::get_mount_mode(),
+        modules,
+    };
+
+    if json {
+        return crate::output::print("module mount-status", status);
+    }
+    println!("mount mode: {}", status.mode);
+    for module in &status.modules {
+        let state = match (module.enabled, module.deferred) {
+            (false, _) => ", disabled",
+            (true, true) => ", deferred",
+            (true, false) => "",
+        };
+        if module.mounts.is_empty() && module.targeted.is_empty() {
+            println!("{}: not mounted{}", module.id, state);
+            continue;
+        }
+        println!("{}: {} mount(s){}", module.id, module.mounts.len(), state);
+        for mount in &module.mounts {
+            let kind = if mount.overlay { "overlay" } else { "bind" };
+            println!("  {} {}", kind, mount.target.display());
+        }
+        for mount in &module.targeted {
+            println!(
+                "  in {} ({}, {}): {} mount(s)",
+                mount.package,
+                mount.pid,
+                mount.mnt_ns,
+                mount.targets.len()
+            );
+            if let Some(error) = &mount.error {
+                println!("    failed: {error}");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_survives_a_round_trip() {
+        let health = Health {
+            boot_count: 7,
+            boot_ok: true,
+            safe_mode: false,
+            mount_fallbacks: 1,
+            modules_failed: 3,
+            apd_version: "11039".to_string(),
+            kernelpatch_version: "0.11.2".to_string(),
+            timestamp: 1_760_000_000,
+            sepolicy_patch_failed: true,
+            disabled_features: "resetprop,busybox".to_string(),
+            mount_failed: true,
+        };
+        assert_eq!(Health::parse(&health.serialize()), health);
+    }
+
+    #[test]
+    fn unknown_and_missing_keys_are_ignored() {
+        let health = Health::parse("boot_count=2\nfuture_key=x\nboot_ok=1\n");
+        assert_eq!(health.boot_count, 2);
+        assert!(health.boot_ok);
+        assert!(!health.mount_failed);
+        assert_eq!(health.modules_failed, 0);
+    }
+
+    #[test]
+    fn only_tried_strategies_count_as_fallbacks() {
+        let mut decision = MountDecision::new(defs::MOUNT_MODE_METAMODULE);
+        let _ = decision.attempt(defs::MOUNT_MODE_METAMODULE, || -> Result<()> {
+            anyhow::bail!("broken")
+        });
+        assert_eq!(decision.fallbacks(), 0);
+        assert!(!decision.succeeded());
+        let _ = decision.attempt(defs::MOUNT_MODE_MAGIC, || Ok(()));
+        assert_eq!(decision.fallbacks(), 1);
+        assert!(decision.succeeded());
+
+        let mut decision = MountDecision::new(defs::MOUNT_MODE_MAGIC);
+        let _ = decision.attempt(defs::MOUNT_MODE_MAGIC, || -> Result<()> {
+            anyhow::bail!("broken")
+        });
+        assert_eq!(decision.fallbacks(), 0);
+        assert!(!decision.succeeded());
+    }
+}
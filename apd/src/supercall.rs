@@ -10,8 +10,11 @@ use std::{
 
 use libc::{EINVAL, c_long, c_void, syscall, uid_t};
 use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 
-use crate::package::{read_ap_package_config, synchronize_package_uid};
+use crate::defs;
+use crate::messages::Message;
+use crate::package::{self, read_ap_package_config, synchronize_package_uid};
 use crate::utils::switch_cgroups;
 
 const MAJOR: c_long = 0;
@@ -21,6 +24,9 @@ const PATCH: c_long = 1;
 const KSTORAGE_EXCLUDE_LIST_GROUP: i32 = 1;
 
 const __NR_SUPERCALL: c_long = 45;
+const SUPERCALL_HELLO: c_long = 0x1000;
+const SUPERCALL_KERNELPATCH_VER: c_long = 0x1008;
+const SUPERCALL_SKEY_SET: c_long = 0x100b;
 const SUPERCALL_SU: c_long = 0x1010;
 const SUPERCALL_KSTORAGE_WRITE: c_long = 0x1041;
 const SUPERCALL_SU_GRANT_UID: c_long = 0x1100;
@@ -30,8 +36,38 @@ const SUPERCALL_SU_LIST: c_long = 0x1103;
 const SUPERCALL_SU_RESET_PATH: c_long = 0x1111;
 const SUPERCALL_SU_GET_SAFEMODE: c_long = 0x1112;
 
+const SUPERCALL_HELLO_MAGIC: c_long = 0x11581158;
+
+const fn kp_version(major: u32, minor: u32, patch: u32) -> u32 {
+    (major << 16) | (minor << 8) | patch
+}
+
+/// Oldest KernelPatch apd is known to work with, older kernels get no root
+/// plumbing but still mount modules
+pub const MIN_KERNELPATCH_VERSION: u32 = kp_version(0, 10, 7);
+
+/// The supercalls apd issues and the KernelPatch version that added them
+const SUPERCALLS: &[(c_long, &str, u32)] = &[
+    (SUPERCALL_HELLO, "hello", kp_version(0, 10, 0)),
+    (SUPERCALL_KERNELPATCH_VER, "kernelpatch_ver", kp_version(0, 10, 0)),
+    (SUPERCALL_SKEY_SET, "skey_set", kp_version(0, 10, 0)),
+    (SUPERCALL_SU, "su", kp_version(0, 10, 0)),
+    (SUPERCALL_SU_GRANT_UID, "su_grant_uid", kp_version(0, 10, 0)),
+    (SUPERCALL_SU_REVOKE_UID, "su_revoke_uid", kp_version(0, 10, 0)),
+    (SUPERCALL_SU_NUMS, "su_nums", kp_version(0, 10, 0)),
+    (SUPERCALL_SU_LIST, "su_list", kp_version(0, 10, 0)),
+    (SUPERCALL_SU_RESET_PATH, "su_reset_path", kp_version(0, 10, 0)),
+    (SUPERCALL_SU_GET_SAFEMODE, "su_get_safemode", kp_version(0, 10, 4)),
+    (SUPERCALL_KSTORAGE_WRITE, "kstorage_write", kp_version(0, 10, 7)),
+];
+
 const SUPERCALL_SCONTEXT_LEN: usize = 0x60;
 
+const SHELL_UID: i32 = 2000;
+
+const ACTION_APP_UNINSTALLED: &str = "me.bmax.apatch.ACTION_APP_UNINSTALLED";
+const ACTION_GRANT_REVOKED: &str = "me.bmax.apatch.ACTION_GRANT_REVOKED";
+
 #[repr(C)]
 struct SuProfile {
     uid: i32,
@@ -44,6 +80,13 @@ fn ver_and_cmd(cmd: c_long) -> c_long {
     ((version_code as c_long) << 32) | (0x1158 << 16) | (cmd & 0xFFFF)
 }
 
+fn sc_hello(key: &CStr) -> c_long {
+    if key.to_bytes().is_empty() {
+        return (-EINVAL).into();
+    }
+    unsafe { syscall(__NR_SUPERCALL, key.as_ptr(), ver_and_cmd(SUPERCALL_HELLO)) as c_long }
+}
+
 fn sc_su_revoke_uid(key: &CStr, uid: uid_t) -> c_long {
     if key.to_bytes().is_empty() {
         return (-EINVAL).into();
@@ -158,6 +201,33 @@ fn sc_su_reset_path(key: &CStr, path: &CStr) -> c_long {
 
 
 
+fn sc_kp_ver(key: &CStr) -> c_long {
+    if key.to_bytes().is_empty() {
+        return (-EINVAL).into();
+    }
+    unsafe {
+        syscall(
+            __NR_SUPERCALL,
+            key.as_ptr(),
+            ver_and_cmd(SUPERCALL_KERNELPATCH_VER),
+        ) as c_long
+    }
+}
+
+fn sc_skey_set(key: &CStr, new_key: &CStr) -> c_long {
+    if key.to_bytes().is_empty() || new_key.to_bytes().is_empty() {
+        return (-EINVAL).into();
+    }
+    unsafe {
+        syscall(
+            __NR_SUPERCALL,
+            key.as_ptr(),
+            ver_and_cmd(SUPERCALL_SKEY_SET),
+            new_key.as_ptr(),
+        ) as c_long
+    }
+}
+
 fn sc_su_uid_nums(key: &CStr) -> c_long {
     if key.to_bytes().is_empty() {
         return (-EINVAL).into();
@@ -202,20 +272,21 @@ fn convert_superkey(s: &Option<String>) -> Option<CString> {
     s.as_ref().and_then(|s| CString::new(s.clone()).ok())
 }
 
-pub fn refresh_ap_package_list(skey: &CStr, mutex: &Arc<Mutex<()>>) {
+/// Returns whether the su list could be read and the refresh went through
+pub fn refresh_ap_package_list(skey: &CStr, mutex: &Arc<Mutex<()>>) -> bool {
     let _lock = mutex.lock().unwrap();
 
     let num = sc_su_uid_nums(skey);
     if num < 0 {
         error!("[refresh_su_list] Error getting number of UIDs: {}", num);
-        return;
+        return false;
     }
     let num = num as usize;
     let mut uids = vec![0 as uid_t; num];
     let n = sc_su_allow_uids(skey, &mut uids);
     if n < 0 {
         error!("[refresh_su_list] Error getting su list");
-        return;
+        return false;
     }
     for uid in &uids {
         if *uid == 0 || *uid == 2000 {
@@ -243,6 +314,15 @@ pub fn refresh_ap_package_list(skey: &CStr, mutex: &Arc<Mutex<()>>) {
         }
     };
 
+    let changes = package::get_package_changes();
+    let mut revoked = Vec::new();
+    if package::revoke_on_reinstall_enabled() && !changes.reinstalled.is_empty() {
+        match package::revoke_reinstalled(&changes.reinstalled) {
+            Ok(pkgs) => revoked = pkgs,
+            Err(e) => error!("Failed to revoke grants of reinstalled packages: {}", e),
+        }
+    }
+
     let package_configs = read_ap_package_config();
     for config in package_configs {
         if config.allow == 1 && config.exclude == 0 {
@@ -258,22 +338,27 @@ pub fn refresh_ap_package_list(skey: &CStr, mutex: &Arc<Mutex<()>>) {
         }
     }
     
-    let (new_packages, uninstalled_packages) = crate::package::get_package_changes();
-    
-    let receiver_target = read_receiver_target("/data/adb/ap/manager_pkg");
+    let receiver_target = read_receiver_target(defs::MANAGER_PKG_FILE);
     let manager_pkg = receiver_target.as_ref().and_then(|t| t.split('/').next());
     
-    new_packages.into_iter().for_each(|pkg| notify_app_change(&pkg, true, &receiver_target, manager_pkg));
+    changes.added.into_iter().for_each(|pkg| notify_app_change(&pkg, None, &receiver_target, manager_pkg));
     
     let mut all_removed = removed_packages;
-    all_removed.extend(uninstalled_packages);
+    all_removed.extend(changes.removed);
     all_removed.sort();
     all_removed.dedup();
     
-    all_removed.into_iter().for_each(|pkg| notify_app_change(&pkg, false, &receiver_target, manager_pkg));
+    all_removed.into_iter().for_each(|pkg| notify_app_change(&pkg, Some(ACTION_APP_UNINSTALLED), &receiver_target, manager_pkg));
+
+    for pkg in revoked {
+        let message = Message::GrantRevokedReinstall { pkg: pkg.clone() };
+        warn!("[refresh_ap_package_list] {} {}", message, message.to_json());
+        notify_app_change(&pkg, Some(ACTION_GRANT_REVOKED), &receiver_target, manager_pkg);
+    }
+    true
 }
 
-fn notify_app_change(pkg_name: &str, is_install: bool, receiver_target: &Option<String>, manager_pkg: Option<&str>) {
+fn notify_app_change(pkg_name: &str, action: Option<&str>, receiver_target: &Option<String>, manager_pkg: Option<&str>) {
     let receiver = match receiver_target {
         Some(target) => target,
         None => return,
@@ -285,7 +370,7 @@ fn notify_app_change(pkg_name: &str, is_install: bool, receiver_target: &Option<
         }
     }
 
-    send_broadcast(receiver, pkg_name, is_install);
+    send_broadcast(receiver, pkg_name, action);
 }
 
 fn read_receiver_target(path: &str) -> Option<String> {
@@ -306,7 +391,7 @@ fn read_receiver_target(path: &str) -> Option<String> {
     })
 }
 
-fn send_broadcast(receiver: &str, pkg_name: &str, is_install: bool) {
+fn send_broadcast(receiver: &str, pkg_name: &str, action: Option<&str>) {
     let mut args = vec![
         "broadcast",
         "--user", "0",
@@ -314,8 +399,8 @@ fn send_broadcast(receiver: &str, pkg_name: &str, is_install: bool) {
         "-n", receiver,
         "--es", "pkg", pkg_name,
     ];
-    if !is_install {
-        args.extend_from_slice(&["-a", "me.bmax.apatch.ACTION_APP_UNINSTALLED"]);
+    if let Some(action) = action {
+        args.extend_from_slice(&["-a", action]);
     }
 
     unsafe {
@@ -346,6 +431,134 @@ pub fn privilege_apd_profile(superkey: &Option<String>) {
     }
 }
 
+/// Check that the kernel accepts the superkey with a harmless hello supercall
+pub fn validate_superkey(superkey: &Option<String>) -> bool {
+    let Some(key) = convert_superkey(superkey) else {
+        warn!("[validate_superkey] Superkey is None");
+        return false;
+    };
+    let rc = sc_hello(&key);
+    if rc != SUPERCALL_HELLO_MAGIC {
+        warn!("[validate_superkey] hello supercall returned {}", rc);
+        return false;
+    }
+    true
+}
+
+/// KernelPatch version as `major.minor.patch`, `None` if the superkey is refused
+pub fn kernelpatch_version(superkey: &Option<String>) -> Option<String> {
+    let key = convert_superkey(superkey)?;
+    let version = sc_kp_ver(&key);
+    (version > 0).then(|| format_version(version as u32))
+}
+
+fn format_version(version: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        (version >> 16) & 0xff,
+        (version >> 8) & 0xff,
+        version & 0xff
+    )
+}
+
+/// Names of the supercalls KernelPatch `version` does not have yet
+pub fn missing_supercalls(version: u32) -> Vec<&'static str> {
+    SUPERCALLS
+        .iter()
+        .filter(|(_, _, since)| version < *since)
+        .map(|(_, name, _)| *name)
+        .collect()
+}
+
+/// Outcome of the version handshake at the start of post-fs-data, kept in
+/// `.kernel_handshake` so `apd status` can tell why root is missing
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Handshake {
+    /// reported KernelPatch version, empty if the kernel did not answer
+    pub kernelpatch_version: String,
+    pub minimum: String,
+    /// false only if the kernel answered with a version older than `minimum`,
+    /// a refused superkey is reported on its own
+    pub compatible: bool,
+    pub missing: Vec<String>,
+    pub boot_id: String,
+}
+
+impl Handshake {
+    pub fn load() -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(defs::KERNEL_HANDSHAKE_FILE)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// The handshake of this boot, `None` before post-fs-data
+    pub fn current() -> Option<Self> {
+        Self::load()
+            .ok()
+            .filter(|handshake| handshake.boot_id == crate::script_history::boot_id())
+    }
+
+    pub fn store(&self) -> anyhow::Result<()> {
+        let tmp = format!("{}.tmp", defs::KERNEL_HANDSHAKE_FILE);
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)? + "\n")?;
+        std::fs::rename(&tmp, defs::KERNEL_HANDSHAKE_FILE)?;
+        Ok(())
+    }
+}
+
+/// Ask the kernel for its KernelPatch version and compare it to the minimum
+/// apd supports
+pub fn handshake(superkey: &Option<String>) -> Handshake {
+    let version = convert_superkey(superkey)
+        .map(|key| sc_kp_ver(&key))
+        .and_then(|version| u32::try_from(version).ok())
+        .filter(|version| *version > 0);
+    handshake_of(version)
+}
+
+/// The handshake with a kernel reporting `version`, `None` if it did not answer
+fn handshake_of(version: Option<u32>) -> Handshake {
+    let mut handshake = Handshake {
+        kernelpatch_version: version.map(format_version).unwrap_or_default(),
+        minimum: format_version(MIN_KERNELPATCH_VERSION),
+        compatible: true,
+        missing: Vec::new(),
+        boot_id: crate::script_history::boot_id(),
+    };
+    if let Some(version) = version {
+        handshake.compatible = version >= MIN_KERNELPATCH_VERSION;
+        handshake.missing = missing_supercalls(version)
+            .into_iter()
+            .map(String::from)
+            .collect();
+    }
+    handshake
+}
+
+/// Replace the superkey held by the kernel, returns the supercall error code
+pub fn set_superkey(superkey: &Option<String>, new_key: &str) -> Result<(), c_long> {
+    let key = convert_superkey(superkey).ok_or(c_long::from(-EINVAL))?;
+    let new_key = CString::new(new_key).map_err(|_| c_long::from(-EINVAL))?;
+    match sc_skey_set(&key, &new_key) {
+        0 => Ok(()),
+        rc => Err(rc),
+    }
+}
+
+/// Grant root to the adb shell uid so su keeps working when modules are disabled
+pub fn grant_shell_su(superkey: &Option<String>) {
+    let Some(key) = convert_superkey(superkey) else {
+        warn!("Superkey is None, skipping shell grant");
+        return;
+    };
+    let profile = SuProfile {
+        uid: SHELL_UID,
+        to_uid: 0,
+        scontext: convert_string_to_u8_array("u:r:magisk:s0"),
+    };
+    let rc = sc_su_grant_uid(&key, &profile);
+    info!("[grant_shell_su] result = {}", rc);
+}
+
 pub fn init_load_package_uid_config(superkey: &Option<String>) {
     let package_configs = read_ap_package_config();
     let key = convert_superkey(superkey);
@@ -412,3 +625,52 @@ pub fn init_load_su_path(superkey: &Option<String>) {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_shown_as_major_minor_patch() {
+        assert_eq!(format_version(kp_version(0, 10, 7)), "0.10.7");
+        assert_eq!(format_version(kp_version(1, 2, 255)), "1.2.255");
+    }
+
+    #[test]
+    fn the_supercall_table_fits_the_minimum() {
+        for (i, (call, name, since)) in SUPERCALLS.iter().enumerate() {
+            assert!(*since <= MIN_KERNELPATCH_VERSION, "{name} is newer than the minimum");
+            assert!(
+                SUPERCALLS[i + 1..].iter().all(|(c, n, _)| c != call && n != name),
+                "{name} is listed twice"
+            );
+        }
+        assert!(missing_supercalls(MIN_KERNELPATCH_VERSION).is_empty());
+    }
+
+    #[test]
+    fn older_kernels_miss_the_newer_supercalls() {
+        assert_eq!(missing_supercalls(kp_version(0, 10, 4)), ["kstorage_write"]);
+        assert_eq!(
+            missing_supercalls(kp_version(0, 10, 3)),
+            ["su_get_safemode", "kstorage_write"]
+        );
+        assert_eq!(missing_supercalls(kp_version(0, 9, 0)).len(), SUPERCALLS.len());
+    }
+
+    #[test]
+    fn only_an_answered_old_version_is_incompatible() {
+        let silent = handshake_of(None);
+        assert!(silent.compatible);
+        assert!(silent.kernelpatch_version.is_empty() && silent.missing.is_empty());
+
+        let old = handshake_of(Some(kp_version(0, 10, 4)));
+        assert!(!old.compatible);
+        assert_eq!(old.kernelpatch_version, "0.10.4");
+        assert_eq!(old.minimum, "0.10.7");
+        assert_eq!(old.missing, ["kstorage_write"]);
+
+        let current = handshake_of(Some(kp_version(0, 11, 0)));
+        assert!(current.compatible && current.missing.is_empty());
+    }
+}
@@ -0,0 +1,82 @@
+//! `apd superkey verify|rotate`
+//!
+//! Keys are never taken from argv, where `/proc/<pid>/cmdline` shows them. The
+//! current key comes from `--superkey-fd`, `APD_SUPERKEY` or the first line of
+//! stdin, the new key of a rotation from `APD_NEW_SUPERKEY` or the next line.
+
+use std::io::{self, BufRead};
+
+use anyhow::{Context, Result, bail, ensure};
+use serde::Serialize;
+
+use crate::supercall;
+
+const CURRENT_KEY_ENV: &str = "APD_SUPERKEY";
+const NEW_KEY_ENV: &str = "APD_NEW_SUPERKEY";
+
+fn read_key(given: Option<String>, env: &str, what: &str) -> Result<String> {
+    if let Some(key) = given.filter(|key| !key.is_empty()) {
+        return Ok(key);
+    }
+    if let Ok(key) = std::env::var(env)
+        && !key.is_empty()
+    {
+        return Ok(key);
+    }
+    let mut line = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .with_context(|| format!("Failed to read the {what} from stdin"))?;
+    let key = line.trim_end_matches(['\n', '\r']).to_string();
+    ensure!(!key.is_empty(), "no {what} given, set {env} or pass it on stdin");
+    Ok(key)
+}
+
+#[derive(Serialize)]
+struct Verified {
+    accepted: bool,
+    kernelpatch_version: Option<String>,
+}
+
+/// Check the key with a harmless supercall and show the KernelPatch version
+pub fn verify(superkey: Option<String>) -> Result<()> {
+    let superkey = Some(read_key(superkey, CURRENT_KEY_ENV, "superkey")?);
+    let verified = Verified {
+        accepted: supercall::validate_superkey(&superkey),
+        kernelpatch_version: supercall::kernelpatch_version(&superkey),
+    };
+    if crate::output::json() {
+        crate::output::print("superkey verify", &verified)?;
+    } else if verified.accepted {
+        println!(
+            "superkey accepted, KernelPatch {}",
+            verified.kernelpatch_version.as_deref().unwrap_or("unknown")
+        );
+    }
+    ensure!(verified.accepted, "superkey rejected by the kernel");
+    Ok(())
+}
+
+/// Make the kernel accept `new` instead of the current key and load the su
+/// setup again with it. apd keeps no copy of the key, nothing else to update.
+pub fn rotate(superkey: Option<String>) -> Result<()> {
+    let old = Some(read_key(superkey, CURRENT_KEY_ENV, "current superkey")?);
+    let new = read_key(None, NEW_KEY_ENV, "new superkey")?;
+    ensure!(
+        supercall::validate_superkey(&old),
+        "current superkey rejected by the kernel"
+    );
+    if let Err(rc) = supercall::set_superkey(&old, &new) {
+        bail!("kernel refused the new superkey: {rc}");
+    }
+    let new = Some(new);
+    ensure!(
+        supercall::validate_superkey(&new),
+        "new superkey set but not accepted, the kernel may need a reboot"
+    );
+    supercall::init_load_su_path(&new);
+    supercall::privilege_apd_profile(&new);
+    println!("superkey rotated, update it in the manager too");
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! Modules mounted only for selected apps
+//!
+//! A module whose module.prop lists packages in `targets=`, separated by
+//! commas or spaces, is left out of the global magic mount. The uid listener
+//! polls `/proc` for processes of those packages and bind mounts the files of
+//! the module in the mount namespace of each, so no other process sees them.
+//!
+//! Only files replacing existing ones are mounted, new files and dirs of such
+//! a module have nothing to be bound onto and are skipped. What was mounted in
+//! which namespace is kept in `.targeted_mounts` for `apd module mount-status`.
+//! A mount failing in an app is logged and recorded, the boot goes on as is.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, ensure};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{critical, defs, flags, magic_mount, module, script_history, umount, utils};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Packages in `targets=` of the module at `module_path`, empty for modules
+/// which are mounted globally
+pub fn targets(module_path: &Path) -> Vec<String> {
+    module::read_module_prop(module_path)
+        .ok()
+        .and_then(|props| props.get("targets").cloned())
+        .unwrap_or_default()
+        .split([',', ' '])
+        .map(str::trim)
+        .filter(|package| !package.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+pub fn is_targeted(module_path: &Path) -> bool {
+    !targets(module_path).is_empty()
+}
+
+/// Mounts of a module in the namespace of one app process
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TargetedMount {
+    pub package: String,
+    pub pid: i32,
+    /// e.g. `mnt:[4026532721]`
+    pub mnt_ns: String,
+    pub targets: Vec<PathBuf>,
+    pub error: Option<String>,
+}
+
+/// Mounts of this boot by module id, in `.targeted_mounts`
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct Record {
+    boot_id: String,
+    modules: BTreeMap<String, Vec<TargetedMount>>,
+}
+
+impl Record {
+    fn current() -> Self {
+        let mut record = fs::read_to_string(defs::TARGETED_MOUNTS_FILE)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Record>(&content).ok())
+            .filter(|record| record.boot_id == script_history::boot_id())
+            .unwrap_or_else(|| Record {
+                boot_id: script_history::boot_id(),
+                ..Default::default()
+            });
+        // a namespace is gone with its process
+        for mounts in record.modules.values_mut() {
+            mounts.retain(|mount| Path::new(&format!("/proc/{}", mount.pid)).exists());
+        }
+        record.modules.retain(|_, mounts| !mounts.is_empty());
+        record
+    }
+
+    fn store(&self) -> Result<()> {
+        fs::write(defs::TARGETED_MOUNTS_FILE, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write {}", defs::TARGETED_MOUNTS_FILE))
+    }
+}
+
+/// Mounts in app processes alive right now, by module id
+pub fn mounts() -> BTreeMap<String, Vec<TargetedMount>> {
+    Record::current().modules
+}
+
+/// The module files of `module_path` and where they belong
+fn module_files(module_path: &Path) -> Vec<(PathBuf, PathBuf)> {
+    std::iter::once("system")
+        .chain(critical::PARTITIONS)
+        .map(|partition| module_path.join(partition))
+        .filter(|dir| dir.is_dir())
+        .flat_map(walkdir::WalkDir::new)
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let relative = entry.path().strip_prefix(module_path).ok()?;
+            Some((entry.path().to_path_buf(), magic_mount::target_of(relative)))
+        })
+        .collect()
+}
+
+/// `apd module inject <id> --pid <pid>`: mount the files of module `id` in the
+/// mount namespace of `pid` and print the mounted targets, one per line
+pub fn inject(id: &str, pid: i32) -> Result<()> {
+    let module_path = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(module_path.exists(), "module: {} not found!", id);
+    let files = module_files(&module_path);
+    let init_ns = umount::mount_ns(1);
+    ensure!(
+        init_ns.is_some() && umount::mount_ns(pid) != init_ns,
+        "process {pid} shares the init mount namespace"
+    );
+    utils::switch_mnt_ns(pid)?;
+    let mut failed = 0;
+    for (source, target) in files {
+        if !target.is_file() {
+            continue;
+        }
+        match crate::mount::bind_mount_file(&source, &target) {
+            Ok(()) => println!("{}", target.display()),
+            Err(e) => {
+                warn!("bind {} -> {}: {e:#}", source.display(), target.display());
+                failed += 1;
+            }
+        }
+    }
+    ensure!(failed == 0, "{failed} file(s) failed to mount");
+    Ok(())
+}
+
+/// Active modules with targets, by module id
+fn targeted_modules() -> BTreeMap<String, Vec<String>> {
+    let mut modules = BTreeMap::new();
+    let _ = module::foreach_module(module::ModuleType::Active, |module_path| {
+        if flags::is_set(module_path.join(defs::SKIP_MOUNT_FILE_NAME)) {
+            return Ok(());
+        }
+        let targets = targets(module_path);
+        if !targets.is_empty() {
+            let id = module_path.file_name().unwrap_or_default().to_string_lossy();
+            modules.insert(id.into_owned(), targets);
+        }
+        Ok(())
+    });
+    modules
+}
+
+/// Run [`inject`] in a process of its own, entering another mount namespace
+/// is not possible for a thread of the listener
+fn inject_in(exe: &Path, id: &str, package: &str, pid: i32) -> TargetedMount {
+    let output = Command::new(exe)
+        .args(["module", "inject", id, "--pid", &pid.to_string()])
+        .output();
+    let (targets, error) = match output {
+        Ok(output) => {
+            let targets = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(PathBuf::from)
+                .collect();
+            let error = (!output.status.success()).then(|| {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                format!("exited with {}: {}", output.status, stderr.trim())
+            });
+            (targets, error)
+        }
+        Err(e) => (Vec::new(), Some(format!("Failed to start {}: {e}", exe.display()))),
+    };
+    TargetedMount {
+        package: package.to_string(),
+        pid,
+        mnt_ns: umount::mount_ns(pid)
+            .map(|ns| ns.display().to_string())
+            .unwrap_or_default(),
+        targets,
+        error,
+    }
+}
+
+/// Start mounting targeted modules in their apps as they start
+pub fn spawn_watcher() {
+    if utils::get_mount_mode() != defs::MOUNT_MODE_MAGIC {
+        return;
+    }
+    let modules = targeted_modules();
+    if modules.is_empty() {
+        return;
+    }
+    let exe = env::current_exe().unwrap_or_else(|_| PathBuf::from(defs::DAEMON_PATH));
+    thread::spawn(move || {
+        let mut seen = HashSet::new();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let mut alive = HashSet::new();
+            let mut injected = Vec::new();
+            let pids = fs::read_dir("/proc")
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok());
+            for pid in pids {
+                alive.insert(pid);
+                if seen.contains(&pid) {
+                    continue;
+                }
+                let Some(name) = umount::process_name(pid) else {
+                    continue;
+                };
+                // freshly forked apps are named after zygote until specialized
+                if name.starts_with("zygote") || name == "<pre-initialized>" {
+                    continue;
+                }
+                seen.insert(pid);
+                let package = name.split(':').next().unwrap_or_default();
+                for (id, targets) in &modules {
+                    if !targets.iter().any(|target| target == package) {
+                        continue;
+                    }
+                    let mount = inject_in(&exe, id, package, pid);
+                    match &mount.error {
+                        None => info!("mounted {id} in {name} ({pid})"),
+                        Some(error) => {
+                            warn!("Failed to mount {id} in {name} ({pid}): {error}");
+                            module::append_log(id, "error", &format!("mount in {name}: {error}"));
+                        }
+                    }
+                    injected.push((id.clone(), mount));
+                }
+            }
+            seen.retain(|pid| alive.contains(pid));
+            if injected.is_empty() {
+                continue;
+            }
+            let mut record = Record::current();
+            for (id, mount) in injected {
+                record.modules.entry(id).or_default().push(mount);
+            }
+            if let Err(e) = record.store() {
+                warn!("{e:#}");
+            }
+        }
+    });
+}
@@ -0,0 +1,210 @@
+//! Boot stage timing
+//!
+//! Always on, unlike the boot profiler, as it only reads the clock around each
+//! step. Every stage records when its phases and scripts started, relative to
+//! the start of the stage, and how long they took. The stages of the last boot
+//! are kept in `log/boot_timing.json`, `apd status --timing` prints them.
+//!
+//! Scripts of the service and boot-completed stages are not waited for, only
+//! their start is known.
+
+use std::{
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{defs, script_history};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Step {
+    pub name: String,
+    /// module the script belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    /// since the start of the stage
+    pub start_ms: u64,
+    /// `None` for scripts apd did not wait for
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StageTiming {
+    pub stage: String,
+    /// time since boot when the stage started
+    pub uptime_ms: u64,
+    pub duration_ms: u64,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BootTiming {
+    pub stages: Vec<StageTiming>,
+}
+
+struct Current {
+    stage: String,
+    start: Instant,
+    uptime_ms: u64,
+    steps: Vec<Step>,
+}
+
+static CURRENT: Mutex<Option<Current>> = Mutex::new(None);
+
+fn uptime_ms() -> u64 {
+    fs::read_to_string("/proc/uptime")
+        .ok()
+        .and_then(|uptime| uptime.split_whitespace().next()?.parse::<f64>().ok())
+        .map_or(0, |secs| (secs * 1000.0) as u64)
+}
+
+/// Start timing `stage`, steps recorded before are dropped
+pub fn begin_stage(stage: &str) {
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = Some(Current {
+            stage: stage.to_string(),
+            start: Instant::now(),
+            uptime_ms: uptime_ms(),
+            steps: Vec::new(),
+        });
+    }
+}
+
+fn push(name: String, module: Option<String>, start: Instant, duration: Option<Duration>) {
+    let Ok(mut current) = CURRENT.lock() else {
+        return;
+    };
+    // nothing is timed outside of a stage, e.g. for `apd stage run`
+    let Some(current) = current.as_mut() else {
+        return;
+    };
+    current.steps.push(Step {
+        name,
+        module,
+        start_ms: start.saturating_duration_since(current.start).as_millis() as u64,
+        duration_ms: duration.map(|d| d.as_millis() as u64),
+    });
+}
+
+pub fn record(name: &str, start: Instant, duration: Duration) {
+    push(name.to_string(), None, start, Some(duration));
+}
+
+pub fn measure<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    record(name, start, start.elapsed());
+    result
+}
+
+/// Record a script, `duration` is `None` if apd did not wait for it
+pub fn record_script(script: &Path, start: Instant, duration: Option<Duration>) {
+    let module = script_history::module_id(script);
+    push(script.display().to_string(), module, start, duration);
+}
+
+pub fn load() -> Option<BootTiming> {
+    let content = fs::read_to_string(defs::BOOT_TIMING_FILE).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Store the timing of the current stage, post-fs-data starts a new boot
+pub fn finish_stage() -> Result<()> {
+    let Some(current) = CURRENT.lock().ok().and_then(|mut current| current.take()) else {
+        return Ok(());
+    };
+    let mut timing = match current.stage.as_str() {
+        "post-fs-data" => BootTiming::default(),
+        _ => load().unwrap_or_default(),
+    };
+    timing.stages.retain(|stage| stage.stage != current.stage);
+    timing.stages.push(StageTiming {
+        duration_ms: current.start.elapsed().as_millis() as u64,
+        stage: current.stage,
+        uptime_ms: current.uptime_ms,
+        steps: current.steps,
+    });
+    fs::write(defs::BOOT_TIMING_FILE, serde_json::to_string_pretty(&timing)?)
+        .with_context(|| format!("Failed to write {}", defs::BOOT_TIMING_FILE))?;
+    Ok(())
+}
+
+/// The stages and their steps as `apd status --timing` prints them
+pub fn render(timing: &BootTiming) -> String {
+    let mut text = String::new();
+    for stage in &timing.stages {
+        text.push_str(&format!(
+            "{} at {:.3}s, took {} ms\n",
+            stage.stage,
+            stage.uptime_ms as f64 / 1000.0,
+            stage.duration_ms
+        ));
+        // phases are recorded once done, after the scripts they ran
+        let mut steps: Vec<&Step> = stage.steps.iter().collect();
+        steps.sort_by_key(|step| step.start_ms);
+        for step in steps {
+            let duration = step
+                .duration_ms
+                .map_or("started".to_string(), |ms| format!("{ms} ms"));
+            let module = step
+                .module
+                .as_ref()
+                .map(|id| format!(" [{id}]"))
+                .unwrap_or_default();
+            text.push_str(&format!(
+                "  +{:<7} {:>9}  {}{}\n",
+                step.start_ms, duration, step.name, module
+            ));
+        }
+    }
+    text
+}
+
+pub fn show() -> Result<()> {
+    let timing = load().with_context(|| "No boot timing recorded yet")?;
+    if crate::output::json() {
+        return crate::output::print("status --timing", timing);
+    }
+    print!("{}", render(&timing));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_steps_in_start_order() {
+        let timing = BootTiming {
+            stages: vec![StageTiming {
+                stage: "post-fs-data".to_string(),
+                uptime_ms: 4250,
+                duration_ms: 900,
+                steps: vec![
+                    Step {
+                        name: "mount".to_string(),
+                        module: None,
+                        start_ms: 300,
+                        duration_ms: Some(600),
+                    },
+                    Step {
+                        name: "/data/adb/modules/example/post-fs-data.sh".to_string(),
+                        module: Some("example".to_string()),
+                        start_ms: 10,
+                        duration_ms: None,
+                    },
+                ],
+            }],
+        };
+        assert_eq!(
+            render(&timing),
+            "post-fs-data at 4.250s, took 900 ms\n\
+             \x20 +10        started  /data/adb/modules/example/post-fs-data.sh [example]\n\
+             \x20 +300        600 ms  mount\n"
+        );
+    }
+}
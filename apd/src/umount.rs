@@ -0,0 +1,304 @@
+//! Hide module mounts from selected apps
+//!
+//! Packages listed in `/data/adb/ap/umount_packages`, one per line, get the
+//! module mounts detached in their own mount namespace once they started. The
+//! mounts come from the registry written right after modules were mounted.
+//!
+//! The uid listener notices new app processes by polling `/proc`, an app which
+//! inspects its mounts within the first moments of its life may still see them.
+//! Polling only runs if the list existed when the uid listener started, edits
+//! to it apply right away.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, ensure};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{assets, config, defs, metamodule, script_history};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// One mount of the registry
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MountEntry {
+    /// increasing in mount order, what `apd mount undo` takes
+    pub id: u32,
+    pub target: PathBuf,
+    /// mount source as in mountinfo, e.g. the block device or `APatch`
+    pub source: String,
+    /// mounted dir within its filesystem, the module file of a bind mount
+    pub root: PathBuf,
+    pub fstype: String,
+    /// kernel mount id, `-1` if the mount was not found in mountinfo
+    pub mnt_id: i32,
+}
+
+/// Mount points created by module mounting, outermost only
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MountRegistry {
+    /// boot the mounts were made in, the registry of another boot is ignored
+    #[serde(default)]
+    pub boot_id: String,
+    #[serde(default)]
+    pub entries: Vec<MountEntry>,
+}
+
+impl MountRegistry {
+    pub fn load() -> Self {
+        fs::read_to_string(defs::MOUNT_LIST_FILE)
+            .ok()
+            .and_then(|content| serde_json::from_str::<MountRegistry>(&content).ok())
+            .filter(|registry| registry.boot_id == script_history::boot_id())
+            .unwrap_or_default()
+    }
+
+    pub fn mounts(&self) -> Vec<PathBuf> {
+        self.entries.iter().map(|entry| entry.target.clone()).collect()
+    }
+
+    /// Record the module mounts as they are now, called after mounting. Mounts
+    /// a metamodule reported are included, their source may be anywhere.
+    pub fn record() -> Result<()> {
+        let mut mounts = crate::mount::module_mount_points()?;
+        mounts.extend(metamodule::reported_mounts());
+        let registry = MountRegistry {
+            boot_id: script_history::boot_id(),
+            entries: describe(crate::mount::outermost(mounts))?,
+        };
+        registry.store()
+    }
+
+    /// Written atomically, the uid listener reads it at any time
+    fn store(&self) -> Result<()> {
+        let tmp = format!("{}.tmp", defs::MOUNT_LIST_FILE);
+        fs::write(&tmp, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write {tmp}"))?;
+        fs::rename(&tmp, defs::MOUNT_LIST_FILE)
+            .with_context(|| format!("Failed to rename {tmp} to {}", defs::MOUNT_LIST_FILE))
+    }
+}
+
+/// Look the mount points up in the mountinfo of init, numbered in mount order
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn describe(points: Vec<PathBuf>) -> Result<Vec<MountEntry>> {
+    let mountinfo = procfs::process::Process::new(1)?.mountinfo()?;
+    let mut entries: Vec<MountEntry> = points
+        .into_iter()
+        .map(|target| {
+            // the mount on top of a point is listed last
+            let info = mountinfo.iter().rev().find(|info| info.mount_point == target);
+            MountEntry {
+                id: 0,
+                source: info
+                    .and_then(|info| info.mount_source.clone())
+                    .unwrap_or_default(),
+                root: info.map(|info| PathBuf::from(&info.root)).unwrap_or_default(),
+                fstype: info.map(|info| info.fs_type.clone()).unwrap_or_default(),
+                mnt_id: info.map_or(-1, |info| info.mnt_id),
+                target,
+            }
+        })
+        .collect();
+    // kernel mount ids only grow, unknown mounts go last
+    entries.sort_by_key(|entry| (entry.mnt_id < 0, entry.mnt_id));
+    for (id, entry) in entries.iter_mut().enumerate() {
+        entry.id = id as u32 + 1;
+    }
+    Ok(entries)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn describe(_points: Vec<PathBuf>) -> Result<Vec<MountEntry>> {
+    unimplemented!()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn live_mount_ids() -> Result<HashSet<i32>> {
+    Ok(procfs::process::Process::new(1)?
+        .mountinfo()?
+        .into_iter()
+        .map(|info| info.mnt_id)
+        .collect())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn live_mount_ids() -> Result<HashSet<i32>> {
+    unimplemented!()
+}
+
+#[derive(Serialize)]
+struct ListedMount {
+    #[serde(flatten)]
+    entry: MountEntry,
+    /// still mounted, false once it was detached or mounted over
+    present: bool,
+}
+
+/// `apd mount list`: the registry, checked against the current mounts
+pub fn list() -> Result<()> {
+    let live = live_mount_ids()?;
+    let mounts: Vec<ListedMount> = MountRegistry::load()
+        .entries
+        .into_iter()
+        .map(|entry| ListedMount {
+            present: live.contains(&entry.mnt_id),
+            entry,
+        })
+        .collect();
+    if crate::output::json() {
+        return crate::output::print("mount list", mounts);
+    }
+    if mounts.is_empty() {
+        println!("no module mounts recorded in this boot");
+    }
+    for mount in &mounts {
+        let entry = &mount.entry;
+        println!(
+            "{:>3} {} <- {}:{} ({}){}",
+            entry.id,
+            entry.target.display(),
+            entry.source,
+            entry.root.display(),
+            entry.fstype,
+            if mount.present { "" } else { " GONE" }
+        );
+    }
+    Ok(())
+}
+
+/// `apd mount undo`: detach the entries `ids`, or all of them, newest first
+pub fn undo(ids: &[u32], all: bool) -> Result<()> {
+    let mut registry = MountRegistry::load();
+    if !all {
+        for id in ids {
+            ensure!(
+                registry.entries.iter().any(|entry| entry.id == *id),
+                "no mount with id {id}, see apd mount list"
+            );
+        }
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    crate::utils::switch_mnt_ns(1)?;
+
+    let mut selected: Vec<MountEntry> = registry
+        .entries
+        .iter()
+        .filter(|entry| all || ids.contains(&entry.id))
+        .cloned()
+        .collect();
+    selected.sort_by_key(|entry| std::cmp::Reverse(entry.id));
+    let mut failed = 0;
+    for entry in &selected {
+        match crate::mount::detach(&entry.target) {
+            Ok(()) => {
+                println!("detached {} ({})", entry.target.display(), entry.id);
+                registry.entries.retain(|kept| kept.id != entry.id);
+            }
+            Err(e) => {
+                warn!("{:#}", e);
+                failed += 1;
+            }
+        }
+    }
+    registry.store()?;
+    ensure!(failed == 0, "{failed} mount(s) could not be detached");
+    Ok(())
+}
+
+fn load_packages(list: &str) -> HashSet<String> {
+    fs::read_to_string(list)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Process name of `pid`, e.g. `com.example.app:remote` for an app process
+pub fn process_name(pid: i32) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let name = cmdline.split(|b| *b == 0).next()?;
+    Some(String::from_utf8_lossy(name).into_owned())
+}
+
+pub fn mount_ns(pid: i32) -> Option<PathBuf> {
+    fs::read_link(format!("/proc/{pid}/ns/mnt")).ok()
+}
+
+/// Detach `mounts` in the mount namespace of `pid`, which must have its own
+fn detach_in(pid: i32, mounts: &[PathBuf]) -> Result<()> {
+    let init_ns = mount_ns(1);
+    ensure!(
+        init_ns.is_some() && mount_ns(pid) != init_ns,
+        "process {pid} shares the init mount namespace"
+    );
+    let status = Command::new(assets::BUSYBOX_PATH)
+        .args(["nsenter", "-t", &pid.to_string(), "-m", "--"])
+        .args([assets::BUSYBOX_PATH, "umount", "-l"])
+        .args(mounts)
+        .status()?;
+    ensure!(status.success(), "umount in process {pid} exited with {status}");
+    Ok(())
+}
+
+/// Start detaching module mounts in listed apps as they start
+pub fn spawn_watcher() {
+    let list = config::umount_packages_file();
+    if !Path::new(&list).exists() {
+        return;
+    }
+    thread::spawn(move || {
+        let mut seen = HashSet::new();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let packages = load_packages(&list);
+            if packages.is_empty() {
+                seen.clear();
+                continue;
+            }
+            let registry = MountRegistry::load().mounts();
+            if registry.is_empty() {
+                continue;
+            }
+
+            let mut alive = HashSet::new();
+            let pids = fs::read_dir("/proc")
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok());
+            for pid in pids {
+                alive.insert(pid);
+                if seen.contains(&pid) {
+                    continue;
+                }
+                let Some(name) = process_name(pid) else {
+                    continue;
+                };
+                // freshly forked apps are named after zygote until specialized
+                if name.starts_with("zygote") || name == "<pre-initialized>" {
+                    continue;
+                }
+                seen.insert(pid);
+                let package = name.split(':').next().unwrap_or_default();
+                if !packages.contains(package) {
+                    continue;
+                }
+                match detach_in(pid, &registry) {
+                    Ok(()) => info!("detached module mounts in {name} ({pid})"),
+                    Err(e) => warn!("Failed to detach module mounts in {name}: {e:#}"),
+                }
+            }
+            seen.retain(|pid| alive.contains(pid));
+        }
+    });
+}
@@ -0,0 +1,122 @@
+//! Removal of everything APatch keeps in userspace
+//!
+//! `apd uninstall` stops the helpers apd left running, detaches the module
+//! mounts and removes the module dirs and `/data/adb/ap`. `--keep-modules`
+//! leaves the modules in place for a later reinstall. Paths already gone are
+//! skipped, so running it again after an interruption finishes the job.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, ensure};
+use log::warn;
+
+use crate::{
+    defs, module, reset,
+    restorecon::{self, ADB_CON},
+    shutdown,
+    umount::MountRegistry,
+    utils,
+};
+
+/// Module scripts still running, e.g. a service.sh which never returned
+fn running_module_scripts() -> Vec<String> {
+    let Ok(dir) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let own = std::process::id().to_string();
+    dir.flatten()
+        .filter(|entry| entry.file_name().to_str().is_some_and(|name| name != own))
+        .filter_map(|entry| fs::read(entry.path().join("cmdline")).ok())
+        .filter_map(|cmdline| {
+            cmdline
+                .split(|b| *b == 0)
+                .map(String::from_utf8_lossy)
+                .find(|arg| arg.starts_with(defs::MODULE_DIR) && arg.ends_with(".sh"))
+                .map(|arg| arg.into_owned())
+        })
+        .collect()
+}
+
+fn remove(path: &str, removed: &mut Vec<String>) -> Result<()> {
+    let path = path.trim_end_matches('/');
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to stat {path}")),
+    };
+    if meta.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+    .with_context(|| format!("Failed to remove {path}"))?;
+    removed.push(path.to_string());
+    Ok(())
+}
+
+pub fn uninstall(keep_modules: bool) -> Result<()> {
+    ensure!(
+        utils::getprop("sys.boot_completed").as_deref() == Some("1"),
+        "Android is still booting, try again once boot completed"
+    );
+    let running = running_module_scripts();
+    ensure!(
+        running.is_empty(),
+        "module scripts are still running: {}",
+        running.join(", ")
+    );
+
+    shutdown::stop_children();
+
+    // the mount registry lives in the working dir, read it before that goes
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    utils::switch_mnt_ns(1)?;
+    let mut mounts = MountRegistry::load().mounts();
+    mounts.extend(crate::mount::module_mount_points().unwrap_or_default());
+    let mut detached = 0;
+    for point in crate::mount::outermost(mounts) {
+        match crate::mount::detach(&point) {
+            Ok(()) => detached += 1,
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+
+    let mut removed = Vec::new();
+    if !keep_modules {
+        // the lock file is in the working dir, gone on a second run
+        let _guard = Path::new(defs::WORKING_DIR)
+            .exists()
+            .then(module::lock_modules)
+            .transpose()?;
+        for id in reset::module_ids() {
+            println!("- Uninstalling {id}");
+            reset::uninstall(&id);
+        }
+        for path in [
+            defs::MODULE_UPDATE_DIR,
+            defs::METAMODULE_DIR,
+            defs::MODULE_DIR,
+            defs::MODULE_DATA_DIR,
+        ] {
+            remove(path, &mut removed)?;
+        }
+    }
+    for path in [defs::GLOBAL_NAMESPACE_FILE, defs::WORKING_DIR] {
+        remove(path, &mut removed)?;
+    }
+    if Path::new(defs::DAEMON_PATH).exists() {
+        restorecon::ensure_con(defs::DAEMON_PATH, ADB_CON)?;
+    }
+
+    println!("- Detached {detached} module mount(s)");
+    if removed.is_empty() {
+        println!("- Nothing left to remove");
+    }
+    for path in &removed {
+        println!("- Removed {path}");
+    }
+    if keep_modules {
+        println!("- Kept {}", defs::MODULE_DIR);
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Module updates from `updateJson`
+//!
+//! Modules declare `updateJson=<url>` in module.prop, pointing to
+//!
+//! ```json
+//! {"version": "v2", "versionCode": 2, "zipUrl": "...", "changelog": "..."}
+//! ```
+//!
+//! `apd module check-updates` lists the modules with a higher `versionCode`,
+//! `apd module update <id>` installs the zip like a local install. Fetching
+//! needs the `update-check` feature, minimal builds carry no HTTP client.
+
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{defs, module};
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UpdateJson {
+    version: String,
+    version_code: i64,
+    zip_url: String,
+    #[serde(default)]
+    changelog: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Update {
+    pub id: String,
+    pub version: String,
+    pub version_code: i64,
+    pub new_version: String,
+    pub new_version_code: i64,
+    pub zip_url: String,
+    pub changelog: String,
+}
+
+#[cfg(feature = "update-check")]
+mod fetch {
+    use std::{io::Read, time::Duration};
+
+    use anyhow::{Context, Result};
+
+    /// Update jsons are tiny, module zips are not
+    const MAX_JSON: u64 = 1024 * 1024;
+    const MAX_ZIP: u64 = 512 * 1024 * 1024;
+
+    fn agent() -> ureq::Agent {
+        // no proxy from the environment, boot scripts and adb shells differ there
+        ureq::Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(30)))
+            .proxy(None)
+            .build()
+            .into()
+    }
+
+    pub fn get(url: &str, limit: u64) -> Result<Vec<u8>> {
+        let mut response = agent()
+            .get(url)
+            .call()
+            .with_context(|| format!("Failed to fetch {url}"))?;
+        let mut body = Vec::new();
+        response
+            .body_mut()
+            .as_reader()
+            .take(limit + 1)
+            .read_to_end(&mut body)
+            .with_context(|| format!("Failed to read {url}"))?;
+        anyhow::ensure!(body.len() as u64 <= limit, "{url} is larger than {limit} bytes");
+        Ok(body)
+    }
+
+    pub fn json(url: &str) -> Result<Vec<u8>> {
+        get(url, MAX_JSON)
+    }
+
+    pub fn zip(url: &str) -> Result<Vec<u8>> {
+        get(url, MAX_ZIP)
+    }
+}
+
+#[cfg(not(feature = "update-check"))]
+mod fetch {
+    use anyhow::{Result, bail};
+
+    pub fn json(_url: &str) -> Result<Vec<u8>> {
+        bail!("apd was built without the update-check feature")
+    }
+
+    pub fn zip(_url: &str) -> Result<Vec<u8>> {
+        bail!("apd was built without the update-check feature")
+    }
+}
+
+/// The update of the module at `module_path`, `None` if it is up to date or
+/// declares no `updateJson`
+fn check(module_path: &Path) -> Result<Option<Update>> {
+    let prop = module::read_module_prop(module_path)?;
+    let Some(url) = prop.get("updateJson").filter(|url| !url.is_empty()) else {
+        return Ok(None);
+    };
+    let version_code = prop
+        .get("versionCode")
+        .and_then(|code| code.trim().parse().ok())
+        .unwrap_or(0);
+    let update: UpdateJson = serde_json::from_slice(&fetch::json(url)?)
+        .map_err(|e| anyhow::anyhow!("Failed to parse {url}: {e}"))?;
+    if update.version_code <= version_code {
+        return Ok(None);
+    }
+    Ok(Some(Update {
+        id: prop.get("id").cloned().unwrap_or_default(),
+        version: prop.get("version").cloned().unwrap_or_default(),
+        version_code,
+        new_version: update.version,
+        new_version_code: update.version_code,
+        zip_url: update.zip_url,
+        changelog: update.changelog,
+    }))
+}
+
+pub fn check_updates() -> Result<()> {
+    let mut updates = Vec::new();
+    module::foreach_module(module::ModuleType::All, |module_path| {
+        match check(module_path) {
+            Ok(Some(update)) => updates.push(update),
+            Ok(None) => {}
+            Err(e) => log::warn!("{}: {:#}", module_path.display(), e),
+        }
+        Ok(())
+    })?;
+    if crate::output::json() {
+        return crate::output::print("module check-updates", &updates);
+    }
+    if updates.is_empty() {
+        println!("all modules are up to date");
+    }
+    for update in &updates {
+        println!(
+            "{}: {} ({}) -> {} ({})",
+            update.id,
+            update.version,
+            update.version_code,
+            update.new_version,
+            update.new_version_code
+        );
+        println!("  zip: {}", update.zip_url);
+        if !update.changelog.is_empty() {
+            println!("  changelog: {}", update.changelog);
+        }
+    }
+    Ok(())
+}
+
+/// Download the update of module `id` and stage it like a local install
+pub fn update(id: &str, allow_unconfined: bool) -> Result<()> {
+    let module_path = Path::new(defs::MODULE_DIR).join(id);
+    anyhow::ensure!(module_path.exists(), "module: {} not found!", id);
+    let Some(update) = check(&module_path)? else {
+        println!("{id} is up to date");
+        return Ok(());
+    };
+    println!("- Downloading {} {}", id, update.new_version);
+    let zip = fetch::zip(&update.zip_url)?;
+    let zip_path = Path::new(defs::WORKING_DIR).join(format!(".update-{id}.zip"));
+    std::fs::write(&zip_path, zip)?;
+    let result = module::install_module(&zip_path.to_string_lossy(), allow_unconfined);
+    let _ = std::fs::remove_file(&zip_path);
+    result
+}
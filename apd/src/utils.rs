@@ -5,7 +5,7 @@ use std::os::unix::prelude::PermissionsExt;
 use std::{
     ffi::CString,
     fs::{File, OpenOptions, create_dir_all, metadata},
-    io::{ErrorKind::AlreadyExists, Write},
+    io::Write,
     path::Path,
     process::{Command, Stdio},
 };
@@ -15,18 +15,9 @@ use log::{info, warn};
 
 use crate::{defs, supercall::sc_su_get_safemode};
 
+/// Create a flag file, see [`crate::flags::set`]
 pub fn ensure_file_exists<T: AsRef<Path>>(file: T) -> Result<()> {
-    match File::options().write(true).create_new(true).open(&file) {
-        Result::Ok(_) => Ok(()),
-        Err(err) => {
-            if err.kind() == AlreadyExists && file.as_ref().is_file() {
-                Ok(())
-            } else {
-                Err(Error::from(err))
-                    .with_context(|| format!("{} is not a regular file", file.as_ref().display()))
-            }
-        }
-    }
+    crate::flags::set(file)
 }
 
 pub fn ensure_dir_exists<T: AsRef<Path>>(dir: T) -> Result<()> {
@@ -68,16 +59,62 @@ pub fn run_command(
     let child = command_builder.spawn()?;
     Ok(child)
 }
-pub fn is_safe_mode(superkey: Option<String>) -> bool {
-    let safemode = getprop("persist.sys.safemode")
-        .filter(|prop| prop == "1")
-        .is_some()
-        || getprop("ro.sys.safemode")
-            .filter(|prop| prop == "1")
-            .is_some();
-    info!("safemode: {}", safemode);
-    if safemode {
-        return true;
+/// What put the device into safe mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SafeModeReason {
+    /// `apd safemode enter`
+    Forced,
+    /// persist.sys.safemode, chosen from the power menu
+    Requested,
+    /// ro.sys.safemode
+    System,
+    /// the kernel saw the volume key held during boot
+    Kernel,
+    /// too many boots in a row never completed, only disables modules
+    Bootloop,
+}
+
+impl std::fmt::Display for SafeModeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SafeModeReason::Forced => "forced",
+            SafeModeReason::Requested => "requested",
+            SafeModeReason::System => "system",
+            SafeModeReason::Kernel => "kernel",
+            SafeModeReason::Bootloop => "bootloop",
+        })
+    }
+}
+
+impl std::str::FromStr for SafeModeReason {
+    type Err = Error;
+
+    fn from_str(reason: &str) -> Result<Self> {
+        Ok(match reason {
+            "forced" => SafeModeReason::Forced,
+            "requested" => SafeModeReason::Requested,
+            "system" => SafeModeReason::System,
+            "kernel" => SafeModeReason::Kernel,
+            "bootloop" => SafeModeReason::Bootloop,
+            _ => bail!("unknown safe mode reason {reason}"),
+        })
+    }
+}
+
+/// Why the device is in safe mode, `None` if it is not
+pub fn safe_mode_reason(superkey: Option<String>) -> Option<SafeModeReason> {
+    if Path::new(defs::SAFEMODE_FORCE_FILE).exists() {
+        info!("safemode: forced");
+        return Some(SafeModeReason::Forced);
+    }
+    if getprop("persist.sys.safemode").as_deref() == Some("1") {
+        info!("safemode: requested");
+        return Some(SafeModeReason::Requested);
+    }
+    if getprop("ro.sys.safemode").as_deref() == Some("1") {
+        info!("safemode: system");
+        return Some(SafeModeReason::System);
     }
     let safemode = superkey
         .as_ref()
@@ -90,7 +127,11 @@ pub fn is_safe_mode(superkey: Option<String>) -> bool {
             |cstr| sc_su_get_safemode(&cstr) == 1,
         );
     info!("kernel_safemode: {}", safemode);
-    safemode
+    safemode.then_some(SafeModeReason::Kernel)
+}
+
+pub fn is_safe_mode(superkey: Option<String>) -> bool {
+    safe_mode_reason(superkey).is_some()
 }
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -145,8 +186,29 @@ pub fn umask(_mask: u32) {
     unimplemented!("umask is not supported on this platform")
 }
 
+/// Whether a working Magisk is installed. A `magisk` in PATH which can't tell
+/// its version, e.g. a stub left over by an old install, does not count.
 pub fn has_magisk() -> bool {
-    which::which("magisk").is_ok()
+    let Some(magisk) = which::which("magisk").ok() else {
+        return false;
+    };
+    let working = Command::new(&magisk)
+        .arg("-V")
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .is_some_and(|version| version.trim().parse::<u32>().is_ok());
+    if !working {
+        warn!("{} does not work, ignoring it as a leftover", magisk.display());
+    }
+    working
+}
+
+/// Whether the user asked to run alongside a working Magisk
+pub fn coexist_with_magisk() -> bool {
+    Path::new(defs::IGNORE_MAGISK_FILE).exists()
 }
 pub fn get_tmp_path() -> &'static str {
     if metadata(defs::TEMP_DIR_LEGACY).is_ok() {
@@ -158,18 +220,140 @@ pub fn get_tmp_path() -> &'static str {
     ""
 }
 pub fn get_mount_mode() -> String {
-    let mode_file = Path::new(defs::MOUNT_MODE_FILE);
-    if mode_file.exists() {
-        if let Result::Ok(content) = std::fs::read_to_string(mode_file) {
-            let mode = content.trim();
-            match mode {
-                defs::MOUNT_MODE_MAGIC | defs::MOUNT_MODE_METAMODULE | defs::MOUNT_MODE_DISABLED => {
-                    return mode.to_string();
-                }
-                _ => {}
+    if let Some(mode) = crate::config::mount_mode() {
+        match mode.as_str() {
+            defs::MOUNT_MODE_MAGIC | defs::MOUNT_MODE_METAMODULE | defs::MOUNT_MODE_DISABLED => {
+                return mode;
             }
+            _ => warn!("unknown mount mode {mode}, ignored"),
         }
     }
     // Default to magic mount for backwards compatibility
     defs::MOUNT_MODE_MAGIC.to_string()
 }
+
+/// Whether zygote already forked app processes, which keep a copy of the
+/// mounts they were started with
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn apps_started() -> bool {
+    let Some(processes) = procfs::process::all_processes().ok() else {
+        return false;
+    };
+    let processes: Vec<_> = processes.flatten().collect();
+    // the comm of zygote is `main`, only its cmdline tells it apart
+    let zygotes: Vec<i32> = processes
+        .iter()
+        .filter(|p| {
+            p.cmdline()
+                .ok()
+                .and_then(|args| args.into_iter().next())
+                .is_some_and(|arg0| arg0.starts_with("zygote"))
+        })
+        .map(|p| p.pid)
+        .collect();
+    processes
+        .iter()
+        .filter_map(|p| p.stat().ok())
+        .any(|stat| zygotes.contains(&stat.ppid))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn apps_started() -> bool {
+    unimplemented!()
+}
+
+/// Streaming sha256 of a file, as lowercase hex
+pub fn hash_file<T: AsRef<Path>>(path: T) -> Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let mut file = File::open(&path)
+        .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// What a dir takes on disk, see [`disk_usage`]
+#[derive(serde::Serialize, serde::Deserialize, Default, Clone, Copy, Debug)]
+pub struct DiskUsage {
+    /// sizes of the files and symlinks below it, hardlinks counted once
+    pub bytes: u64,
+    /// entries of any type below it
+    pub entries: u64,
+}
+
+/// Walk `dir` in parallel and sum up what is below it, symlinks are not
+/// followed
+pub fn disk_usage(dir: &Path) -> DiskUsage {
+    use std::{collections::HashSet, os::unix::fs::MetadataExt};
+
+    let mut usage = DiskUsage::default();
+    let mut linked = HashSet::new();
+    let entries = jwalk::WalkDir::new(dir)
+        .skip_hidden(false)
+        .min_depth(1)
+        .into_iter()
+        .flatten();
+    for entry in entries {
+        usage.entries += 1;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let Result::Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        // the other names of a file take no space of their own
+        if metadata.nlink() > 1 && !linked.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        usage.bytes += metadata.len();
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn hardlinks_are_counted_once() {
+        let root = TempDir::new("du-hardlink");
+        let file = root.write("module/system/lib/libfoo.so", "12345678");
+        fs::hard_link(&file, root.path().join("module/system/lib/libbar.so")).unwrap();
+        root.write("module/module.prop", "id=x");
+
+        let usage = disk_usage(&root.path().join("module"));
+        assert_eq!(usage.bytes, 8 + 4);
+        // system, lib, the two names of the library and module.prop
+        assert_eq!(usage.entries, 5);
+    }
+
+    #[test]
+    fn symlinks_count_as_themselves() {
+        let root = TempDir::new("du-symlink");
+        root.write("outside/big", &"x".repeat(4096));
+        root.write("module/file", "123");
+        let module = root.path().join("module");
+        symlink("../outside", module.join("dir_link")).unwrap();
+        symlink("file", module.join("file_link")).unwrap();
+
+        let usage = disk_usage(&module);
+        // the links take the length of their target path, nothing behind them
+        assert_eq!(usage.bytes, 3 + "../outside".len() as u64 + "file".len() as u64);
+        assert_eq!(usage.entries, 3);
+    }
+
+    #[test]
+    fn a_missing_dir_is_empty() {
+        let root = TempDir::new("du-missing");
+        let usage = disk_usage(&root.path().join("missing"));
+        assert_eq!((usage.bytes, usage.entries), (0, 0));
+    }
+}
@@ -0,0 +1,588 @@
+//! Module WebUI server
+//!
+//! `apd module webui <id>` serves `webroot/` of a module on 127.0.0.1, like
+//! KernelSU managers do for module WebUIs. `POST /exec` with
+//! `{"script": "<name>"}` runs one of the scripts module.prop lists in
+//! `webui_exec=` (comma separated, files in the module dir) with the stage
+//! script environment and returns its exit code and output. Requests are
+//! logged to `log/webui/<id>.log`.
+//!
+//! Any app may connect to 127.0.0.1 and any page of the browser may post to
+//! it, so requests need the token of the session. It is drawn at start and
+//! only printed to the caller, in the URL `http://127.0.0.1:<port>/?token=<t>`.
+//! Loading that URL sets it as a `SameSite=Strict` cookie for the page and its
+//! requests. `/exec` further wants `Content-Type: application/json`, which
+//! other origins cannot send without a preflight apd never answers, and an
+//! `Origin`, if any, of the server itself. Requests with a `Host` other than
+//! 127.0.0.1 or localhost are refused against DNS rebinding.
+//!
+//! At most [`MAX_CONNECTIONS`] requests are served at once, a request must
+//! arrive within [`READ_TIMEOUT`] and a script is killed after [`EXEC_TIMEOUT`].
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    os::unix::process::CommandExt,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{assets, defs, module};
+
+/// Largest request body accepted, exec requests are tiny
+const MAX_BODY: usize = 64 * 1024;
+/// Requests served at once, further connections get a 503
+const MAX_CONNECTIONS: usize = 8;
+/// Time a client has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a script of `/exec` may run before its process group is killed
+const EXEC_TIMEOUT: Duration = Duration::from_secs(60);
+const COOKIE_NAME: &str = "apd_webui";
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    /// token to set as cookie
+    cookie: Option<String>,
+}
+
+impl Response {
+    fn error(status: u16, message: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: message.as_bytes().to_vec(),
+            cookie: None,
+        }
+    }
+
+    fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: "application/json",
+                body,
+                cookie: None,
+            },
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+}
+
+/// What the server checks requests against
+struct Session {
+    token: String,
+    address: SocketAddr,
+    module_dir: PathBuf,
+    webroot: PathBuf,
+}
+
+/// The header lines of a request apd looks at
+#[derive(Default)]
+struct Headers {
+    content_length: usize,
+    content_type: Option<String>,
+    host: Option<String>,
+    origin: Option<String>,
+    cookie: Option<String>,
+    token: Option<String>,
+}
+
+impl Headers {
+    fn add(&mut self, line: &str) {
+        let Some((name, value)) = line.split_once(':') else {
+            return;
+        };
+        let value = value.trim().to_string();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => self.content_length = value.parse().unwrap_or(0),
+            "content-type" => self.content_type = Some(value),
+            "host" => self.host = Some(value),
+            "origin" => self.origin = Some(value),
+            "cookie" => self.cookie = Some(value),
+            "x-apd-token" => self.token = Some(value),
+            _ => {}
+        }
+    }
+
+    fn cookie_token(&self) -> Option<&str> {
+        self.cookie
+            .as_deref()?
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == COOKIE_NAME)
+            .map(|(_, value)| value)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Random token of a session, 32 hex digits
+fn session_token() -> Result<String> {
+    let uuid = fs::read_to_string("/proc/sys/kernel/random/uuid").context("read random uuid")?;
+    Ok(uuid.trim().replace('-', ""))
+}
+
+/// Compare without stopping at the first difference
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// Value of `name` in the query of a request path
+fn query_param<'a>(path: &'a str, name: &str) -> Option<&'a str> {
+    path.split_once('?')?
+        .1
+        .split(['&', '#'])
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Whether `host` names the loopback address the server listens on
+fn local_host(host: &str, port: u16) -> bool {
+    [format!("127.0.0.1:{port}"), format!("localhost:{port}")].contains(&host.to_string())
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// The file below `webroot` a request path names, `None` if it escapes
+fn resolve(webroot: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode(path.split(['?', '#']).next()?)?;
+    let relative = Path::new(path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    let mut file = webroot.join(relative);
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    // symlinks must not lead out of the webroot either
+    let file = file.canonicalize().ok()?;
+    file.starts_with(webroot).then_some(file)
+}
+
+#[derive(Deserialize)]
+struct ExecRequest {
+    script: String,
+}
+
+#[derive(Serialize)]
+struct ExecResponse {
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+/// Run `script` with its output collected, killed once [`EXEC_TIMEOUT`] passes
+fn run_exec(module_dir: &Path, script: &Path) -> Result<ExecResponse> {
+    let mut child = Command::new(assets::BUSYBOX_PATH)
+        .current_dir(module_dir)
+        .arg("sh")
+        .arg(script)
+        .envs(module::get_common_script_envs())
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // read meanwhile, a full pipe would block the script until it is killed
+    let mut stdout = child.stdout.take().context("no stdout pipe")?;
+    let mut stderr = child.stderr.take().context("no stderr pipe")?;
+    let stdout = thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stdout.read_to_end(&mut output);
+        output
+    });
+    let stderr = thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stderr.read_to_end(&mut output);
+        output
+    });
+    let (status, _, timed_out) = module::wait_script(&child, Some(EXEC_TIMEOUT))?;
+    let (stdout, stderr) = (
+        stdout.join().unwrap_or_default(),
+        stderr.join().unwrap_or_default(),
+    );
+    if timed_out {
+        bail!(
+            "did not finish within {}s and was killed",
+            EXEC_TIMEOUT.as_secs()
+        );
+    }
+    Ok(ExecResponse {
+        exit_code: status.code(),
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+    })
+}
+
+fn exec(module_dir: &Path, body: &[u8]) -> Response {
+    let Ok(request) = serde_json::from_slice::<ExecRequest>(body) else {
+        return Response::error(400, "expected {\"script\": \"<name>\"}");
+    };
+    let allowed = module::read_module_prop(module_dir)
+        .ok()
+        .and_then(|prop| prop.get("webui_exec").cloned())
+        .unwrap_or_default();
+    let is_allowed = allowed
+        .split(',')
+        .map(str::trim)
+        .any(|script| !script.is_empty() && script == request.script);
+    if !is_allowed || request.script.contains('/') {
+        return Response::error(403, "script is not listed in webui_exec");
+    }
+    let script = module_dir.join(&request.script);
+    if !script.is_file() {
+        return Response::error(404, "script not found");
+    }
+
+    match run_exec(module_dir, &script) {
+        Ok(response) => Response::json(&response),
+        Err(e) => Response::error(500, &format!("Failed to run {}: {e:#}", request.script)),
+    }
+}
+
+/// Why a request is refused, `None` if it may be served. A valid `?token=`
+/// on a page load counts, it is turned into the cookie.
+fn refusal(session: &Session, method: &str, path: &str, headers: &Headers) -> Option<&'static str> {
+    let port = session.address.port();
+    if !headers
+        .host
+        .as_deref()
+        .is_some_and(|host| local_host(host, port))
+    {
+        return Some("unexpected host");
+    }
+    let query_token = (method != "POST")
+        .then(|| query_param(path, "token"))
+        .flatten();
+    let token = headers
+        .token
+        .as_deref()
+        .or(headers.cookie_token())
+        .or(query_token);
+    if !token.is_some_and(|token| same_token(token, &session.token)) {
+        return Some("missing or wrong session token");
+    }
+    if method == "POST" {
+        if let Some(origin) = &headers.origin
+            && !origin
+                .strip_prefix("http://")
+                .is_some_and(|host| local_host(host, port))
+        {
+            return Some("cross-origin request");
+        }
+        let json = headers
+            .content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+        if !json {
+            return Some("expected Content-Type: application/json");
+        }
+    }
+    None
+}
+
+fn write_response(mut stream: &TcpStream, method: &str, response: &Response) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    )?;
+    if let Some(token) = &response.cookie {
+        write!(
+            stream,
+            "Set-Cookie: {COOKIE_NAME}={token}; Path=/; HttpOnly; SameSite=Strict\r\n"
+        )?;
+    }
+    write!(stream, "Connection: close\r\n\r\n")?;
+    if method != "HEAD" {
+        stream.write_all(&response.body)?;
+    }
+    Ok(())
+}
+
+fn handle(session: &Session, stream: &TcpStream) -> Result<(String, u16)> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    // lines are bounded too, a client must not grow them without end
+    let mut reader = BufReader::new(stream.take(MAX_BODY as u64 * 2));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = Headers::default();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        headers.add(&header);
+    }
+
+    let response = if let Some(refusal) = refusal(session, &method, &path, &headers) {
+        Response::error(403, refusal)
+    } else {
+        match (method.as_str(), path.as_str()) {
+            ("POST", "/exec") if headers.content_length > MAX_BODY => {
+                Response::error(413, "request body too large")
+            }
+            ("POST", "/exec") => {
+                let mut body = vec![0; headers.content_length];
+                reader.read_exact(&mut body)?;
+                exec(&session.module_dir, &body)
+            }
+            ("GET" | "HEAD", _) => match resolve(&session.webroot, &path) {
+                Some(file) => match fs::read(&file) {
+                    Ok(body) => Response {
+                        status: 200,
+                        content_type: mime_type(&file),
+                        body,
+                        cookie: query_param(&path, "token").map(|_| session.token.clone()),
+                    },
+                    Err(_) => Response::error(404, "not found"),
+                },
+                None => Response::error(404, "not found"),
+            },
+            _ => Response::error(405, "method not allowed"),
+        }
+    };
+
+    write_response(stream, &method, &response)?;
+    // the token is not logged
+    let logged = path.split('?').next().unwrap_or_default();
+    Ok((format!("{method} {logged}"), response.status))
+}
+
+fn log_request(log: &Path, request: &str, status: u16) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .and_then(|mut file| writeln!(file, "{now} {request} {status}"));
+    if let Err(e) = result {
+        warn!("Failed to write {}: {}", log.display(), e);
+    }
+}
+
+/// Whether `id` is a module installed in [`defs::MODULE_DIR`]
+fn installed(id: &str) -> Result<bool> {
+    let mut found = false;
+    module::foreach_module(module::ModuleType::All, |module_path| {
+        found |= module_path.file_name().is_some_and(|name| name == id);
+        Ok(())
+    })?;
+    Ok(found)
+}
+
+/// Serve the WebUI of module `id` until killed
+pub fn serve(id: &str, port: u16) -> Result<()> {
+    ensure!(module::valid_module_id(id), "invalid module id {id}");
+    ensure!(installed(id)?, "module {id} is not installed");
+    let module_dir = Path::new(defs::MODULE_DIR).join(id);
+    let webroot = module_dir.join("webroot");
+    ensure!(webroot.is_dir(), "module {id} has no webroot");
+    let webroot = webroot.canonicalize()?;
+    ensure!(
+        webroot.starts_with(Path::new(defs::MODULE_DIR).canonicalize()?),
+        "webroot of {id} leads out of the module dir"
+    );
+
+    let log_dir = Path::new(defs::APATCH_LOG_FOLDER).join("webui");
+    fs::create_dir_all(&log_dir)?;
+    let log = log_dir.join(format!("{id}.log"));
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .with_context(|| format!("Failed to listen on 127.0.0.1:{port}"))?;
+    let address = listener.local_addr()?;
+    let session = Arc::new(Session {
+        token: session_token()?,
+        address,
+        module_dir,
+        webroot,
+    });
+    info!("serving WebUI of {id} on http://{address}");
+    println!("http://{address}/?token={}", session.token);
+
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            let _ = stream.set_write_timeout(Some(READ_TIMEOUT));
+            let _ = write_response(&stream, "GET", &Response::error(503, "too many requests"));
+            continue;
+        }
+        let (session, active, log) = (session.clone(), active.clone(), log.clone());
+        thread::spawn(move || {
+            match handle(&session, &stream) {
+                Ok((request, status)) => log_request(&log, &request, status),
+                Err(e) => warn!("WebUI request failed: {}", e),
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session {
+            token: "0123456789abcdef0123456789abcdef".to_string(),
+            address: "127.0.0.1:8080".parse().unwrap(),
+            module_dir: PathBuf::from("/data/adb/modules/test"),
+            webroot: PathBuf::from("/data/adb/modules/test/webroot"),
+        }
+    }
+
+    fn headers(lines: &[&str]) -> Headers {
+        let mut headers = Headers::default();
+        for line in lines {
+            headers.add(line);
+        }
+        headers
+    }
+
+    #[test]
+    fn page_load_with_token() {
+        let session = session();
+        let request = headers(&["Host: 127.0.0.1:8080"]);
+        let path = format!("/?token={}", session.token);
+        assert_eq!(refusal(&session, "GET", &path, &request), None);
+        assert!(refusal(&session, "GET", "/", &request).is_some());
+        assert!(refusal(&session, "GET", "/?token=wrong", &request).is_some());
+    }
+
+    #[test]
+    fn cookie_authorizes_assets() {
+        let session = session();
+        let cookie = format!("Cookie: other=1; {COOKIE_NAME}={}", session.token);
+        let request = headers(&["Host: localhost:8080", &cookie]);
+        assert_eq!(refusal(&session, "GET", "/app.js", &request), None);
+    }
+
+    #[test]
+    fn foreign_host_is_refused() {
+        let session = session();
+        let cookie = format!("Cookie: {COOKIE_NAME}={}", session.token);
+        let request = headers(&["Host: attacker.example:8080", &cookie]);
+        assert!(refusal(&session, "GET", "/", &request).is_some());
+        assert!(refusal(&session, "GET", "/", &headers(&[&cookie])).is_some());
+    }
+
+    #[test]
+    fn exec_needs_json_and_same_origin() {
+        let session = session();
+        let cookie = format!("Cookie: {COOKIE_NAME}={}", session.token);
+        let json = "Content-Type: application/json";
+        let allowed = headers(&["Host: 127.0.0.1:8080", &cookie, json]);
+        assert_eq!(refusal(&session, "POST", "/exec", &allowed), None);
+
+        // what a form of another page can send without a preflight
+        let plain = headers(&["Host: 127.0.0.1:8080", &cookie, "Content-Type: text/plain"]);
+        assert!(refusal(&session, "POST", "/exec", &plain).is_some());
+
+        let foreign = headers(&[
+            "Host: 127.0.0.1:8080",
+            &cookie,
+            json,
+            "Origin: http://evil.com",
+        ]);
+        assert!(refusal(&session, "POST", "/exec", &foreign).is_some());
+
+        // the token of the query only counts for page loads
+        let query = headers(&["Host: 127.0.0.1:8080", json]);
+        let path = format!("/exec?token={}", session.token);
+        assert!(refusal(&session, "POST", &path, &query).is_some());
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(same_token("abc", "abc"));
+        assert!(!same_token("abc", "abd"));
+        assert!(!same_token("abc", "abcd"));
+    }
+}